@@ -0,0 +1,161 @@
+//! Air Quality Index of the particulate matter concentrations
+//!
+//! The US EPA AQI of PM2.5 and PM10, with the breakpoints revised in 2024.
+//! The index of a reading is the larger of both sub-indices. The computation
+//! is integer only, so it is available without the `float` feature: the
+//! PM2.5 concentration is taken in 0.1 μg/m³ and the PM10 one in μg/m³, the
+//! resolutions at which the EPA truncates them.
+//!
+//! See: [`index_u16()`], [`index()`].
+
+#[cfg(feature = "float")]
+use crate::types::AirInfo;
+use crate::types::AirInfoU16;
+
+/// Upper end of the index
+pub const MAX_INDEX: u16 = 500;
+
+/// (concentration low, concentration high, index low, index high)
+type Breakpoint = (u32, u32, u16, u16);
+
+/// PM2.5 breakpoints [0.1 μg/m³]
+const PM2_5_BREAKPOINTS: [Breakpoint; 6] = [
+    (0, 90, 0, 50),
+    (91, 354, 51, 100),
+    (355, 554, 101, 150),
+    (555, 1254, 151, 200),
+    (1255, 2254, 201, 300),
+    (2255, 3254, 301, 500),
+];
+
+/// PM10 breakpoints [μg/m³]
+const PM10_BREAKPOINTS: [Breakpoint; 6] = [
+    (0, 54, 0, 50),
+    (55, 154, 51, 100),
+    (155, 254, 101, 150),
+    (255, 354, 151, 200),
+    (355, 424, 201, 300),
+    (425, 604, 301, 500),
+];
+
+/// AQI category
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Category {
+    /// 0 to 50
+    Good,
+    /// 51 to 100
+    Moderate,
+    /// 101 to 150
+    UnhealthyForSensitiveGroups,
+    /// 151 to 200
+    Unhealthy,
+    /// 201 to 300
+    VeryUnhealthy,
+    /// 301 and above
+    Hazardous,
+}
+
+impl Category {
+    /// Category of an index
+    pub fn from_index(index: u16) -> Self {
+        match index {
+            0..=50 => Category::Good,
+            51..=100 => Category::Moderate,
+            101..=150 => Category::UnhealthyForSensitiveGroups,
+            151..=200 => Category::Unhealthy,
+            201..=300 => Category::VeryUnhealthy,
+            _ => Category::Hazardous,
+        }
+    }
+
+    /// Name of the category as used by the EPA
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Good => "Good",
+            Category::Moderate => "Moderate",
+            Category::UnhealthyForSensitiveGroups => "Unhealthy for Sensitive Groups",
+            Category::Unhealthy => "Unhealthy",
+            Category::VeryUnhealthy => "Very Unhealthy",
+            Category::Hazardous => "Hazardous",
+        }
+    }
+}
+
+/// Pollutant determining the index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pollutant {
+    /// Mass concentration of PM2.5
+    Pm2_5,
+    /// Mass concentration of PM10
+    Pm10,
+}
+
+/// Air Quality Index of a reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Aqi {
+    /// Index, 0 to [`MAX_INDEX`]
+    pub index: u16,
+    /// Category of the index
+    pub category: Category,
+    /// Pollutant with the larger sub-index
+    pub pollutant: Pollutant,
+}
+
+/// Linear interpolation between the breakpoints, rounded to the nearest
+/// integer and capped at [`MAX_INDEX`]
+fn interpolate(breakpoints: &[Breakpoint], concentration: u32) -> u16 {
+    for &(c_low, c_high, i_low, i_high) in breakpoints {
+        if concentration <= c_high {
+            let range = c_high - c_low;
+            let offset = u32::from(i_high - i_low) * (concentration - c_low);
+            return i_low + ((2 * offset + range) / (2 * range)) as u16;
+        }
+    }
+    MAX_INDEX
+}
+
+/// Sub-index of a PM2.5 concentration [0.1 μg/m³]
+pub fn pm2_5_index(tenths: u32) -> u16 {
+    interpolate(&PM2_5_BREAKPOINTS, tenths)
+}
+
+/// Sub-index of a PM10 concentration [μg/m³]
+pub fn pm10_index(ug: u32) -> u16 {
+    interpolate(&PM10_BREAKPOINTS, ug)
+}
+
+fn combine(pm2_5: u16, pm10: u16) -> Aqi {
+    let (index, pollutant) = if pm10 > pm2_5 { (pm10, Pollutant::Pm10) } else { (pm2_5, Pollutant::Pm2_5) };
+    Aqi {
+        index,
+        category: Category::from_index(index),
+        pollutant,
+    }
+}
+
+/// Index of a reading in the integer format
+///
+/// The PM2.5 concentration has a resolution of 1 μg/m³ in this format.
+/// Returns `None` if a concentration is not available.
+pub fn index_u16(air_info: &AirInfoU16) -> Option<Aqi> {
+    if air_info.mass_pm2_5 == AirInfoU16::NOT_AVAILABLE || air_info.mass_pm10 == AirInfoU16::NOT_AVAILABLE {
+        return None;
+    }
+    Some(combine(
+        pm2_5_index(u32::from(air_info.mass_pm2_5) * 10),
+        pm10_index(u32::from(air_info.mass_pm10)),
+    ))
+}
+
+/// Index of a reading
+///
+/// Returns `None` if a concentration is NaN or negative.
+#[cfg(feature = "float")]
+pub fn index(air_info: &AirInfo) -> Option<Aqi> {
+    let (pm2_5, pm10) = (air_info.mass_pm2_5, air_info.mass_pm10);
+    if !(pm2_5 >= 0.0 && pm10 >= 0.0) {
+        return None;
+    }
+    // Truncated as specified by the EPA, saturating
+    Some(combine(pm2_5_index((pm2_5 * 10.0) as u32), pm10_index(pm10 as u32)))
+}
@@ -0,0 +1,741 @@
+//! Command-line tool for the SPS30 particulate matter sensor
+
+use linux_embedded_hal::{Delay, I2cdev};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::termios::{self, BaudRate, SetArg, SpecialCharacterIndices};
+use sps30_i2c::broker::{self, Broker};
+use sps30_i2c::clock::StdClock;
+use sps30_i2c::exporter::{self, Exporter, METRICS_PATH};
+use sps30_i2c::fan_cleaning::FanCleaning;
+use sps30_i2c::fit::{self, Model, Series};
+use sps30_i2c::http::{self, HttpApi};
+use sps30_i2c::logger::{DataLogger, LogFormat, LoggerConfig, DEFAULT_PREFIX};
+use sps30_i2c::modbus::{self, ModbusServer, DEFAULT_UNIT_ID};
+use sps30_i2c::mqtt::{MqttConfig, MqttPublisher, PublishError, DEFAULT_BASE_TOPIC, DEFAULT_DISCOVERY_PREFIX, DEFAULT_KEEP_ALIVE_S};
+use sps30_i2c::output::{Format, OutputWriter, Record};
+use sps30_i2c::{Error, Sps30};
+use std::env;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::raw::c_int;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_BUS: &str = "/dev/i2c-1";
+const DEFAULT_ADDRESS: u8 = 0x69;
+const DEFAULT_LISTEN: &str = "127.0.0.1:9130";
+const DEFAULT_API_LISTEN: &str = "127.0.0.1:9131";
+const DEFAULT_MODBUS_LISTEN: &str = "127.0.0.1:5020";
+const DEFAULT_SOCKET: &str = "/tmp/sps30.sock";
+const DEFAULT_BROKER: &str = "localhost:1883";
+/// Data-ready polling interval [ms]
+const POLL_INTERVAL_MS: u64 = 100;
+
+const USAGE: &str = "\
+Usage: sps30 <command> [options]
+
+Commands:
+  info           Print the product type, serial number, firmware version,
+                 auto-cleaning interval and status
+  measure        Start measuring and print the measured values
+      --samples <n>        Number of samples, 0 to measure until interrupted (default: 10)
+      --interval <s>       Time between samples [s] (default: 1)
+      --format <format>    table, csv, jsonl, influx or prometheus (default: table)
+  status         Print the device status register
+  clear-status   Clear the device status register
+  clean          Run a fan cleaning and wait until it is finished
+  get-interval   Print the auto-cleaning interval [s]
+  set-interval   Set the auto-cleaning interval
+      --interval <s>       Interval [s], 0 to disable the automatic cleaning
+  sleep          Enter sleep mode
+  wake           Exit sleep mode
+  reset          Reset the device
+  exporter       Serve the measurements to Prometheus until interrupted, then
+                 stop measuring and put the sensor to sleep
+      --listen <addr>      Address to listen on (default: 127.0.0.1:9130)
+  api            Serve a JSON API and a WebSocket stream of the measurements
+                 until interrupted, then stop measuring and put the sensor to
+                 sleep
+      --listen <addr>      Address to listen on (default: 127.0.0.1:9131)
+      --token <token>      Allow the fan cleaning and reset requests with this
+                           bearer token (default: refuse them)
+  modbus         Serve the measurements as a Modbus TCP or RTU server until
+                 interrupted, then stop measuring and put the sensor to sleep
+      --listen <addr>      Modbus TCP address to listen on (default: 127.0.0.1:5020)
+      --serial <device>    Serve Modbus RTU on a serial device instead
+      --baud <rate>        Baud rate of the serial device (default: 9600)
+      --unit <id>          Unit identifier of the RTU server (default: 1)
+  broker         Share the sensor with other processes over a Unix socket until
+                 interrupted, then stop measuring and put the sensor to sleep
+      --socket <path>      Socket path (default: /tmp/sps30.sock)
+  log            Log the measurements to rotating files until interrupted, then
+                 stop measuring and put the sensor to sleep
+      --dir <dir>          Directory of the files
+      --format <format>    csv or binary (default: csv)
+      --prefix <prefix>    Prefix of the file names (default: sps30)
+      --daily <bool>       Start a new file every UTC day (default: true)
+      --max-size <bytes>   Start a new file before one exceeds this size
+      --compress <bool>    Gzip-compress the closed files (default: true)
+      --keep-files <n>     Number of closed files to keep
+      --keep-days <n>      Number of days to keep the closed files
+  mqtt           Publish the measurements to an MQTT broker with Home Assistant
+                 discovery until interrupted, then stop measuring and put the
+                 sensor to sleep
+      --broker <addr>      Broker address (default: localhost:1883)
+      --interval <s>       Time between samples [s] (default: 10)
+      --prefix <prefix>    Discovery prefix (default: homeassistant)
+      --topic <topic>      Base of the state topics (default: sps30)
+      --client-id <id>     Client identifier (default: unique ID of the sensor)
+      --username <user>    User name
+      --password <pass>    Password
+  calibrate   Fit a calibration profile from co-located measurements
+      --sensor <file>      SPS30 series, CSV
+      --reference <file>   Reference instrument series, CSV
+      --serial <serial>    Serial number of the sensor
+      --tolerance <s>      Maximum timestamp difference [s] (default: 30)
+      --model <model>      linear, quadratic, cubic or humidity (default: linear)
+      --output <file>      Write the profile to a file instead of stdout
+
+Sensor options:
+  --bus <device>       I2C bus device (default: /dev/i2c-1)
+  --address <addr>     I2C address, decimal or 0x-prefixed hex (default: 0x69)
+
+Exit codes: 0 success, 1 runtime error, 2 usage error, 3 sensor error
+";
+
+/// Failure of a command, with the process exit code
+enum Failure {
+    Usage(String),
+    Runtime(String),
+    Sensor(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Usage(_) => 2,
+            Failure::Runtime(_) => 1,
+            Failure::Sensor(_) => 3,
+        }
+    }
+}
+
+/// Parsed `--name value` options
+struct Options {
+    values: Vec<(String, String)>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, Failure> {
+        let mut values = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let name = arg
+                .strip_prefix("--")
+                .ok_or_else(|| Failure::Usage(format!("unexpected argument `{}`", arg)))?;
+            let value = args
+                .next()
+                .ok_or_else(|| Failure::Usage(format!("missing value for `--{}`", name)))?;
+            values.push((name.to_string(), value.clone()));
+        }
+        Ok(Options { values })
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.values.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    fn required(&self, name: &str) -> Result<&str, Failure> {
+        self.get(name)
+            .ok_or_else(|| Failure::Usage(format!("missing option `--{}`", name)))
+    }
+
+    fn parsed<T: std::str::FromStr>(&self, name: &str, default: T) -> Result<T, Failure> {
+        match self.get(name) {
+            Some(v) => v
+                .parse()
+                .map_err(|_| Failure::Usage(format!("invalid value `{}` for `--{}`", v, name))),
+            None => Ok(default),
+        }
+    }
+}
+
+type Sensor = Sps30<I2cdev, Delay, StdClock>;
+
+/// Sensor failure while executing `what`
+fn sensor_failure<E: Display>(what: &str) -> impl FnOnce(Error<E>) -> Failure + '_ {
+    move |e| match e {
+        Error::I2C(e) => Failure::Sensor(format!("{}: I2C error: {}", what, e)),
+        Error::ChecksumMismatch => Failure::Sensor(format!("{}: checksum mismatch", what)),
+    }
+}
+
+fn parse_address(value: &str) -> Option<u8> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+    .filter(|&address| address < 0x80)
+}
+
+fn open(options: &Options) -> Result<Sensor, Failure> {
+    let bus = options.get("bus").unwrap_or(DEFAULT_BUS);
+    let address = match options.get("address") {
+        Some(v) => parse_address(v)
+            .ok_or_else(|| Failure::Usage(format!("invalid value `{}` for `--address`", v)))?,
+        None => DEFAULT_ADDRESS,
+    };
+
+    let i2c = I2cdev::new(bus).map_err(|e| Failure::Sensor(format!("{}: {}", bus, e)))?;
+    Ok(Sps30::new_sps30(i2c, Delay).with_address(address).with_clock(StdClock::new()))
+}
+
+fn printable(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+fn print_status(sensor: &mut Sensor) -> Result<(), Failure> {
+    let status = sensor
+        .read_device_status_register()
+        .map_err(sensor_failure("read status register"))?;
+
+    let flag = |set: bool| if set { "error" } else { "ok" };
+    println!("fan speed:    {}", if status.speed { "out of range" } else { "ok" });
+    println!("laser:        {}", flag(status.laser));
+    println!("fan:          {}", flag(status.fan));
+    Ok(())
+}
+
+fn info(sensor: &mut Sensor) -> Result<(), Failure> {
+    let product_type = sensor
+        .read_device_product_type()
+        .map_err(sensor_failure("read product type"))?;
+    let serial = sensor
+        .read_device_serial_number()
+        .map_err(sensor_failure("read serial number"))?;
+    let (major, minor) = sensor
+        .read_firmware_version()
+        .map_err(sensor_failure("read firmware version"))?;
+    let interval = sensor
+        .read_auto_cleaning_interval()
+        .map_err(sensor_failure("read auto-cleaning interval"))?;
+
+    println!("product type: {}", printable(&product_type));
+    println!("serial:       {}", printable(&serial));
+    println!("firmware:     {}.{}", major, minor);
+    println!("cleaning:     every {} s", interval);
+    print_status(sensor)
+}
+
+fn measure(sensor: &mut Sensor, samples: u32, interval: f64, format: Format) -> Result<(), Failure> {
+    let serial = sensor
+        .read_device_serial_number()
+        .map_err(sensor_failure("read serial number"))?;
+    let serial = printable(&serial);
+    let mut writer = OutputWriter::new(io::stdout(), format);
+    install_shutdown_handler()?;
+
+    sensor.ensure_measuring().map_err(sensor_failure("start measurement"))?;
+
+    let mut res = Ok(());
+    let mut count = 0;
+    while (samples == 0 || count < samples) && !SHUTDOWN.load(Ordering::SeqCst) {
+        if count > 0 {
+            sleep_unless_shutdown(Duration::from_secs_f64(interval));
+        }
+        res = read_sample(sensor, &serial, &mut writer);
+        if res.is_err() {
+            break;
+        }
+        count += 1;
+    }
+
+    // Leave the sensor idle even if reading failed or was interrupted
+    let stopped = sensor.stop_measurement().map_err(sensor_failure("stop measurement"));
+    res.and(stopped)
+}
+
+fn read_sample<W: Write>(sensor: &mut Sensor, serial: &str, writer: &mut OutputWriter<W>) -> Result<(), Failure> {
+    while !sensor.read_data_ready_flag().map_err(sensor_failure("read data-ready flag"))? {
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    }
+    let reading = sensor
+        .read_validated_values()
+        .map_err(sensor_failure("read measured values"))?;
+
+    let record = Record {
+        timestamp: unix_time_ms(),
+        serial,
+        air_info: reading.air_info,
+        flags: Some(reading.flags),
+    };
+    writer.write(&record).map_err(|e| Failure::Runtime(format!("write output: {}", e)))
+}
+
+/// Sleep in steps of the polling interval, returning early on SIGINT or
+/// SIGTERM
+fn sleep_unless_shutdown(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        thread::sleep((deadline - now).min(Duration::from_millis(POLL_INTERVAL_MS)));
+    }
+}
+
+fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn clean(sensor: &mut Sensor) -> Result<(), Failure> {
+    // The cleaning only runs in measurement mode. Unless the sensor was
+    // measuring already, it is returned to idle afterwards.
+    let started_measurement = !sensor.ensure_measuring().map_err(sensor_failure("start measurement"))?;
+
+    let cleaned = sensor.start_fan_cleaning().map_err(sensor_failure("start fan cleaning"));
+    if cleaned.is_ok() {
+        eprintln!("cleaning...");
+        thread::sleep(Duration::from_millis(u64::from(FanCleaning::default().duration_ms)));
+    }
+
+    if started_measurement {
+        // Leave the sensor idle even if the cleaning failed
+        let stopped = sensor.stop_measurement().map_err(sensor_failure("stop measurement"));
+        return cleaned.and(stopped);
+    }
+    cleaned
+}
+
+/// Transport of the Modbus server
+enum ModbusTransport {
+    Tcp(String),
+    Rtu { device: String, baud: BaudRate, unit: u8 },
+}
+
+/// Command talking to the sensor, with its validated options
+enum SensorCommand {
+    Info,
+    Measure { samples: u32, interval: f64, format: Format },
+    Status,
+    ClearStatus,
+    Clean,
+    GetInterval,
+    SetInterval(u32),
+    Sleep,
+    Wake,
+    Reset,
+    Exporter(String),
+    Api { listen: String, token: Option<String> },
+    Modbus(ModbusTransport),
+    Broker(String),
+    Log(LoggerConfig),
+    Mqtt { broker: String, interval: u64, config: MqttConfig },
+}
+
+impl SensorCommand {
+    fn parse(command: &str, options: &Options) -> Result<Self, Failure> {
+        Ok(match command {
+            "info" => SensorCommand::Info,
+            "measure" => {
+                let samples = options.parsed("samples", 10)?;
+                let interval: f64 = options.parsed("interval", 1.0)?;
+                if !(interval >= 0.0 && interval.is_finite()) {
+                    return Err(Failure::Usage(format!("invalid value `{}` for `--interval`", interval)));
+                }
+                let format = options.parsed("format", Format::Table)?;
+                SensorCommand::Measure { samples, interval, format }
+            }
+            "status" => SensorCommand::Status,
+            "clear-status" => SensorCommand::ClearStatus,
+            "clean" => SensorCommand::Clean,
+            "get-interval" => SensorCommand::GetInterval,
+            "set-interval" => {
+                options.required("interval")?;
+                SensorCommand::SetInterval(options.parsed("interval", 0)?)
+            }
+            "sleep" => SensorCommand::Sleep,
+            "wake" => SensorCommand::Wake,
+            "reset" => SensorCommand::Reset,
+            "exporter" => SensorCommand::Exporter(options.get("listen").unwrap_or(DEFAULT_LISTEN).to_string()),
+            "api" => SensorCommand::Api {
+                listen: options.get("listen").unwrap_or(DEFAULT_API_LISTEN).to_string(),
+                token: options.get("token").map(str::to_string),
+            },
+            "modbus" => SensorCommand::Modbus(match options.get("serial") {
+                Some(device) => {
+                    let baud = options.parsed("baud", 9_600)?;
+                    let baud = baud_rate(baud).ok_or_else(|| Failure::Usage(format!("unsupported baud rate `{}`", baud)))?;
+                    let unit = options.parsed("unit", DEFAULT_UNIT_ID)?;
+                    if !(1..=247).contains(&unit) {
+                        return Err(Failure::Usage(format!("invalid value `{}` for `--unit`", unit)));
+                    }
+                    ModbusTransport::Rtu { device: device.to_string(), baud, unit }
+                }
+                None => ModbusTransport::Tcp(options.get("listen").unwrap_or(DEFAULT_MODBUS_LISTEN).to_string()),
+            }),
+            "broker" => SensorCommand::Broker(options.get("socket").unwrap_or(DEFAULT_SOCKET).to_string()),
+            "log" => {
+                let mut config = LoggerConfig::new(options.required("dir")?);
+                config.format = match options.get("format").unwrap_or("csv") {
+                    "csv" => LogFormat::Csv,
+                    "binary" => LogFormat::Binary,
+                    f => return Err(Failure::Usage(format!("unknown format `{}`", f))),
+                };
+                config.prefix = options.get("prefix").unwrap_or(DEFAULT_PREFIX).to_string();
+                config.rotate_daily = options.parsed("daily", true)?;
+                config.max_file_size = options.get("max-size").map(|_| options.parsed("max-size", 0)).transpose()?;
+                config.compress = options.parsed("compress", true)?;
+                config.max_files = options.get("keep-files").map(|_| options.parsed("keep-files", 0)).transpose()?;
+                config.max_age_days = options.get("keep-days").map(|_| options.parsed("keep-days", 0)).transpose()?;
+                SensorCommand::Log(config)
+            }
+            "mqtt" => {
+                let interval: u64 = options.parsed("interval", 10)?;
+                if interval == 0 {
+                    return Err(Failure::Usage("invalid value `0` for `--interval`".to_string()));
+                }
+                // The broker drops clients silent for 1.5 keep alive intervals
+                let keep_alive_s = interval.saturating_mul(2).clamp(u64::from(DEFAULT_KEEP_ALIVE_S), u64::from(u16::MAX));
+                let config = MqttConfig {
+                    discovery_prefix: options.get("prefix").unwrap_or(DEFAULT_DISCOVERY_PREFIX).to_string(),
+                    base_topic: options.get("topic").unwrap_or(DEFAULT_BASE_TOPIC).to_string(),
+                    client_id: options.get("client-id").map(str::to_string),
+                    keep_alive_s: keep_alive_s as u16,
+                    username: options.get("username").map(str::to_string),
+                    password: options.get("password").map(str::to_string),
+                };
+                let broker = options.get("broker").unwrap_or(DEFAULT_BROKER).to_string();
+                SensorCommand::Mqtt { broker, interval, config }
+            }
+            c => return Err(Failure::Usage(format!("unknown command `{}`", c))),
+        })
+    }
+
+    fn run(self, mut sensor: Sensor) -> Result<(), Failure> {
+        match self {
+            SensorCommand::Info => info(&mut sensor),
+            SensorCommand::Measure { samples, interval, format } => measure(&mut sensor, samples, interval, format),
+            SensorCommand::Status => print_status(&mut sensor),
+            SensorCommand::ClearStatus => sensor
+                .clear_device_status_register()
+                .map_err(sensor_failure("clear status register")),
+            SensorCommand::Clean => clean(&mut sensor),
+            SensorCommand::GetInterval => {
+                let interval = sensor
+                    .read_auto_cleaning_interval()
+                    .map_err(sensor_failure("read auto-cleaning interval"))?;
+                println!("{}", interval);
+                Ok(())
+            }
+            SensorCommand::SetInterval(interval) => sensor
+                .write_auto_cleaning_interval(interval)
+                .map_err(sensor_failure("write auto-cleaning interval")),
+            SensorCommand::Sleep => sensor.sleep().map_err(sensor_failure("sleep")),
+            SensorCommand::Wake => sensor.wake_up().map_err(sensor_failure("wake up")),
+            SensorCommand::Reset => sensor.device_reset().map_err(sensor_failure("reset")),
+            SensorCommand::Exporter(listen) => export(sensor, &listen),
+            SensorCommand::Api { listen, token } => serve_api(sensor, &listen, token.as_deref()),
+            SensorCommand::Modbus(transport) => serve_modbus(sensor, transport),
+            SensorCommand::Broker(socket) => serve_broker(sensor, &socket),
+            SensorCommand::Log(config) => log(sensor, config),
+            SensorCommand::Mqtt { broker, interval, config } => publish(sensor, &broker, interval, &config),
+        }
+    }
+}
+
+/// Set by SIGINT and SIGTERM
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_shutdown_signal(_: c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+fn install_shutdown_handler() -> Result<(), Failure> {
+    let action = SigAction::new(SigHandler::Handler(on_shutdown_signal), SaFlags::empty(), SigSet::empty());
+    for &signal in [Signal::SIGINT, Signal::SIGTERM].iter() {
+        // The handler only stores to an atomic, which is async-signal-safe
+        #[allow(unsafe_code)]
+        unsafe { sigaction(signal, &action) }
+            .map_err(|e| Failure::Runtime(format!("install {} handler: {}", signal, e)))?;
+    }
+    Ok(())
+}
+
+fn export(sensor: Sensor, listen: &str) -> Result<(), Failure> {
+    let listener = TcpListener::bind(listen).map_err(|e| Failure::Runtime(format!("{}: {}", listen, e)))?;
+    install_shutdown_handler()?;
+
+    let mut exporter = Exporter::new(sensor);
+    exporter.start().map_err(sensor_failure("start exporter"))?;
+    eprintln!("serving http://{}{}", listen, METRICS_PATH);
+
+    let served = exporter::serve(&mut exporter, &listener, &SHUTDOWN)
+        .map_err(|e| Failure::Runtime(format!("{}: {}", listen, e)));
+
+    // Stop measuring even if serving failed
+    eprintln!("shutting down");
+    let stopped = exporter.shutdown().map(drop).map_err(sensor_failure("shut down"));
+    served.and(stopped)
+}
+
+fn serve_api(sensor: Sensor, listen: &str, token: Option<&str>) -> Result<(), Failure> {
+    let listener = TcpListener::bind(listen).map_err(|e| Failure::Runtime(format!("{}: {}", listen, e)))?;
+    install_shutdown_handler()?;
+
+    let mut api = HttpApi::new(sensor);
+    if let Some(token) = token {
+        api = api.with_token(token);
+    }
+    api.start().map_err(sensor_failure("start API"))?;
+    eprintln!("serving http://{}/api", listen);
+
+    let served = http::serve(&mut api, &listener, &SHUTDOWN).map_err(|e| Failure::Runtime(format!("{}: {}", listen, e)));
+
+    // Stop measuring even if serving failed
+    eprintln!("shutting down");
+    let stopped = api.shutdown().map(drop).map_err(sensor_failure("shut down"));
+    served.and(stopped)
+}
+
+fn baud_rate(baud: u32) -> Option<BaudRate> {
+    Some(match baud {
+        1_200 => BaudRate::B1200,
+        2_400 => BaudRate::B2400,
+        4_800 => BaudRate::B4800,
+        9_600 => BaudRate::B9600,
+        19_200 => BaudRate::B19200,
+        38_400 => BaudRate::B38400,
+        57_600 => BaudRate::B57600,
+        115_200 => BaudRate::B115200,
+        _ => return None,
+    })
+}
+
+/// Open a serial device in raw mode, 8N1, with reads timing out after 0.1 s
+fn open_serial(device: &str, baud: BaudRate) -> Result<File, Failure> {
+    let failure = |e: &dyn Display| Failure::Runtime(format!("{}: {}", device, e));
+    let port = OpenOptions::new().read(true).write(true).open(device).map_err(|e| failure(&e))?;
+
+    let mut settings = termios::tcgetattr(port.as_raw_fd()).map_err(|e| failure(&e))?;
+    termios::cfmakeraw(&mut settings);
+    termios::cfsetspeed(&mut settings, baud).map_err(|e| failure(&e))?;
+    settings.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+    settings.control_chars[SpecialCharacterIndices::VTIME as usize] = 1;
+    termios::tcsetattr(port.as_raw_fd(), SetArg::TCSANOW, &settings).map_err(|e| failure(&e))?;
+    Ok(port)
+}
+
+fn serve_modbus(sensor: Sensor, transport: ModbusTransport) -> Result<(), Failure> {
+    let (server, served) = match transport {
+        ModbusTransport::Tcp(listen) => {
+            let listener = TcpListener::bind(&listen).map_err(|e| Failure::Runtime(format!("{}: {}", listen, e)))?;
+            install_shutdown_handler()?;
+
+            let mut server = ModbusServer::new(sensor);
+            server.start().map_err(sensor_failure("start Modbus server"))?;
+            eprintln!("serving Modbus TCP on {}", listen);
+            let served = modbus::serve_tcp(&mut server, &listener, &SHUTDOWN);
+            (server, served.map_err(|e| Failure::Runtime(format!("{}: {}", listen, e))))
+        }
+        ModbusTransport::Rtu { device, baud, unit } => {
+            let mut port = open_serial(&device, baud)?;
+            install_shutdown_handler()?;
+
+            let mut server = ModbusServer::new(sensor).with_unit_id(unit);
+            server.start().map_err(sensor_failure("start Modbus server"))?;
+            eprintln!("serving Modbus RTU unit {} on {}", unit, device);
+            let served = modbus::serve_rtu(&mut server, &mut port, &SHUTDOWN);
+            (server, served.map_err(|e| Failure::Runtime(format!("{}: {}", device, e))))
+        }
+    };
+
+    // Stop measuring even if serving failed
+    eprintln!("shutting down");
+    let stopped = server.shutdown().map(drop).map_err(sensor_failure("shut down"));
+    served.and(stopped)
+}
+
+fn serve_broker(sensor: Sensor, socket: &str) -> Result<(), Failure> {
+    let failure = |e: io::Error| Failure::Runtime(format!("{}: {}", socket, e));
+    // A socket nobody listens on is left over from a previous run
+    if UnixStream::connect(socket).is_ok() {
+        return Err(Failure::Runtime(format!("{}: a broker is already running", socket)));
+    }
+    let _ = fs::remove_file(socket);
+    let listener = broker::bind(socket).map_err(failure)?;
+    install_shutdown_handler()?;
+
+    let mut broker = Broker::new(sensor);
+    broker.start().map_err(sensor_failure("start broker"))?;
+    eprintln!("serving {}", socket);
+
+    let served = broker::serve(&mut broker, &listener, &SHUTDOWN).map_err(failure);
+    let _ = fs::remove_file(socket);
+
+    // Stop measuring even if serving failed
+    eprintln!("shutting down");
+    let stopped = broker.shutdown().map(drop).map_err(sensor_failure("shut down"));
+    served.and(stopped)
+}
+
+fn log(mut sensor: Sensor, config: LoggerConfig) -> Result<(), Failure> {
+    let dir = config.dir.display().to_string();
+    let failure = |e: io::Error| Failure::Runtime(format!("{}: {}", dir, e));
+    let mut logger = DataLogger::open(config).map_err(failure)?;
+    install_shutdown_handler()?;
+
+    sensor.ensure_measuring().map_err(sensor_failure("start measurement"))?;
+    eprintln!("logging to {}", dir);
+
+    let mut logged = Ok(());
+    while logged.is_ok() && !SHUTDOWN.load(Ordering::SeqCst) {
+        logged = match sensor.read_data_ready_flag() {
+            Ok(true) => match sensor.read_validated_values() {
+                Ok(reading) => logger.log(unix_time_ms(), &reading).map_err(failure),
+                Err(e) => Err(sensor_failure("read measured values")(e)),
+            },
+            Ok(false) => Ok(()),
+            Err(e) => Err(sensor_failure("read data-ready flag")(e)),
+        };
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    }
+
+    // Stop measuring and keep the logged readings even if logging failed
+    eprintln!("shutting down");
+    let closed = logger.close().map_err(failure);
+    let stopped = sensor
+        .stop_measurement()
+        .and_then(|_| sensor.sleep())
+        .map_err(sensor_failure("shut down"));
+    logged.and(closed).and(stopped)
+}
+
+/// Sensor or MQTT broker failure while executing `what`
+fn publish_failure<E: Display>(what: &str) -> impl FnOnce(PublishError<E>) -> Failure + '_ {
+    move |e| match e {
+        PublishError::Sensor(e) => sensor_failure(what)(e),
+        PublishError::Mqtt(e) => Failure::Runtime(format!("{}: {}", what, e)),
+    }
+}
+
+fn publish(sensor: Sensor, broker: &str, interval: u64, config: &MqttConfig) -> Result<(), Failure> {
+    let failure = |e: io::Error| Failure::Runtime(format!("{}: {}", broker, e));
+    let stream = TcpStream::connect(broker).map_err(failure)?;
+    // A broker that stops answering must not block the pings forever
+    let timeout = match config.keep_alive_s {
+        0 => DEFAULT_KEEP_ALIVE_S,
+        keep_alive_s => keep_alive_s,
+    };
+    stream.set_read_timeout(Some(Duration::from_secs(u64::from(timeout)))).map_err(failure)?;
+    install_shutdown_handler()?;
+
+    let mut publisher = MqttPublisher::start(sensor, stream, config).map_err(publish_failure("start publisher"))?;
+    eprintln!("publishing to {} under {}", broker, publisher.home_assistant().state_topic("#"));
+
+    let interval = Duration::from_secs(interval);
+    let mut next_sample = Instant::now();
+    let mut published = Ok(());
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        if Instant::now() >= next_sample {
+            match publisher.poll() {
+                Ok(true) => next_sample += interval,
+                Ok(false) => {}
+                Err(e) => {
+                    published = Err(publish_failure("publish")(e));
+                    break;
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    }
+
+    // Stop measuring even if publishing failed
+    eprintln!("shutting down");
+    let stopped = publisher.shutdown().map(drop).map_err(publish_failure("shut down"));
+    published.and(stopped)
+}
+
+fn read_series(path: &str) -> Result<Series, Failure> {
+    let file = File::open(path).map_err(|e| Failure::Runtime(format!("{}: {}", path, e)))?;
+    Series::from_csv(BufReader::new(file)).map_err(|e| Failure::Runtime(format!("{}: {}", path, e)))
+}
+
+fn calibrate(options: &Options) -> Result<(), Failure> {
+    let sensor = read_series(options.required("sensor")?)?;
+    let reference = read_series(options.required("reference")?)?;
+    let serial = options.required("serial")?;
+    let tolerance: f64 = options.parsed("tolerance", 30.0)?;
+    let model = match options.get("model").unwrap_or("linear") {
+        "linear" => Model::Linear,
+        "quadratic" => Model::Polynomial(2),
+        "cubic" => Model::Polynomial(3),
+        "humidity" => Model::Humidity,
+        m => return Err(Failure::Usage(format!("unknown model `{}`", m))),
+    };
+
+    let calibration = fit::calibrate(&sensor, &reference, serial.as_bytes(), tolerance, model)
+        .map_err(|e| Failure::Runtime(e.to_string()))?;
+
+    eprintln!("{:<14} {:<14} {:>8} {:>10} {:>10} {:>8}", "field", "model", "r2", "rmse", "bias", "samples");
+    for report in &calibration.reports {
+        for f in &report.fits {
+            eprintln!(
+                "{:<14} {:<14} {:>8.4} {:>10.3} {:>10.3} {:>8}",
+                report.field, f.model.to_string(), f.r_squared, f.rmse, f.bias, f.samples
+            );
+        }
+    }
+
+    let json = serde_json::to_string_pretty(&calibration.profile)
+        .map_err(|e| Failure::Runtime(e.to_string()))?;
+    let mut out: Box<dyn Write> = match options.get("output") {
+        Some(path) => Box::new(File::create(path).map_err(|e| Failure::Runtime(format!("{}: {}", path, e)))?),
+        None => Box::new(io::stdout()),
+    };
+    writeln!(out, "{}", json).map_err(|e| Failure::Runtime(e.to_string()))
+}
+
+fn run(args: &[String]) -> Result<(), Failure> {
+    let (command, rest) = args
+        .split_first()
+        .ok_or_else(|| Failure::Usage("missing command".into()))?;
+    if matches!(command.as_str(), "help" | "--help" | "-h") {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    let options = Options::parse(rest)?;
+
+    match command.as_str() {
+        "calibrate" => calibrate(&options),
+        c => {
+            let command = SensorCommand::parse(c, &options)?;
+            command.run(open(&options)?)
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if let Err(failure) = run(&args) {
+        match &failure {
+            Failure::Usage(message) => eprintln!("error: {}\n\n{}", message, USAGE),
+            Failure::Runtime(message) | Failure::Sensor(message) => eprintln!("error: {}", message),
+        }
+        process::exit(failure.exit_code());
+    }
+}
@@ -0,0 +1,698 @@
+//! Unix socket broker sharing one sensor between processes
+//!
+//! A [`Broker`] owns the driver, samples the sensor whenever the data-ready
+//! flag is set and serves up to 16 clients on a Unix domain socket bound with
+//! [`bind()`], which only lets the owner connect. [`BrokerClient`] is the
+//! client side.
+//!
+//! The protocol is line based. A client opens with `HELLO <version>`, the
+//! broker answers `WELCOME <version> <client id>` if it speaks
+//! [`PROTOCOL_VERSION`] and closes the connection otherwise. Every request
+//! then gets exactly one response line, in order:
+//!
+//! | Request              | Response                                          |
+//! |----------------------|---------------------------------------------------|
+//! | `INFO`               | `INFO <product type> <serial> <major>.<minor>`    |
+//! | `READING`            | `READING <timestamp> <flags> <values>`            |
+//! | `STATUS`             | `STATUS <speed> <laser> <fan>`, each `0` or `1`   |
+//! | `INTERVAL`           | `INTERVAL <auto-cleaning interval [s]>`           |
+//! | `SUBSCRIBE`          | `OK`, then every new reading is pushed            |
+//! | `UNSUBSCRIBE`        | `OK`                                              |
+//! | `ACQUIRE`            | `OK` if the client now holds the control lease    |
+//! | `RELEASE`            | `OK`                                              |
+//! | `RESET`              | `OK`, needs the lease                             |
+//! | `SET-INTERVAL <s>`   | `OK`, needs the lease                             |
+//!
+//! Readings carry the time [ms since the Unix epoch], the raw
+//! [`QualityFlags`] bits and the ten [`AirInfo`] values in the order of
+//! [`FIELD_NAMES`]. Refused requests are answered with `ERROR <code>`, the
+//! codes are `unsupported_version`, `hello_required`, `unknown_command`,
+//! `invalid_argument`, `no_reading`, `busy`, `lease_required`, `i2c` and
+//! `checksum_mismatch`.
+//!
+//! Lines starting with `EVENT` are pushed by the broker between responses:
+//! `EVENT READING ...` to subscribers, and `EVENT RESET`,
+//! `EVENT INTERVAL <s>` and `EVENT LEASE <client id or "none">` to every
+//! client.
+//!
+//! The privileged requests are arbitrated with a single control lease. It is
+//! held until released, until its holder disconnects or until it was not
+//! renewed by another `ACQUIRE` for the lease timeout. Requests of the other
+//! clients are refused with `busy` meanwhile.
+//!
+//! [`QualityFlags`]: ../quality/struct.QualityFlags.html
+//! [`AirInfo`]: ../struct.AirInfo.html
+//! [`FIELD_NAMES`]: ../calibration/constant.FIELD_NAMES.html
+
+use crate::clock::Clock;
+use crate::quality::{QualityFlags, Reading};
+use crate::types::{AirInfo, Error, StatusRegisterResult};
+use crate::Sps30;
+use embedded_hal::blocking::{delay, i2c};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::fs::{self, DirBuilder, Permissions};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Version of the protocol
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Interval at which the data-ready flag is polled [ms]
+pub const POLL_INTERVAL_MS: u64 = 250;
+/// Default time a control lease lasts without renewal [ms]
+pub const DEFAULT_LEASE_TIMEOUT_MS: u64 = 30_000;
+/// Maximum length of a request line [bytes]
+const MAX_LINE_LEN: usize = 256;
+/// Sleep between accept attempts while idle [ms]
+const ACCEPT_INTERVAL_MS: u64 = 10;
+/// Clients served at once, further connections are closed
+const MAX_CLIENTS: usize = 16;
+
+/// Connection of a client
+struct Connection {
+    id: u32,
+    stream: UnixStream,
+    inbox: Vec<u8>,
+    welcomed: bool,
+    subscribed: bool,
+    /// A line could not be written whole, the stream is out of sync
+    failed: bool,
+}
+
+/// Holder of the control lease
+struct Lease {
+    client: u32,
+    expires: Instant,
+}
+
+/// Sensor owner serving the clients
+pub struct Broker<I2C, D, C> {
+    sensor: Sps30<I2C, D, C>,
+    product_type: String,
+    serial: String,
+    firmware: (u8, u8),
+    reading: Option<Reading>,
+    connections: Vec<Connection>,
+    next_id: u32,
+    lease: Option<Lease>,
+    lease_timeout: Duration,
+}
+
+impl<I2C, D, C, E> Broker<I2C, D, C>
+where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+D: delay::DelayMs<u8>,
+C: Clock {
+    /// Create a broker owning the driver
+    pub fn new(sensor: Sps30<I2C, D, C>) -> Self {
+        Broker {
+            sensor,
+            product_type: String::new(),
+            serial: String::new(),
+            firmware: (0, 0),
+            reading: None,
+            connections: Vec::new(),
+            next_id: 1,
+            lease: None,
+            lease_timeout: Duration::from_millis(DEFAULT_LEASE_TIMEOUT_MS),
+        }
+    }
+
+    /// Set the time a control lease lasts without renewal
+    pub fn with_lease_timeout(mut self, timeout: Duration) -> Self {
+        self.lease_timeout = timeout;
+        self
+    }
+
+    /// Wake the sensor up if it is sleeping, start measuring and read its
+    /// identity
+    ///
+    /// See [`Sps30::ensure_measuring()`](../struct.Sps30.html#method.ensure_measuring).
+    pub fn start(&mut self) -> Result<(), Error<E>> {
+        self.sensor.ensure_measuring()?;
+
+        self.product_type = word(&self.sensor.read_device_product_type()?);
+        self.serial = word(&self.sensor.read_device_serial_number()?);
+        self.firmware = self.sensor.read_firmware_version()?;
+        Ok(())
+    }
+
+    /// Read a sample if new values are ready and push it to the
+    /// subscribers, returning whether one was read
+    pub fn poll(&mut self) -> Result<bool, Error<E>> {
+        if !self.sensor.read_data_ready_flag()? {
+            return Ok(false);
+        }
+
+        let mut reading = self.sensor.read_validated_values()?;
+        reading.timestamp = Some(unix_time_ms());
+        self.reading = Some(reading);
+
+        let event = format!("EVENT {}", format_reading(&reading));
+        for connection in self.connections.iter_mut().filter(|c| c.subscribed) {
+            // Dropped below
+            let _ = connection.send(&event);
+        }
+        self.drop_failed();
+        Ok(true)
+    }
+
+    /// Last sample, stamped with the time [ms since the Unix epoch]
+    pub fn reading(&self) -> Option<&Reading> {
+        self.reading.as_ref()
+    }
+
+    /// Number of connected clients
+    pub fn clients(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Client holding the control lease
+    pub fn lease_holder(&mut self) -> Option<u32> {
+        self.expire_lease();
+        self.lease.as_ref().map(|lease| lease.client)
+    }
+
+    /// Disconnect the clients, stop measuring, put the sensor to sleep and
+    /// return the driver
+    pub fn shutdown(mut self) -> Result<Sps30<I2C, D, C>, Error<E>> {
+        self.connections.clear();
+        self.sensor.stop_measurement()?;
+        self.sensor.sleep()?;
+        Ok(self.sensor)
+    }
+
+    /// Add a client connection, unless there are too many
+    fn accept(&mut self, stream: UnixStream) -> io::Result<()> {
+        if self.connections.len() >= MAX_CLIENTS {
+            return Ok(());
+        }
+        stream.set_nonblocking(true)?;
+        self.connections.push(Connection {
+            id: self.next_id,
+            stream,
+            inbox: Vec::new(),
+            welcomed: false,
+            subscribed: false,
+            failed: false,
+        });
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        Ok(())
+    }
+
+    /// Answer the complete requests of every client, dropping the ones that
+    /// are gone
+    fn receive(&mut self) {
+        for i in 0..self.connections.len() {
+            // Possibly failed by an event pushed to it meanwhile
+            if !self.connections[i].failed && !self.receive_from(i).unwrap_or(false) {
+                self.connections[i].failed = true;
+            }
+        }
+        self.drop_failed();
+    }
+
+    /// Drop the failed connections, giving up their leases
+    fn drop_failed(&mut self) {
+        // Giving up a lease pushes an event, which may fail more connections
+        while let Some(i) = self.connections.iter().position(|c| c.failed) {
+            let connection = self.connections.remove(i);
+            self.release(connection.id);
+        }
+    }
+
+    /// Answer the complete requests of a client, returns false once it is
+    /// gone
+    fn receive_from(&mut self, index: usize) -> io::Result<bool> {
+        let mut buffer = [0; MAX_LINE_LEN + 1];
+        loop {
+            let connection = &mut self.connections[index];
+            // Room for one line and its newline, the rest is read once the
+            // complete lines are answered
+            let room = MAX_LINE_LEN + 1 - connection.inbox.len().min(MAX_LINE_LEN + 1);
+            if room == 0 {
+                break;
+            }
+            match connection.stream.read(&mut buffer[..room]) {
+                Ok(0) => return Ok(false),
+                Ok(len) => connection.inbox.extend_from_slice(&buffer[..len]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        while let Some(end) = self.connections[index].inbox.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.connections[index].inbox.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+            let (response, keep) = self.request(index, &line);
+            self.connections[index].send(&response)?;
+            if !keep || self.connections[index].failed {
+                return Ok(false);
+            }
+        }
+        Ok(self.connections[index].inbox.len() <= MAX_LINE_LEN)
+    }
+
+    /// Answer a request line, returning the response and whether the
+    /// connection is kept
+    fn request(&mut self, index: usize, line: &str) -> (String, bool) {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let argument = words.next();
+        let client = self.connections[index].id;
+
+        if !self.connections[index].welcomed {
+            return match (command, argument.and_then(|v| v.parse::<u32>().ok())) {
+                ("HELLO", Some(PROTOCOL_VERSION)) => {
+                    self.connections[index].welcomed = true;
+                    (format!("WELCOME {} {}", PROTOCOL_VERSION, client), true)
+                }
+                ("HELLO", _) => ("ERROR unsupported_version".to_string(), false),
+                _ => ("ERROR hello_required".to_string(), false),
+            };
+        }
+
+        let res = match command {
+            "INFO" => Ok(format!(
+                "INFO {} {} {}.{}",
+                self.product_type, self.serial, self.firmware.0, self.firmware.1
+            )),
+            "READING" => match &self.reading {
+                Some(reading) => Ok(format_reading(reading)),
+                None => return ("ERROR no_reading".to_string(), true),
+            },
+            "STATUS" => self.sensor.read_device_status_register().map(|status| {
+                format!("STATUS {} {} {}", status.speed as u8, status.laser as u8, status.fan as u8)
+            }),
+            "INTERVAL" => self
+                .sensor
+                .read_auto_cleaning_interval()
+                .map(|interval| format!("INTERVAL {}", interval)),
+            "SUBSCRIBE" | "UNSUBSCRIBE" => {
+                self.connections[index].subscribed = command == "SUBSCRIBE";
+                Ok("OK".to_string())
+            }
+            "ACQUIRE" => {
+                if let Some(holder) = self.lease_holder().filter(|&holder| holder != client) {
+                    return (format!("ERROR busy {}", holder), true);
+                }
+                let new = self.lease.is_none();
+                self.lease = Some(Lease { client, expires: Instant::now() + self.lease_timeout });
+                if new {
+                    self.broadcast(&format!("EVENT LEASE {}", client));
+                }
+                Ok("OK".to_string())
+            }
+            "RELEASE" => {
+                self.release(client);
+                Ok("OK".to_string())
+            }
+            "RESET" | "SET-INTERVAL" => {
+                match self.lease_holder() {
+                    Some(holder) if holder == client => {}
+                    Some(holder) => return (format!("ERROR busy {}", holder), true),
+                    None => return ("ERROR lease_required".to_string(), true),
+                }
+
+                if command == "RESET" {
+                    // The device is idle after a reset
+                    let reset = self.sensor.device_reset().and_then(|_| self.sensor.start_measurement());
+                    reset.map(|_| {
+                        self.broadcast("EVENT RESET");
+                        "OK".to_string()
+                    })
+                } else {
+                    let interval = match argument.and_then(|v| v.parse::<u32>().ok()) {
+                        Some(interval) => interval,
+                        None => return ("ERROR invalid_argument".to_string(), true),
+                    };
+                    self.sensor.write_auto_cleaning_interval(interval).map(|_| {
+                        self.broadcast(&format!("EVENT INTERVAL {}", interval));
+                        "OK".to_string()
+                    })
+                }
+            }
+            _ => return ("ERROR unknown_command".to_string(), true),
+        };
+
+        match res {
+            Ok(response) => (response, true),
+            Err(Error::I2C(_)) => ("ERROR i2c".to_string(), true),
+            Err(Error::ChecksumMismatch) => ("ERROR checksum_mismatch".to_string(), true),
+        }
+    }
+
+    /// Give up the lease if the client holds it
+    fn release(&mut self, client: u32) {
+        if self.lease.as_ref().map_or(false, |lease| lease.client == client) {
+            self.lease = None;
+            self.broadcast("EVENT LEASE none");
+        }
+    }
+
+    fn expire_lease(&mut self) {
+        if self.lease.as_ref().map_or(false, |lease| Instant::now() >= lease.expires) {
+            self.lease = None;
+            self.broadcast("EVENT LEASE none");
+        }
+    }
+
+    /// Push an event to every welcomed client
+    ///
+    /// The clients that cannot take it are marked failed and dropped by the
+    /// next [`receive()`](Self::receive) or [`poll()`](Self::poll).
+    fn broadcast(&mut self, event: &str) {
+        for connection in self.connections.iter_mut().filter(|c| c.welcomed) {
+            let _ = connection.send(event);
+        }
+    }
+}
+
+impl Connection {
+    /// Write a line, marking the connection failed if it cannot be written
+    /// whole
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        // Short lines fit the socket buffer unless the client stopped reading
+        let res = self.stream.write_all(format!("{}\n", line).as_bytes());
+        if res.is_err() {
+            self.failed = true;
+        }
+        res
+    }
+}
+
+/// Bind the socket of a broker, accessible to its owner only
+///
+/// The socket is bound in a private directory next to `path` and moved into
+/// place, so other users never get a chance to connect.
+pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+    let path = path.as_ref();
+    let mut private = path.as_os_str().to_owned();
+    private.push(".tmp");
+    let private = PathBuf::from(private);
+    DirBuilder::new().mode(0o700).create(&private)?;
+
+    let bound = private.join("socket");
+    let res = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&bound);
+    let _ = fs::remove_dir(&private);
+    res
+}
+
+/// Sample the sensor and serve the clients until `shutdown` is set
+///
+/// The broker must have been started. Clients that stop reading are
+/// disconnected, and clients beyond 16 are closed right away.
+pub fn serve<I2C, D, C, E>(broker: &mut Broker<I2C, D, C>, listener: &UnixListener, shutdown: &AtomicBool) -> io::Result<()>
+where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+D: delay::DelayMs<u8>,
+C: Clock {
+    listener.set_nonblocking(true)?;
+    let poll_interval = Duration::from_millis(POLL_INTERVAL_MS);
+    let mut next_poll = Instant::now();
+
+    while !shutdown.load(Ordering::SeqCst) {
+        if Instant::now() >= next_poll {
+            // Failures are retried at the next poll
+            let _ = broker.poll();
+            next_poll += poll_interval;
+        }
+        broker.expire_lease();
+
+        match listener.accept() {
+            // A misbehaving client must not stop the broker
+            Ok((stream, _)) => {
+                let _ = broker.accept(stream);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+        broker.receive();
+        thread::sleep(Duration::from_millis(ACCEPT_INTERVAL_MS));
+    }
+
+    Ok(())
+}
+
+/// Reading line without the leading `EVENT`
+fn format_reading(reading: &Reading) -> String {
+    let mut line = format!("READING {} {}", reading.timestamp.unwrap_or(0), reading.flags.bits());
+    for value in reading.air_info.to_array().iter() {
+        line.push_str(&format!(" {}", value));
+    }
+    line
+}
+
+fn parse_reading(fields: &[&str]) -> Option<Reading> {
+    if fields.len() != 12 {
+        return None;
+    }
+    let mut values = [0.0; 10];
+    for (value, field) in values.iter_mut().zip(fields[2..].iter()) {
+        *value = field.parse().ok()?;
+    }
+    Some(Reading {
+        air_info: AirInfo::from_array(&values),
+        flags: QualityFlags::from_bits(fields[1].parse().ok()?),
+        timestamp: Some(fields[0].parse().ok()?),
+    })
+}
+
+/// Text up to the first nul, with whitespace replaced to keep it one word
+fn word(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len])
+        .chars()
+        .map(|c| if c.is_whitespace() { '_' } else { c })
+        .collect()
+}
+
+fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// All possible errors of the client
+#[derive(Debug)]
+pub enum ClientError {
+    /// Socket error
+    Io(io::Error),
+    /// The broker refused the request, with the error code
+    Refused(String),
+    /// The broker sent something unexpected
+    Protocol,
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::Refused(code) => write!(f, "refused: {}", code),
+            ClientError::Protocol => f.write_str("protocol error"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Event pushed by the broker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// New reading, to subscribers
+    Reading(Reading),
+    /// The device was reset
+    Reset,
+    /// The auto-cleaning interval was changed [s]
+    Interval(u32),
+    /// The control lease changed hands
+    Lease(Option<u32>),
+}
+
+/// Identity of the sensor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Info {
+    /// Product type
+    pub product_type: String,
+    /// Serial number
+    pub serial: String,
+    /// Firmware version, as `(major, minor)`
+    pub firmware: (u8, u8),
+}
+
+/// Client of a broker
+pub struct BrokerClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    id: u32,
+    events: VecDeque<Event>,
+}
+
+impl BrokerClient {
+    /// Connect to the broker listening on `path`
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, ClientError> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        let mut client = BrokerClient { reader, writer, id: 0, events: VecDeque::new() };
+
+        let welcome = client.request(&format!("HELLO {}", PROTOCOL_VERSION))?;
+        client.id = match welcome.as_slice() {
+            [w, version, id] if w == "WELCOME" && *version == PROTOCOL_VERSION.to_string() => {
+                id.parse().map_err(|_| ClientError::Protocol)?
+            }
+            _ => return Err(ClientError::Protocol),
+        };
+        Ok(client)
+    }
+
+    /// Identifier the broker assigned to the client
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Identity of the sensor
+    pub fn info(&mut self) -> Result<Info, ClientError> {
+        match self.request("INFO")?.as_slice() {
+            [_, product_type, serial, firmware] => {
+                let (major, minor) = firmware.split_once('.').ok_or(ClientError::Protocol)?;
+                Ok(Info {
+                    product_type: product_type.clone(),
+                    serial: serial.clone(),
+                    firmware: (
+                        major.parse().map_err(|_| ClientError::Protocol)?,
+                        minor.parse().map_err(|_| ClientError::Protocol)?,
+                    ),
+                })
+            }
+            _ => Err(ClientError::Protocol),
+        }
+    }
+
+    /// Last reading of the broker
+    pub fn reading(&mut self) -> Result<Reading, ClientError> {
+        let fields = self.request("READING")?;
+        let fields: Vec<&str> = fields.iter().skip(1).map(String::as_str).collect();
+        parse_reading(&fields).ok_or(ClientError::Protocol)
+    }
+
+    /// Device status register
+    pub fn status(&mut self) -> Result<StatusRegisterResult, ClientError> {
+        match self.request("STATUS")?.as_slice() {
+            [_, speed, laser, fan] => Ok(StatusRegisterResult {
+                speed: speed == "1",
+                laser: laser == "1",
+                fan: fan == "1",
+            }),
+            _ => Err(ClientError::Protocol),
+        }
+    }
+
+    /// Auto-cleaning interval [s]
+    pub fn auto_cleaning_interval(&mut self) -> Result<u32, ClientError> {
+        match self.request("INTERVAL")?.as_slice() {
+            [_, interval] => interval.parse().map_err(|_| ClientError::Protocol),
+            _ => Err(ClientError::Protocol),
+        }
+    }
+
+    /// Have every new reading pushed as an event
+    pub fn subscribe(&mut self) -> Result<(), ClientError> {
+        self.request("SUBSCRIBE").map(drop)
+    }
+
+    /// Stop the readings being pushed
+    pub fn unsubscribe(&mut self) -> Result<(), ClientError> {
+        self.request("UNSUBSCRIBE").map(drop)
+    }
+
+    /// Acquire or renew the control lease
+    pub fn acquire(&mut self) -> Result<(), ClientError> {
+        self.request("ACQUIRE").map(drop)
+    }
+
+    /// Release the control lease
+    pub fn release(&mut self) -> Result<(), ClientError> {
+        self.request("RELEASE").map(drop)
+    }
+
+    /// Reset the device, needs the control lease
+    pub fn device_reset(&mut self) -> Result<(), ClientError> {
+        self.request("RESET").map(drop)
+    }
+
+    /// Write the auto-cleaning interval [s], needs the control lease
+    pub fn write_auto_cleaning_interval(&mut self, interval: u32) -> Result<(), ClientError> {
+        self.request(&format!("SET-INTERVAL {}", interval)).map(drop)
+    }
+
+    /// Wait for the next event
+    pub fn next_event(&mut self) -> Result<Event, ClientError> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            let line = self.read_line()?;
+            if !self.queue_event(&line)? {
+                return Err(ClientError::Protocol);
+            }
+        }
+    }
+
+    /// Send a request, returning the words of the response
+    fn request(&mut self, request: &str) -> Result<Vec<String>, ClientError> {
+        self.writer.write_all(format!("{}\n", request).as_bytes())?;
+        loop {
+            let line = self.read_line()?;
+            if self.queue_event(&line)? {
+                continue;
+            }
+
+            let words: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+            return match words.first().map(String::as_str) {
+                Some("ERROR") => Err(ClientError::Refused(words[1..].join(" "))),
+                Some(_) => Ok(words),
+                None => Err(ClientError::Protocol),
+            };
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String, ClientError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(ClientError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(line)
+    }
+
+    /// Queue the line if it is an event, returning whether it was one
+    fn queue_event(&mut self, line: &str) -> Result<bool, ClientError> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let event = match words.as_slice() {
+            ["EVENT", "READING", fields @ ..] => Event::Reading(parse_reading(fields).ok_or(ClientError::Protocol)?),
+            ["EVENT", "RESET"] => Event::Reset,
+            ["EVENT", "INTERVAL", interval] => Event::Interval(interval.parse().map_err(|_| ClientError::Protocol)?),
+            ["EVENT", "LEASE", "none"] => Event::Lease(None),
+            ["EVENT", "LEASE", id] => Event::Lease(Some(id.parse().map_err(|_| ClientError::Protocol)?)),
+            ["EVENT", ..] => return Err(ClientError::Protocol),
+            _ => return Ok(false),
+        };
+        self.events.push_back(event);
+        Ok(true)
+    }
+}
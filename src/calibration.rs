@@ -0,0 +1,299 @@
+//! Per-sensor calibration profiles
+//!
+//! A profile holds one correction for every [`AirInfo`] field and
+//! is keyed by the device serial number. Profiles are collected in a
+//! [`CalibrationRegistry`], from which the driver picks the one matching the
+//! connected sensor. See: [`load_calibration_profile()`].
+//!
+//! With the `serde` feature enabled, profiles can be (de)serialized. The
+//! serial number is represented as a string and fields that are left out
+//! default to the identity correction.
+//!
+//! [`load_calibration_profile()`]: ../struct.Sps30.html#method.load_calibration_profile
+
+use crate::Sps30;
+use crate::clock::Clock;
+use crate::types::{AirInfo, Error};
+use embedded_hal::blocking::{delay, i2c};
+
+/// Length of the serial number returned by the device
+pub const SERIAL_NUMBER_LEN: usize = 32;
+
+/// Names of the [`AirInfo`] fields, in the order the device reports them
+pub const FIELD_NAMES: [&str; 10] = [
+    "mass_pm1_0",
+    "mass_pm2_5",
+    "mass_pm4_0",
+    "mass_pm10",
+    "number_pm0_5",
+    "number_pm1_0",
+    "number_pm2_5",
+    "number_pm4_0",
+    "number_pm10",
+    "typical_size",
+];
+
+/// Field correction `y = c0 + c1·x + c2·x² + c3·x³ + h·RH`
+///
+/// The humidity term is only applied when the relative humidity is known.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Correction {
+    /// Polynomial coefficients, lowest order first
+    pub coefficients: [f32; 4],
+    /// Relative humidity [%] coefficient
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub humidity: f32,
+}
+
+impl Correction {
+    /// Correction that leaves the value unchanged
+    pub const IDENTITY: Self = Correction {
+        coefficients: [0.0, 1.0, 0.0, 0.0],
+        humidity: 0.0,
+    };
+
+    /// Linear correction `y = offset + gain·x`
+    pub const fn linear(gain: f32, offset: f32) -> Self {
+        Correction {
+            coefficients: [offset, gain, 0.0, 0.0],
+            humidity: 0.0,
+        }
+    }
+
+    /// Apply the correction to a value, leaving out the humidity term
+    pub fn apply(&self, x: f32) -> f32 {
+        self.coefficients
+            .iter()
+            .rev()
+            .fold(0.0, |acc, c| acc * x + c)
+    }
+
+    /// Apply the correction to a value measured at the given relative
+    /// humidity [%]
+    pub fn apply_with_humidity(&self, x: f32, relative_humidity: f32) -> f32 {
+        self.apply(x) + self.humidity * relative_humidity
+    }
+}
+
+impl Default for Correction {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+/// Correction coefficients for one sensor
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CalibrationProfile {
+    /// Serial number of the sensor, as returned by the device
+    #[cfg_attr(feature = "serde", serde(with = "serial_number"))]
+    pub serial: [u8; SERIAL_NUMBER_LEN],
+    /// Mass Concentration PM1.0 correction
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mass_pm1_0: Correction,
+    /// Mass Concentration PM2.5 correction
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mass_pm2_5: Correction,
+    /// Mass Concentration PM4.0 correction
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mass_pm4_0: Correction,
+    /// Mass Concentration PM10 correction
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mass_pm10: Correction,
+    /// Number Concentration PM0.5 correction
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub number_pm0_5: Correction,
+    /// Number Concentration PM1.0 correction
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub number_pm1_0: Correction,
+    /// Number Concentration PM2.5 correction
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub number_pm2_5: Correction,
+    /// Number Concentration PM4.0 correction
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub number_pm4_0: Correction,
+    /// Number Concentration PM10 correction
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub number_pm10: Correction,
+    /// Typical Particle Size correction
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub typical_size: Correction,
+}
+
+impl CalibrationProfile {
+    /// Create a profile that leaves every field unchanged
+    ///
+    /// `serial` is the serial number as printed on the device; it is padded
+    /// with zeros the same way the device pads it. Longer serials are
+    /// truncated.
+    pub fn new(serial: &[u8]) -> Self {
+        CalibrationProfile {
+            serial: pad_serial_number(serial),
+            mass_pm1_0: Correction::IDENTITY,
+            mass_pm2_5: Correction::IDENTITY,
+            mass_pm4_0: Correction::IDENTITY,
+            mass_pm10: Correction::IDENTITY,
+            number_pm0_5: Correction::IDENTITY,
+            number_pm1_0: Correction::IDENTITY,
+            number_pm2_5: Correction::IDENTITY,
+            number_pm4_0: Correction::IDENTITY,
+            number_pm10: Correction::IDENTITY,
+            typical_size: Correction::IDENTITY,
+        }
+    }
+
+    /// Apply the profile to a measurement, leaving out the humidity terms
+    pub fn apply(&self, air_info: &AirInfo) -> AirInfo {
+        self.apply_each(air_info, |c, x| c.apply(x))
+    }
+
+    /// Apply the profile to a measurement taken at the given relative
+    /// humidity [%]
+    pub fn apply_with_humidity(&self, air_info: &AirInfo, relative_humidity: f32) -> AirInfo {
+        self.apply_each(air_info, |c, x| c.apply_with_humidity(x, relative_humidity))
+    }
+
+    /// Correction of the field with the given name, see [`FIELD_NAMES`]
+    pub fn correction_mut(&mut self, field: &str) -> Option<&mut Correction> {
+        match field {
+            "mass_pm1_0" => Some(&mut self.mass_pm1_0),
+            "mass_pm2_5" => Some(&mut self.mass_pm2_5),
+            "mass_pm4_0" => Some(&mut self.mass_pm4_0),
+            "mass_pm10" => Some(&mut self.mass_pm10),
+            "number_pm0_5" => Some(&mut self.number_pm0_5),
+            "number_pm1_0" => Some(&mut self.number_pm1_0),
+            "number_pm2_5" => Some(&mut self.number_pm2_5),
+            "number_pm4_0" => Some(&mut self.number_pm4_0),
+            "number_pm10" => Some(&mut self.number_pm10),
+            "typical_size" => Some(&mut self.typical_size),
+            _ => None,
+        }
+    }
+
+    fn apply_each<F: Fn(&Correction, f32) -> f32>(&self, air_info: &AirInfo, f: F) -> AirInfo {
+        AirInfo {
+            mass_pm1_0: f(&self.mass_pm1_0, air_info.mass_pm1_0),
+            mass_pm2_5: f(&self.mass_pm2_5, air_info.mass_pm2_5),
+            mass_pm4_0: f(&self.mass_pm4_0, air_info.mass_pm4_0),
+            mass_pm10: f(&self.mass_pm10, air_info.mass_pm10),
+            number_pm0_5: f(&self.number_pm0_5, air_info.number_pm0_5),
+            number_pm1_0: f(&self.number_pm1_0, air_info.number_pm1_0),
+            number_pm2_5: f(&self.number_pm2_5, air_info.number_pm2_5),
+            number_pm4_0: f(&self.number_pm4_0, air_info.number_pm4_0),
+            number_pm10: f(&self.number_pm10, air_info.number_pm10),
+            typical_size: f(&self.typical_size, air_info.typical_size),
+        }
+    }
+}
+
+/// Set of calibration profiles, looked up by serial number
+#[derive(Debug, Clone, Copy)]
+pub struct CalibrationRegistry<'a> {
+    profiles: &'a [CalibrationProfile],
+}
+
+impl<'a> CalibrationRegistry<'a> {
+    /// Create a registry over the given profiles
+    pub fn new(profiles: &'a [CalibrationProfile]) -> Self {
+        CalibrationRegistry { profiles }
+    }
+
+    /// Find the profile of the sensor with the given serial number
+    pub fn find(&self, serial: &[u8; SERIAL_NUMBER_LEN]) -> Option<&'a CalibrationProfile> {
+        self.profiles.iter().find(|p| &p.serial == serial)
+    }
+}
+
+fn pad_serial_number(serial: &[u8]) -> [u8; SERIAL_NUMBER_LEN] {
+    let mut res = [0; SERIAL_NUMBER_LEN];
+    let len = serial.len().min(SERIAL_NUMBER_LEN);
+    res[..len].clone_from_slice(&serial[..len]);
+    res
+}
+
+impl<I2C, D, C, E> Sps30<I2C, D, C>
+where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+D: delay::DelayMs<u8>,
+C: Clock {
+    /// Set the calibration profile applied to the measured values
+    pub fn set_calibration_profile(&mut self, profile: Option<CalibrationProfile>) {
+        self.calibration = profile;
+    }
+
+    /// Set the ambient relative humidity [%] used by the humidity terms of
+    /// the calibration profile
+    ///
+    /// While it is `None`, the humidity terms are left out of the corrected
+    /// values.
+    pub fn set_relative_humidity(&mut self, relative_humidity: Option<f32>) {
+        self.humidity = relative_humidity;
+    }
+
+    /// Read the device serial number and select the matching profile
+    ///
+    /// Returns whether a profile was found. When none matches, the measured
+    /// values are returned uncorrected.
+    pub fn load_calibration_profile(&mut self, registry: &CalibrationRegistry<'_>) -> Result<bool, Error<E>> {
+        let serial = self.read_device_serial_number()?;
+        self.calibration = registry.find(&serial).copied();
+
+        Ok(self.calibration.is_some())
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serial_number {
+    use super::{pad_serial_number, SERIAL_NUMBER_LEN};
+    use core::fmt;
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(serial: &[u8; SERIAL_NUMBER_LEN], serializer: S) -> Result<S::Ok, S::Error> {
+        let len = serial.iter().position(|&b| b == 0).unwrap_or(SERIAL_NUMBER_LEN);
+        match core::str::from_utf8(&serial[..len]) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => serializer.serialize_bytes(&serial[..len]),
+        }
+    }
+
+    /// Accepts both forms written by `serialize()`: a string, or the bytes
+    /// of a serial number that is not UTF-8
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; SERIAL_NUMBER_LEN], D::Error> {
+        deserializer.deserialize_any(SerialNumberVisitor)
+    }
+
+    struct SerialNumberVisitor;
+
+    impl<'de> Visitor<'de> for SerialNumberVisitor {
+        type Value = [u8; SERIAL_NUMBER_LEN];
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "a serial number of at most {} bytes", SERIAL_NUMBER_LEN)
+        }
+
+        fn visit_str<Er: de::Error>(self, v: &str) -> Result<Self::Value, Er> {
+            self.visit_bytes(v.as_bytes())
+        }
+
+        fn visit_bytes<Er: de::Error>(self, v: &[u8]) -> Result<Self::Value, Er> {
+            if v.len() > SERIAL_NUMBER_LEN {
+                return Err(Er::invalid_length(v.len(), &self));
+            }
+            Ok(pad_serial_number(v))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut serial = [0; SERIAL_NUMBER_LEN];
+            let mut len = 0;
+            while let Some(byte) = seq.next_element::<u8>()? {
+                if len == SERIAL_NUMBER_LEN {
+                    return Err(de::Error::invalid_length(len + 1, &self));
+                }
+                serial[len] = byte;
+                len += 1;
+            }
+            Ok(serial)
+        }
+    }
+}
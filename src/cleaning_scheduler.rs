@@ -0,0 +1,211 @@
+//! Host-side fan cleaning scheduler
+//!
+//! The device resets its auto-cleaning timer on every power cycle, so a
+//! sensor whose power is duty-cycled may never reach the interval set with
+//! [`write_auto_cleaning_interval()`]. The [`CleaningScheduler`] instead
+//! tracks the cumulative fan runtime across sleep, wake-up and power cycles,
+//! persisting it through a [`RuntimeStore`], and starts the fan cleaning
+//! once the runtime budget is used up. If that happens while the sensor is
+//! idle, the cleaning is started the next time the sensor is measuring.
+//!
+//! The scheduler has to be told when the fan starts and stops, which is
+//! easiest by issuing [`start_measurement()`] and [`stop_measurement()`]
+//! through it. These take the time from the clock attached to the sensor
+//! with [`with_clock()`]. Consider disabling the automatic cleaning of the
+//! device by writing an interval of 0.
+//!
+//! [`write_auto_cleaning_interval()`]: ../struct.Sps30.html#method.write_auto_cleaning_interval
+//! [`with_clock()`]: ../struct.Sps30.html#method.with_clock
+//! [`start_measurement()`]: struct.CleaningScheduler.html#method.start_measurement
+//! [`stop_measurement()`]: struct.CleaningScheduler.html#method.stop_measurement
+
+use crate::Sps30;
+use crate::clock::Clock;
+use crate::fan_cleaning::DEFAULT_AUTO_CLEANING_INTERVAL;
+use crate::types::Error;
+use embedded_hal::blocking::{delay, i2c};
+
+/// How often the runtime is persisted while the fan is running [ms]
+pub const PERSIST_INTERVAL_MS: u64 = 60_000;
+
+/// Persistent storage of the fan runtime
+pub trait RuntimeStore {
+    /// Storage error
+    type Error;
+
+    /// Load the fan runtime since the last cleaning [ms]
+    fn load(&mut self) -> Result<u64, Self::Error>;
+
+    /// Store the fan runtime since the last cleaning [ms]
+    fn store(&mut self, runtime_ms: u64) -> Result<(), Self::Error>;
+}
+
+/// Volatile runtime store, e.g. for tests or battery-backed RAM
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStore(pub u64);
+
+impl RuntimeStore for MemoryStore {
+    type Error = core::convert::Infallible;
+
+    fn load(&mut self) -> Result<u64, Self::Error> {
+        Ok(self.0)
+    }
+
+    fn store(&mut self, runtime_ms: u64) -> Result<(), Self::Error> {
+        self.0 = runtime_ms;
+        Ok(())
+    }
+}
+
+/// All possible errors of the scheduler
+#[derive(Debug)]
+pub enum SchedulerError<E, SE> {
+    /// Sensor error
+    Sensor(Error<E>),
+    /// Runtime store error
+    Store(SE),
+    /// No clock is attached to the sensor
+    NoClock,
+}
+
+/// Fan cleaning scheduler based on the cumulative fan runtime
+#[derive(Debug)]
+pub struct CleaningScheduler<S> {
+    store: S,
+    budget_ms: u64,
+    /// Runtime up to `running_since`, or in total if the fan is stopped
+    runtime_ms: u64,
+    running_since: Option<u64>,
+    persisted_ms: u64,
+}
+
+impl<S: RuntimeStore> CleaningScheduler<S> {
+    /// Create a scheduler cleaning the fan every `budget_s` seconds of
+    /// runtime, restoring the runtime from the store
+    pub fn new(mut store: S, budget_s: u32) -> Result<Self, S::Error> {
+        let runtime_ms = store.load()?;
+
+        Ok(CleaningScheduler {
+            store,
+            budget_ms: u64::from(budget_s) * 1000,
+            runtime_ms,
+            running_since: None,
+            persisted_ms: runtime_ms,
+        })
+    }
+
+    /// Create a scheduler with the default interval of the device
+    pub fn with_default_budget(store: S) -> Result<Self, S::Error> {
+        Self::new(store, DEFAULT_AUTO_CLEANING_INTERVAL)
+    }
+
+    /// Destroy the scheduler, returning the store
+    pub fn destroy(self) -> S {
+        self.store
+    }
+
+    /// Fan runtime since the last cleaning [ms], at `now` [ms]
+    pub fn runtime_ms(&self, now: u64) -> u64 {
+        self.runtime_ms + self.running_since.map_or(0, |since| now.saturating_sub(since))
+    }
+
+    /// Whether the runtime budget is used up at `now` [ms]
+    pub fn is_due(&self, now: u64) -> bool {
+        self.budget_ms != 0 && self.runtime_ms(now) >= self.budget_ms
+    }
+
+    /// Whether the fan is running
+    pub fn is_running(&self) -> bool {
+        self.running_since.is_some()
+    }
+
+    /// Record that the fan started at `now` [ms], e.g. the sensor entered
+    /// measurement mode
+    pub fn fan_started(&mut self, now: u64) {
+        if self.running_since.is_none() {
+            self.running_since = Some(now);
+        }
+    }
+
+    /// Record that the fan stopped at `now` [ms] and persist the runtime
+    pub fn fan_stopped(&mut self, now: u64) -> Result<(), S::Error> {
+        self.runtime_ms = self.runtime_ms(now);
+        self.running_since = None;
+        self.persist()
+    }
+
+    /// Enter measurement mode and record that the fan started
+    ///
+    /// A cleaning that became due while the sensor was idle is started
+    /// right away.
+    pub fn start_measurement<I2C, D, C, E>(
+        &mut self,
+        sensor: &mut Sps30<I2C, D, C>,
+    ) -> Result<bool, SchedulerError<E, S::Error>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+    {
+        let now = sensor.now().ok_or(SchedulerError::NoClock)?;
+        sensor.start_measurement().map_err(SchedulerError::Sensor)?;
+        self.fan_started(now);
+        self.poll(sensor)
+    }
+
+    /// Exit measurement mode and record that the fan stopped
+    pub fn stop_measurement<I2C, D, C, E>(
+        &mut self,
+        sensor: &mut Sps30<I2C, D, C>,
+    ) -> Result<(), SchedulerError<E, S::Error>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+    {
+        let now = sensor.now().ok_or(SchedulerError::NoClock)?;
+        sensor.stop_measurement().map_err(SchedulerError::Sensor)?;
+        self.fan_stopped(now).map_err(SchedulerError::Store)
+    }
+
+    /// Start the fan cleaning if it is due and the sensor is measuring
+    ///
+    /// Call this periodically while measuring; it also persists the runtime
+    /// every [`PERSIST_INTERVAL_MS`]. Returns whether a cleaning was started.
+    pub fn poll<I2C, D, C, E>(
+        &mut self,
+        sensor: &mut Sps30<I2C, D, C>,
+    ) -> Result<bool, SchedulerError<E, S::Error>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+    {
+        if !self.is_running() {
+            return Ok(false);
+        }
+        let now = sensor.now().ok_or(SchedulerError::NoClock)?;
+
+        if self.is_due(now) {
+            sensor.start_fan_cleaning().map_err(SchedulerError::Sensor)?;
+            self.runtime_ms = 0;
+            self.running_since = Some(now);
+            self.persist().map_err(SchedulerError::Store)?;
+            return Ok(true);
+        }
+
+        if self.runtime_ms(now) >= self.persisted_ms + PERSIST_INTERVAL_MS {
+            let runtime_ms = self.runtime_ms(now);
+            self.store.store(runtime_ms).map_err(SchedulerError::Store)?;
+            self.persisted_ms = runtime_ms;
+        }
+
+        Ok(false)
+    }
+
+    fn persist(&mut self) -> Result<(), S::Error> {
+        self.store.store(self.runtime_ms)?;
+        self.persisted_ms = self.runtime_ms;
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+//! Time sources
+//!
+//! The driver only needs the time to tag readings, e.g. with the time since
+//! the measurement was started. Attach a clock with [`with_clock()`].
+//!
+//! [`with_clock()`]: ../struct.Sps30.html#method.with_clock
+
+/// Monotonic time source
+pub trait Clock {
+    /// Milliseconds elapsed since an arbitrary, fixed point in time
+    fn now_ms(&mut self) -> u64;
+}
+
+/// Clock of a driver that has none attached
+///
+/// The driver never reads it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoClock;
+
+impl Clock for NoClock {
+    fn now_ms(&mut self) -> u64 {
+        0
+    }
+}
+
+/// Clock based on `std::time::Instant`
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+pub struct StdClock {
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl StdClock {
+    /// Create a clock starting at zero now
+    pub fn new() -> Self {
+        StdClock {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now_ms(&mut self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &mut C {
+    fn now_ms(&mut self) -> u64 {
+        (**self).now_ms()
+    }
+}
@@ -0,0 +1,261 @@
+//! Compact encodings of measurements for low-bandwidth links
+//!
+//! An [`AirInfo`] of ten f32 values takes 40 bytes. The encodings here trade
+//! precision for size; each has an encoder for the device and a decoder for
+//! the server side.
+//!
+//! - uint16: the ten fields quantized to uint16, big endian, in the order
+//!   the device reports them and in its integer output format. 20 bytes.
+//!   See: [`encode_uint16()`].
+//! - Delta: a batch of up to 255 readings, the first as uint16 and the
+//!   others as the differences of their quantized fields to the previous
+//!   reading, so slowly changing readings take about 10 bytes each. Every
+//!   batch decodes on its own, so a lost payload does not break the next.
+//!   See: [`encode_delta()`].
+//! - Cayenne LPP: one entry per field on consecutive channels, for
+//!   platforms decoding Cayenne Low Power Payload. 40 bytes.
+//!   See: [`encode_lpp()`].
+//!
+//! # Precision
+//!
+//! Values are rounded to the resolution; the error is at most half of it.
+//! Unknown, invalid and negative values are encoded as "not available" and
+//! decode to NaN; values too large for the encoding are saturated.
+//!
+//! | Field                 | uint16 and delta      | Cayenne LPP                        |
+//! |-----------------------|-----------------------|------------------------------------|
+//! | Mass concentrations   | 1 μg/m³, max 65534    | 1 μg/m³, max 65534 (concentration) |
+//! | Number concentrations | 1 #/cm³, max 65534    | 1 #/cm³, max 65534 (concentration) |
+//! | Typical particle size | 1 nm, max 65.534 μm   | 0.01 μm, max 327.67 (analog input) |
+//!
+//! The delta encoding is lossless relative to the uint16 one.
+
+use crate::types::{AirInfo, AirInfoU16};
+
+/// Factors of the uint16 fields, in the sensor's integer output format:
+/// 1 μg/m³, 1 #/cm³ and 1 nm
+pub const UINT16_SCALES: [f32; 10] = [1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1000.0];
+/// Factors of the finer uint16 fields of the flash log and the Modbus
+/// server: 0.1 μg/m³, 0.1 #/cm³ and 1 nm
+pub const FINE_SCALES: [f32; 10] = [10.0, 10.0, 10.0, 10.0, 10.0, 10.0, 10.0, 10.0, 10.0, 1000.0];
+/// Quantized value of unknown or invalid values
+pub const NOT_AVAILABLE: u16 = AirInfoU16::NOT_AVAILABLE;
+/// Size of a uint16 encoded reading [bytes]
+pub const UINT16_LEN: usize = 20;
+/// Size of a Cayenne LPP encoded reading [bytes]
+pub const LPP_LEN: usize = 10 * 4;
+/// Cayenne LPP type of the concentrations: uint16, resolution 1
+pub const LPP_CONCENTRATION: u8 = 125;
+/// Cayenne LPP type of the typical particle size: int16, resolution 0.01
+pub const LPP_ANALOG_INPUT: u8 = 2;
+/// Analog input value of an unknown or invalid typical particle size
+pub const LPP_ANALOG_NOT_AVAILABLE: i16 = i16::MIN;
+
+/// Encoding or decoding error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecError {
+    /// The output buffer is too small
+    BufferTooSmall,
+    /// The input ends in the middle of a reading
+    Truncated,
+    /// The input is not of the expected encoding
+    Invalid,
+}
+
+/// Quantize a value to uint16 at `scale` steps per unit
+///
+/// Unknown, invalid and negative values are [`NOT_AVAILABLE`]; values too
+/// large are saturated just below it.
+pub fn quantize(value: f32, scale: f32) -> u16 {
+    let value = value * scale + 0.5;
+    if value.is_nan() || value < 0.0 {
+        NOT_AVAILABLE
+    } else if value >= f32::from(NOT_AVAILABLE) {
+        NOT_AVAILABLE - 1
+    } else {
+        value as u16
+    }
+}
+
+/// Value of a quantized uint16 at `scale` steps per unit, NaN if
+/// [`NOT_AVAILABLE`]
+pub fn dequantize(value: u16, scale: f32) -> f32 {
+    if value == NOT_AVAILABLE {
+        f32::NAN
+    } else {
+        f32::from(value) / scale
+    }
+}
+
+fn quantize_all(air_info: &AirInfo) -> [u16; 10] {
+    AirInfoU16::from(*air_info).to_array()
+}
+
+/// Encode a reading as ten uint16
+pub fn encode_uint16(air_info: &AirInfo) -> [u8; UINT16_LEN] {
+    let mut bytes = [0; UINT16_LEN];
+    for (chunk, value) in bytes.chunks_mut(2).zip(quantize_all(air_info).iter()) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+    bytes
+}
+
+fn read_uint16(bytes: &[u8]) -> [u16; 10] {
+    let mut values = [0; 10];
+    for (value, chunk) in values.iter_mut().zip(bytes.chunks(2)) {
+        *value = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+    values
+}
+
+/// Decode a reading encoded by [`encode_uint16()`]
+pub fn decode_uint16(bytes: &[u8; UINT16_LEN]) -> AirInfo {
+    AirInfo::from(AirInfoU16::from_array(&read_uint16(bytes)))
+}
+
+/// Encode a batch of consecutive readings, returning the encoded size
+///
+/// The batch starts with the number of readings, followed by the first one
+/// as by [`encode_uint16()`] and, for every other field, the difference of
+/// the quantized value to the previous reading as a zigzag LEB128 varint.
+/// An empty batch or one of more than 255 readings is
+/// [`CodecError::Invalid`].
+pub fn encode_delta(readings: &[AirInfo], out: &mut [u8]) -> Result<usize, CodecError> {
+    let (first, rest) = readings.split_first().ok_or(CodecError::Invalid)?;
+    if readings.len() > usize::from(u8::MAX) {
+        return Err(CodecError::Invalid);
+    }
+    if out.len() < 1 + UINT16_LEN {
+        return Err(CodecError::BufferTooSmall);
+    }
+
+    out[0] = readings.len() as u8;
+    out[1..1 + UINT16_LEN].copy_from_slice(&encode_uint16(first));
+    let mut len = 1 + UINT16_LEN;
+    let mut previous = quantize_all(first);
+    for air_info in rest {
+        let values = quantize_all(air_info);
+        for (value, previous) in values.iter().zip(previous.iter()) {
+            let delta = i32::from(*value) - i32::from(*previous);
+            let mut zigzag = ((delta << 1) ^ (delta >> 31)) as u32;
+            loop {
+                let byte = out.get_mut(len).ok_or(CodecError::BufferTooSmall)?;
+                len += 1;
+                if zigzag < 0x80 {
+                    *byte = zigzag as u8;
+                    break;
+                }
+                *byte = zigzag as u8 | 0x80;
+                zigzag >>= 7;
+            }
+        }
+        previous = values;
+    }
+    Ok(len)
+}
+
+/// Decode a batch encoded by [`encode_delta()`], returning the number of
+/// readings
+pub fn decode_delta(bytes: &[u8], out: &mut [AirInfo]) -> Result<usize, CodecError> {
+    let (&count, rest) = bytes.split_first().ok_or(CodecError::Truncated)?;
+    let count = usize::from(count);
+    if count == 0 {
+        return Err(CodecError::Invalid);
+    }
+    if out.len() < count {
+        return Err(CodecError::BufferTooSmall);
+    }
+    if rest.len() < UINT16_LEN {
+        return Err(CodecError::Truncated);
+    }
+
+    let mut values = read_uint16(&rest[..UINT16_LEN]);
+    out[0] = AirInfo::from(AirInfoU16::from_array(&values));
+    let mut varints = rest[UINT16_LEN..].iter();
+    for air_info in out[1..count].iter_mut() {
+        for value in values.iter_mut() {
+            let mut zigzag = 0u32;
+            let mut shift = 0;
+            loop {
+                let byte = *varints.next().ok_or(CodecError::Truncated)?;
+                if shift > 14 {
+                    return Err(CodecError::Invalid);
+                }
+                zigzag |= u32::from(byte & 0x7F) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            let delta = (zigzag >> 1) as i32 ^ -((zigzag & 1) as i32);
+            let next = i32::from(*value) + delta;
+            if !(0..=i32::from(u16::MAX)).contains(&next) {
+                return Err(CodecError::Invalid);
+            }
+            *value = next as u16;
+        }
+        *air_info = AirInfo::from(AirInfoU16::from_array(&values));
+    }
+    if varints.next().is_some() {
+        return Err(CodecError::Invalid);
+    }
+    Ok(count)
+}
+
+/// Encode a reading as Cayenne LPP on the channels from `first_channel`
+///
+/// The fields are on consecutive channels in the order the device reports
+/// them: the concentrations as [`LPP_CONCENTRATION`] and the typical
+/// particle size as [`LPP_ANALOG_INPUT`] in μm.
+pub fn encode_lpp(air_info: &AirInfo, first_channel: u8) -> [u8; LPP_LEN] {
+    let values = air_info.to_array();
+    let mut bytes = [0; LPP_LEN];
+    for (i, entry) in bytes.chunks_mut(4).enumerate() {
+        entry[0] = first_channel.wrapping_add(i as u8);
+        let data = if i < 9 {
+            entry[1] = LPP_CONCENTRATION;
+            quantize(values[i], 1.0).to_be_bytes()
+        } else {
+            entry[1] = LPP_ANALOG_INPUT;
+            let size = values[i] * 100.0 + 0.5;
+            let size = if size.is_nan() || size < 0.0 {
+                LPP_ANALOG_NOT_AVAILABLE
+            } else if size >= f32::from(i16::MAX) {
+                i16::MAX
+            } else {
+                size as i16
+            };
+            size.to_be_bytes()
+        };
+        entry[2..].copy_from_slice(&data);
+    }
+    bytes
+}
+
+/// Decode a reading encoded by [`encode_lpp()`] on the same channels
+pub fn decode_lpp(bytes: &[u8], first_channel: u8) -> Result<AirInfo, CodecError> {
+    if bytes.len() < LPP_LEN {
+        return Err(CodecError::Truncated);
+    }
+    if bytes.len() > LPP_LEN {
+        return Err(CodecError::Invalid);
+    }
+
+    let mut values = [0.0; 10];
+    for (i, entry) in bytes.chunks(4).enumerate() {
+        let kind = if i < 9 { LPP_CONCENTRATION } else { LPP_ANALOG_INPUT };
+        if entry[0] != first_channel.wrapping_add(i as u8) || entry[1] != kind {
+            return Err(CodecError::Invalid);
+        }
+        let data = [entry[2], entry[3]];
+        values[i] = if i < 9 {
+            dequantize(u16::from_be_bytes(data), 1.0)
+        } else {
+            match i16::from_be_bytes(data) {
+                LPP_ANALOG_NOT_AVAILABLE => f32::NAN,
+                size => f32::from(size) / 100.0,
+            }
+        };
+    }
+    Ok(AirInfo::from_array(&values))
+}
@@ -1,3 +1,4 @@
+/// CRC-8 of a data word (polynomial 0x31, initialization 0xFF)
 pub fn calc_crc(data: &[u8; 2]) -> u8 {
     let mut crc: u8 = 0xFF;
 
@@ -0,0 +1,412 @@
+//! Low-power duty-cycle measurement
+//!
+//! The [`DutyCycleScheduler`] repeats the sequence `wake_up` →
+//! `start_measurement` → wait for stabilization → average the samples →
+//! `stop_measurement` → `sleep` once per period, and returns one aggregated
+//! reading per cycle.
+//!
+//! It is a state machine driven by a monotonic time. Non-blocking users call
+//! [`poll()`] whenever they like, ideally at [`next_poll_at()`]; blocking
+//! users call [`run_cycle()`]. Both need the `float` feature; [`poll_u16()`]
+//! and [`run_cycle_u16()`] average in the integer output format instead. A
+//! scheduler is driven by one of the two formats only.
+//!
+//! The sensor is expected to be idle when the scheduler is created, as it
+//! is after power-up.
+//!
+//! [`poll()`]: struct.DutyCycleScheduler.html#method.poll
+//! [`next_poll_at()`]: struct.DutyCycleScheduler.html#method.next_poll_at
+//! [`run_cycle()`]: struct.DutyCycleScheduler.html#method.run_cycle
+//! [`poll_u16()`]: struct.DutyCycleScheduler.html#method.poll_u16
+//! [`run_cycle_u16()`]: struct.DutyCycleScheduler.html#method.run_cycle_u16
+
+use crate::Sps30;
+use crate::clock::Clock;
+#[cfg(feature = "float")]
+use crate::quality::Reading;
+use crate::quality::{QualityFlags, ReadingU16};
+#[cfg(feature = "float")]
+use crate::types::AirInfo;
+use crate::types::{AirInfoU16, Error, OutputFormat};
+use embedded_hal::blocking::{delay, i2c};
+
+/// Duty cycle configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DutyCycleConfig {
+    /// Time from the start of one cycle to the start of the next [ms]
+    pub period_ms: u32,
+    /// Time after entering measurement mode before sampling [ms]
+    pub warm_up_ms: u32,
+    /// Number of samples averaged per cycle
+    pub samples: u16,
+    /// Time between data-ready polls while sampling [ms]
+    pub poll_interval_ms: u32,
+    /// Time without new data after which the cycle is abandoned [ms]
+    pub data_ready_timeout_ms: u32,
+}
+
+impl Default for DutyCycleConfig {
+    /// 30 s of measurement every 10 min
+    fn default() -> Self {
+        DutyCycleConfig {
+            period_ms: 600_000,
+            warm_up_ms: 20_000,
+            samples: 10,
+            poll_interval_ms: 1_000,
+            data_ready_timeout_ms: 10_000,
+        }
+    }
+}
+
+/// State of the duty cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DutyCycleState {
+    /// Waiting for the next cycle; the sensor is asleep, or idle before the
+    /// first cycle
+    Waiting,
+    /// Measuring, waiting for the readings to stabilize
+    WarmingUp,
+    /// Measuring, collecting samples
+    Sampling,
+    /// Done sampling, stopping the measurement and putting the sensor to
+    /// sleep
+    Stopping,
+}
+
+/// Duty-cycle measurement scheduler
+#[derive(Debug, Clone)]
+pub struct DutyCycleScheduler {
+    config: DutyCycleConfig,
+    state: DutyCycleState,
+    asleep: bool,
+    measuring: bool,
+    cycle_started: u64,
+    next_poll: u64,
+    last_data: u64,
+    sums: Sums,
+    count: u16,
+    flags: QualityFlags,
+    /// Time the last sample of the cycle was taken, the reading is returned
+    /// once the sensor is asleep
+    completed: Option<u64>,
+}
+
+/// Running sums of the samples of a cycle, in the format they were read
+#[derive(Debug, Clone, Copy, Default)]
+struct Sums {
+    #[cfg(feature = "float")]
+    float: [f32; 10],
+    /// `u32::MAX`, more than the sum of `u16::MAX` samples, once a value was
+    /// not available
+    uint16: [u32; 10],
+}
+
+/// Reading the scheduler averages
+trait Sample: Sized {
+    /// Output format the measurement is started in
+    const FORMAT: OutputFormat;
+
+    fn read<I2C, D, C, E>(sensor: &mut Sps30<I2C, D, C>) -> Result<Self, Error<E>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock;
+
+    fn flags(&self) -> QualityFlags;
+
+    fn add_to(&self, sums: &mut Sums);
+
+    fn mean(sums: &Sums, count: u16, flags: QualityFlags, timestamp: u64) -> Self;
+}
+
+#[cfg(feature = "float")]
+impl Sample for Reading {
+    const FORMAT: OutputFormat = OutputFormat::Float;
+
+    fn read<I2C, D, C, E>(sensor: &mut Sps30<I2C, D, C>) -> Result<Self, Error<E>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+    {
+        Ok(sensor.read_measured_values()?.validate())
+    }
+
+    fn flags(&self) -> QualityFlags {
+        self.flags
+    }
+
+    fn add_to(&self, sums: &mut Sums) {
+        for (sum, value) in sums.float.iter_mut().zip(self.air_info.to_array().iter()) {
+            *sum += value;
+        }
+    }
+
+    fn mean(sums: &Sums, count: u16, flags: QualityFlags, timestamp: u64) -> Self {
+        let n = f32::from(count);
+        Reading {
+            air_info: AirInfo::from_array(&sums.float.map(|sum| sum / n)),
+            flags,
+            timestamp: Some(timestamp),
+        }
+    }
+}
+
+impl Sample for ReadingU16 {
+    const FORMAT: OutputFormat = OutputFormat::UInt16;
+
+    fn read<I2C, D, C, E>(sensor: &mut Sps30<I2C, D, C>) -> Result<Self, Error<E>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+    {
+        Ok(sensor.read_measured_values_u16()?.validate())
+    }
+
+    fn flags(&self) -> QualityFlags {
+        self.flags
+    }
+
+    fn add_to(&self, sums: &mut Sums) {
+        for (sum, &value) in sums.uint16.iter_mut().zip(self.air_info.to_array().iter()) {
+            *sum = if value == AirInfoU16::NOT_AVAILABLE {
+                u32::MAX
+            } else {
+                sum.saturating_add(u32::from(value))
+            };
+        }
+    }
+
+    fn mean(sums: &Sums, count: u16, flags: QualityFlags, timestamp: u64) -> Self {
+        let n = u32::from(count);
+        let mut values = [AirInfoU16::NOT_AVAILABLE; 10];
+        for (value, &sum) in values.iter_mut().zip(sums.uint16.iter()) {
+            if sum != u32::MAX {
+                // Rounded to the nearest, stays below `NOT_AVAILABLE` like the samples
+                *value = ((sum + n / 2) / n) as u16;
+            }
+        }
+        ReadingU16 {
+            air_info: AirInfoU16::from_array(&values),
+            flags,
+            timestamp: Some(timestamp),
+        }
+    }
+}
+
+impl DutyCycleScheduler {
+    /// Create a scheduler whose first cycle starts at the first poll
+    pub fn new(config: DutyCycleConfig) -> Self {
+        DutyCycleScheduler {
+            config,
+            state: DutyCycleState::Waiting,
+            asleep: false,
+            measuring: false,
+            cycle_started: 0,
+            next_poll: 0,
+            last_data: 0,
+            sums: Sums::default(),
+            count: 0,
+            flags: QualityFlags::empty(),
+            completed: None,
+        }
+    }
+
+    /// Configuration of the scheduler
+    pub fn config(&self) -> &DutyCycleConfig {
+        &self.config
+    }
+
+    /// Current state
+    pub fn state(&self) -> DutyCycleState {
+        self.state
+    }
+
+    /// Time the scheduler next has something to do [ms]
+    pub fn next_poll_at(&self) -> u64 {
+        self.next_poll
+    }
+
+    /// Advance the state machine
+    ///
+    /// Returns the aggregated reading when a cycle completes. On error the
+    /// state is kept, so the failed step is retried by the next poll.
+    ///
+    /// If the sensor reports no new data for
+    /// [`data_ready_timeout_ms`](struct.DutyCycleConfig.html#structfield.data_ready_timeout_ms),
+    /// the samples of the cycle are discarded, the sensor is put to sleep
+    /// and the next cycle starts on schedule without a reading.
+    #[cfg(feature = "float")]
+    pub fn poll<I2C, D, C, E>(&mut self, sensor: &mut Sps30<I2C, D, C>, now: u64) -> Result<Option<Reading>, Error<E>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+    {
+        self.advance(sensor, now)
+    }
+
+    /// Advance the state machine, averaging in the integer output format
+    ///
+    /// See [`poll()`](#method.poll). The measurement is started in the
+    /// uint16 output format. A field is not available in the aggregated
+    /// reading if it was not in one of the samples.
+    pub fn poll_u16<I2C, D, C, E>(&mut self, sensor: &mut Sps30<I2C, D, C>, now: u64) -> Result<Option<ReadingU16>, Error<E>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+    {
+        self.advance(sensor, now)
+    }
+
+    /// Run until the current cycle completes, waiting with `delay`
+    #[cfg(feature = "float")]
+    pub fn run_cycle<I2C, D, C, E, CL, DL>(
+        &mut self,
+        sensor: &mut Sps30<I2C, D, C>,
+        clock: &mut CL,
+        delay: &mut DL,
+    ) -> Result<Reading, Error<E>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+        CL: Clock,
+        DL: delay::DelayMs<u32>,
+    {
+        self.run(sensor, clock, delay)
+    }
+
+    /// Run until the current cycle completes, waiting with `delay`, averaging
+    /// in the integer output format
+    pub fn run_cycle_u16<I2C, D, C, E, CL, DL>(
+        &mut self,
+        sensor: &mut Sps30<I2C, D, C>,
+        clock: &mut CL,
+        delay: &mut DL,
+    ) -> Result<ReadingU16, Error<E>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+        CL: Clock,
+        DL: delay::DelayMs<u32>,
+    {
+        self.run(sensor, clock, delay)
+    }
+
+    fn advance<R: Sample, I2C, D, C, E>(&mut self, sensor: &mut Sps30<I2C, D, C>, now: u64) -> Result<Option<R>, Error<E>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+    {
+        if now < self.next_poll {
+            return Ok(None);
+        }
+
+        match self.state {
+            DutyCycleState::Waiting => {
+                if self.asleep {
+                    sensor.wake_up()?;
+                    self.asleep = false;
+                }
+                sensor.start_measurement_with_format(R::FORMAT)?;
+                self.measuring = true;
+                self.cycle_started = now;
+                self.state = DutyCycleState::WarmingUp;
+                self.next_poll = now + u64::from(self.config.warm_up_ms);
+                Ok(None)
+            }
+            DutyCycleState::WarmingUp => {
+                self.state = DutyCycleState::Sampling;
+                self.last_data = now;
+                self.sample(sensor, now)
+            }
+            DutyCycleState::Sampling => self.sample(sensor, now),
+            DutyCycleState::Stopping => self.stop(sensor, now),
+        }
+    }
+
+    fn run<R: Sample, I2C, D, C, E, CL, DL>(
+        &mut self,
+        sensor: &mut Sps30<I2C, D, C>,
+        clock: &mut CL,
+        delay: &mut DL,
+    ) -> Result<R, Error<E>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+        CL: Clock,
+        DL: delay::DelayMs<u32>,
+    {
+        loop {
+            let now = clock.now_ms();
+            if let Some(reading) = self.advance(sensor, now)? {
+                return Ok(reading);
+            }
+
+            let wait = self.next_poll.saturating_sub(now);
+            if wait > 0 {
+                delay.delay_ms(wait.min(u64::from(u32::MAX)) as u32);
+            }
+        }
+    }
+
+    fn sample<R: Sample, I2C, D, C, E>(&mut self, sensor: &mut Sps30<I2C, D, C>, now: u64) -> Result<Option<R>, Error<E>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+    {
+        self.next_poll = now + u64::from(self.config.poll_interval_ms);
+        if !sensor.read_data_ready_flag()? {
+            if now.saturating_sub(self.last_data) >= u64::from(self.config.data_ready_timeout_ms) {
+                self.state = DutyCycleState::Stopping;
+                return self.stop(sensor, now);
+            }
+            return Ok(None);
+        }
+
+        let reading = R::read(sensor)?;
+        self.last_data = now;
+        reading.add_to(&mut self.sums);
+        self.flags |= reading.flags();
+        self.count += 1;
+        if self.count < self.config.samples {
+            return Ok(None);
+        }
+
+        self.completed = Some(now);
+        self.state = DutyCycleState::Stopping;
+        self.stop(sensor, now)
+    }
+
+    /// Stop the measurement, put the sensor to sleep and end the cycle
+    fn stop<R: Sample, I2C, D, C, E>(&mut self, sensor: &mut Sps30<I2C, D, C>, now: u64) -> Result<Option<R>, Error<E>>
+    where
+        I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+        D: delay::DelayMs<u8>,
+        C: Clock,
+    {
+        self.next_poll = now + u64::from(self.config.poll_interval_ms);
+        if self.measuring {
+            sensor.stop_measurement()?;
+            self.measuring = false;
+        }
+        sensor.sleep()?;
+        self.asleep = true;
+
+        let reading = self.completed.take().map(|timestamp| R::mean(&self.sums, self.count, self.flags, timestamp));
+        self.state = DutyCycleState::Waiting;
+        self.next_poll = self.cycle_started + u64::from(self.config.period_ms);
+        self.sums = Sums::default();
+        self.count = 0;
+        self.flags = QualityFlags::empty();
+
+        Ok(reading)
+    }
+}
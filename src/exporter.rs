@@ -0,0 +1,333 @@
+//! Prometheus exporter
+//!
+//! An [`Exporter`] owns the driver, samples the sensor whenever the
+//! data-ready flag is set and renders the metrics in the Prometheus text
+//! exposition format:
+//!
+//! - one gauge per [`AirInfo`] field and the quality flags, as written by
+//!   [`write_prometheus()`], once a sample was read and for
+//!   [`STALE_AFTER_MS`] after it
+//! - `sps30_status_speed`, `sps30_status_laser` and `sps30_status_fan`, the
+//!   bits of the device status register, under the same condition
+//! - `sps30_info`, always 1, with the `serial` and `firmware` labels
+//! - `sps30_samples_total` and `sps30_errors_total` by `kind` (`i2c` or
+//!   `checksum`) counters
+//! - `sps30_up`, 1 if the last exchange with the sensor succeeded
+//!
+//! [`serve()`] runs the exporter with a minimal HTTP server answering
+//! `GET /metrics` until a shutdown flag is raised. Clients are served from
+//! their own threads with the metrics rendered after the last poll, so a
+//! slow client does not delay the sampling. [`Exporter::shutdown()`] then
+//! stops the measurement and puts the sensor to sleep.
+//!
+//! [`AirInfo`]: ../struct.AirInfo.html
+//! [`write_prometheus()`]: ../output/fn.write_prometheus.html
+
+use crate::clock::Clock;
+use crate::output::{prometheus_label, write_prometheus, Record};
+use crate::quality::Reading;
+use crate::types::{Error, StatusRegisterResult};
+use crate::Sps30;
+use embedded_hal::blocking::{delay, i2c};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Interval at which the data-ready flag is polled [ms]
+pub const POLL_INTERVAL_MS: u64 = 1_000;
+/// Age after which the last sample is no longer exported [ms]
+pub const STALE_AFTER_MS: u64 = 10_000;
+/// Path of the metrics
+pub const METRICS_PATH: &str = "/metrics";
+/// Content type of the text exposition format
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// Maximum size of a request head [bytes]
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+/// Time a client gets to send its request [ms]
+const REQUEST_TIMEOUT_MS: u64 = 2_000;
+/// Sleep between accept attempts while idle [ms]
+const ACCEPT_INTERVAL_MS: u64 = 10;
+/// Maximum number of clients served at once, others are disconnected
+const MAX_CLIENTS: usize = 8;
+
+/// Sensor sampler and metrics renderer
+pub struct Exporter<I2C, D, C> {
+    sensor: Sps30<I2C, D, C>,
+    serial: String,
+    firmware: Option<(u8, u8)>,
+    reading: Option<Reading>,
+    status: Option<StatusRegisterResult>,
+    /// Time of the last sample [ms]
+    sampled_at: Option<u64>,
+    created: Instant,
+    samples: u64,
+    i2c_errors: u64,
+    checksum_errors: u64,
+    up: bool,
+}
+
+impl<I2C, D, C, E> Exporter<I2C, D, C>
+where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+D: delay::DelayMs<u8>,
+C: Clock {
+    /// Create an exporter owning the driver
+    pub fn new(sensor: Sps30<I2C, D, C>) -> Self {
+        Exporter {
+            sensor,
+            serial: String::new(),
+            firmware: None,
+            reading: None,
+            status: None,
+            sampled_at: None,
+            created: Instant::now(),
+            samples: 0,
+            i2c_errors: 0,
+            checksum_errors: 0,
+            up: false,
+        }
+    }
+
+    /// Wake the sensor up if it is sleeping, start measuring and read its
+    /// identity
+    ///
+    /// See [`Sps30::ensure_measuring()`](../struct.Sps30.html#method.ensure_measuring).
+    pub fn start(&mut self) -> Result<(), Error<E>> {
+        self.count(|s| s.ensure_measuring())?;
+
+        let serial = self.count(|s| s.read_device_serial_number())?;
+        let len = serial.iter().position(|&b| b == 0).unwrap_or(serial.len());
+        self.serial = String::from_utf8_lossy(&serial[..len]).into_owned();
+        self.firmware = Some(self.count(|s| s.read_firmware_version())?);
+        Ok(())
+    }
+
+    /// Read a sample and the status register if new values are ready
+    ///
+    /// Returns whether a sample was read. Errors are counted, not returned.
+    /// The last sample is dropped once it is older than [`STALE_AFTER_MS`].
+    pub fn poll(&mut self) -> bool {
+        let sample = (|| {
+            if !self.count(|s| s.read_data_ready_flag())? {
+                return Ok(false);
+            }
+            self.reading = Some(self.count(|s| s.read_validated_values())?);
+            self.status = Some(self.count(|s| s.read_device_status_register())?);
+            Ok::<_, Error<E>>(true)
+        })();
+
+        let sampled = sample.unwrap_or(false);
+        let now = self.now_ms();
+        if sampled {
+            self.samples += 1;
+            self.sampled_at = Some(now);
+        } else if self.sampled_at.map_or(false, |at| now.saturating_sub(at) >= STALE_AFTER_MS) {
+            self.reading = None;
+            self.status = None;
+        }
+        sampled
+    }
+
+    /// Time on the clock of the driver, or since the exporter was created
+    fn now_ms(&mut self) -> u64 {
+        match self.sensor.now() {
+            Some(now) => now,
+            None => self.created.elapsed().as_millis() as u64,
+        }
+    }
+
+    /// Stop measuring, put the sensor to sleep and return the driver
+    pub fn shutdown(mut self) -> Result<Sps30<I2C, D, C>, Error<E>> {
+        self.sensor.stop_measurement()?;
+        self.sensor.sleep()?;
+        Ok(self.sensor)
+    }
+
+    /// Last sample, unless it is stale
+    pub fn reading(&self) -> Option<&Reading> {
+        self.reading.as_ref()
+    }
+
+    /// Number of failed exchanges with the sensor, as
+    /// `(bus errors, checksum mismatches)`
+    pub fn errors(&self) -> (u64, u64) {
+        (self.i2c_errors, self.checksum_errors)
+    }
+
+    /// Write the metrics in the text exposition format
+    pub fn render<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let serial = prometheus_label(&self.serial);
+
+        if let Some(reading) = &self.reading {
+            let record = Record {
+                timestamp: 0,
+                serial: &self.serial,
+                air_info: reading.air_info,
+                flags: Some(reading.flags),
+            };
+            write_prometheus(out, &record, false)?;
+        }
+
+        if let Some(status) = &self.status {
+            let bits = [
+                ("speed", "Fan speed out of range", status.speed),
+                ("laser", "Laser failure", status.laser),
+                ("fan", "Fan failure, fan is mechanically blocked or broken", status.fan),
+            ];
+            for (name, description, set) in bits.iter() {
+                writeln!(out, "# HELP sps30_status_{} {}", name, description)?;
+                writeln!(out, "# TYPE sps30_status_{} gauge", name)?;
+                writeln!(out, "sps30_status_{}{{serial=\"{}\"}} {}", name, serial, *set as u8)?;
+            }
+        }
+
+        if let Some((major, minor)) = self.firmware {
+            writeln!(out, "# HELP sps30_info Sensor identity")?;
+            writeln!(out, "# TYPE sps30_info gauge")?;
+            writeln!(out, "sps30_info{{serial=\"{}\",firmware=\"{}.{}\"}} 1", serial, major, minor)?;
+        }
+
+        writeln!(out, "# HELP sps30_samples_total Samples read")?;
+        writeln!(out, "# TYPE sps30_samples_total counter")?;
+        writeln!(out, "sps30_samples_total {}", self.samples)?;
+        writeln!(out, "# HELP sps30_errors_total Failed exchanges with the sensor")?;
+        writeln!(out, "# TYPE sps30_errors_total counter")?;
+        writeln!(out, "sps30_errors_total{{kind=\"i2c\"}} {}", self.i2c_errors)?;
+        writeln!(out, "sps30_errors_total{{kind=\"checksum\"}} {}", self.checksum_errors)?;
+        writeln!(out, "# HELP sps30_up Whether the last exchange with the sensor succeeded")?;
+        writeln!(out, "# TYPE sps30_up gauge")?;
+        writeln!(out, "sps30_up {}", self.up as u8)
+    }
+
+    /// Run a driver command, counting its errors
+    fn count<T, F>(&mut self, f: F) -> Result<T, Error<E>>
+    where F: FnOnce(&mut Sps30<I2C, D, C>) -> Result<T, Error<E>> {
+        let res = f(&mut self.sensor);
+        match &res {
+            Ok(_) => self.up = true,
+            Err(Error::I2C(_)) => {
+                self.i2c_errors += 1;
+                self.up = false;
+            }
+            Err(Error::ChecksumMismatch) => {
+                self.checksum_errors += 1;
+                self.up = false;
+            }
+        }
+        res
+    }
+}
+
+/// Sample the sensor and answer metrics requests until `shutdown` is set
+///
+/// The exporter must have been started. Every client is served from its own
+/// thread, up to a limit, with the metrics rendered after the last poll.
+pub fn serve<I2C, D, C, E>(exporter: &mut Exporter<I2C, D, C>, listener: &TcpListener, shutdown: &AtomicBool) -> io::Result<()>
+where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+D: delay::DelayMs<u8>,
+C: Clock {
+    listener.set_nonblocking(true)?;
+    let poll_interval = Duration::from_millis(POLL_INTERVAL_MS);
+    let mut next_poll = Instant::now();
+    let metrics = Arc::new(Mutex::new(Vec::new()));
+    let clients = Arc::new(AtomicUsize::new(0));
+
+    while !shutdown.load(Ordering::SeqCst) {
+        if Instant::now() >= next_poll {
+            exporter.poll();
+            next_poll += poll_interval;
+
+            let mut body = Vec::new();
+            exporter.render(&mut body)?;
+            *metrics.lock().unwrap_or_else(|e| e.into_inner()) = body;
+        }
+
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                let metrics = Arc::clone(&metrics);
+                let clients = Arc::clone(&clients);
+                thread::spawn(move || {
+                    // A misbehaving client must not stop the exporter
+                    let _ = handle(stream, &metrics);
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(ACCEPT_INTERVAL_MS));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, metrics: &Mutex<Vec<u8>>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(Duration::from_millis(REQUEST_TIMEOUT_MS)))?;
+
+    let head = match read_request_head(&mut stream, Duration::from_millis(REQUEST_TIMEOUT_MS))? {
+        Some(head) => head,
+        None => return respond(&mut stream, "400 Bad Request", "text/plain", b"bad request\n", true),
+    };
+    let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+    let path = path.split('?').next().unwrap_or("");
+
+    match (method, path) {
+        ("GET", METRICS_PATH) | ("HEAD", METRICS_PATH) => {
+            let body = metrics.lock().unwrap_or_else(|e| e.into_inner()).clone();
+            respond(&mut stream, "200 OK", CONTENT_TYPE, &body, method == "GET")
+        }
+        ("GET", _) | ("HEAD", _) => respond(&mut stream, "404 Not Found", "text/plain", b"not found\n", method == "GET"),
+        _ => respond(&mut stream, "405 Method Not Allowed", "text/plain", b"method not allowed\n", true),
+    }
+}
+
+/// Read up to the end of the request head within `timeout`, `None` if it is
+/// invalid
+///
+/// The timeout covers the whole head, so a client trickling bytes cannot
+/// hold the connection longer.
+pub(crate) fn read_request_head(stream: &mut TcpStream, timeout: Duration) -> io::Result<Option<String>> {
+    let deadline = Instant::now() + timeout;
+    let mut head = Vec::new();
+    let mut buffer = [0; 512];
+
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        let len = stream.read(&mut buffer)?;
+        if len == 0 || head.len() + len > MAX_REQUEST_LEN {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buffer[..len]);
+    }
+
+    Ok(String::from_utf8(head).ok())
+}
+
+pub(crate) fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8], with_body: bool) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    if with_body {
+        stream.write_all(body)?;
+    }
+    stream.flush()
+}
@@ -0,0 +1,62 @@
+//! Fan cleaning windows
+//!
+//! During fan cleaning the fan runs at maximum speed for about 10 s and the
+//! number concentrations are meaningless. Cleaning is started manually with
+//! [`start_fan_cleaning()`] or automatically, every auto-cleaning interval
+//! after the measurement mode was entered.
+//!
+//! With a clock attached, the driver predicts the automatic cleaning from
+//! the last interval read or written, and flags readings taken while the
+//! fan is cleaning with [`QualityFlags::FAN_CLEANING`].
+//!
+//! [`start_fan_cleaning()`]: ../struct.Sps30.html#method.start_fan_cleaning
+//! [`QualityFlags::FAN_CLEANING`]: ../quality/struct.QualityFlags.html#associatedconstant.FAN_CLEANING
+
+/// Default auto-cleaning interval of the device [s]
+pub const DEFAULT_AUTO_CLEANING_INTERVAL: u32 = 604_800;
+
+/// Fan cleaning window configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FanCleaning {
+    /// Duration of the cleaning [ms]
+    pub duration_ms: u32,
+}
+
+impl FanCleaning {
+    /// Start of the next automatic cleaning at or after `now` [ms]
+    ///
+    /// Returns `None` when automatic cleaning is disabled, i.e. the interval
+    /// is zero.
+    pub fn next_auto_cleaning(&self, measurement_started: u64, interval_s: u32, now: u64) -> Option<u64> {
+        let interval = u64::from(interval_s) * 1000;
+        if interval == 0 {
+            return None;
+        }
+
+        let elapsed = now.saturating_sub(measurement_started);
+        Some(measurement_started + ((elapsed + interval - 1) / interval).max(1) * interval)
+    }
+
+    /// Whether an automatic cleaning is running at `now` [ms]
+    pub fn is_auto_cleaning(&self, measurement_started: u64, interval_s: u32, now: u64) -> bool {
+        let interval = u64::from(interval_s) * 1000;
+        if interval == 0 {
+            return false;
+        }
+
+        let elapsed = now.saturating_sub(measurement_started);
+        elapsed >= interval && elapsed % interval < u64::from(self.duration_ms)
+    }
+
+    /// Whether a manual cleaning started at `started` is running at `now` [ms]
+    pub fn is_manual_cleaning(&self, started: u64, now: u64) -> bool {
+        now.saturating_sub(started) < u64::from(self.duration_ms)
+    }
+}
+
+impl Default for FanCleaning {
+    /// Cleaning duration of the datasheet
+    fn default() -> Self {
+        FanCleaning { duration_ms: 10_000 }
+    }
+}
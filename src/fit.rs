@@ -0,0 +1,419 @@
+//! Co-location calibration fitting
+//!
+//! Fits the corrections of a [`CalibrationProfile`] from a time series
+//! recorded by the SPS30 and one recorded by a reference instrument at the
+//! same location.
+//!
+//! Both series are read from CSV files with a header row. The first column
+//! is the timestamp, either in Unix seconds or as an ISO 8601 UTC date-time
+//! (`2020-08-11T12:00:00Z`). The other columns are named after the
+//! [`AirInfo`] fields (see [`FIELD_NAMES`]), and the sensor series may have
+//! an additional `humidity` column with the relative humidity [%]. Every
+//! field present in both series is fitted.
+//!
+//! [`AirInfo`]: ../struct.AirInfo.html
+
+use crate::calibration::{CalibrationProfile, Correction, FIELD_NAMES};
+use std::fmt;
+use std::io::{self, BufRead};
+
+/// Name of the relative humidity column
+pub const HUMIDITY_COLUMN: &str = "humidity";
+
+/// All possible errors of the fitting
+#[derive(Debug)]
+pub enum FitError {
+    /// Reading the input failed
+    Io(io::Error),
+    /// Malformed CSV input
+    Parse {
+        /// Line number, starting at 1
+        line: usize,
+        /// What went wrong
+        message: String,
+    },
+    /// A required column is missing
+    MissingColumn(String),
+    /// The series have no field in common
+    NoCommonField,
+    /// Not enough samples could be aligned to fit the model
+    NotEnoughSamples,
+}
+
+impl fmt::Display for FitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FitError::Io(e) => write!(f, "{}", e),
+            FitError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            FitError::MissingColumn(name) => write!(f, "missing column `{}`", name),
+            FitError::NoCommonField => write!(f, "the series have no field in common"),
+            FitError::NotEnoughSamples => write!(f, "not enough aligned samples"),
+        }
+    }
+}
+
+impl std::error::Error for FitError {}
+
+impl From<io::Error> for FitError {
+    fn from(e: io::Error) -> Self {
+        FitError::Io(e)
+    }
+}
+
+/// Time series read from a CSV file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    /// Column names, without the timestamp column
+    pub columns: Vec<String>,
+    /// Timestamps [s], sorted ascending
+    pub timestamps: Vec<f64>,
+    /// One row of values per timestamp
+    pub rows: Vec<Vec<f64>>,
+}
+
+impl Series {
+    /// Read a series from CSV
+    ///
+    /// Empty cells are read as NaN and such samples are skipped when fitting.
+    pub fn from_csv<R: BufRead>(reader: R) -> Result<Self, FitError> {
+        let mut lines = reader.lines().enumerate();
+        let columns: Vec<String> = match lines.next() {
+            Some((_, header)) => header?.split(',').skip(1).map(|c| c.trim().to_string()).collect(),
+            None => return Err(FitError::Parse { line: 1, message: "missing header".into() }),
+        };
+
+        let mut samples: Vec<(f64, Vec<f64>)> = Vec::new();
+        for (i, line) in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parse_error = |message: String| FitError::Parse { line: i + 1, message };
+
+            let mut cells = line.split(',').map(str::trim);
+            let timestamp = parse_timestamp(cells.next().unwrap_or_default())
+                .ok_or_else(|| parse_error("invalid timestamp".into()))?;
+            let values = cells
+                .map(|c| if c.is_empty() { Ok(f64::NAN) } else { c.parse::<f64>() })
+                .collect::<Result<Vec<f64>, _>>()
+                .map_err(|e| parse_error(e.to_string()))?;
+            if values.len() != columns.len() {
+                return Err(parse_error(format!("expected {} values, found {}", columns.len(), values.len())));
+            }
+            samples.push((timestamp, values));
+        }
+        samples.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        Ok(Series {
+            columns,
+            timestamps: samples.iter().map(|s| s.0).collect(),
+            rows: samples.into_iter().map(|s| s.1).collect(),
+        })
+    }
+
+    /// Index of the column with the given name
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c == name)
+    }
+}
+
+/// Pair every sensor sample with the nearest reference sample
+///
+/// Returns the `(sensor, reference)` row indices of the samples whose
+/// timestamps are at most `tolerance` seconds apart.
+pub fn align(sensor: &Series, reference: &Series, tolerance: f64) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    let mut j = 0;
+
+    for (i, &t) in sensor.timestamps.iter().enumerate() {
+        while j + 1 < reference.timestamps.len()
+            && (reference.timestamps[j + 1] - t).abs() <= (reference.timestamps[j] - t).abs()
+        {
+            j += 1;
+        }
+        if let Some(&r) = reference.timestamps.get(j) {
+            if (r - t).abs() <= tolerance {
+                pairs.push((i, j));
+            }
+        }
+    }
+
+    pairs
+}
+
+/// Regression model
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    /// `y = c0 + c1·x`
+    Linear,
+    /// `y = c0 + c1·x + … + cn·xⁿ`, with a degree of 2 or 3
+    Polynomial(usize),
+    /// `y = c0 + c1·x + h·RH`
+    Humidity,
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Model::Linear => write!(f, "linear"),
+            Model::Polynomial(degree) => write!(f, "polynomial({})", degree),
+            Model::Humidity => write!(f, "humidity"),
+        }
+    }
+}
+
+/// Result of fitting one model to one field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fit {
+    /// Fitted model
+    pub model: Model,
+    /// Fitted correction
+    pub correction: Correction,
+    /// Coefficient of determination of the corrected values
+    pub r_squared: f64,
+    /// Root mean square error of the corrected values
+    pub rmse: f64,
+    /// Mean difference of the corrected values to the reference
+    pub bias: f64,
+    /// Number of samples used
+    pub samples: usize,
+}
+
+/// Fit a model to the sensor values `x` and reference values `y`
+///
+/// `humidity` is required by [`Model::Humidity`]. Returns `None` when there
+/// are not enough samples or they do not determine the model, and for a
+/// polynomial degree above 3, which a [`Correction`] cannot hold.
+pub fn fit(x: &[f64], y: &[f64], humidity: Option<&[f64]>, model: Model) -> Option<Fit> {
+    if let Model::Polynomial(degree) = model {
+        if degree > 3 {
+            return None;
+        }
+    }
+    let row = |i: usize| -> Option<Vec<f64>> {
+        match model {
+            Model::Linear => Some(vec![1.0, x[i]]),
+            Model::Polynomial(degree) => Some((0..=degree).map(|p| x[i].powi(p as i32)).collect()),
+            Model::Humidity => humidity.map(|h| vec![1.0, x[i], h[i]]),
+        }
+    };
+
+    let mut design: Vec<Vec<f64>> = Vec::new();
+    let mut target: Vec<f64> = Vec::new();
+    for (i, &t) in y.iter().enumerate().take(x.len()) {
+        let r = row(i)?;
+        if r.iter().all(|v| v.is_finite()) && t.is_finite() {
+            design.push(r);
+            target.push(t);
+        }
+    }
+
+    let beta = least_squares(&design, &target)?;
+    let predicted: Vec<f64> = design
+        .iter()
+        .map(|r| r.iter().zip(&beta).map(|(a, b)| a * b).sum())
+        .collect();
+
+    let mut correction = Correction { coefficients: [0.0; 4], humidity: 0.0 };
+    match model {
+        Model::Humidity => {
+            correction.coefficients[0] = beta[0] as f32;
+            correction.coefficients[1] = beta[1] as f32;
+            correction.humidity = beta[2] as f32;
+        }
+        _ => {
+            for (c, b) in correction.coefficients.iter_mut().zip(&beta) {
+                *c = *b as f32;
+            }
+        }
+    }
+
+    let n = target.len() as f64;
+    let mean = target.iter().sum::<f64>() / n;
+    let ss_tot: f64 = target.iter().map(|t| (t - mean).powi(2)).sum();
+    let ss_res: f64 = predicted.iter().zip(&target).map(|(p, t)| (p - t).powi(2)).sum();
+    let bias = predicted.iter().zip(&target).map(|(p, t)| p - t).sum::<f64>() / n;
+
+    Some(Fit {
+        model,
+        correction,
+        r_squared: if ss_tot > 0.0 { 1.0 - ss_res / ss_tot } else { 1.0 },
+        rmse: (ss_res / n).sqrt(),
+        bias,
+        samples: target.len(),
+    })
+}
+
+/// Fits of every model to one field
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldReport {
+    /// Field name, see [`FIELD_NAMES`]
+    pub field: &'static str,
+    /// Fit of every model that could be determined
+    pub fits: Vec<Fit>,
+}
+
+/// Outcome of a co-location calibration
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    /// Profile built from the selected model
+    pub profile: CalibrationProfile,
+    /// Statistics of every fitted field and model
+    pub reports: Vec<FieldReport>,
+}
+
+/// Fit every field present in both series
+///
+/// Fields are fitted with the linear, quadratic, cubic and, when the sensor
+/// series has a `humidity` column, the humidity model. The profile is built
+/// from `model`, falling back to the linear one for fields where it could
+/// not be fitted.
+pub fn calibrate(
+    sensor: &Series,
+    reference: &Series,
+    serial: &[u8],
+    tolerance: f64,
+    model: Model,
+) -> Result<Calibration, FitError> {
+    let pairs = align(sensor, reference, tolerance);
+    let humidity: Option<Vec<f64>> = sensor
+        .column(HUMIDITY_COLUMN)
+        .map(|c| pairs.iter().map(|&(i, _)| sensor.rows[i][c]).collect());
+    if model == Model::Humidity && humidity.is_none() {
+        return Err(FitError::MissingColumn(HUMIDITY_COLUMN.into()));
+    }
+
+    let mut profile = CalibrationProfile::new(serial);
+    let mut reports = Vec::new();
+
+    for &field in FIELD_NAMES.iter() {
+        let (s, r) = match (sensor.column(field), reference.column(field)) {
+            (Some(s), Some(r)) => (s, r),
+            _ => continue,
+        };
+        let x: Vec<f64> = pairs.iter().map(|&(i, _)| sensor.rows[i][s]).collect();
+        let y: Vec<f64> = pairs.iter().map(|&(_, j)| reference.rows[j][r]).collect();
+
+        let models = [Model::Linear, Model::Polynomial(2), Model::Polynomial(3), Model::Humidity];
+        let fits: Vec<Fit> = models
+            .iter()
+            .filter_map(|&m| fit(&x, &y, humidity.as_deref(), m))
+            .collect();
+
+        let selected = fits
+            .iter()
+            .find(|f| f.model == model)
+            .or_else(|| fits.iter().find(|f| f.model == Model::Linear))
+            .ok_or(FitError::NotEnoughSamples)?;
+        if let Some(c) = profile.correction_mut(field) {
+            *c = selected.correction;
+        }
+
+        reports.push(FieldReport { field, fits });
+    }
+
+    if reports.is_empty() {
+        return Err(FitError::NoCommonField);
+    }
+
+    Ok(Calibration { profile, reports })
+}
+
+/// Solve the normal equations of `design · β = target`
+fn least_squares(design: &[Vec<f64>], target: &[f64]) -> Option<Vec<f64>> {
+    let n = design.first()?.len();
+    if design.len() < n {
+        return None;
+    }
+
+    // Augmented matrix [AᵀA | Aᵀy]
+    let mut m = vec![vec![0.0; n + 1]; n];
+    for (row, &t) in design.iter().zip(target) {
+        for i in 0..n {
+            for j in 0..n {
+                m[i][j] += row[i] * row[j];
+            }
+            m[i][n] += row[i] * t;
+        }
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..n {
+        // No pivot if the sums overflowed to NaN
+        let pivot = (col..n).try_fold(col, |best, r| match m[r][col].abs() {
+            v if v.is_nan() => None,
+            v if v > m[best][col].abs() => Some(r),
+            _ => Some(best),
+        })?;
+        if m[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        m.swap(col, pivot);
+        let pivot_row = m[col].clone();
+        for (r, row) in m.iter_mut().enumerate() {
+            if r != col {
+                let factor = row[col] / pivot_row[col];
+                for (v, p) in row.iter_mut().zip(&pivot_row).skip(col) {
+                    *v -= factor * p;
+                }
+            }
+        }
+    }
+
+    let beta: Vec<f64> = (0..n).map(|i| m[i][n] / m[i][i]).collect();
+    if beta.iter().all(|b| b.is_finite()) { Some(beta) } else { None }
+}
+
+/// Parse a timestamp in Unix seconds or ISO 8601 UTC
+fn parse_timestamp(s: &str) -> Option<f64> {
+    if let Ok(t) = s.parse::<f64>() {
+        return Some(t);
+    }
+
+    // YYYY-MM-DDTHH:MM:SS[.fff][Z]
+    let s = s.trim_end_matches('Z');
+    let (date, time) = s.split_at(s.find(['T', ' '])?);
+    let time = &time[1..];
+
+    let mut date = date.split('-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.split(':');
+    let hour = time.next()?.parse::<f64>().ok()?;
+    let minute = time.next()?.parse::<f64>().ok()?;
+    let second = time.next().map_or(Some(0.0), |p| p.parse::<f64>().ok())?;
+
+    Some(days_from_civil(year, month, day) as f64 * 86400.0 + hour * 3600.0 + minute * 60.0 + second)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_timestamp_check() {
+        assert_eq!(parse_timestamp("1597147200"), Some(1_597_147_200.0));
+        assert_eq!(parse_timestamp("2020-08-11T12:00:00Z"), Some(1_597_147_200.0));
+        assert_eq!(parse_timestamp("2020-08-11 12:00:30.5"), Some(1_597_147_230.5));
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn least_squares_check() {
+        let design = vec![vec![1.0, 0.0], vec![1.0, 1.0], vec![1.0, 2.0]];
+        let beta = least_squares(&design, &[1.0, 3.0, 5.0]).unwrap();
+
+        assert!((beta[0] - 1.0).abs() < 1e-9);
+        assert!((beta[1] - 2.0).abs() < 1e-9);
+    }
+}
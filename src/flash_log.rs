@@ -0,0 +1,358 @@
+//! Sample ring log in NOR flash
+//!
+//! A [`FlashLog`] buffers readings in an [`embedded-storage`] [`NorFlash`]
+//! while they cannot be uploaded. Every reading gets a sequence number;
+//! [`FlashLog::pending()`] iterates the readings not acknowledged yet and
+//! [`FlashLog::acknowledge()`] marks them uploaded.
+//!
+//! The flash is used as a ring of erase sectors. Records are appended to the
+//! newest sector; when it is full, the oldest sector is erased and becomes the
+//! newest, so all sectors are erased equally often. Readings in the erased
+//! sector are lost if they were not acknowledged.
+//!
+//! # Layout
+//!
+//! Each sector starts with a header slot, followed by record slots. Slots are
+//! [`RECORD_LEN`] bytes padded to the write and read sizes of the flash; the
+//! padding is left erased. All integers are little endian.
+//!
+//! | Slot       | Content                                                       |
+//! |------------|---------------------------------------------------------------|
+//! | header     | kind, sector number, next sequence number, acknowledged one    |
+//! | sample     | kind, sequence number, timestamp (u64), values, quality flags |
+//! | acknowledge| kind, sequence number acknowledged up to                       |
+//!
+//! The values are the [`AirInfo`] fields as uint16, scaled by [`SCALES`]
+//! with [`NOT_AVAILABLE`] for unknown or invalid values. Every slot ends with
+//! a CRC-16 (CCITT), so a slot torn by a power loss is ignored when the log
+//! is opened again. The acknowledged sequence number is carried into every
+//! new sector header, so it survives the erasure of older sectors.
+//!
+//! [`embedded-storage`]: https://docs.rs/embedded-storage
+//! [`NorFlash`]: https://docs.rs/embedded-storage/0.3/embedded_storage/nor_flash/trait.NorFlash.html
+
+use crate::codec::{dequantize, quantize};
+use crate::quality::{QualityFlags, Reading};
+use crate::types::AirInfo;
+use embedded_storage::nor_flash::NorFlash;
+
+pub use crate::codec::{FINE_SCALES as SCALES, NOT_AVAILABLE};
+
+/// Size of a record before padding [bytes]
+pub const RECORD_LEN: usize = 1 + 4 + 8 + 10 * 2 + 2 + 2;
+/// Largest supported slot, the record padded to the write and read sizes [bytes]
+pub const MAX_SLOT_LEN: usize = 64;
+
+const KIND_HEADER: u8 = 0x5A;
+const KIND_SAMPLE: u8 = 0x3C;
+const KIND_ACKNOWLEDGE: u8 = 0x0F;
+const ERASED: u8 = 0xFF;
+
+/// Flash log error
+#[derive(Debug)]
+pub enum FlashLogError<E> {
+    /// Flash error
+    Flash(E),
+    /// The flash has fewer than two sectors, a sector does not hold a header
+    /// and a record or the slot would exceed [`MAX_SLOT_LEN`]
+    Geometry,
+}
+
+impl<E> From<E> for FlashLogError<E> {
+    fn from(e: E) -> Self {
+        FlashLogError::Flash(e)
+    }
+}
+
+/// Reading read back from the log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// Sequence number
+    pub sequence: u32,
+    /// Reading, with the timestamp it was appended with
+    pub reading: Reading,
+}
+
+/// Content of a valid slot
+enum Slot {
+    Header { sector: u32, next_sequence: u32, acknowledged: u32 },
+    Sample(Sample),
+    Acknowledge(u32),
+}
+
+/// Ring log of readings in NOR flash
+#[derive(Debug)]
+pub struct FlashLog<F> {
+    flash: F,
+    sectors: u32,
+    slot_len: usize,
+    /// Index of the sector being appended to
+    head: u32,
+    /// Number of the head sector, counting every sector started
+    head_number: u32,
+    /// Offset of the next free slot in the head sector
+    write_offset: usize,
+    next_sequence: u32,
+    acknowledged: u32,
+}
+
+impl<F: NorFlash> FlashLog<F> {
+    /// Open the log on the whole flash, recovering its content
+    ///
+    /// A flash without a valid sector is formatted.
+    pub fn open(flash: F) -> Result<Self, FlashLogError<F::Error>> {
+        let align = F::WRITE_SIZE.max(F::READ_SIZE);
+        let slot_len = (RECORD_LEN + align - 1) / align * align;
+        let sectors = (flash.capacity() / F::ERASE_SIZE) as u32;
+        if sectors < 2 || slot_len > MAX_SLOT_LEN || F::ERASE_SIZE < 2 * slot_len {
+            return Err(FlashLogError::Geometry);
+        }
+
+        let mut log = FlashLog {
+            flash,
+            sectors,
+            slot_len,
+            head: 0,
+            head_number: 0,
+            write_offset: 0,
+            next_sequence: 1,
+            acknowledged: 0,
+        };
+
+        let mut found = false;
+        for sector in 0..sectors {
+            if let Some(Slot::Header { sector: number, next_sequence, acknowledged }) = log.read_slot(sector, 0)? {
+                if !found || number > log.head_number {
+                    log.head = sector;
+                    log.head_number = number;
+                }
+                found = true;
+                log.next_sequence = log.next_sequence.max(next_sequence);
+                log.acknowledged = log.acknowledged.max(acknowledged);
+                log.recover_sector(sector)?;
+            }
+        }
+
+        if found {
+            log.write_offset = log.last_used_slot(log.head)? + log.slot_len;
+        } else {
+            log.start_sector(0, 1)?;
+        }
+        Ok(log)
+    }
+
+    /// Sequence number of the next appended reading
+    pub fn next_sequence(&self) -> u32 {
+        self.next_sequence
+    }
+
+    /// Sequence number the readings are acknowledged up to, 0 if none
+    pub fn acknowledged(&self) -> u32 {
+        self.acknowledged
+    }
+
+    /// Append a reading taken at `timestamp`, returning its sequence number
+    pub fn append(&mut self, timestamp: u64, reading: &Reading) -> Result<u32, FlashLogError<F::Error>> {
+        let sequence = self.next_sequence;
+        let mut slot = [ERASED; MAX_SLOT_LEN];
+        slot[0] = KIND_SAMPLE;
+        slot[1..5].copy_from_slice(&sequence.to_le_bytes());
+        slot[5..13].copy_from_slice(&timestamp.to_le_bytes());
+        for (i, value) in reading.air_info.to_array().iter().enumerate() {
+            slot[13 + 2 * i..15 + 2 * i].copy_from_slice(&quantize(*value, SCALES[i]).to_le_bytes());
+        }
+        slot[33..35].copy_from_slice(&reading.flags.bits().to_le_bytes());
+
+        self.write_record(&mut slot)?;
+        self.next_sequence = sequence.wrapping_add(1);
+        Ok(sequence)
+    }
+
+    /// Acknowledge the readings up to and including `sequence`
+    pub fn acknowledge(&mut self, sequence: u32) -> Result<(), FlashLogError<F::Error>> {
+        let sequence = sequence.min(self.next_sequence - 1);
+        if sequence <= self.acknowledged {
+            return Ok(());
+        }
+
+        let mut slot = [ERASED; MAX_SLOT_LEN];
+        slot[0] = KIND_ACKNOWLEDGE;
+        slot[1..5].copy_from_slice(&sequence.to_le_bytes());
+        self.write_record(&mut slot)?;
+        self.acknowledged = sequence;
+        Ok(())
+    }
+
+    /// Readings not acknowledged yet, oldest first
+    pub fn pending(&mut self) -> Pending<'_, F> {
+        Pending { log: self, sector: 0, offset: 0 }
+    }
+
+    /// Destroy the log, returning the flash
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    /// Write a record slot, moving to the next sector if the head is full
+    fn write_record(&mut self, slot: &mut [u8; MAX_SLOT_LEN]) -> Result<(), FlashLogError<F::Error>> {
+        if self.write_offset + self.slot_len > F::ERASE_SIZE {
+            let next = (self.head + 1) % self.sectors;
+            self.start_sector(next, self.head_number.wrapping_add(1))?;
+        }
+
+        seal(slot);
+        let offset = self.head as usize * F::ERASE_SIZE + self.write_offset;
+        self.flash.write(offset as u32, &slot[..self.slot_len])?;
+        self.write_offset += self.slot_len;
+        Ok(())
+    }
+
+    /// Erase a sector and make it the head
+    fn start_sector(&mut self, sector: u32, number: u32) -> Result<(), FlashLogError<F::Error>> {
+        let start = sector * F::ERASE_SIZE as u32;
+        self.flash.erase(start, start + F::ERASE_SIZE as u32)?;
+
+        let mut slot = [ERASED; MAX_SLOT_LEN];
+        slot[0] = KIND_HEADER;
+        slot[1..5].copy_from_slice(&number.to_le_bytes());
+        slot[5..9].copy_from_slice(&self.next_sequence.to_le_bytes());
+        slot[9..13].copy_from_slice(&self.acknowledged.to_le_bytes());
+        seal(&mut slot);
+        self.flash.write(start, &slot[..self.slot_len])?;
+
+        self.head = sector;
+        self.head_number = number;
+        self.write_offset = self.slot_len;
+        Ok(())
+    }
+
+    /// Take the sequence numbers of the records of a sector into account
+    fn recover_sector(&mut self, sector: u32) -> Result<(), FlashLogError<F::Error>> {
+        let mut offset = self.slot_len;
+        while offset + self.slot_len <= F::ERASE_SIZE {
+            match self.read_slot(sector, offset)? {
+                Some(Slot::Sample(sample)) => {
+                    self.next_sequence = self.next_sequence.max(sample.sequence.wrapping_add(1));
+                }
+                Some(Slot::Acknowledge(sequence)) => self.acknowledged = self.acknowledged.max(sequence),
+                _ => {}
+            }
+            offset += self.slot_len;
+        }
+        Ok(())
+    }
+
+    /// Offset of the last slot of a sector that is not erased
+    fn last_used_slot(&mut self, sector: u32) -> Result<usize, FlashLogError<F::Error>> {
+        let mut last = 0;
+        let mut offset = self.slot_len;
+        let mut slot = [0; MAX_SLOT_LEN];
+        while offset + self.slot_len <= F::ERASE_SIZE {
+            let start = sector as usize * F::ERASE_SIZE + offset;
+            self.flash.read(start as u32, &mut slot[..self.slot_len])?;
+            if slot[..self.slot_len].iter().any(|&b| b != ERASED) {
+                last = offset;
+            }
+            offset += self.slot_len;
+        }
+        Ok(last)
+    }
+
+    /// Read a slot, `None` if it is erased or torn
+    fn read_slot(&mut self, sector: u32, offset: usize) -> Result<Option<Slot>, FlashLogError<F::Error>> {
+        let mut slot = [0; MAX_SLOT_LEN];
+        let start = sector as usize * F::ERASE_SIZE + offset;
+        self.flash.read(start as u32, &mut slot[..self.slot_len])?;
+        if crc16(&slot[..RECORD_LEN - 2]).to_le_bytes() != slot[RECORD_LEN - 2..RECORD_LEN] {
+            return Ok(None);
+        }
+
+        let word = |i: usize| u32::from_le_bytes([slot[i], slot[i + 1], slot[i + 2], slot[i + 3]]);
+        Ok(match slot[0] {
+            KIND_HEADER => Some(Slot::Header { sector: word(1), next_sequence: word(5), acknowledged: word(9) }),
+            KIND_ACKNOWLEDGE => Some(Slot::Acknowledge(word(1))),
+            KIND_SAMPLE => {
+                let mut timestamp = [0; 8];
+                timestamp.copy_from_slice(&slot[5..13]);
+                let mut values = [0.0; 10];
+                for (i, value) in values.iter_mut().enumerate() {
+                    *value = dequantize(u16::from_le_bytes([slot[13 + 2 * i], slot[14 + 2 * i]]), SCALES[i]);
+                }
+                Some(Slot::Sample(Sample {
+                    sequence: word(1),
+                    reading: Reading {
+                        air_info: AirInfo::from_array(&values),
+                        flags: QualityFlags::from_bits(u16::from_le_bytes([slot[33], slot[34]])),
+                        timestamp: Some(u64::from_le_bytes(timestamp)),
+                    },
+                }))
+            }
+            _ => None,
+        })
+    }
+}
+
+/// Iterator over the readings not acknowledged yet
+#[derive(Debug)]
+pub struct Pending<'a, F> {
+    log: &'a mut FlashLog<F>,
+    /// Sectors visited, starting after the head
+    sector: u32,
+    offset: usize,
+}
+
+impl<F: NorFlash> Iterator for Pending<'_, F> {
+    type Item = Result<Sample, FlashLogError<F::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let log = &mut *self.log;
+        while self.sector < log.sectors {
+            let sector = (log.head + 1 + self.sector) % log.sectors;
+            let end = if sector == log.head { log.write_offset } else { F::ERASE_SIZE };
+
+            if self.offset == 0 {
+                // Skip the sectors that were never started or were torn
+                match log.read_slot(sector, 0) {
+                    Ok(Some(Slot::Header { .. })) => self.offset = log.slot_len,
+                    Ok(_) => self.offset = F::ERASE_SIZE,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            while self.offset + log.slot_len <= end {
+                let slot = log.read_slot(sector, self.offset);
+                self.offset += log.slot_len;
+                match slot {
+                    Ok(Some(Slot::Sample(sample))) if sample.sequence > log.acknowledged => return Some(Ok(sample)),
+                    Ok(_) => {}
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            self.sector += 1;
+            self.offset = 0;
+        }
+        None
+    }
+}
+
+/// Append the CRC of the record
+fn seal(slot: &mut [u8; MAX_SLOT_LEN]) {
+    let crc = crc16(&slot[..RECORD_LEN - 2]);
+    slot[RECORD_LEN - 2..RECORD_LEN].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, initialization 0xFFFF)
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
@@ -0,0 +1,535 @@
+//! HTTP JSON API and WebSocket live stream
+//!
+//! An [`HttpApi`] owns the driver, samples the sensor whenever the
+//! data-ready flag is set and answers these requests:
+//!
+//! - `GET /api/reading`: last sample, as a JSON Lines record of the
+//!   [`output`] module, or `503` before the first one
+//! - `GET /api/info`: `product_type`, `serial` and `firmware`
+//! - `GET /api/status`: `speed`, `laser` and `fan`, the bits of the device
+//!   status register
+//! - `GET /api/auto-cleaning-interval`: `interval`, in seconds
+//! - `POST /api/fan-cleaning`: start a fan cleaning
+//! - `POST /api/reset`: reset the device and start measuring again
+//! - `GET /api/stream`: WebSocket pushing every new sample as a text message
+//!   in the format of `/api/reading`, starting with the last one
+//!
+//! The `POST` requests are refused with `403` unless a token was set with
+//! [`HttpApi::with_token()`] and the request carries it as
+//! `Authorization: Bearer <token>`.
+//!
+//! Errors are JSON objects with an `error` name: `no_reading`, `forbidden`,
+//! `i2c` or `checksum_mismatch`. Sensor errors are answered with `502`.
+//!
+//! Stream frames that a client cannot take right away are buffered; a client
+//! that falls further behind than [`MAX_PENDING_LEN`] bytes is closed.
+//!
+//! [`serve()`] runs the API until a shutdown flag is raised. Clients send
+//! their request and take the response on their own threads, up to a limit,
+//! while the sensor commands are run between samples, so a slow client does
+//! not delay the sampling nor the streams. [`HttpApi::shutdown()`] then
+//! closes the streams, stops the measurement and puts the sensor to sleep.
+//!
+//! [`output`]: ../output/index.html
+
+use crate::clock::Clock;
+use crate::exporter::{read_request_head, respond};
+use crate::output::{json_string, write_json, Record};
+use crate::quality::Reading;
+use crate::types::Error;
+use crate::Sps30;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use embedded_hal::blocking::{delay, i2c};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sha1::{Digest, Sha1};
+
+/// Interval at which the data-ready flag is polled [ms]
+pub const POLL_INTERVAL_MS: u64 = 250;
+/// Path of the WebSocket stream
+pub const STREAM_PATH: &str = "/api/stream";
+/// Maximum size of the frames buffered for a stream client [bytes]
+pub const MAX_PENDING_LEN: usize = 64 * 1024;
+/// Content type of the responses
+const CONTENT_TYPE: &str = "application/json";
+/// Time a client gets to send its request, and to take the response [ms]
+const REQUEST_TIMEOUT_MS: u64 = 2_000;
+/// Sleep between accept attempts while idle [ms]
+const ACCEPT_INTERVAL_MS: u64 = 10;
+/// Maximum number of requests served at once, others are disconnected
+const MAX_CLIENTS: usize = 8;
+/// Maximum size of the frames received from a stream client [bytes]
+const MAX_FRAME_LEN: usize = 4 * 1024;
+/// GUID of the WebSocket handshake, RFC 6455
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Paths answered by the API
+const ROUTES: [&str; 7] = [
+    "/api/reading",
+    "/api/info",
+    "/api/status",
+    "/api/auto-cleaning-interval",
+    "/api/fan-cleaning",
+    "/api/reset",
+    STREAM_PATH,
+];
+
+/// Message from a client thread to the sampling thread
+enum Message {
+    /// Request head to answer
+    Request(String, Sender<Reply>),
+    /// Stream client that completed the WebSocket handshake
+    Stream(TcpStream),
+}
+
+/// Answer of the sampling thread to a request
+enum Reply {
+    /// Response with a status and a JSON body, none if empty
+    Respond(&'static str, Vec<u8>),
+    /// Complete the WebSocket handshake, sending the last sample if any
+    Upgrade(Option<String>),
+}
+
+/// Client of the WebSocket stream
+struct StreamClient {
+    stream: TcpStream,
+    inbox: Vec<u8>,
+    /// Frames not written yet
+    outbox: Vec<u8>,
+}
+
+/// Sensor sampler and HTTP request handler
+pub struct HttpApi<I2C, D, C> {
+    sensor: Sps30<I2C, D, C>,
+    product_type: String,
+    serial: String,
+    firmware: Option<(u8, u8)>,
+    reading: Option<(u64, Reading)>,
+    clients: Vec<StreamClient>,
+    token: Option<String>,
+}
+
+impl<I2C, D, C, E> HttpApi<I2C, D, C>
+where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+D: delay::DelayMs<u8>,
+C: Clock {
+    /// Create an API owning the driver
+    pub fn new(sensor: Sps30<I2C, D, C>) -> Self {
+        HttpApi {
+            sensor,
+            product_type: String::new(),
+            serial: String::new(),
+            firmware: None,
+            reading: None,
+            clients: Vec::new(),
+            token: None,
+        }
+    }
+
+    /// Allow the `POST` requests carrying the bearer token
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Wake the sensor up if it is sleeping, start measuring and read its
+    /// identity
+    ///
+    /// See [`Sps30::ensure_measuring()`](../struct.Sps30.html#method.ensure_measuring).
+    pub fn start(&mut self) -> Result<(), Error<E>> {
+        self.sensor.ensure_measuring()?;
+
+        self.product_type = text(&self.sensor.read_device_product_type()?);
+        self.serial = text(&self.sensor.read_device_serial_number()?);
+        self.firmware = Some(self.sensor.read_firmware_version()?);
+        Ok(())
+    }
+
+    /// Read a sample if new values are ready and push it to the stream
+    /// clients, returning whether one was read
+    pub fn poll(&mut self) -> Result<bool, Error<E>> {
+        if !self.sensor.read_data_ready_flag()? {
+            return Ok(false);
+        }
+
+        let reading = self.sensor.read_validated_values()?;
+        self.reading = Some((unix_time_ms(), reading));
+
+        let message = self.reading_json().unwrap_or_default();
+        self.retain_clients(|client| client.send(OPCODE_TEXT, message.as_bytes()));
+        Ok(true)
+    }
+
+    /// Last sample, with its time [ms since the Unix epoch]
+    pub fn reading(&self) -> Option<&(u64, Reading)> {
+        self.reading.as_ref()
+    }
+
+    /// Number of connected stream clients
+    pub fn stream_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Close the streams, stop measuring, put the sensor to sleep and return
+    /// the driver
+    pub fn shutdown(mut self) -> Result<Sps30<I2C, D, C>, Error<E>> {
+        for mut client in self.clients.drain(..) {
+            // Best effort, the client may not take the pending frames
+            client.send(OPCODE_CLOSE, &[]);
+        }
+        self.sensor.stop_measurement()?;
+        self.sensor.sleep()?;
+        Ok(self.sensor)
+    }
+
+    /// Last sample as a JSON object
+    fn reading_json(&self) -> Option<String> {
+        let (timestamp, reading) = self.reading?;
+        let record = Record {
+            timestamp,
+            serial: &self.serial,
+            air_info: reading.air_info,
+            flags: Some(reading.flags),
+        };
+        let mut json = Vec::new();
+        write_json(&mut json, &record).ok()?;
+        String::from_utf8(json).ok().map(|json| json.trim_end().to_string())
+    }
+
+    /// Answer a request head
+    fn answer(&mut self, head: &str) -> Reply {
+        let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+        let method = parts.next().unwrap_or("");
+        let path = parts.next().unwrap_or("");
+        let path = path.split('?').next().unwrap_or("");
+
+        let authorized = match (&self.token, header(head, "Authorization")) {
+            (Some(token), Some(value)) => value.strip_prefix("Bearer ").map_or(false, |v| v.trim() == token),
+            _ => false,
+        };
+
+        let res = match (method, path) {
+            ("GET", "/api/reading") => match self.reading_json() {
+                Some(json) => Ok(json),
+                None => return Reply::Respond("503 Service Unavailable", error_json("no_reading")),
+            },
+            ("GET", "/api/info") => Ok(self.info_json()),
+            ("GET", "/api/status") => self.sensor.read_device_status_register().map(|status| {
+                format!("{{\"speed\":{},\"laser\":{},\"fan\":{}}}", status.speed, status.laser, status.fan)
+            }),
+            ("GET", "/api/auto-cleaning-interval") => self
+                .sensor
+                .read_auto_cleaning_interval()
+                .map(|interval| format!("{{\"interval\":{}}}", interval)),
+            ("POST", "/api/fan-cleaning") | ("POST", "/api/reset") if !authorized => {
+                return Reply::Respond("403 Forbidden", error_json("forbidden"));
+            }
+            ("POST", "/api/fan-cleaning") => self.sensor.start_fan_cleaning().map(|_| String::new()),
+            ("POST", "/api/reset") => {
+                // The device is idle after a reset
+                let reset = self.sensor.device_reset().and_then(|_| self.sensor.start_measurement());
+                reset.map(|_| String::new())
+            }
+            ("GET", STREAM_PATH) => {
+                let is_websocket = header(head, "Upgrade").map_or(false, |v| v.eq_ignore_ascii_case("websocket"));
+                if !is_websocket || header(head, "Sec-WebSocket-Key").is_none() {
+                    return Reply::Respond("426 Upgrade Required", error_json("websocket_required"));
+                }
+                return Reply::Upgrade(self.reading_json());
+            }
+            (_, path) if ROUTES.contains(&path) => {
+                return Reply::Respond("405 Method Not Allowed", error_json("method_not_allowed"));
+            }
+            _ => return Reply::Respond("404 Not Found", error_json("not_found")),
+        };
+
+        match res {
+            Ok(body) if body.is_empty() => Reply::Respond("204 No Content", Vec::new()),
+            Ok(body) => Reply::Respond("200 OK", body.into_bytes()),
+            Err(Error::I2C(_)) => Reply::Respond("502 Bad Gateway", error_json("i2c")),
+            Err(Error::ChecksumMismatch) => Reply::Respond("502 Bad Gateway", error_json("checksum_mismatch")),
+        }
+    }
+
+    /// Answer the requests and add the stream clients the client threads
+    /// sent
+    fn dispatch(&mut self, messages: &Receiver<Message>) {
+        while let Ok(message) = messages.try_recv() {
+            match message {
+                Message::Request(head, reply) => {
+                    // The client thread is gone if it timed out
+                    let _ = reply.send(self.answer(&head));
+                }
+                Message::Stream(stream) => self.clients.push(StreamClient {
+                    stream,
+                    inbox: Vec::new(),
+                    outbox: Vec::new(),
+                }),
+            }
+        }
+    }
+
+    fn info_json(&self) -> String {
+        let firmware = match self.firmware {
+            Some((major, minor)) => json_string(&format!("{}.{}", major, minor)),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"product_type\":{},\"serial\":{},\"firmware\":{}}}",
+            json_string(&self.product_type),
+            json_string(&self.serial),
+            firmware
+        )
+    }
+
+    /// Answer the control frames of the stream clients, write their pending
+    /// frames and drop the ones that are gone
+    fn receive(&mut self) {
+        self.retain_clients(|client| client.receive() && client.flush());
+    }
+
+    /// Keep the stream clients for which `f` returns true
+    fn retain_clients<F: FnMut(&mut StreamClient) -> bool>(&mut self, mut f: F) {
+        let mut i = 0;
+        while i < self.clients.len() {
+            if f(&mut self.clients[i]) {
+                i += 1;
+            } else {
+                self.clients.remove(i);
+            }
+        }
+    }
+}
+
+impl StreamClient {
+    /// Queue a frame and write as much of the pending ones as the client
+    /// takes, returns false once the client is gone
+    fn send(&mut self, opcode: u8, payload: &[u8]) -> bool {
+        // Writing into a `Vec` cannot fail
+        let _ = write_frame(&mut self.outbox, opcode, payload);
+        if self.outbox.len() > MAX_PENDING_LEN {
+            // Too slow, close it rather than cutting a frame short
+            let _ = self.stream.shutdown(Shutdown::Both);
+            return false;
+        }
+        self.flush()
+    }
+
+    /// Write as much of the pending frames as the client takes, returns
+    /// false once the client is gone
+    fn flush(&mut self) -> bool {
+        while !self.outbox.is_empty() {
+            match self.stream.write(&self.outbox) {
+                Ok(0) => return false,
+                Ok(len) => {
+                    self.outbox.drain(..len);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+
+    /// Handle the frames received, returns false once the client is gone
+    fn receive(&mut self) -> bool {
+        let mut buffer = [0; 512];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return false,
+                Ok(len) => self.inbox.extend_from_slice(&buffer[..len]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+            if self.inbox.len() > MAX_FRAME_LEN {
+                return false;
+            }
+        }
+
+        while let Some((opcode, payload, len)) = read_frame(&self.inbox) {
+            self.inbox.drain(..len);
+            match opcode {
+                OPCODE_CLOSE => {
+                    // Echo the status code
+                    self.send(OPCODE_CLOSE, &payload[..payload.len().min(2)]);
+                    return false;
+                }
+                OPCODE_PING if !self.send(OPCODE_PONG, &payload) => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+}
+
+/// Sample the sensor and answer requests until `shutdown` is set
+///
+/// The API must have been started. Every client is served from its own
+/// thread, up to a limit, with the sensor commands run between samples.
+pub fn serve<I2C, D, C, E>(api: &mut HttpApi<I2C, D, C>, listener: &TcpListener, shutdown: &AtomicBool) -> io::Result<()>
+where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+D: delay::DelayMs<u8>,
+C: Clock {
+    listener.set_nonblocking(true)?;
+    let poll_interval = Duration::from_millis(POLL_INTERVAL_MS);
+    let mut next_poll = Instant::now();
+    let (sender, messages) = mpsc::channel();
+    let clients = Arc::new(AtomicUsize::new(0));
+
+    while !shutdown.load(Ordering::SeqCst) {
+        if Instant::now() >= next_poll {
+            // Failures are retried at the next poll
+            let _ = api.poll();
+            next_poll += poll_interval;
+        }
+        api.receive();
+        api.dispatch(&messages);
+
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                let sender = sender.clone();
+                let clients = Arc::clone(&clients);
+                thread::spawn(move || {
+                    // A misbehaving client must not stop the API
+                    let _ = handle(stream, &sender);
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(ACCEPT_INTERVAL_MS));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a request, have the sampling thread answer it and write the
+/// response, or complete the WebSocket handshake and hand the stream over
+fn handle(mut stream: TcpStream, sender: &Sender<Message>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(Duration::from_millis(REQUEST_TIMEOUT_MS)))?;
+
+    let head = match read_request_head(&mut stream, Duration::from_millis(REQUEST_TIMEOUT_MS))? {
+        Some(head) => head,
+        None => return respond(&mut stream, "400 Bad Request", CONTENT_TYPE, &error_json("bad_request"), true),
+    };
+
+    let (reply, answer) = mpsc::channel();
+    let gone = || io::Error::new(io::ErrorKind::BrokenPipe, "server stopped");
+    let key = header(&head, "Sec-WebSocket-Key").unwrap_or("").to_string();
+    sender.send(Message::Request(head, reply)).map_err(|_| gone())?;
+
+    match answer.recv().map_err(|_| gone())? {
+        Reply::Respond(status, body) => respond(&mut stream, status, CONTENT_TYPE, &body, !body.is_empty()),
+        Reply::Upgrade(reading) => {
+            write!(
+                stream,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(&key)
+            )?;
+            if let Some(json) = reading {
+                write_frame(&mut stream, OPCODE_TEXT, json.as_bytes())?;
+            }
+            stream.flush()?;
+            stream.set_nonblocking(true)?;
+            sender.send(Message::Stream(stream)).map_err(|_| gone())
+        }
+    }
+}
+
+/// `Sec-WebSocket-Accept` value answering a `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WEBSOCKET_GUID.as_bytes());
+    STANDARD.encode(sha1.finalize())
+}
+
+fn text(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn error_json(name: &str) -> Vec<u8> {
+    format!("{{\"error\":\"{}\"}}", name).into_bytes()
+}
+
+/// Value of a header of the request head
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim())
+}
+
+/// Write an unmasked, unfragmented frame
+fn write_frame<W: Write>(out: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    out.write_all(&frame)
+}
+
+/// Decode the first complete frame, as `(opcode, unmasked payload, frame
+/// length)`
+fn read_frame(data: &[u8]) -> Option<(u8, Vec<u8>, usize)> {
+    let opcode = data.first()? & 0x0f;
+    let masked = data.get(1)? & 0x80 != 0;
+    let (len, mut pos) = match data[1] & 0x7f {
+        126 => (usize::from(u16::from_be_bytes([*data.get(2)?, *data.get(3)?])), 4),
+        127 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(data.get(2..10)?);
+            (usize::try_from(u64::from_be_bytes(bytes)).ok()?, 10)
+        }
+        len => (usize::from(len), 2),
+    };
+
+    let mut mask = [0; 4];
+    if masked {
+        mask.copy_from_slice(data.get(pos..pos + 4)?);
+        pos += 4;
+    }
+    let end = pos.checked_add(len)?;
+    let payload = data.get(pos..end)?.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m).collect();
+    Some((opcode, payload, end))
+}
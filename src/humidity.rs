@@ -0,0 +1,111 @@
+//! Relative humidity correction of the mass concentrations
+//!
+//! Optical sensors see particles that have taken up water, so the reported
+//! mass is overestimated at high humidity. The correction uses the
+//! single-parameter κ-Köhler theory:
+//!
+//! `C = 1 + (κ · ρw / ρp) / (1 / aw - 1)`
+//!
+//! where `aw` is the water activity (RH / 100), `ρw` the density of water and
+//! `ρp` the dry particle density. The corrected mass is the raw mass divided
+//! by `C`.
+
+use crate::types::AirInfo;
+
+/// Density of water [g/cm³]
+const WATER_DENSITY: f32 = 1.0;
+
+/// κ-Köhler growth model parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HygroscopicGrowth {
+    /// Hygroscopicity parameter κ
+    pub kappa: f32,
+    /// Dry particle density [g/cm³]
+    pub density: f32,
+    /// Relative humidity [%] above which the correction is clamped, below
+    /// 100
+    pub max_humidity: f32,
+}
+
+impl HygroscopicGrowth {
+    /// Ammonium sulfate, κ = 0.61
+    pub const AMMONIUM_SULFATE: Self = Self::with_defaults(0.61, 1.77);
+    /// Ammonium nitrate, κ = 0.67
+    pub const AMMONIUM_NITRATE: Self = Self::with_defaults(0.67, 1.72);
+    /// Sea salt, κ = 1.28
+    pub const SEA_SALT: Self = Self::with_defaults(1.28, 2.17);
+    /// Mixed urban background aerosol, κ = 0.38
+    pub const URBAN: Self = Self::with_defaults(0.38, 1.65);
+    /// Secondary organic aerosol, κ = 0.1
+    pub const ORGANIC: Self = Self::with_defaults(0.1, 1.4);
+    /// Mineral dust, κ = 0.03
+    pub const MINERAL_DUST: Self = Self::with_defaults(0.03, 2.6);
+
+    /// Default relative humidity clamp [%]
+    pub const DEFAULT_MAX_HUMIDITY: f32 = 95.0;
+
+    const fn with_defaults(kappa: f32, density: f32) -> Self {
+        HygroscopicGrowth {
+            kappa,
+            density,
+            max_humidity: Self::DEFAULT_MAX_HUMIDITY,
+        }
+    }
+
+    /// Create a growth model from κ and the dry particle density [g/cm³]
+    pub fn new(kappa: f32, density: f32) -> Self {
+        Self::with_defaults(kappa, density)
+    }
+
+    /// Mass growth factor `C` at the given relative humidity [%]
+    ///
+    /// The factor is 1, so the values are left unchanged, if the humidity is
+    /// NaN or `max_humidity` is not below 100 %, where `C` diverges.
+    pub fn factor(&self, relative_humidity: f32) -> f32 {
+        if relative_humidity.is_nan() || self.max_humidity.is_nan() || self.max_humidity >= 100.0 {
+            return 1.0;
+        }
+        let rh = if relative_humidity < 0.0 {
+            0.0
+        } else if relative_humidity > self.max_humidity {
+            self.max_humidity
+        } else {
+            relative_humidity
+        };
+        if rh == 0.0 {
+            return 1.0;
+        }
+
+        let aw = rh / 100.0;
+        1.0 + (self.kappa * WATER_DENSITY / self.density) / (1.0 / aw - 1.0)
+    }
+
+    /// Correct the mass concentrations of a measurement taken at the given
+    /// relative humidity [%]
+    pub fn correct(&self, air_info: &AirInfo, relative_humidity: f32) -> CorrectedMass {
+        let factor = self.factor(relative_humidity);
+
+        CorrectedMass {
+            mass_pm1_0: air_info.mass_pm1_0 / factor,
+            mass_pm2_5: air_info.mass_pm2_5 / factor,
+            mass_pm4_0: air_info.mass_pm4_0 / factor,
+            mass_pm10: air_info.mass_pm10 / factor,
+            factor,
+        }
+    }
+}
+
+/// Humidity corrected mass concentrations
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CorrectedMass {
+    /// Mass Concentration PM1.0 [μg/m³]
+    pub mass_pm1_0: f32,
+    /// Mass Concentration PM2.5 [μg/m³]
+    pub mass_pm2_5: f32,
+    /// Mass Concentration PM4.0 [μg/m³]
+    pub mass_pm4_0: f32,
+    /// Mass Concentration PM10 [μg/m³]
+    pub mass_pm10: f32,
+    /// Growth factor the raw values were divided by
+    pub factor: f32,
+}
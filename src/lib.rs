@@ -3,9 +3,10 @@
 //! 
 //! [`embedded-hal`]: https://github.com/rust-embedded/embedded-hal
 //! 
-//! The driver supports only floating point output and is written for the
-//! firmware version 2.1. Although it is fully compatible with older versions
-//! and the 2.2 version, it doesn't fully support the latest.
+//! The driver supports the floating point and the unsigned 16-bit integer
+//! output formats and is written for the firmware version 2.1. Although it is
+//! fully compatible with older versions and the 2.2 version, it doesn't fully
+//! support the latest.
 //!
 //! This driver allows you to:
 //! - Enter measurement mode. See: [`start_measurement()`].
@@ -23,6 +24,47 @@
 //! - Read device status register. See: [`read_device_status_register()`].
 //! - Clear device status register. See: [`clear_device_status_register()`].
 //! - Reset the device. See: [`device_reset()`].
+//! - Correct mass concentrations for relative humidity. See: [`humidity`].
+//! - Apply per-sensor calibration profiles. See: [`load_calibration_profile()`].
+//! - Fit calibration profiles from co-located measurements (`std` feature). See: `fit`.
+//! - Read the measured values with quality flags. See: [`read_validated_values()`].
+//! - Drop readings taken while the sensor is warming up or the fan is cleaning.
+//!   See: [`read_stable_values()`].
+//! - Schedule the fan cleaning by cumulative fan runtime across power cycles.
+//!   See: [`cleaning_scheduler`].
+//! - Measure in low-power duty cycles. See: [`duty_cycle`].
+//! - Estimate the power consumption and log the time spent in every mode.
+//!   See: [`power`] and [`mode_residency()`].
+//! - Simulate the device on the I2C traits, with injectable faults (`sim` feature). See: `sim`.
+//! - Stream measurements as a table, CSV, JSON Lines, InfluxDB line protocol or
+//!   Prometheus text (`std` feature). See: `output`.
+//! - Export the measurements, status and error counters to Prometheus over HTTP
+//!   (`std` feature). See: `exporter`.
+//! - Serve the latest reading, device information and commands as a JSON API over
+//!   HTTP, with a WebSocket live stream (`http` feature). See: `http`.
+//! - Expose the measurements, status and auto-cleaning interval as a Modbus TCP or RTU
+//!   server (`std` feature). See: `modbus`.
+//! - Share one sensor between processes through a Unix socket broker with a control
+//!   lease for the privileged commands (`std` feature). See: `broker`.
+//! - Log the measurements to daily or size rotated CSV or binary files, gzip-compressed
+//!   and pruned (`std` feature). See: `logger`.
+//! - Encode readings compactly for low-bandwidth links as uint16, delta batches or
+//!   Cayenne LPP, with decoders. See: [`codec`].
+//! - Read the measurements in the integer output format, and build the driver with no
+//!   floating point at all (without the default `float` feature). The quality flags,
+//!   warm-up and duty cycles have integer variants.
+//!   See: [`read_measured_values_u16()`], [`read_validated_values_u16()`] and
+//!   [`DutyCycleScheduler::poll_u16()`].
+//! - Compute the US EPA Air Quality Index with integer arithmetic. See: [`aqi`].
+//! - Read only the mass, or the mass and number concentrations, in a shorter frame to
+//!   save bus time. See: [`read_selected_values()`].
+//! - Buffer readings in a wear-leveled, power-loss safe ring log in NOR flash until they
+//!   are acknowledged (`flash` feature). See: `flash_log`.
+//! - Publish the measurements over MQTT with Home Assistant discovery (`std` feature).
+//!   See: `mqtt`.
+//! - Record the I2C transactions and replay them as a regression test. See: [`record`].
+//! - Build command and response frames for `embedded-hal-mock` tests (`testing` feature).
+//!   See: `testing`.
 //! 
 //! [`start_measurement()`]: struct.Sps30.html#method.start_measurement
 //! [`stop_measurement`]: struct.Sps30.html#method.stop_measurement
@@ -39,6 +81,14 @@
 //! [`read_device_status_register()`]: struct.Sps30.html#method.read_device_status_register
 //! [`clear_device_status_register()`]: struct.Sps30.html#method.clear_device_status_register
 //! [`device_reset()`]: struct.Sps30.html#method.device_reset
+//! [`load_calibration_profile()`]: struct.Sps30.html#method.load_calibration_profile
+//! [`read_measured_values_u16()`]: struct.Sps30.html#method.read_measured_values_u16
+//! [`read_selected_values()`]: struct.Sps30.html#method.read_selected_values
+//! [`read_validated_values()`]: struct.Sps30.html#method.read_validated_values
+//! [`read_stable_values()`]: struct.Sps30.html#method.read_stable_values
+//! [`read_validated_values_u16()`]: struct.Sps30.html#method.read_validated_values_u16
+//! [`DutyCycleScheduler::poll_u16()`]: duty_cycle/struct.DutyCycleScheduler.html#method.poll_u16
+//! [`mode_residency()`]: struct.Sps30.html#method.mode_residency
 //! 
 //! ## The device
 //! 
@@ -71,17 +121,78 @@
 //! Please see examples folder.
 
 #![deny(missing_docs, rust_2018_idioms, unsafe_code, unused_qualifications, warnings)]
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(feature = "testing")]
+extern crate alloc;
+
+pub mod aqi;
+#[cfg(all(feature = "std", unix))]
+pub mod broker;
+#[cfg(feature = "float")]
+pub mod calibration;
+pub mod cleaning_scheduler;
+pub mod clock;
+#[cfg(feature = "float")]
+pub mod codec;
 mod crc;
+pub mod duty_cycle;
+#[cfg(feature = "std")]
+pub mod exporter;
+pub mod fan_cleaning;
+#[cfg(feature = "std")]
+pub mod fit;
+#[cfg(feature = "flash")]
+pub mod flash_log;
+#[cfg(feature = "float")]
+pub mod humidity;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "std")]
+pub mod logger;
+#[cfg(feature = "std")]
+pub mod modbus;
+#[cfg(feature = "std")]
+pub mod mqtt;
+#[cfg(feature = "std")]
+pub mod output;
+pub mod power;
+pub mod quality;
+pub mod record;
 mod register_access;
+#[cfg(feature = "sim")]
+pub mod sim;
 mod sps30;
+#[cfg(feature = "testing")]
+pub mod testing;
 mod types;
+pub mod warm_up;
+
+#[cfg(feature = "float")]
+pub use crate::types::AirInfo;
+pub use crate::types::{AirInfoU16, Error, FieldSelection, OutputFormat, PartialAirInfo, StatusRegisterResult};
 
 /// SPS30 device driver
-pub struct Sps30<I2C, D> {
+pub struct Sps30<I2C, D, C = clock::NoClock> {
     /// Tbe concrete I2C implementation
     i2c: I2C,
     delay: D,
     address: u8,
+    #[cfg(feature = "float")]
+    calibration: Option<calibration::CalibrationProfile>,
+    #[cfg(feature = "float")]
+    humidity: Option<f32>,
+    clock: Option<C>,
+    /// Time the measurement mode was entered, if a clock is attached
+    measurement_started: Option<u64>,
+    warm_up: warm_up::WarmUp,
+    /// Time the last manual fan cleaning was started, if a clock is attached
+    cleaning_started: Option<u64>,
+    auto_cleaning_interval: u32,
+    fan_cleaning: fan_cleaning::FanCleaning,
+    mode: power::Mode,
+    /// Time the current mode was entered, if a clock is attached
+    mode_since: Option<u64>,
+    residency: power::Residency,
+    output_format: OutputFormat,
 }
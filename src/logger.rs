@@ -0,0 +1,633 @@
+//! Rotating on-disk data logger
+//!
+//! A [`DataLogger`] appends every reading with its timestamp and quality
+//! flags to files named `<prefix>-<YYYYMMDD>-<NNNN>.<csv|bin>` in one
+//! directory. The date is the UTC day of the first reading of the file and
+//! the sequence number counts the files of that day.
+//!
+//! - A new file is started on a new day with [`LoggerConfig::rotate_daily`]
+//!   and before a file would exceed [`LoggerConfig::max_file_size`].
+//! - Closed files are gzip-compressed to `<name>.gz` with
+//!   [`LoggerConfig::compress`].
+//! - The oldest closed files are deleted beyond [`LoggerConfig::max_files`]
+//!   and after [`LoggerConfig::max_age_days`].
+//!
+//! Compression and deletion run on a background thread, so rotating does
+//! not hold up [`DataLogger::log()`]. [`DataLogger::finish_closed()`] waits
+//! for them and reports their errors, as does [`DataLogger::close()`].
+//!
+//! Every record goes to the operating system with a single write. After a
+//! crash or power cut, [`DataLogger::open()`] cuts a partially written
+//! record off the last file and appends to it, finishes interrupted
+//! compressions and removes their temporary files.
+//!
+//! # Formats
+//!
+//! CSV files start with the header `timestamp,<fields>,flags`, the fields
+//! named as in [`FIELD_NAMES`], followed by one line per reading with the
+//! timestamp [ms since the Unix epoch], the values and the raw
+//! [`QualityFlags`] bits.
+//!
+//! Binary files start with [`MAGIC`], followed by records of
+//! [`RECORD_LEN`] bytes: the timestamp as u64, the ten values as f32 and
+//! the flags as u16, all little endian.
+//!
+//! [`read_log()`] reads both back, compressed or not.
+//!
+//! [`FIELD_NAMES`]: ../calibration/constant.FIELD_NAMES.html
+//! [`QualityFlags`]: ../quality/struct.QualityFlags.html
+
+use crate::calibration::FIELD_NAMES;
+use crate::quality::{QualityFlags, Reading};
+use crate::types::AirInfo;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+/// First bytes of a binary log file, including the format version
+pub const MAGIC: [u8; 5] = *b"SPSL\x01";
+/// Size of a binary record [bytes]
+pub const RECORD_LEN: usize = 8 + 10 * 4 + 2;
+/// Default prefix of the file names
+pub const DEFAULT_PREFIX: &str = "sps30";
+
+const MS_PER_DAY: u64 = 86_400_000;
+const COMPRESSED_EXTENSION: &str = ".gz";
+const TEMPORARY_EXTENSION: &str = ".tmp";
+
+/// Format of the log files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Comma-separated values with a header
+    Csv,
+    /// Fixed-size little-endian records
+    Binary,
+}
+
+impl LogFormat {
+    /// Extension of the file names
+    pub fn extension(self) -> &'static str {
+        match self {
+            LogFormat::Csv => "csv",
+            LogFormat::Binary => "bin",
+        }
+    }
+
+    fn header(self) -> Vec<u8> {
+        match self {
+            LogFormat::Csv => format!("timestamp,{},flags\n", FIELD_NAMES.join(",")).into_bytes(),
+            LogFormat::Binary => MAGIC.to_vec(),
+        }
+    }
+
+    fn encode(self, timestamp: u64, reading: &Reading) -> Vec<u8> {
+        let values = reading.air_info.to_array();
+        match self {
+            LogFormat::Csv => {
+                let mut line = timestamp.to_string();
+                for value in values.iter() {
+                    line.push_str(&format!(",{}", value));
+                }
+                line.push_str(&format!(",{}\n", reading.flags.bits()));
+                line.into_bytes()
+            }
+            LogFormat::Binary => {
+                let mut record = Vec::with_capacity(RECORD_LEN);
+                record.extend_from_slice(&timestamp.to_le_bytes());
+                for value in values.iter() {
+                    record.extend_from_slice(&value.to_le_bytes());
+                }
+                record.extend_from_slice(&reading.flags.bits().to_le_bytes());
+                record
+            }
+        }
+    }
+
+    /// Length of the valid content, cutting a partial record off
+    fn valid_len(self, content: &[u8]) -> io::Result<usize> {
+        let header = self.header();
+        if content.len() < header.len() {
+            return if header.starts_with(content) { Ok(0) } else { Err(invalid_data()) };
+        }
+        if !content.starts_with(&header) {
+            return Err(invalid_data());
+        }
+
+        Ok(match self {
+            LogFormat::Csv => content.iter().rposition(|&b| b == b'\n').map_or(0, |end| end + 1),
+            LogFormat::Binary => header.len() + (content.len() - header.len()) / RECORD_LEN * RECORD_LEN,
+        })
+    }
+}
+
+/// Settings of the logger
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggerConfig {
+    /// Directory of the files
+    pub dir: PathBuf,
+    /// Prefix of the file names
+    pub prefix: String,
+    /// Format of the files
+    pub format: LogFormat,
+    /// Start a new file on every UTC day
+    pub rotate_daily: bool,
+    /// Start a new file before one would exceed this size [bytes]
+    pub max_file_size: Option<u64>,
+    /// Compress the closed files
+    pub compress: bool,
+    /// Number of closed files to keep
+    pub max_files: Option<usize>,
+    /// Number of days to keep the closed files
+    pub max_age_days: Option<u32>,
+}
+
+impl LoggerConfig {
+    /// Daily rotated and compressed CSV files in `dir`, kept forever
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        LoggerConfig {
+            dir: dir.into(),
+            prefix: DEFAULT_PREFIX.to_string(),
+            format: LogFormat::Csv,
+            rotate_daily: true,
+            max_file_size: None,
+            compress: true,
+            max_files: None,
+            max_age_days: None,
+        }
+    }
+}
+
+/// Name of a log file, parsed
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct LogFile {
+    day: u64,
+    sequence: u32,
+    compressed: bool,
+    path: PathBuf,
+}
+
+/// File being appended to
+#[derive(Debug)]
+struct ActiveFile {
+    file: File,
+    path: PathBuf,
+    day: u64,
+    sequence: u32,
+    size: u64,
+}
+
+/// File closed by a rotation, for the background thread
+#[derive(Debug)]
+struct ClosedFile {
+    path: PathBuf,
+    /// Day and sequence number of the file that replaced it
+    next: (u64, u32),
+}
+
+/// Background thread compressing and deleting the closed files
+#[derive(Debug)]
+struct Worker {
+    closed: Sender<ClosedFile>,
+    handle: JoinHandle<io::Result<()>>,
+}
+
+/// Appender of readings to rotating files
+#[derive(Debug)]
+pub struct DataLogger {
+    config: LoggerConfig,
+    active: Option<ActiveFile>,
+    worker: Option<Worker>,
+}
+
+impl DataLogger {
+    /// Open the logger, recovering the files of a previous run
+    ///
+    /// The newest file is appended to if it is not compressed, so logging
+    /// resumes in the file that was open before a restart or crash.
+    pub fn open(config: LoggerConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let mut logger = DataLogger { config, active: None, worker: None };
+
+        for entry in fs::read_dir(&logger.config.dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if name.starts_with(&logger.config.prefix) && name.ends_with(TEMPORARY_EXTENSION) {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let mut files = logger.files()?;
+        if let Some(newest) = files.pop().filter(|file| !file.compressed) {
+            logger.active = Some(logger.resume(newest)?);
+        }
+        if logger.config.compress {
+            for file in files.iter().filter(|file| !file.compressed) {
+                compress_file(&file.path)?;
+            }
+        }
+        if let Some(today) = logger.files()?.last().map(|file| file.day) {
+            let active = logger.active.as_ref().map(|active| (active.day, active.sequence));
+            apply_retention(&logger.config, active, today)?;
+        }
+
+        Ok(logger)
+    }
+
+    /// Settings of the logger
+    pub fn config(&self) -> &LoggerConfig {
+        &self.config
+    }
+
+    /// File being appended to
+    pub fn active_file(&self) -> Option<&Path> {
+        self.active.as_ref().map(|active| active.path.as_path())
+    }
+
+    /// Log files of the directory, oldest first
+    pub fn log_files(&self) -> io::Result<Vec<PathBuf>> {
+        Ok(self.files()?.into_iter().map(|file| file.path).collect())
+    }
+
+    /// Append a reading taken at `timestamp` [ms since the Unix epoch]
+    pub fn log(&mut self, timestamp: u64, reading: &Reading) -> io::Result<()> {
+        let day = timestamp / MS_PER_DAY;
+        let record = self.config.format.encode(timestamp, reading);
+        let header_len = self.config.format.header().len() as u64;
+
+        if let Some(active) = &self.active {
+            let new_day = self.config.rotate_daily && active.day != day;
+            let full = self
+                .config
+                .max_file_size
+                .map_or(false, |max| active.size > header_len && active.size + record.len() as u64 > max);
+            if new_day || full {
+                self.rotate(day)?;
+            }
+        }
+
+        let active = match self.active.take() {
+            Some(active) => active,
+            None => self.create(day)?,
+        };
+        let active = self.active.insert(active);
+        active.file.write_all(&record)?;
+        active.size += record.len() as u64;
+        Ok(())
+    }
+
+    /// Flush the active file to the storage device
+    pub fn sync(&mut self) -> io::Result<()> {
+        match &self.active {
+            Some(active) => active.file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Wait until the closed files are compressed and the old ones deleted
+    ///
+    /// Returns the first error of the background thread since the last call.
+    pub fn finish_closed(&mut self) -> io::Result<()> {
+        match self.worker.take() {
+            Some(worker) => {
+                drop(worker.closed);
+                worker
+                    .handle
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "log compression thread panicked")))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Flush the active file to the storage device, wait for the closed
+    /// files and close the logger
+    ///
+    /// The active file is left uncompressed, to be appended to on the next
+    /// open.
+    pub fn close(mut self) -> io::Result<()> {
+        let synced = self.sync();
+        let finished = self.finish_closed();
+        synced.and(finished)
+    }
+
+    /// Close the active file, start a new one on the day and hand the closed
+    /// one to the background thread
+    fn rotate(&mut self, day: u64) -> io::Result<()> {
+        let closed = match self.active.take() {
+            Some(active) => {
+                active.file.sync_all()?;
+                Some(active.path)
+            }
+            None => None,
+        };
+        let active = self.active.insert(self.create(day)?);
+        let next = (active.day, active.sequence);
+
+        if let Some(path) = closed {
+            let closed = ClosedFile { path, next };
+            let closed = match &self.worker {
+                Some(worker) => worker.closed.send(closed).err().map(|e| e.0),
+                None => Some(closed),
+            };
+            // Not started yet, or stopped by a panic
+            if let Some(closed) = closed {
+                let _ = self.finish_closed();
+                self.worker = Some(self.spawn_worker(closed));
+            }
+        }
+        Ok(())
+    }
+
+    fn spawn_worker(&self, first: ClosedFile) -> Worker {
+        let (sender, receiver) = mpsc::channel();
+        // Cannot fail, the receiver is alive
+        let _ = sender.send(first);
+        let config = self.config.clone();
+        let handle = thread::spawn(move || {
+            let mut res = Ok(());
+            for closed in receiver {
+                // Keep going, the files are retried on the next open
+                let done = process_closed(&config, &closed);
+                if res.is_ok() {
+                    res = done;
+                }
+            }
+            res
+        });
+        Worker { closed: sender, handle }
+    }
+
+    fn create(&self, day: u64) -> io::Result<ActiveFile> {
+        let sequence = self
+            .files()?
+            .iter()
+            .filter(|file| file.day == day)
+            .map(|file| file.sequence + 1)
+            .max()
+            .unwrap_or(0);
+        let (year, month, date) = civil_from_days(day);
+        let path = self.config.dir.join(format!(
+            "{}-{:04}{:02}{:02}-{:04}.{}",
+            self.config.prefix,
+            year,
+            month,
+            date,
+            sequence,
+            self.config.format.extension()
+        ));
+
+        let mut file = OpenOptions::new().append(true).create_new(true).open(&path)?;
+        let header = self.config.format.header();
+        file.write_all(&header)?;
+        Ok(ActiveFile { file, path, day, sequence, size: header.len() as u64 })
+    }
+
+    /// Reopen a file for appending, cutting a partial record off
+    fn resume(&self, log: LogFile) -> io::Result<ActiveFile> {
+        let content = fs::read(&log.path)?;
+        let len = self.config.format.valid_len(&content)?;
+
+        let mut file = OpenOptions::new().append(true).open(&log.path)?;
+        file.set_len(len as u64)?;
+        let mut size = len as u64;
+        if len == 0 {
+            let header = self.config.format.header();
+            file.write_all(&header)?;
+            size = header.len() as u64;
+        }
+        Ok(ActiveFile { file, path: log.path, day: log.day, sequence: log.sequence, size })
+    }
+
+    /// Log files of the directory, oldest first
+    fn files(&self) -> io::Result<Vec<LogFile>> {
+        files(&self.config)
+    }
+}
+
+impl Drop for DataLogger {
+    /// Wait for the closed files, so they are not left half compressed
+    fn drop(&mut self) {
+        let _ = self.finish_closed();
+    }
+}
+
+/// Compress a closed file if configured and delete the closed files beyond
+/// the limits, on the background thread
+fn process_closed(config: &LoggerConfig, closed: &ClosedFile) -> io::Result<()> {
+    if config.compress {
+        compress_file(&closed.path)?;
+    }
+    apply_retention(config, Some(closed.next), closed.next.0)
+}
+
+/// Delete the closed files older than the file `active` beyond the limits
+fn apply_retention(config: &LoggerConfig, active: Option<(u64, u32)>, today: u64) -> io::Result<()> {
+    let mut closed: Vec<LogFile> = files(config)?
+        .into_iter()
+        .filter(|file| active.map_or(true, |active| (file.day, file.sequence) < active))
+        .collect();
+
+    if let Some(max_age) = config.max_age_days {
+        for file in closed.iter().filter(|file| file.day + u64::from(max_age) < today) {
+            fs::remove_file(&file.path)?;
+        }
+        closed.retain(|file| file.day + u64::from(max_age) >= today);
+    }
+    if let Some(max_files) = config.max_files {
+        let excess = closed.len().saturating_sub(max_files);
+        for file in closed.drain(..excess) {
+            fs::remove_file(&file.path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Log files of the directory, oldest first
+fn files(config: &LoggerConfig) -> io::Result<Vec<LogFile>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&config.dir)? {
+        let path = entry?.path();
+        if let Some(file) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| parse_name(config, name))
+        {
+            files.push(LogFile { path, ..file });
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Parse a file name of the logger, with an empty path
+fn parse_name(config: &LoggerConfig, name: &str) -> Option<LogFile> {
+    let rest = name.strip_prefix(&config.prefix)?.strip_prefix('-')?;
+    let (rest, compressed) = match rest.strip_suffix(COMPRESSED_EXTENSION) {
+        Some(rest) => (rest, true),
+        None => (rest, false),
+    };
+    let rest = rest.strip_suffix(config.format.extension())?.strip_suffix('.')?;
+    let (date, sequence) = rest.split_once('-')?;
+    if date.len() != 8 || sequence.len() != 4 || !date.bytes().chain(sequence.bytes()).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let (year, month, day) = (date[..4].parse().ok()?, date[4..6].parse().ok()?, date[6..].parse().ok()?);
+    Some(LogFile {
+        day: days_from_civil(year, month, day)?,
+        sequence: sequence.parse().ok()?,
+        compressed,
+        path: PathBuf::new(),
+    })
+}
+
+/// Read the readings of a log file, compressed or not
+///
+/// The format is taken from the file name. A partial record at the end is
+/// ignored. The timestamps of the readings are [ms since the Unix epoch].
+pub fn read_log<P: AsRef<Path>>(path: P) -> io::Result<Vec<Reading>> {
+    let path = path.as_ref();
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    let mut content = Vec::new();
+    let name = match name.strip_suffix(COMPRESSED_EXTENSION) {
+        Some(name) => {
+            GzDecoder::new(File::open(path)?).read_to_end(&mut content)?;
+            name
+        }
+        None => {
+            File::open(path)?.read_to_end(&mut content)?;
+            name
+        }
+    };
+    let format = if name.ends_with(".csv") {
+        LogFormat::Csv
+    } else if name.ends_with(".bin") {
+        LogFormat::Binary
+    } else {
+        return Err(invalid_data());
+    };
+
+    let header_len = format.header().len();
+    let len = format.valid_len(&content)?;
+    let records = content.get(header_len..len).unwrap_or(&[]);
+    match format {
+        LogFormat::Csv => records
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| parse_csv_line(line).ok_or_else(invalid_data))
+            .collect(),
+        LogFormat::Binary => Ok(records.chunks(RECORD_LEN).map(parse_record).collect()),
+    }
+}
+
+fn parse_csv_line(line: &[u8]) -> Option<Reading> {
+    let line = std::str::from_utf8(line).ok()?;
+    let fields: Vec<&str> = line.split(',').collect();
+    if fields.len() != 12 {
+        return None;
+    }
+
+    let mut values = [0.0; 10];
+    for (value, field) in values.iter_mut().zip(fields[1..11].iter()) {
+        *value = field.parse().ok()?;
+    }
+    Some(Reading {
+        air_info: AirInfo::from_array(&values),
+        flags: QualityFlags::from_bits(fields[11].parse().ok()?),
+        timestamp: Some(fields[0].parse().ok()?),
+    })
+}
+
+fn parse_record(record: &[u8]) -> Reading {
+    let mut timestamp = [0; 8];
+    timestamp.copy_from_slice(&record[..8]);
+    let mut values = [0.0; 10];
+    for (value, bytes) in values.iter_mut().zip(record[8..48].chunks(4)) {
+        *value = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    Reading {
+        air_info: AirInfo::from_array(&values),
+        flags: QualityFlags::from_bits(u16::from_le_bytes([record[48], record[49]])),
+        timestamp: Some(u64::from_le_bytes(timestamp)),
+    }
+}
+
+/// Compress a closed file to `<path>.gz`, through a temporary file
+fn compress_file(path: &Path) -> io::Result<()> {
+    let mut compressed = path.as_os_str().to_owned();
+    compressed.push(COMPRESSED_EXTENSION);
+    let compressed = PathBuf::from(compressed);
+    let mut temporary = compressed.as_os_str().to_owned();
+    temporary.push(TEMPORARY_EXTENSION);
+    let temporary = PathBuf::from(temporary);
+
+    let mut encoder = GzEncoder::new(File::create(&temporary)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::rename(&temporary, &compressed)?;
+    // The compressed file must be durable before the original goes
+    if let Some(dir) = path.parent() {
+        sync_dir(dir)?;
+    }
+    fs::remove_file(path)
+}
+
+/// Flush the entries of a directory to the storage device
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    // An empty parent is the current directory
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    File::open(dir)?.sync_all()
+}
+
+/// Flush the entries of a directory to the storage device
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    // Directories cannot be opened for syncing on other platforms
+    Ok(())
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "not a log file of this format")
+}
+
+/// Date of a day since the Unix epoch, as `(year, month, day)`
+fn civil_from_days(days: u64) -> (u64, u32, u32) {
+    // Howard Hinnant's algorithm, shifted to years starting in March
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Day since the Unix epoch of a date, `None` if invalid or before 1970
+fn days_from_civil(year: u64, month: u32, day: u32) -> Option<u64> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+        return None;
+    }
+    let year = year - u64::from(month <= 2);
+    let era = year / 400;
+    let yoe = year % 400;
+    let mp = u64::from(if month > 2 { month - 3 } else { month + 9 });
+    let doy = (153 * mp + 2) / 5 + u64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe;
+    let days = days.checked_sub(719_468)?;
+    // Reject days past the end of the month
+    if civil_from_days(days) == (year + u64::from(month <= 2), month, day) {
+        Some(days)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,550 @@
+//! Modbus TCP and RTU server
+//!
+//! A [`ModbusServer`] owns the driver, samples the sensor whenever the
+//! data-ready flag is set and answers Modbus requests from this register
+//! map (addresses are zero-based):
+//!
+//! | Table             | Address   | Content                                               |
+//! |-------------------|-----------|-------------------------------------------------------|
+//! | Input registers   | 0 - 19    | [`AirInfo`] fields as float32, high word first        |
+//! | Input registers   | 100 - 109 | [`AirInfo`] fields as uint16, scaled by [`SCALES`]    |
+//! | Input register    | 200       | Quality flags of the last sample                      |
+//! | Input register    | 201       | Number of samples read, wrapping                      |
+//! | Discrete inputs   | 0 - 2     | Status bits: fan speed, laser, fan                    |
+//! | Holding registers | 0 - 1     | Auto-cleaning interval [s] as uint32, high word first |
+//! | Coil              | 0         | Fan cleaning running, write ON to start one           |
+//!
+//! The fields are in the order of [`FIELD_NAMES`]. Before the first sample
+//! the float32 registers hold NaN and the scaled ones [`NOT_AVAILABLE`];
+//! scaled values are saturated just below it.
+//!
+//! The supported functions are read coils (1), read discrete inputs (2),
+//! read holding registers (3), read input registers (4), write single coil
+//! (5) and write multiple registers (16). The holding registers can only be
+//! written together. Sensor errors are answered with the server device
+//! failure exception.
+//!
+//! [`serve_tcp()`] and [`serve_rtu()`] run the server until a shutdown flag
+//! is raised. [`ModbusServer::shutdown()`] then stops the measurement and
+//! puts the sensor to sleep.
+//!
+//! [`AirInfo`]: ../struct.AirInfo.html
+//! [`FIELD_NAMES`]: ../calibration/constant.FIELD_NAMES.html
+
+use crate::clock::Clock;
+use crate::codec::quantize;
+use crate::quality::Reading;
+use crate::types::{Error, StatusRegisterResult};
+use crate::Sps30;
+use embedded_hal::blocking::{delay, i2c};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub use crate::codec::{FINE_SCALES as SCALES, NOT_AVAILABLE};
+
+/// Interval at which the data-ready flag is polled [ms]
+pub const POLL_INTERVAL_MS: u64 = 250;
+/// Default unit identifier of the RTU server
+pub const DEFAULT_UNIT_ID: u8 = 1;
+
+/// First input register of the float32 values
+pub const FLOAT_REGISTERS: u16 = 0;
+/// First input register of the scaled uint16 values
+pub const SCALED_REGISTERS: u16 = 100;
+/// Input register of the quality flags
+pub const FLAGS_REGISTER: u16 = 200;
+/// Input register of the sample counter
+pub const SAMPLES_REGISTER: u16 = 201;
+/// First holding register of the auto-cleaning interval
+pub const AUTO_CLEANING_INTERVAL_REGISTER: u16 = 0;
+/// Coil of the fan cleaning
+pub const FAN_CLEANING_COIL: u16 = 0;
+
+const READ_COILS: u8 = 0x01;
+const READ_DISCRETE_INPUTS: u8 = 0x02;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+/// Maximum number of bits of a read
+const MAX_READ_BITS: u16 = 2000;
+/// Maximum number of registers of a read
+const MAX_READ_REGISTERS: u16 = 125;
+/// Maximum size of a Modbus TCP frame [bytes]
+const MAX_TCP_FRAME_LEN: usize = 260;
+/// Maximum size of a Modbus RTU frame [bytes]
+const MAX_RTU_FRAME_LEN: usize = 256;
+/// Sleep between accept attempts while idle [ms]
+const ACCEPT_INTERVAL_MS: u64 = 10;
+/// Maximum number of Modbus TCP clients, others are disconnected
+const MAX_CLIENTS: usize = 8;
+
+/// Exception code of a refused request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    /// The function is not supported
+    IllegalFunction = 0x01,
+    /// The addresses are not in the register map
+    IllegalDataAddress = 0x02,
+    /// The request is malformed or a value is out of range
+    IllegalDataValue = 0x03,
+    /// The sensor did not answer
+    ServerDeviceFailure = 0x04,
+}
+
+impl<E> From<Error<E>> for Exception {
+    fn from(_: Error<E>) -> Self {
+        Exception::ServerDeviceFailure
+    }
+}
+
+/// Sensor sampler and Modbus request handler
+pub struct ModbusServer<I2C, D, C> {
+    sensor: Sps30<I2C, D, C>,
+    unit_id: u8,
+    reading: Option<Reading>,
+    status: Option<StatusRegisterResult>,
+    samples: u16,
+}
+
+impl<I2C, D, C, E> ModbusServer<I2C, D, C>
+where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+D: delay::DelayMs<u8>,
+C: Clock {
+    /// Create a server owning the driver
+    pub fn new(sensor: Sps30<I2C, D, C>) -> Self {
+        ModbusServer {
+            sensor,
+            unit_id: DEFAULT_UNIT_ID,
+            reading: None,
+            status: None,
+            samples: 0,
+        }
+    }
+
+    /// Set the unit identifier answered by the RTU server
+    pub fn with_unit_id(mut self, unit_id: u8) -> Self {
+        self.unit_id = unit_id;
+        self
+    }
+
+    /// Unit identifier answered by the RTU server
+    pub fn unit_id(&self) -> u8 {
+        self.unit_id
+    }
+
+    /// Wake the sensor up if it is sleeping, start measuring and read the
+    /// auto-cleaning interval
+    ///
+    /// See [`Sps30::ensure_measuring()`](../struct.Sps30.html#method.ensure_measuring).
+    pub fn start(&mut self) -> Result<(), Error<E>> {
+        self.sensor.ensure_measuring()?;
+        // Served from the driver from now on
+        self.sensor.read_auto_cleaning_interval()?;
+        Ok(())
+    }
+
+    /// Read a sample and the status register if new values are ready,
+    /// returning whether a sample was read
+    pub fn poll(&mut self) -> Result<bool, Error<E>> {
+        if !self.sensor.read_data_ready_flag()? {
+            return Ok(false);
+        }
+
+        self.reading = Some(self.sensor.read_validated_values()?);
+        self.status = Some(self.sensor.read_device_status_register()?);
+        self.samples = self.samples.wrapping_add(1);
+        Ok(true)
+    }
+
+    /// Last sample
+    pub fn reading(&self) -> Option<&Reading> {
+        self.reading.as_ref()
+    }
+
+    /// Stop measuring, put the sensor to sleep and return the driver
+    pub fn shutdown(mut self) -> Result<Sps30<I2C, D, C>, Error<E>> {
+        self.sensor.stop_measurement()?;
+        self.sensor.sleep()?;
+        Ok(self.sensor)
+    }
+
+    /// Answer a request PDU (function code and data) with a response PDU
+    pub fn process(&mut self, pdu: &[u8]) -> Vec<u8> {
+        let function = match pdu.first() {
+            Some(&function) => function,
+            None => return vec![0x80, Exception::IllegalFunction as u8],
+        };
+
+        match self.execute(function, &pdu[1..]) {
+            Ok(data) => {
+                let mut res = vec![function];
+                res.extend_from_slice(&data);
+                res
+            }
+            Err(exception) => vec![function | 0x80, exception as u8],
+        }
+    }
+
+    /// Answer a Modbus TCP frame, `None` if it is not one
+    pub fn process_tcp(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        if frame.len() < 8 || frame[2..4] != [0, 0] || usize::from(read_u16(&frame[4..6])) != frame.len() - 6 {
+            return None;
+        }
+
+        let pdu = self.process(&frame[7..]);
+        let mut res = frame[..7].to_vec();
+        res[4..6].copy_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+        res.extend_from_slice(&pdu);
+        Some(res)
+    }
+
+    /// Answer a Modbus RTU frame
+    ///
+    /// Frames with a wrong CRC or for other units are ignored, broadcasts are
+    /// executed without an answer.
+    pub fn process_rtu(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        if frame.len() < 4 {
+            return None;
+        }
+        let (content, crc) = frame.split_at(frame.len() - 2);
+        if crc16(content).to_le_bytes() != crc {
+            return None;
+        }
+
+        let address = content[0];
+        if address != self.unit_id && address != 0 {
+            return None;
+        }
+        let pdu = self.process(&content[1..]);
+        if address == 0 {
+            return None;
+        }
+
+        let mut res = vec![address];
+        res.extend_from_slice(&pdu);
+        res.extend_from_slice(&crc16(&res).to_le_bytes());
+        Some(res)
+    }
+
+    fn execute(&mut self, function: u8, data: &[u8]) -> Result<Vec<u8>, Exception> {
+        match function {
+            READ_COILS | READ_DISCRETE_INPUTS => {
+                let (address, count) = read_range(data, MAX_READ_BITS)?;
+                let bits = (address..address + count)
+                    .map(|address| match function {
+                        READ_COILS => self.coil(address),
+                        _ => self.discrete_input(address),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let mut res = vec![0; 1 + (bits.len() + 7) / 8];
+                res[0] = (res.len() - 1) as u8;
+                for (i, _) in bits.iter().enumerate().filter(|(_, &set)| set) {
+                    res[1 + i / 8] |= 1 << (i % 8);
+                }
+                Ok(res)
+            }
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                let (address, count) = read_range(data, MAX_READ_REGISTERS)?;
+                let registers = match function {
+                    READ_HOLDING_REGISTERS => self.holding_registers(address, count)?,
+                    _ => (address..address + count)
+                        .map(|address| self.input_register(address))
+                        .collect::<Result<Vec<_>, _>>()?,
+                };
+
+                let mut res = vec![(registers.len() * 2) as u8];
+                for register in registers {
+                    res.extend_from_slice(&register.to_be_bytes());
+                }
+                Ok(res)
+            }
+            WRITE_SINGLE_COIL => {
+                if data.len() != 4 {
+                    return Err(Exception::IllegalDataValue);
+                }
+                let on = match read_u16(&data[2..4]) {
+                    0xFF00 => true,
+                    0x0000 => false,
+                    _ => return Err(Exception::IllegalDataValue),
+                };
+                if read_u16(&data[..2]) != FAN_CLEANING_COIL {
+                    return Err(Exception::IllegalDataAddress);
+                }
+                // A running cleaning cannot be stopped
+                if on {
+                    self.sensor.start_fan_cleaning()?;
+                }
+                Ok(data.to_vec())
+            }
+            WRITE_MULTIPLE_REGISTERS => {
+                if data.len() < 5 {
+                    return Err(Exception::IllegalDataValue);
+                }
+                let (address, count) = (read_u16(&data[..2]), read_u16(&data[2..4]));
+                let values = &data[5..];
+                if count == 0 || usize::from(data[4]) != values.len() || values.len() != usize::from(count) * 2 {
+                    return Err(Exception::IllegalDataValue);
+                }
+                if address != AUTO_CLEANING_INTERVAL_REGISTER || count != 2 {
+                    return Err(Exception::IllegalDataAddress);
+                }
+                let interval = u32::from(read_u16(&values[..2])) << 16 | u32::from(read_u16(&values[2..]));
+                self.sensor.write_auto_cleaning_interval(interval)?;
+                Ok(data[..4].to_vec())
+            }
+            _ => Err(Exception::IllegalFunction),
+        }
+    }
+
+    fn coil(&mut self, address: u16) -> Result<bool, Exception> {
+        match address {
+            FAN_CLEANING_COIL => Ok(self.sensor.is_fan_cleaning()),
+            _ => Err(Exception::IllegalDataAddress),
+        }
+    }
+
+    fn discrete_input(&self, address: u16) -> Result<bool, Exception> {
+        let status = self.status.unwrap_or(StatusRegisterResult { speed: false, laser: false, fan: false });
+        match address {
+            0 => Ok(status.speed),
+            1 => Ok(status.laser),
+            2 => Ok(status.fan),
+            _ => Err(Exception::IllegalDataAddress),
+        }
+    }
+
+    fn holding_registers(&self, address: u16, count: u16) -> Result<Vec<u16>, Exception> {
+        let end = address + count;
+        if end > AUTO_CLEANING_INTERVAL_REGISTER + 2 {
+            return Err(Exception::IllegalDataAddress);
+        }
+        let interval = self.sensor.auto_cleaning_interval();
+        let registers = [(interval >> 16) as u16, interval as u16];
+        Ok(registers[usize::from(address)..usize::from(end)].to_vec())
+    }
+
+    fn input_register(&self, address: u16) -> Result<u16, Exception> {
+        let values = self.reading.map(|reading| reading.air_info.to_array());
+        match address {
+            a if (FLOAT_REGISTERS..FLOAT_REGISTERS + 20).contains(&a) => {
+                let offset = usize::from(a - FLOAT_REGISTERS);
+                let bits = values.map_or(f32::NAN, |values| values[offset / 2]).to_bits();
+                Ok(if offset % 2 == 0 { (bits >> 16) as u16 } else { bits as u16 })
+            }
+            a if (SCALED_REGISTERS..SCALED_REGISTERS + 10).contains(&a) => {
+                let offset = usize::from(a - SCALED_REGISTERS);
+                Ok(values.map_or(NOT_AVAILABLE, |values| quantize(values[offset], SCALES[offset])))
+            }
+            FLAGS_REGISTER => Ok(self.reading.map_or(0, |reading| reading.flags.bits())),
+            SAMPLES_REGISTER => Ok(self.samples),
+            _ => Err(Exception::IllegalDataAddress),
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+/// Start address and count of a read request
+fn read_range(data: &[u8], max: u16) -> Result<(u16, u16), Exception> {
+    if data.len() != 4 {
+        return Err(Exception::IllegalDataValue);
+    }
+    let (address, count) = (read_u16(&data[..2]), read_u16(&data[2..]));
+    if count == 0 || count > max {
+        return Err(Exception::IllegalDataValue);
+    }
+    if address.checked_add(count).is_none() {
+        return Err(Exception::IllegalDataAddress);
+    }
+    Ok((address, count))
+}
+
+/// CRC of Modbus RTU frames, sent low byte first
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF;
+    for &byte in data {
+        crc ^= u16::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// Connection of a Modbus TCP client
+struct TcpClient {
+    stream: TcpStream,
+    inbox: Vec<u8>,
+    /// Responses not written yet
+    outbox: Vec<u8>,
+}
+
+/// Sample the sensor and answer Modbus TCP clients until `shutdown` is set
+///
+/// The server must have been started. Any unit identifier is answered, to
+/// at most 8 clients at once. A client is read at most one frame length per
+/// pass, and not at all while it has not taken its responses, so it can
+/// neither starve the others nor block the server.
+pub fn serve_tcp<I2C, D, C, E>(server: &mut ModbusServer<I2C, D, C>, listener: &TcpListener, shutdown: &AtomicBool) -> io::Result<()>
+where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+D: delay::DelayMs<u8>,
+C: Clock {
+    listener.set_nonblocking(true)?;
+    let poll_interval = Duration::from_millis(POLL_INTERVAL_MS);
+    let mut next_poll = Instant::now();
+    let mut clients: Vec<TcpClient> = Vec::new();
+
+    while !shutdown.load(Ordering::SeqCst) {
+        if Instant::now() >= next_poll {
+            // Failures are retried at the next poll
+            let _ = server.poll();
+            next_poll += poll_interval;
+        }
+
+        match listener.accept() {
+            // Dropping the stream disconnects the client
+            Ok((_, _)) if clients.len() >= MAX_CLIENTS => {}
+            Ok((stream, _)) => {
+                stream.set_nonblocking(true)?;
+                clients.push(TcpClient {
+                    stream,
+                    inbox: Vec::new(),
+                    outbox: Vec::new(),
+                });
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+
+        // A misbehaving client is disconnected
+        let mut i = 0;
+        while i < clients.len() {
+            if clients[i].receive(server).unwrap_or(false) {
+                i += 1;
+            } else {
+                clients.remove(i);
+            }
+        }
+        thread::sleep(Duration::from_millis(ACCEPT_INTERVAL_MS));
+    }
+
+    Ok(())
+}
+
+impl TcpClient {
+    /// Answer the complete frames received, returns false once the client is
+    /// gone
+    fn receive<I2C, D, C, E>(&mut self, server: &mut ModbusServer<I2C, D, C>) -> io::Result<bool>
+    where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+    D: delay::DelayMs<u8>,
+    C: Clock {
+        if !self.flush()? {
+            return Ok(false);
+        }
+        if !self.outbox.is_empty() {
+            // Not reading until the client takes its responses
+            return Ok(true);
+        }
+
+        let mut buffer = [0; MAX_TCP_FRAME_LEN];
+        match self.stream.read(&mut buffer) {
+            Ok(0) => return Ok(false),
+            Ok(len) => self.inbox.extend_from_slice(&buffer[..len]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+
+        while self.inbox.len() >= 6 {
+            let len = 6 + usize::from(read_u16(&self.inbox[4..6]));
+            if len > MAX_TCP_FRAME_LEN {
+                return Ok(false);
+            }
+            if self.inbox.len() < len {
+                break;
+            }
+            let frame: Vec<u8> = self.inbox.drain(..len).collect();
+            match server.process_tcp(&frame) {
+                Some(res) => self.outbox.extend_from_slice(&res),
+                None => return Ok(false),
+            }
+        }
+        self.flush()
+    }
+
+    /// Write as much of the pending responses as the client takes, returns
+    /// false once the client is gone
+    fn flush(&mut self) -> io::Result<bool> {
+        while !self.outbox.is_empty() {
+            match self.stream.write(&self.outbox) {
+                Ok(0) => return Ok(false),
+                Ok(len) => {
+                    self.outbox.drain(..len);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Sample the sensor and answer Modbus RTU requests on a serial port until
+/// `shutdown` is set
+///
+/// The server must have been started. The port must have a short read
+/// timeout: a read that times out or returns no bytes ends the frame, in
+/// place of the 3.5 character silent interval.
+pub fn serve_rtu<I2C, D, C, E, P>(server: &mut ModbusServer<I2C, D, C>, port: &mut P, shutdown: &AtomicBool) -> io::Result<()>
+where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+D: delay::DelayMs<u8>,
+C: Clock,
+P: Read + Write {
+    let poll_interval = Duration::from_millis(POLL_INTERVAL_MS);
+    let mut next_poll = Instant::now();
+    let mut frame = Vec::new();
+    let mut buffer = [0; MAX_RTU_FRAME_LEN];
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let idle = match port.read(&mut buffer) {
+            Ok(0) => true,
+            Ok(len) => {
+                frame.extend_from_slice(&buffer[..len]);
+                false
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => true,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => false,
+            Err(e) => return Err(e),
+        };
+
+        if idle && !frame.is_empty() {
+            if let Some(res) = server.process_rtu(&frame) {
+                port.write_all(&res)?;
+                port.flush()?;
+            }
+            frame.clear();
+        } else if frame.len() > MAX_RTU_FRAME_LEN {
+            // Noise on the line
+            frame.clear();
+        }
+
+        // Never while a frame is being received
+        if frame.is_empty() && Instant::now() >= next_poll {
+            let _ = server.poll();
+            next_poll = Instant::now() + poll_interval;
+        }
+        if idle {
+            thread::sleep(Duration::from_millis(ACCEPT_INTERVAL_MS));
+        }
+    }
+
+    Ok(())
+}
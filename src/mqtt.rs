@@ -0,0 +1,667 @@
+//! MQTT publisher with Home Assistant discovery
+//!
+//! [`MqttPublisher`] owns the driver and an MQTT 3.1.1 connection over any
+//! `Read + Write` stream, e.g. a `TcpStream`. On start it publishes a
+//! retained [Home Assistant discovery] config per [`AirInfo`] field and
+//! marks the sensor online; the broker marks it offline through the last
+//! will if the connection drops. Every sample is then published to one state
+//! topic per field:
+//!
+//! | Topic                                                  | Payload              |
+//! |--------------------------------------------------------|----------------------|
+//! | `<prefix>/sensor/sps30_<serial>/<field>/config`        | discovery, retained  |
+//! | `<base>/<serial>/availability`                         | `online`/`offline`, retained |
+//! | `<base>/<serial>/<field>`                              | value                |
+//! | `<base>/<serial>/flags`                                | JSON array of quality flag names |
+//!
+//! The serial number is the unique ID; characters other than ASCII
+//! alphanumerics, `_` and `-` are replaced by `_`. Only QoS 0 is supported.
+//!
+//! [Home Assistant discovery]: https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery
+//! [`AirInfo`]: ../struct.AirInfo.html
+
+use crate::calibration::FIELD_NAMES;
+use crate::clock::Clock;
+use crate::output::json_string;
+use crate::quality::Reading;
+use crate::types::Error;
+use crate::Sps30;
+use embedded_hal::blocking::{delay, i2c};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+/// Default discovery prefix of Home Assistant
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+/// Default base of the state topics
+pub const DEFAULT_BASE_TOPIC: &str = "sps30";
+/// Default keep alive interval [s]
+pub const DEFAULT_KEEP_ALIVE_S: u16 = 60;
+
+const PROTOCOL_NAME: &str = "MQTT";
+const PROTOCOL_LEVEL: u8 = 4;
+/// Largest remaining length that fits into four bytes
+const MAX_REMAINING_LEN: usize = 268_435_455;
+/// Largest remaining length of the packets a publisher receives, the CONNACK
+const MAX_RECEIVED_LEN: usize = 2;
+
+/// Display name, device class and unit of the fields, in the order of
+/// [`FIELD_NAMES`](../calibration/constant.FIELD_NAMES.html)
+const FIELDS: [(&str, Option<&str>, &str); 10] = [
+    ("PM1.0", Some("pm1"), "µg/m³"),
+    ("PM2.5", Some("pm25"), "µg/m³"),
+    ("PM4.0", None, "µg/m³"),
+    ("PM10", Some("pm10"), "µg/m³"),
+    ("PM0.5 count", None, "#/cm³"),
+    ("PM1.0 count", None, "#/cm³"),
+    ("PM2.5 count", None, "#/cm³"),
+    ("PM4.0 count", None, "#/cm³"),
+    ("PM10 count", None, "#/cm³"),
+    ("Typical particle size", None, "µm"),
+];
+
+/// All possible MQTT errors
+#[derive(Debug)]
+pub enum MqttError {
+    /// Stream error
+    Io(io::Error),
+    /// The broker refused the connection, with the CONNACK return code
+    Refused(u8),
+    /// The peer violated the protocol
+    Protocol(&'static str),
+    /// A string or binary field exceeds 65535 bytes, the packet exceeds
+    /// the maximum remaining length, or a received packet exceeds the limit
+    /// of the reader
+    TooLarge,
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::Io(e) => e.fmt(f),
+            MqttError::Refused(code) => write!(f, "connection refused by the broker (return code {})", code),
+            MqttError::Protocol(message) => write!(f, "protocol error: {}", message),
+            MqttError::TooLarge => f.write_str("field or packet too large for MQTT"),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {}
+
+impl From<io::Error> for MqttError {
+    fn from(e: io::Error) -> Self {
+        MqttError::Io(e)
+    }
+}
+
+/// Application message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// Topic name
+    pub topic: String,
+    /// Payload
+    pub payload: Vec<u8>,
+    /// Whether the broker keeps the message for future subscribers
+    pub retain: bool,
+}
+
+impl Message {
+    /// Create a message
+    pub fn new<T: Into<String>, P: Into<Vec<u8>>>(topic: T, payload: P, retain: bool) -> Self {
+        Message {
+            topic: topic.into(),
+            payload: payload.into(),
+            retain,
+        }
+    }
+}
+
+/// Connection request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connect {
+    /// Client identifier
+    pub client_id: String,
+    /// Keep alive interval [s], 0 to disable
+    pub keep_alive_s: u16,
+    /// User name
+    pub username: Option<String>,
+    /// Password
+    pub password: Option<Vec<u8>>,
+    /// Message the broker publishes if the connection drops
+    pub will: Option<Message>,
+}
+
+/// MQTT control packet, as far as a publisher needs them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    /// Connection request
+    Connect(Connect),
+    /// Connection acknowledgement
+    ConnAck {
+        /// Whether the broker has a session for the client
+        session_present: bool,
+        /// 0 if the connection is accepted
+        return_code: u8,
+    },
+    /// Publish message, QoS 0
+    Publish(Message),
+    /// Ping request
+    PingReq,
+    /// Ping response
+    PingResp,
+    /// Disconnect notification
+    Disconnect,
+}
+
+impl Packet {
+    /// Encode the packet
+    ///
+    /// Fails with [`MqttError::TooLarge`] if a field or the packet does not
+    /// fit into its length prefix.
+    pub fn encode(&self) -> Result<Vec<u8>, MqttError> {
+        let (header, body) = match self {
+            Packet::Connect(connect) => {
+                let mut body = Vec::new();
+                put_str(&mut body, PROTOCOL_NAME)?;
+                body.push(PROTOCOL_LEVEL);
+
+                // Clean session
+                let mut flags = 0x02;
+                if let Some(will) = &connect.will {
+                    flags |= 0x04;
+                    if will.retain {
+                        flags |= 0x20;
+                    }
+                }
+                if connect.password.is_some() {
+                    flags |= 0x40;
+                }
+                if connect.username.is_some() {
+                    flags |= 0x80;
+                }
+                body.push(flags);
+                body.extend_from_slice(&connect.keep_alive_s.to_be_bytes());
+
+                put_str(&mut body, &connect.client_id)?;
+                if let Some(will) = &connect.will {
+                    put_str(&mut body, &will.topic)?;
+                    put_bytes(&mut body, &will.payload)?;
+                }
+                if let Some(username) = &connect.username {
+                    put_str(&mut body, username)?;
+                }
+                if let Some(password) = &connect.password {
+                    put_bytes(&mut body, password)?;
+                }
+                (0x10, body)
+            }
+            Packet::ConnAck { session_present, return_code } => (0x20, vec![*session_present as u8, *return_code]),
+            Packet::Publish(message) => {
+                let mut body = Vec::new();
+                put_str(&mut body, &message.topic)?;
+                body.extend_from_slice(&message.payload);
+                (0x30 | message.retain as u8, body)
+            }
+            Packet::PingReq => (0xC0, Vec::new()),
+            Packet::PingResp => (0xD0, Vec::new()),
+            Packet::Disconnect => (0xE0, Vec::new()),
+        };
+
+        if body.len() > MAX_REMAINING_LEN {
+            return Err(MqttError::TooLarge);
+        }
+
+        let mut res = vec![header];
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            if len == 0 {
+                res.push(byte);
+                break;
+            }
+            res.push(byte | 0x80);
+        }
+        res.extend_from_slice(&body);
+        Ok(res)
+    }
+
+    /// Read and decode a packet
+    ///
+    /// Fails with [`MqttError::TooLarge`] before reading the rest of the
+    /// packet if its remaining length exceeds `max_len` [bytes].
+    pub fn read<R: Read>(reader: &mut R, max_len: usize) -> Result<Self, MqttError> {
+        let mut header = [0; 1];
+        reader.read_exact(&mut header)?;
+
+        let mut len = 0;
+        let mut multiplier = 1;
+        loop {
+            let mut byte = [0; 1];
+            reader.read_exact(&mut byte)?;
+            len += usize::from(byte[0] & 0x7F) * multiplier;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+            multiplier *= 128;
+            if multiplier > 128 * 128 * 128 {
+                return Err(MqttError::Protocol("malformed remaining length"));
+            }
+        }
+
+        if len > max_len {
+            return Err(MqttError::TooLarge);
+        }
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body)?;
+        let mut body = Body(&body);
+
+        let packet = match header[0] >> 4 {
+            1 => {
+                if body.str()? != PROTOCOL_NAME || body.u8()? != PROTOCOL_LEVEL {
+                    return Err(MqttError::Protocol("unsupported protocol"));
+                }
+                let flags = body.u8()?;
+                let keep_alive_s = body.u16()?;
+                let client_id = body.str()?;
+                let will = if flags & 0x04 != 0 {
+                    let topic = body.str()?;
+                    let payload = body.bytes()?;
+                    Some(Message::new(topic, payload, flags & 0x20 != 0))
+                } else {
+                    None
+                };
+                let username = if flags & 0x80 != 0 { Some(body.str()?) } else { None };
+                let password = if flags & 0x40 != 0 { Some(body.bytes()?) } else { None };
+                Packet::Connect(Connect { client_id, keep_alive_s, username, password, will })
+            }
+            2 => Packet::ConnAck {
+                session_present: body.u8()? & 0x01 != 0,
+                return_code: body.u8()?,
+            },
+            3 => {
+                let topic = body.str()?;
+                if header[0] & 0x06 != 0 {
+                    // Packet identifier of QoS 1 and 2
+                    body.u16()?;
+                }
+                Packet::Publish(Message::new(topic, body.0, header[0] & 0x01 != 0))
+            }
+            12 => Packet::PingReq,
+            13 => Packet::PingResp,
+            14 => Packet::Disconnect,
+            _ => return Err(MqttError::Protocol("unsupported packet type")),
+        };
+
+        Ok(packet)
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) -> Result<(), MqttError> {
+    let len = u16::try_from(bytes.len()).map_err(|_| MqttError::TooLarge)?;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) -> Result<(), MqttError> {
+    put_bytes(out, s.as_bytes())
+}
+
+/// Remaining part of a packet body
+struct Body<'a>(&'a [u8]);
+
+impl Body<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], MqttError> {
+        if self.0.len() < len {
+            return Err(MqttError::Protocol("truncated packet"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, MqttError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MqttError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, MqttError> {
+        let len = self.u16()?;
+        Ok(self.take(usize::from(len))?.to_vec())
+    }
+
+    fn str(&mut self) -> Result<String, MqttError> {
+        String::from_utf8(self.bytes()?).map_err(|_| MqttError::Protocol("invalid UTF-8 string"))
+    }
+}
+
+/// Publishing MQTT client
+///
+/// Reads block until the broker answers. Give the stream a read timeout,
+/// e.g. with `TcpStream::set_read_timeout()`, so a broker that stops
+/// answering fails the client instead of blocking it forever.
+#[derive(Debug)]
+pub struct MqttClient<S> {
+    stream: S,
+    keep_alive: Duration,
+    last_sent: Instant,
+}
+
+impl<S: Read + Write> MqttClient<S> {
+    /// Connect over the stream and wait for the acknowledgement
+    pub fn connect(mut stream: S, connect: Connect) -> Result<Self, MqttError> {
+        let keep_alive = Duration::from_secs(u64::from(connect.keep_alive_s));
+        stream.write_all(&Packet::Connect(connect).encode()?)?;
+        stream.flush()?;
+
+        match Packet::read(&mut stream, MAX_RECEIVED_LEN)? {
+            Packet::ConnAck { return_code: 0, .. } => Ok(MqttClient {
+                stream,
+                keep_alive,
+                last_sent: Instant::now(),
+            }),
+            Packet::ConnAck { return_code, .. } => Err(MqttError::Refused(return_code)),
+            _ => Err(MqttError::Protocol("expected CONNACK")),
+        }
+    }
+
+    /// Publish a message with QoS 0
+    pub fn publish(&mut self, message: Message) -> Result<(), MqttError> {
+        self.send(&Packet::Publish(message))
+    }
+
+    /// Ping the broker if nothing was sent for half the keep alive interval,
+    /// returning whether it was pinged
+    ///
+    /// Waits for the PINGRESP, which is the only packet the broker sends to
+    /// a client without subscriptions, as long as the read timeout of the
+    /// stream allows.
+    pub fn keep_alive(&mut self) -> Result<bool, MqttError> {
+        if self.keep_alive == Duration::from_secs(0) || self.last_sent.elapsed() < self.keep_alive / 2 {
+            return Ok(false);
+        }
+
+        self.send(&Packet::PingReq)?;
+        match Packet::read(&mut self.stream, MAX_RECEIVED_LEN)? {
+            Packet::PingResp => Ok(true),
+            _ => Err(MqttError::Protocol("expected PINGRESP")),
+        }
+    }
+
+    fn send(&mut self, packet: &Packet) -> Result<(), MqttError> {
+        self.stream.write_all(&packet.encode()?)?;
+        self.stream.flush()?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Disconnect cleanly, so the broker discards the will, and return the
+    /// stream
+    pub fn disconnect(mut self) -> Result<S, MqttError> {
+        self.send(&Packet::Disconnect)?;
+        Ok(self.stream)
+    }
+}
+
+/// Topics and payloads of one sensor in Home Assistant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HomeAssistant {
+    discovery_prefix: String,
+    base_topic: String,
+    id: String,
+    firmware: Option<(u8, u8)>,
+}
+
+impl HomeAssistant {
+    /// Create the topics of the sensor with the given serial number
+    pub fn new(serial: &str) -> Self {
+        let id = serial
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+            .collect();
+
+        HomeAssistant {
+            discovery_prefix: DEFAULT_DISCOVERY_PREFIX.to_string(),
+            base_topic: DEFAULT_BASE_TOPIC.to_string(),
+            id,
+            firmware: None,
+        }
+    }
+
+    /// Use a different discovery prefix
+    pub fn with_discovery_prefix(mut self, prefix: &str) -> Self {
+        self.discovery_prefix = prefix.to_string();
+        self
+    }
+
+    /// Use a different base of the state topics
+    pub fn with_base_topic(mut self, base_topic: &str) -> Self {
+        self.base_topic = base_topic.to_string();
+        self
+    }
+
+    /// Report the firmware version in the device info
+    pub fn with_firmware_version(mut self, major: u8, minor: u8) -> Self {
+        self.firmware = Some((major, minor));
+        self
+    }
+
+    /// Unique ID of the sensor
+    pub fn unique_id(&self) -> String {
+        format!("sps30_{}", self.id)
+    }
+
+    /// Topic of the availability
+    pub fn availability_topic(&self) -> String {
+        format!("{}/{}/availability", self.base_topic, self.id)
+    }
+
+    /// State topic of a field, see
+    /// [`FIELD_NAMES`](../calibration/constant.FIELD_NAMES.html)
+    pub fn state_topic(&self, field: &str) -> String {
+        format!("{}/{}/{}", self.base_topic, self.id, field)
+    }
+
+    /// Retained `online` availability
+    pub fn online(&self) -> Message {
+        Message::new(self.availability_topic(), "online", true)
+    }
+
+    /// Retained `offline` availability, also used as the last will
+    pub fn offline(&self) -> Message {
+        Message::new(self.availability_topic(), "offline", true)
+    }
+
+    /// Retained discovery configs, one per field
+    pub fn discovery(&self) -> Vec<Message> {
+        let unique_id = self.unique_id();
+        let mut device = format!(
+            "{{\"identifiers\":[{}],\"name\":{},\"manufacturer\":\"Sensirion\",\"model\":\"SPS30\"",
+            json_string(&unique_id),
+            json_string(&format!("SPS30 {}", self.id))
+        );
+        if let Some((major, minor)) = self.firmware {
+            device.push_str(&format!(",\"sw_version\":\"{}.{}\"", major, minor));
+        }
+        device.push('}');
+
+        FIELD_NAMES
+            .iter()
+            .zip(FIELDS.iter())
+            .map(|(field, (name, device_class, unit))| {
+                let mut config = format!(
+                    "{{\"name\":{},\"unique_id\":{},\"object_id\":{},\"state_topic\":{},\
+                     \"availability_topic\":{},\"unit_of_measurement\":{},\"state_class\":\"measurement\"",
+                    json_string(name),
+                    json_string(&format!("{}_{}", unique_id, field)),
+                    json_string(&format!("{}_{}", unique_id, field)),
+                    json_string(&self.state_topic(field)),
+                    json_string(&self.availability_topic()),
+                    json_string(unit),
+                );
+                if let Some(device_class) = device_class {
+                    config.push_str(&format!(",\"device_class\":\"{}\"", device_class));
+                }
+                config.push_str(&format!(",\"device\":{}}}", device));
+
+                let topic = format!("{}/sensor/{}/{}/config", self.discovery_prefix, unique_id, field);
+                Message::new(topic, config, true)
+            })
+            .collect()
+    }
+
+    /// State messages of a reading
+    ///
+    /// Values that are not finite are left out.
+    pub fn states(&self, reading: &Reading) -> Vec<Message> {
+        let mut messages: Vec<_> = FIELD_NAMES
+            .iter()
+            .zip(reading.air_info.to_array().iter())
+            .filter(|(_, value)| value.is_finite())
+            .map(|(field, value)| Message::new(self.state_topic(field), value.to_string(), false))
+            .collect();
+
+        let flags: Vec<_> = reading.flags.names().map(|name| format!("\"{}\"", name)).collect();
+        messages.push(Message::new(self.state_topic("flags"), format!("[{}]", flags.join(",")), false));
+        messages
+    }
+}
+
+/// Connection settings of the publisher
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttConfig {
+    /// Discovery prefix of Home Assistant
+    pub discovery_prefix: String,
+    /// Base of the state topics
+    pub base_topic: String,
+    /// Client identifier, the unique ID of the sensor if `None`
+    pub client_id: Option<String>,
+    /// Keep alive interval [s]; the broker is pinged between samples after
+    /// half of it
+    pub keep_alive_s: u16,
+    /// User name
+    pub username: Option<String>,
+    /// Password
+    pub password: Option<String>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            discovery_prefix: DEFAULT_DISCOVERY_PREFIX.to_string(),
+            base_topic: DEFAULT_BASE_TOPIC.to_string(),
+            client_id: None,
+            keep_alive_s: DEFAULT_KEEP_ALIVE_S,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+/// All possible errors of the publisher
+#[derive(Debug)]
+pub enum PublishError<E> {
+    /// Sensor error
+    Sensor(Error<E>),
+    /// MQTT error
+    Mqtt(MqttError),
+}
+
+impl<E> From<MqttError> for PublishError<E> {
+    fn from(e: MqttError) -> Self {
+        PublishError::Mqtt(e)
+    }
+}
+
+/// Publisher of the measurements of one sensor
+pub struct MqttPublisher<I2C, D, C, S> {
+    sensor: Sps30<I2C, D, C>,
+    client: MqttClient<S>,
+    home_assistant: HomeAssistant,
+}
+
+impl<I2C, D, C, S, E> MqttPublisher<I2C, D, C, S>
+where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+D: delay::DelayMs<u8>,
+C: Clock,
+S: Read + Write {
+    /// Wake the sensor up if it is sleeping, start measuring, connect to the
+    /// broker and announce the sensor
+    ///
+    /// See [`Sps30::ensure_measuring()`](../struct.Sps30.html#method.ensure_measuring).
+    /// The stream should have a read timeout of about the keep alive
+    /// interval, see [`MqttClient`].
+    pub fn start(mut sensor: Sps30<I2C, D, C>, stream: S, config: &MqttConfig) -> Result<Self, PublishError<E>> {
+        sensor.ensure_measuring().map_err(PublishError::Sensor)?;
+
+        let serial = sensor.read_device_serial_number().map_err(PublishError::Sensor)?;
+        let (major, minor) = sensor.read_firmware_version().map_err(PublishError::Sensor)?;
+        let len = serial.iter().position(|&b| b == 0).unwrap_or(serial.len());
+        let home_assistant = HomeAssistant::new(&String::from_utf8_lossy(&serial[..len]))
+            .with_discovery_prefix(&config.discovery_prefix)
+            .with_base_topic(&config.base_topic)
+            .with_firmware_version(major, minor);
+
+        let connect = Connect {
+            client_id: config.client_id.clone().unwrap_or_else(|| home_assistant.unique_id()),
+            keep_alive_s: config.keep_alive_s,
+            username: config.username.clone(),
+            password: config.password.clone().map(String::into_bytes),
+            will: Some(home_assistant.offline()),
+        };
+        let mut client = MqttClient::connect(stream, connect)?;
+        for message in home_assistant.discovery() {
+            client.publish(message)?;
+        }
+        client.publish(home_assistant.online())?;
+
+        Ok(MqttPublisher { sensor, client, home_assistant })
+    }
+
+    /// Topics and payloads of the sensor
+    pub fn home_assistant(&self) -> &HomeAssistant {
+        &self.home_assistant
+    }
+
+    /// Publish a sample if new values are ready, returning whether one was
+    /// published
+    ///
+    /// Pings the broker instead if the connection has been idle for half the
+    /// keep alive interval.
+    pub fn poll(&mut self) -> Result<bool, PublishError<E>> {
+        if !self.sensor.read_data_ready_flag().map_err(PublishError::Sensor)? {
+            self.client.keep_alive()?;
+            return Ok(false);
+        }
+
+        let reading = self.sensor.read_validated_values().map_err(PublishError::Sensor)?;
+        for message in self.home_assistant.states(&reading) {
+            self.client.publish(message)?;
+        }
+        Ok(true)
+    }
+
+    /// Stop measuring, put the sensor to sleep, mark it offline and
+    /// disconnect
+    ///
+    /// Both steps are attempted even if the other fails. Returns the driver;
+    /// the stream is dropped.
+    pub fn shutdown(mut self) -> Result<Sps30<I2C, D, C>, PublishError<E>> {
+        let sensor = &mut self.sensor;
+        let stopped = sensor.stop_measurement().and_then(|_| sensor.sleep());
+
+        let mut client = self.client;
+        let offline = self.home_assistant.offline();
+        let disconnected = client.publish(offline).and_then(|_| client.disconnect());
+
+        stopped.map_err(PublishError::Sensor)?;
+        disconnected?;
+        Ok(self.sensor)
+    }
+}
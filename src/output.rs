@@ -0,0 +1,347 @@
+//! Output formats for streamed measurements
+//!
+//! An [`OutputWriter`] writes one [`Record`] at a time in the chosen
+//! [`Format`]. Every format carries the timestamp, the serial number of the
+//! sensor, the ten [`AirInfo`] fields under the names of [`FIELD_NAMES`] and,
+//! when known, the quality flags under the names of [`QualityFlags::ALL`]:
+//!
+//! - `table`: aligned columns for humans, with a header line. Flags are
+//!   comma separated, `-` if none and empty if unknown.
+//! - `csv`: header line, then `timestamp,serial,<fields>,flags`. Flags are
+//!   `|` separated and empty if none or unknown.
+//! - `jsonl`: one object per line with `timestamp`, `serial`, the fields and
+//!   `flags` as an array of names, left out if unknown. Values that are not
+//!   finite are `null`.
+//! - `influx`: InfluxDB line protocol, measurement `sps30`, tag `serial`, the
+//!   fields as floats and `flags` as the raw bits, left out if unknown.
+//!   Values that are not finite are left out, and records left without any
+//!   field are skipped, as the protocol requires one.
+//! - `prometheus`: text exposition format, one gauge `sps30_<field>` per
+//!   field and `sps30_quality_flag` with a `flag` label per flag, every
+//!   sample labelled with `serial`. The `# HELP` and `# TYPE` lines of a
+//!   metric come once, before its first sample. Records are separated by
+//!   empty lines.
+//!
+//! Timestamps are milliseconds since the Unix epoch, except for the line
+//! protocol which uses nanoseconds.
+//!
+//! [`FIELD_NAMES`]: ../calibration/constant.FIELD_NAMES.html
+//! [`QualityFlags::ALL`]: ../quality/struct.QualityFlags.html#associatedconstant.ALL
+
+use crate::calibration::FIELD_NAMES;
+use crate::quality::QualityFlags;
+use crate::types::AirInfo;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// Descriptions of the [`AirInfo`] fields, in the order of [`FIELD_NAMES`]
+///
+/// [`FIELD_NAMES`]: ../calibration/constant.FIELD_NAMES.html
+pub const FIELD_DESCRIPTIONS: [&str; 10] = [
+    "Mass concentration PM1.0 [ug/m3]",
+    "Mass concentration PM2.5 [ug/m3]",
+    "Mass concentration PM4.0 [ug/m3]",
+    "Mass concentration PM10 [ug/m3]",
+    "Number concentration PM0.5 [#/cm3]",
+    "Number concentration PM1.0 [#/cm3]",
+    "Number concentration PM2.5 [#/cm3]",
+    "Number concentration PM4.0 [#/cm3]",
+    "Number concentration PM10 [#/cm3]",
+    "Typical particle size [um]",
+];
+
+/// Output format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Aligned columns for humans
+    Table,
+    /// Comma-separated values with a header
+    Csv,
+    /// JSON Lines
+    JsonLines,
+    /// InfluxDB line protocol
+    Influx,
+    /// Prometheus text exposition format
+    Prometheus,
+}
+
+impl Format {
+    /// Every format
+    pub const ALL: [Format; 5] = [Format::Table, Format::Csv, Format::JsonLines, Format::Influx, Format::Prometheus];
+
+    /// Name of the format, as parsed by `from_str`
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Table => "table",
+            Format::Csv => "csv",
+            Format::JsonLines => "jsonl",
+            Format::Influx => "influx",
+            Format::Prometheus => "prometheus",
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The format name is not known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownFormat(pub String);
+
+impl fmt::Display for UnknownFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown format `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnknownFormat {}
+
+impl FromStr for Format {
+    type Err = UnknownFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Format::ALL
+            .iter()
+            .copied()
+            .find(|format| format.name() == s)
+            .ok_or_else(|| UnknownFormat(s.to_string()))
+    }
+}
+
+/// Measurement to output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Record<'a> {
+    /// Time of the measurement [ms since the Unix epoch]
+    pub timestamp: u64,
+    /// Serial number of the sensor
+    pub serial: &'a str,
+    /// Measured values
+    pub air_info: AirInfo,
+    /// Quality flags, if known
+    pub flags: Option<QualityFlags>,
+}
+
+/// Writer of records in one format
+#[derive(Debug)]
+pub struct OutputWriter<W> {
+    out: W,
+    format: Format,
+    records: u64,
+    /// Whether the Prometheus metadata of the quality flags was written
+    flags_described: bool,
+}
+
+impl<W: Write> OutputWriter<W> {
+    /// Create a writer
+    pub fn new(out: W, format: Format) -> Self {
+        OutputWriter { out, format, records: 0, flags_described: false }
+    }
+
+    /// Format of the output
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Destroy the writer, returning the output
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Write a record and flush the output
+    pub fn write(&mut self, record: &Record<'_>) -> io::Result<()> {
+        let first = self.records == 0;
+        self.records += 1;
+
+        match self.format {
+            Format::Table => write_table(&mut self.out, record, first)?,
+            Format::Csv => write_csv(&mut self.out, record, first)?,
+            Format::JsonLines => write_json(&mut self.out, record)?,
+            Format::Influx => write_influx(&mut self.out, record)?,
+            Format::Prometheus => {
+                if !first {
+                    writeln!(self.out)?;
+                }
+                let describe_flags = !self.flags_described && record.flags.is_some();
+                write_metrics(&mut self.out, record, true, first, describe_flags)?;
+                self.flags_described |= describe_flags;
+            }
+        }
+        self.out.flush()
+    }
+}
+
+fn write_table<W: Write>(out: &mut W, record: &Record<'_>, header: bool) -> io::Result<()> {
+    if header {
+        write!(out, "{:>13} {:<16}", "timestamp", "serial")?;
+        for name in FIELD_NAMES.iter() {
+            write!(out, " {:>12}", name)?;
+        }
+        writeln!(out, " flags")?;
+    }
+
+    write!(out, "{:>13} {:<16}", record.timestamp, record.serial)?;
+    for value in record.air_info.to_array().iter() {
+        write!(out, " {:>12.3}", value)?;
+    }
+    match record.flags {
+        Some(flags) if flags.is_empty() => writeln!(out, " -"),
+        Some(flags) => writeln!(out, " {}", flags.names().collect::<Vec<_>>().join(",")),
+        None => writeln!(out, " "),
+    }
+}
+
+fn write_csv<W: Write>(out: &mut W, record: &Record<'_>, header: bool) -> io::Result<()> {
+    if header {
+        writeln!(out, "timestamp,serial,{},flags", FIELD_NAMES.join(","))?;
+    }
+
+    write!(out, "{},{}", record.timestamp, csv_field(record.serial))?;
+    for value in record.air_info.to_array().iter() {
+        write!(out, ",{}", value)?;
+    }
+    let flags = record.flags.map(|f| f.names().collect::<Vec<_>>().join("|"));
+    writeln!(out, ",{}", flags.unwrap_or_default())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Write a record as a JSON Lines object
+pub(crate) fn write_json<W: Write>(out: &mut W, record: &Record<'_>) -> io::Result<()> {
+    write!(out, "{{\"timestamp\":{},\"serial\":{}", record.timestamp, json_string(record.serial))?;
+    for (name, value) in FIELD_NAMES.iter().zip(record.air_info.to_array().iter()) {
+        if value.is_finite() {
+            write!(out, ",\"{}\":{}", name, value)?;
+        } else {
+            write!(out, ",\"{}\":null", name)?;
+        }
+    }
+    if let Some(flags) = record.flags {
+        let names: Vec<_> = flags.names().map(|name| format!("\"{}\"", name)).collect();
+        write!(out, ",\"flags\":[{}]", names.join(","))?;
+    }
+    writeln!(out, "}}")
+}
+
+/// JSON string literal of the value
+pub(crate) fn json_string(value: &str) -> String {
+    let mut res = String::with_capacity(value.len() + 2);
+    res.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn write_influx<W: Write>(out: &mut W, record: &Record<'_>) -> io::Result<()> {
+    let mut fields = Vec::new();
+    for (name, value) in FIELD_NAMES.iter().zip(record.air_info.to_array().iter()) {
+        if value.is_finite() {
+            fields.push(format!("{}={}", name, value));
+        }
+    }
+    if let Some(flags) = record.flags {
+        fields.push(format!("flags={}i", flags.bits()));
+    }
+    if fields.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(
+        out,
+        "sps30,serial={} {} {}",
+        influx_tag(record.serial),
+        fields.join(","),
+        u128::from(record.timestamp) * 1_000_000
+    )
+}
+
+fn influx_tag(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    if res.is_empty() {
+        // Empty tag values are not allowed
+        res.push_str("unknown");
+    }
+    res
+}
+
+/// Write a record in the Prometheus text exposition format, optionally with
+/// sample timestamps
+pub fn write_prometheus<W: Write>(out: &mut W, record: &Record<'_>, timestamp: bool) -> io::Result<()> {
+    write_metrics(out, record, timestamp, true, true)
+}
+
+/// Write the samples of a record, with the `# HELP` and `# TYPE` lines of
+/// the fields and of the flags if asked for
+fn write_metrics<W: Write>(
+    out: &mut W,
+    record: &Record<'_>,
+    timestamp: bool,
+    describe_fields: bool,
+    describe_flags: bool,
+) -> io::Result<()> {
+    let serial = prometheus_label(record.serial);
+    let suffix = if timestamp { format!(" {}", record.timestamp) } else { String::new() };
+
+    for ((name, description), value) in FIELD_NAMES
+        .iter()
+        .zip(FIELD_DESCRIPTIONS.iter())
+        .zip(record.air_info.to_array().iter())
+    {
+        if describe_fields {
+            writeln!(out, "# HELP sps30_{} {}", name, description)?;
+            writeln!(out, "# TYPE sps30_{} gauge", name)?;
+        }
+        writeln!(out, "sps30_{}{{serial=\"{}\"}} {}{}", name, serial, prometheus_value(*value), suffix)?;
+    }
+
+    if let Some(flags) = record.flags {
+        if describe_flags {
+            writeln!(out, "# HELP sps30_quality_flag Quality flag of the measurement, 1 if set")?;
+            writeln!(out, "# TYPE sps30_quality_flag gauge")?;
+        }
+        for &(flag, name) in QualityFlags::ALL.iter() {
+            let value = flags.contains(flag) as u8;
+            writeln!(out, "sps30_quality_flag{{serial=\"{}\",flag=\"{}\"}} {}{}", serial, name, value, suffix)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Escape a label value of the Prometheus text exposition format
+pub fn prometheus_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Format a sample value of the Prometheus text exposition format
+pub fn prometheus_value(value: f32) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
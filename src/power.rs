@@ -0,0 +1,161 @@
+//! Power consumption estimation
+//!
+//! A [`PowerModel`] gives the supply current of every operating [`Mode`].
+//! Together with the time spent in each mode, a [`Residency`], it yields the
+//! consumed charge and the average current. A residency can be estimated for
+//! a duty-cycle configuration with [`Residency::for_duty_cycle()`] or, with a
+//! clock attached, logged by the driver while it runs. See:
+//! [`mode_residency()`].
+//!
+//! [`Residency::for_duty_cycle()`]: struct.Residency.html#method.for_duty_cycle
+//! [`mode_residency()`]: ../struct.Sps30.html#method.mode_residency
+
+use crate::duty_cycle::DutyCycleConfig;
+
+/// Milliseconds per day
+pub const DAY_MS: u64 = 86_400_000;
+
+/// Idle time of the commands issued in one duty cycle [ms]
+///
+/// Execution times of `stop_measurement`, `sleep` and `wake_up`.
+pub const DUTY_CYCLE_IDLE_MS: u64 = 20 + 5 + 5;
+
+/// Operating mode of the sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Powered, not measuring
+    Idle,
+    /// Measuring, fan and laser on
+    Measurement,
+    /// Fan cleaning, fan at maximum speed
+    FanCleaning,
+    /// Sleeping
+    Sleep,
+}
+
+/// Supply current of every mode
+#[cfg(feature = "float")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerModel {
+    /// Idle mode supply current [mA]
+    pub idle_ma: f32,
+    /// Measurement mode supply current [mA]
+    pub measurement_ma: f32,
+    /// Fan cleaning supply current [mA]
+    pub fan_cleaning_ma: f32,
+    /// Sleep mode supply current [mA]
+    pub sleep_ma: f32,
+}
+
+#[cfg(feature = "float")]
+impl PowerModel {
+    /// Supply current in the given mode [mA]
+    pub fn current_ma(&self, mode: Mode) -> f32 {
+        match mode {
+            Mode::Idle => self.idle_ma,
+            Mode::Measurement => self.measurement_ma,
+            Mode::FanCleaning => self.fan_cleaning_ma,
+            Mode::Sleep => self.sleep_ma,
+        }
+    }
+
+    /// Charge consumed over the residency [mAh]
+    pub fn charge_mah(&self, residency: &Residency) -> f32 {
+        let ms = |t: u64| t as f32 / 3_600_000.0;
+
+        ms(residency.idle_ms) * self.idle_ma
+            + ms(residency.measurement_ms) * self.measurement_ma
+            + ms(residency.fan_cleaning_ms) * self.fan_cleaning_ma
+            + ms(residency.sleep_ms) * self.sleep_ma
+    }
+
+    /// Average current over the residency [mA]
+    pub fn average_current_ma(&self, residency: &Residency) -> f32 {
+        let total = residency.total_ms();
+        if total == 0 {
+            return 0.0;
+        }
+        self.charge_mah(residency) * 3_600_000.0 / total as f32
+    }
+
+    /// Charge consumed per day by a duty-cycle configuration [mAh]
+    ///
+    /// See [`Residency::for_duty_cycle()`].
+    pub fn charge_per_day_mah(&self, config: &DutyCycleConfig, auto_cleaning_interval_s: u32) -> f32 {
+        self.charge_mah(&Residency::for_duty_cycle(config, auto_cleaning_interval_s))
+    }
+}
+
+#[cfg(feature = "float")]
+impl Default for PowerModel {
+    /// Typical supply currents of the datasheet at 5 V
+    fn default() -> Self {
+        PowerModel {
+            idle_ma: 0.33,
+            measurement_ma: 60.0,
+            fan_cleaning_ma: 80.0,
+            sleep_ma: 0.038,
+        }
+    }
+}
+
+/// Time spent in every mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Residency {
+    /// Time in idle mode [ms]
+    pub idle_ms: u64,
+    /// Time in measurement mode, without fan cleaning [ms]
+    pub measurement_ms: u64,
+    /// Time cleaning the fan [ms]
+    pub fan_cleaning_ms: u64,
+    /// Time in sleep mode [ms]
+    pub sleep_ms: u64,
+}
+
+impl Residency {
+    /// Total time [ms]
+    pub fn total_ms(&self) -> u64 {
+        self.idle_ms + self.measurement_ms + self.fan_cleaning_ms + self.sleep_ms
+    }
+
+    /// Add time spent in a mode
+    pub fn add(&mut self, mode: Mode, ms: u64) {
+        match mode {
+            Mode::Idle => self.idle_ms += ms,
+            Mode::Measurement => self.measurement_ms += ms,
+            Mode::FanCleaning => self.fan_cleaning_ms += ms,
+            Mode::Sleep => self.sleep_ms += ms,
+        }
+    }
+
+    /// Expected residency over one day of a duty-cycle configuration
+    ///
+    /// Every cycle measures for the warm-up plus one second per sample, is
+    /// idle for [`DUTY_CYCLE_IDLE_MS`] and sleeps for the rest of the period.
+    /// A 10 s fan cleaning is accounted for every `auto_cleaning_interval_s`
+    /// seconds of measurement, pro rata; 0 disables it.
+    pub fn for_duty_cycle(config: &DutyCycleConfig, auto_cleaning_interval_s: u32) -> Self {
+        let period = u64::from(config.period_ms).max(1);
+        let measuring = (u64::from(config.warm_up_ms) + u64::from(config.samples) * 1000).min(period);
+        let idle = DUTY_CYCLE_IDLE_MS.min(period - measuring);
+        let cycles = DAY_MS / period;
+        let rest = DAY_MS % period;
+
+        let mut residency = Residency {
+            idle_ms: cycles * idle,
+            measurement_ms: cycles * measuring + rest.min(measuring),
+            fan_cleaning_ms: 0,
+            sleep_ms: 0,
+        };
+        residency.sleep_ms = DAY_MS - residency.idle_ms - residency.measurement_ms;
+
+        let interval = u64::from(auto_cleaning_interval_s) * 1000;
+        if let Some(cleaning) = (residency.measurement_ms * 10_000).checked_div(interval) {
+            let cleaning = cleaning.min(residency.measurement_ms);
+            residency.measurement_ms -= cleaning;
+            residency.fan_cleaning_ms = cleaning;
+        }
+
+        residency
+    }
+}
@@ -0,0 +1,242 @@
+//! Plausibility validation of the measured values
+//!
+//! A corrupted but CRC valid frame, or a reading taken while the sensor is
+//! not in a steady state, decodes to values that are not physically
+//! consistent. [`AirInfo::validate()`] checks a measurement and wraps it,
+//! together with the problems found, in a [`Reading`] (`float` feature).
+//! [`AirInfoU16::validate()`] does the same for the integer output format,
+//! with a [`ReadingU16`].
+//!
+//! [`AirInfo::validate()`]: ../struct.AirInfo.html#method.validate
+//! [`AirInfoU16::validate()`]: ../struct.AirInfoU16.html#method.validate
+
+#[cfg(feature = "float")]
+use crate::types::AirInfo;
+use crate::types::AirInfoU16;
+use core::ops::{BitOr, BitOrAssign};
+
+/// Upper end of the mass concentration range [μg/m³]
+#[cfg(feature = "float")]
+pub const MASS_RANGE_MAX: f32 = 1000.0;
+/// Lower end of the typical particle size range [μm]
+#[cfg(feature = "float")]
+pub const TYPICAL_SIZE_MIN: f32 = 0.3;
+/// Upper end of the typical particle size range [μm]
+#[cfg(feature = "float")]
+pub const TYPICAL_SIZE_MAX: f32 = 10.0;
+/// Upper end of the mass concentration range in the integer format [μg/m³]
+pub const MASS_RANGE_MAX_U16: u16 = 1000;
+/// Lower end of the typical particle size range in the integer format [nm]
+pub const TYPICAL_SIZE_MIN_U16: u16 = 300;
+/// Upper end of the typical particle size range in the integer format [nm]
+pub const TYPICAL_SIZE_MAX_U16: u16 = 10_000;
+
+/// Set of quality flags
+/// Empty is OK, every flag indicates a problem
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct QualityFlags(u16);
+
+impl QualityFlags {
+    /// A value is NaN or infinite
+    pub const NOT_FINITE: Self = QualityFlags(1 << 0);
+    /// A value is negative
+    pub const NEGATIVE: Self = QualityFlags(1 << 1);
+    /// Mass concentrations decrease with the particle size
+    pub const MASS_NOT_CUMULATIVE: Self = QualityFlags(1 << 2);
+    /// Number concentrations decrease with the particle size
+    pub const NUMBER_NOT_CUMULATIVE: Self = QualityFlags(1 << 3);
+    /// A mass concentration exceeds the measurement range
+    pub const MASS_OUT_OF_RANGE: Self = QualityFlags(1 << 4);
+    /// The typical particle size is outside of the measurement range
+    pub const TYPICAL_SIZE_OUT_OF_RANGE: Self = QualityFlags(1 << 5);
+    /// The reading was taken before the sensor stabilized
+    pub const WARMING_UP: Self = QualityFlags(1 << 6);
+    /// The reading was taken while the fan was cleaning
+    pub const FAN_CLEANING: Self = QualityFlags(1 << 7);
+
+    /// Every flag with its name, in bit order
+    pub const ALL: [(Self, &'static str); 8] = [
+        (Self::NOT_FINITE, "not_finite"),
+        (Self::NEGATIVE, "negative"),
+        (Self::MASS_NOT_CUMULATIVE, "mass_not_cumulative"),
+        (Self::NUMBER_NOT_CUMULATIVE, "number_not_cumulative"),
+        (Self::MASS_OUT_OF_RANGE, "mass_out_of_range"),
+        (Self::TYPICAL_SIZE_OUT_OF_RANGE, "typical_size_out_of_range"),
+        (Self::WARMING_UP, "warming_up"),
+        (Self::FAN_CLEANING, "fan_cleaning"),
+    ];
+
+    /// No flags
+    pub const fn empty() -> Self {
+        QualityFlags(0)
+    }
+
+    /// Raw bits of the flags
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    /// Flags from raw bits
+    pub const fn from_bits(bits: u16) -> Self {
+        QualityFlags(bits)
+    }
+
+    /// Whether no flag is set
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether all flags of `other` are set
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Set the flags of `other`
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    /// Clear the flags of `other`
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+
+    /// Names of the set flags, in bit order
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::ALL
+            .iter()
+            .filter(move |(flag, _)| self.contains(*flag))
+            .map(|&(_, name)| name)
+    }
+}
+
+impl BitOr for QualityFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        QualityFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for QualityFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.insert(rhs);
+    }
+}
+
+/// Measurement together with its quality flags
+#[cfg(feature = "float")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    /// Measured values
+    pub air_info: AirInfo,
+    /// Problems found with the values
+    pub flags: QualityFlags,
+    /// Time the values were read [ms], if a clock is attached
+    pub timestamp: Option<u64>,
+}
+
+#[cfg(feature = "float")]
+impl Reading {
+    /// Whether no problem was found
+    pub fn is_valid(&self) -> bool {
+        self.flags.is_empty()
+    }
+}
+
+/// Measurement in the integer output format together with its quality flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadingU16 {
+    /// Measured values
+    pub air_info: AirInfoU16,
+    /// Problems found with the values
+    pub flags: QualityFlags,
+    /// Time the values were read [ms], if a clock is attached
+    pub timestamp: Option<u64>,
+}
+
+impl ReadingU16 {
+    /// Whether no problem was found
+    pub fn is_valid(&self) -> bool {
+        self.flags.is_empty()
+    }
+}
+
+fn is_cumulative<T: PartialOrd>(values: &[T]) -> bool {
+    values.windows(2).all(|w| w[0] <= w[1])
+}
+
+#[cfg(feature = "float")]
+impl AirInfo {
+    /// Check the physical consistency of the values
+    ///
+    /// The typical particle size is only checked when particles were counted.
+    pub fn validate(&self) -> Reading {
+        let mut flags = QualityFlags::empty();
+        let values = self.to_array();
+
+        if values.iter().any(|v| !v.is_finite()) {
+            flags |= QualityFlags::NOT_FINITE;
+        }
+        if values.iter().any(|&v| v < 0.0) {
+            flags |= QualityFlags::NEGATIVE;
+        }
+        if !is_cumulative(&values[..4]) {
+            flags |= QualityFlags::MASS_NOT_CUMULATIVE;
+        }
+        if !is_cumulative(&values[4..9]) {
+            flags |= QualityFlags::NUMBER_NOT_CUMULATIVE;
+        }
+        if values[..4].iter().any(|&v| v > MASS_RANGE_MAX) {
+            flags |= QualityFlags::MASS_OUT_OF_RANGE;
+        }
+        if self.number_pm10 > 0.0
+            && !(TYPICAL_SIZE_MIN..=TYPICAL_SIZE_MAX).contains(&self.typical_size)
+        {
+            flags |= QualityFlags::TYPICAL_SIZE_OUT_OF_RANGE;
+        }
+
+        Reading {
+            air_info: *self,
+            flags,
+            timestamp: None,
+        }
+    }
+}
+
+impl AirInfoU16 {
+    /// Check the physical consistency of the values
+    ///
+    /// A value that is not available is flagged as not finite, the integer
+    /// format has no negative values. The typical particle size is only
+    /// checked when particles were counted.
+    pub fn validate(&self) -> ReadingU16 {
+        let mut flags = QualityFlags::empty();
+        let values = self.to_array();
+
+        if values.contains(&AirInfoU16::NOT_AVAILABLE) {
+            flags |= QualityFlags::NOT_FINITE;
+        }
+        if !is_cumulative(&values[..4]) {
+            flags |= QualityFlags::MASS_NOT_CUMULATIVE;
+        }
+        if !is_cumulative(&values[4..9]) {
+            flags |= QualityFlags::NUMBER_NOT_CUMULATIVE;
+        }
+        if values[..4].iter().any(|&v| v != AirInfoU16::NOT_AVAILABLE && v > MASS_RANGE_MAX_U16) {
+            flags |= QualityFlags::MASS_OUT_OF_RANGE;
+        }
+        if self.number_pm10 > 0
+            && self.typical_size != AirInfoU16::NOT_AVAILABLE
+            && !(TYPICAL_SIZE_MIN_U16..=TYPICAL_SIZE_MAX_U16).contains(&self.typical_size)
+        {
+            flags |= QualityFlags::TYPICAL_SIZE_OUT_OF_RANGE;
+        }
+
+        ReadingU16 {
+            air_info: *self,
+            flags,
+            timestamp: None,
+        }
+    }
+}
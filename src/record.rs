@@ -0,0 +1,479 @@
+//! Recording and replay of I2C transactions
+//!
+//! [`Recorder`] wraps the I2C bus of the driver and logs every transaction:
+//! the bytes written (command word and payload, with CRCs), the bytes read,
+//! the error of a failed transaction and when it happened. The log goes to a
+//! [`LogSink`]: a user buffer with [`BufferLog`] or, with the `std` feature,
+//! any writer such as a file with [`IoLog`].
+//!
+//! The recorder passes the result of every transfer through unchanged. A
+//! sink error stops the recording, and is kept for [`Recorder::log_error()`],
+//! so a full log does not make the driver fail.
+//!
+//! [`Replay`] feeds a recorded log back to the driver, checking that it
+//! issues the same transactions, so a field capture can be turned into a
+//! regression test.
+//!
+//! # Log format
+//!
+//! The log starts with [`MAGIC`], followed by one entry per transaction:
+//!
+//! | Field   | Size    | Content                                          |
+//! |---------|---------|--------------------------------------------------|
+//! | flags   | 1       | bit 0: read, bit 1: failed                       |
+//! | address | 1       | 7-bit device address                             |
+//! | time    | varint  | ms since the previous entry, or since 0          |
+//! | length  | varint  | number of bytes written or requested             |
+//! | data    | length  | bytes written or read, left out for failed reads |
+//! | error   | varint + text | failed entries only: length and `Debug` text of the bus error, at most [`MAX_ERROR_LEN`] bytes |
+//!
+//! Varints are unsigned LEB128. A log whose recording stopped on a sink
+//! error may end with a truncated entry.
+//!
+//! [`Recorder::log_error()`]: struct.Recorder.html#method.log_error
+
+use crate::clock::Clock;
+use core::fmt::{self, Debug, Write as _};
+use embedded_hal::blocking::i2c;
+
+/// First bytes of a log, including the format version
+pub const MAGIC: [u8; 4] = *b"S30\x01";
+
+/// Maximum length of the recorded error text, longer texts are truncated
+pub const MAX_ERROR_LEN: usize = 64;
+
+const FLAG_READ: u8 = 1 << 0;
+const FLAG_FAILED: u8 = 1 << 1;
+
+/// Destination of a log
+pub trait LogSink {
+    /// Sink error
+    type Error;
+
+    /// Append bytes to the log
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// The log buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogFull;
+
+/// Log in a user buffer
+#[derive(Debug)]
+pub struct BufferLog<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> BufferLog<'a> {
+    /// Create an empty log in the buffer
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        BufferLog { buffer, len: 0 }
+    }
+
+    /// Recorded log
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+impl LogSink for BufferLog<'_> {
+    type Error = LogFull;
+
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        let end = self.len + bytes.len();
+        if end > self.buffer.len() {
+            return Err(LogFull);
+        }
+        self.buffer[self.len..end].clone_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Log in a `std::io::Write`, e.g. a file
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct IoLog<W>(pub W);
+
+#[cfg(feature = "std")]
+impl IoLog<std::io::BufWriter<std::fs::File>> {
+    /// Create or truncate the log file at the given path
+    pub fn create<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
+        Ok(IoLog(std::io::BufWriter::new(std::fs::File::create(path)?)))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> LogSink for IoLog<W> {
+    type Error = std::io::Error;
+
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0.write_all(bytes)
+    }
+}
+
+/// I2C bus wrapper recording every transaction
+#[derive(Debug)]
+pub struct Recorder<I2C, C, S: LogSink> {
+    i2c: I2C,
+    clock: C,
+    sink: S,
+    started: bool,
+    last_ms: u64,
+    /// Sink error that stopped the recording
+    log_error: Option<S::Error>,
+}
+
+impl<I2C, C: Clock, S: LogSink> Recorder<I2C, C, S> {
+    /// Wrap a bus, taking the timestamps from the clock
+    pub fn new(i2c: I2C, clock: C, sink: S) -> Self {
+        Recorder {
+            i2c,
+            clock,
+            sink,
+            started: false,
+            last_ms: 0,
+            log_error: None,
+        }
+    }
+
+    /// Sink error that stopped the recording, if any
+    pub fn log_error(&self) -> Option<&S::Error> {
+        self.log_error.as_ref()
+    }
+
+    /// Destroy the recorder, returning the bus, the clock and the sink
+    pub fn destroy(self) -> (I2C, C, S) {
+        (self.i2c, self.clock, self.sink)
+    }
+
+    /// Log a transaction, unless a sink error stopped the recording
+    fn log(&mut self, flags: u8, address: u8, len: usize, data: &[u8], error: Option<&dyn Debug>) {
+        if self.log_error.is_none() {
+            if let Err(e) = self.record(flags, address, len, data, error) {
+                self.log_error = Some(e);
+            }
+        }
+    }
+
+    fn record(&mut self, flags: u8, address: u8, len: usize, data: &[u8], error: Option<&dyn Debug>) -> Result<(), S::Error> {
+        if !self.started {
+            self.sink.write_all(&MAGIC)?;
+            self.started = true;
+        }
+
+        let now = self.clock.now_ms();
+        let delta = now.saturating_sub(self.last_ms);
+        self.last_ms = now;
+
+        self.sink.write_all(&[flags, address])?;
+        write_varint(&mut self.sink, delta)?;
+        write_varint(&mut self.sink, len as u64)?;
+        self.sink.write_all(data)?;
+
+        if let Some(error) = error {
+            let mut text = ErrorText { buffer: [0; MAX_ERROR_LEN], len: 0 };
+            // Only fails when the text is truncated
+            let _ = write!(text, "{:?}", error);
+            write_varint(&mut self.sink, text.len as u64)?;
+            self.sink.write_all(&text.buffer[..text.len])?;
+        }
+        Ok(())
+    }
+}
+
+impl<I2C, C, S, E> i2c::Write for Recorder<I2C, C, S>
+where I2C: i2c::Write<Error = E>,
+C: Clock,
+S: LogSink,
+E: Debug {
+    type Error = E;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        let res = self.i2c.write(address, bytes);
+        match &res {
+            Ok(()) => self.log(0, address, bytes.len(), bytes, None),
+            Err(e) => self.log(FLAG_FAILED, address, bytes.len(), bytes, Some(e)),
+        }
+        res
+    }
+}
+
+impl<I2C, C, S, E> i2c::Read for Recorder<I2C, C, S>
+where I2C: i2c::Read<Error = E>,
+C: Clock,
+S: LogSink,
+E: Debug {
+    type Error = E;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let res = self.i2c.read(address, buffer);
+        match &res {
+            Ok(()) => self.log(FLAG_READ, address, buffer.len(), buffer, None),
+            Err(e) => self.log(FLAG_READ | FLAG_FAILED, address, buffer.len(), &[], Some(e)),
+        }
+        res
+    }
+}
+
+/// Error text, truncated to whole characters
+struct ErrorText {
+    buffer: [u8; MAX_ERROR_LEN],
+    len: usize,
+}
+
+impl fmt::Write for ErrorText {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let end = self.len + c.len_utf8();
+            if end > MAX_ERROR_LEN {
+                return Err(fmt::Error);
+            }
+            c.encode_utf8(&mut self.buffer[self.len..end]);
+            self.len = end;
+        }
+        Ok(())
+    }
+}
+
+fn write_varint<S: LogSink>(sink: &mut S, mut value: u64) -> Result<(), S::Error> {
+    let mut buffer = [0; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buffer[len] = byte;
+            len += 1;
+            break;
+        }
+        buffer[len] = byte | 0x80;
+        len += 1;
+    }
+    sink.write_all(&buffer[..len])
+}
+
+/// Direction of a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Write to the device
+    Write,
+    /// Read from the device
+    Read,
+}
+
+/// Recorded transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// Direction of the transaction
+    pub direction: Direction,
+    /// Device address
+    pub address: u8,
+    /// Time of the transaction [ms]
+    pub timestamp: u64,
+    /// Number of bytes written or requested
+    pub len: usize,
+    /// Bytes written or read, empty for failed reads
+    pub data: &'a [u8],
+    /// Whether the transaction failed
+    pub failed: bool,
+    /// `Debug` text of the bus error of a failed transaction
+    pub error: Option<&'a str>,
+}
+
+/// The log is not valid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidLog;
+
+/// Iterator over the entries of a log
+#[derive(Debug, Clone)]
+pub struct LogReader<'a> {
+    log: &'a [u8],
+    pos: usize,
+    timestamp: u64,
+}
+
+impl<'a> LogReader<'a> {
+    /// Read a log, checking its header
+    ///
+    /// An empty log, i.e. from a recorder that saw no transactions, is valid.
+    pub fn new(log: &'a [u8]) -> Result<Self, InvalidLog> {
+        if log.is_empty() {
+            return Ok(LogReader { log, pos: 0, timestamp: 0 });
+        }
+        if !log.starts_with(&MAGIC) {
+            return Err(InvalidLog);
+        }
+
+        Ok(LogReader { log, pos: MAGIC.len(), timestamp: 0 })
+    }
+
+    fn byte(&mut self) -> Result<u8, InvalidLog> {
+        let byte = *self.log.get(self.pos).ok_or(InvalidLog)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, InvalidLog> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(InvalidLog)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], InvalidLog> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.log.len()).ok_or(InvalidLog)?;
+        let bytes = &self.log[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn entry(&mut self) -> Result<Entry<'a>, InvalidLog> {
+        let flags = self.byte()?;
+        let address = self.byte()?;
+        self.timestamp += self.varint()?;
+        let len = self.varint()? as usize;
+
+        let direction = if flags & FLAG_READ != 0 { Direction::Read } else { Direction::Write };
+        let failed = flags & FLAG_FAILED != 0;
+        let data_len = if direction == Direction::Read && failed { 0 } else { len };
+        let data = self.bytes(data_len)?;
+        let error = if failed {
+            let len = self.varint()? as usize;
+            Some(core::str::from_utf8(self.bytes(len)?).map_err(|_| InvalidLog)?)
+        } else {
+            None
+        };
+
+        Ok(Entry {
+            direction,
+            address,
+            timestamp: self.timestamp,
+            len,
+            data,
+            failed,
+            error,
+        })
+    }
+}
+
+impl<'a> Iterator for LogReader<'a> {
+    type Item = Result<Entry<'a>, InvalidLog>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.log.len() {
+            return None;
+        }
+
+        let res = self.entry();
+        if res.is_err() {
+            // Stop after the first error
+            self.pos = self.log.len();
+        }
+        Some(res)
+    }
+}
+
+/// Errors of the replay transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The transaction failed when it was recorded
+    Recorded,
+    /// The transaction differs from the recorded one
+    Mismatch {
+        /// Index of the recorded transaction
+        index: usize,
+    },
+    /// All recorded transactions were replayed
+    EndOfLog,
+    /// The log is not valid
+    InvalidLog,
+}
+
+/// I2C bus replaying a recorded log
+#[derive(Debug, Clone)]
+pub struct Replay<'a> {
+    reader: LogReader<'a>,
+    index: usize,
+    timestamp: u64,
+}
+
+impl<'a> Replay<'a> {
+    /// Replay a log
+    pub fn new(log: &'a [u8]) -> Result<Self, InvalidLog> {
+        Ok(Replay {
+            reader: LogReader::new(log)?,
+            index: 0,
+            timestamp: 0,
+        })
+    }
+
+    /// Number of transactions replayed so far
+    pub fn position(&self) -> usize {
+        self.index
+    }
+
+    /// Time of the last replayed transaction [ms]
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Whether every recorded transaction was replayed
+    pub fn is_finished(&self) -> bool {
+        self.reader.clone().next().is_none()
+    }
+
+    fn next_entry(&mut self, direction: Direction, address: u8, len: usize) -> Result<Entry<'a>, ReplayError> {
+        let entry = match self.reader.next() {
+            Some(Ok(entry)) => entry,
+            Some(Err(InvalidLog)) => return Err(ReplayError::InvalidLog),
+            None => return Err(ReplayError::EndOfLog),
+        };
+        let index = self.index;
+        self.index += 1;
+        self.timestamp = entry.timestamp;
+
+        if entry.direction != direction || entry.address != address || entry.len != len {
+            return Err(ReplayError::Mismatch { index });
+        }
+        Ok(entry)
+    }
+}
+
+impl i2c::Write for Replay<'_> {
+    type Error = ReplayError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        let index = self.index;
+        let entry = self.next_entry(Direction::Write, address, bytes.len())?;
+        if entry.data != bytes {
+            return Err(ReplayError::Mismatch { index });
+        }
+
+        if entry.failed {
+            Err(ReplayError::Recorded)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl i2c::Read for Replay<'_> {
+    type Error = ReplayError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let entry = self.next_entry(Direction::Read, address, buffer.len())?;
+        if entry.failed {
+            return Err(ReplayError::Recorded);
+        }
+
+        buffer.clone_from_slice(entry.data);
+        Ok(())
+    }
+}
@@ -3,22 +3,38 @@ use crate::crc;
 use embedded_hal::blocking::i2c;
 
 pub mod sps30 {
+    /// Default I2C address
     pub const DEV_ADDR: u8 = 0x69;
+    /// Command words
     pub struct Register {}
     impl Register {
+        /// Start measurement
         pub const START_MEASUREMENT: [u8; 2] = [0x00, 0x10];
+        /// Stop measurement
         pub const STOP_MEASUREMENT: [u8; 2] = [0x01, 0x04];
+        /// Read data-ready flag
         pub const READ_DATA_READY_FLAG: [u8; 2] = [0x02, 0x02];
+        /// Read measured values
         pub const READ_MEASURED_VALUES: [u8; 2] = [0x03, 0x00];
+        /// Sleep
         pub const SLEEP: [u8; 2] = [0x10, 0x01];
+        /// Wake-up
         pub const WAKE_UP: [u8; 2] = [0x11, 0x03];
+        /// Start fan cleaning
         pub const START_FAN_CLEANING: [u8; 2] = [0x56, 0x07];
+        /// Read/Write auto cleaning interval
         pub const READ_WRITE_AUTO_CLEANING_INTERVAL: [u8; 2] = [0x80, 0x04];
+        /// Read device product type
         pub const READ_DEVICE_PRODUCT_TYPE: [u8; 2] = [0xD0, 0x02];
+        /// Read device serial number
         pub const READ_DEVICE_SERIAL_NUMBER: [u8; 2] = [0xD0, 0x33];
+        /// Read firmware version
         pub const READ_FIRMWARE_VERSION: [u8; 2] = [0xD1, 0x00];
+        /// Read device status register
         pub const READ_DEVICE_STATUS_REGISTER: [u8; 2] = [0xD2, 0x06];
+        /// Clear device status register
         pub const CLEAR_DEVICE_STATUS_REGISTER: [u8; 2] = [0xD2, 0x10];
+        /// Reset
         pub const DEVICE_RESET: [u8; 2] = [0xD3, 0x04];
     }
 
@@ -30,7 +46,7 @@ pub mod sps30 {
     }
 }
 
-impl<I2C, D, E> crate::Sps30<I2C, D>
+impl<I2C, D, C, E> crate::Sps30<I2C, D, C>
 where I2C: i2c::Read<Error = E> + i2c::Write<Error = E> {
     pub(crate) fn read_data(&mut self, buffer: &mut [u8]) -> Result<(), Error<E>> {
         self.i2c.read(self.address, buffer).map_err(Error::I2C)?;
@@ -41,7 +57,7 @@ where I2C: i2c::Read<Error = E> + i2c::Write<Error = E> {
     
     pub(crate) fn write_data(&mut self, buffer: &mut [u8]) -> Result<(), Error<E>>{
         if buffer.is_empty() {
-            let _ = self.i2c.write(self.address, &buffer);
+            let _ = self.i2c.write(self.address, buffer);
             return Ok(());
         }
 
@@ -49,7 +65,7 @@ where I2C: i2c::Read<Error = E> + i2c::Write<Error = E> {
             self.add_crc(buffer);
         }
 
-        self.i2c.write(self.address, &buffer).map_err(Error::I2C)
+        self.i2c.write(self.address, buffer).map_err(Error::I2C)
     }
 
     fn check_crc(&mut self, data: &[u8]) -> Result<(), Error<E>> {
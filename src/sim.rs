@@ -0,0 +1,577 @@
+//! Simulated SPS30
+//!
+//! [`VirtualSps30`] implements the blocking I2C traits and behaves like the
+//! device: it understands every command, checks and computes the CRCs,
+//! models the idle, measurement and sleep modes and NACKs the commands that
+//! are illegal in the current mode. While measuring, a new measurement from
+//! a concentration [`Profile`] becomes ready every second.
+//!
+//! Time is taken from a [`Clock`]. [`SimClock`] is a virtual clock that is
+//! also a delay, so a driver using it as its delay advances the simulation
+//! by the command execution times:
+//!
+//! ```
+//! use sps30_i2c::Sps30;
+//! use sps30_i2c::sim::{RandomProfile, SimClock, VirtualSps30};
+//!
+//! let clock = SimClock::new();
+//! let sim = VirtualSps30::new(RandomProfile::new(1), clock.clone());
+//! let mut sensor = Sps30::new_sps30(sim, clock.clone());
+//!
+//! sensor.start_measurement().unwrap();
+//! clock.advance(1_000);
+//! assert!(sensor.read_data_ready_flag().unwrap());
+//! let air_info = sensor.read_measured_values().unwrap();
+//! assert!(air_info.mass_pm2_5 <= air_info.mass_pm10);
+//! ```
+//!
+//! Faults can be injected right away with [`VirtualSps30::inject`] or
+//! scripted to hit a given transaction with [`VirtualSps30::schedule`].
+
+use crate::clock::Clock;
+use crate::crc::calc_crc;
+use crate::fan_cleaning::DEFAULT_AUTO_CLEANING_INTERVAL;
+use crate::register_access::sps30::{DEV_ADDR, Register, StatusRegisterBits};
+use crate::types::AirInfo;
+use embedded_hal::blocking::{delay, i2c};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Interval at which new measurements become ready [ms]
+pub const SAMPLE_INTERVAL_MS: u64 = 1_000;
+/// Duration of the fan cleaning [ms]
+pub const FAN_CLEANING_MS: u64 = 10_000;
+
+/// Fan speed out of range status bit
+pub const STATUS_SPEED: u32 = StatusRegisterBits::SPEED;
+/// Laser failure status bit
+pub const STATUS_LASER: u32 = StatusRegisterBits::LASER;
+/// Fan failure status bit
+pub const STATUS_FAN: u32 = StatusRegisterBits::FAN;
+
+/// Float output format argument of `start_measurement`
+const FORMAT_FLOAT: u8 = 0x03;
+/// Unsigned 16-bit integer output format argument of `start_measurement`
+const FORMAT_UINT16: u8 = 0x05;
+
+/// Errors of the simulated bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimError {
+    /// The device did not acknowledge the transfer
+    Nack,
+}
+
+/// Operating mode of the simulated device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimState {
+    /// Powered, not measuring
+    Idle,
+    /// Measuring
+    Measuring,
+    /// Sleeping
+    Sleeping,
+}
+
+/// Injectable fault
+///
+/// `Nack` and `CorruptCrc` hit a single transaction, `StuckDataReady` and
+/// `FrozenMeasurement` last until [`VirtualSps30::clear_faults`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Do not acknowledge the next transaction
+    Nack,
+    /// Corrupt the CRC of the given word of the next response
+    CorruptCrc(usize),
+    /// Report the data-ready flag with the given value
+    StuckDataReady(bool),
+    /// Keep returning the last measurement
+    FrozenMeasurement,
+    /// Set bits in the device status register, see [`STATUS_SPEED`],
+    /// [`STATUS_LASER`] and [`STATUS_FAN`]
+    StatusBits(u32),
+    /// Lose power, returning to the power-on state
+    ///
+    /// The auto-cleaning interval is kept, it is stored in non-volatile
+    /// memory.
+    PowerLoss,
+}
+
+/// Source of the simulated concentrations
+pub trait Profile {
+    /// Measured values at the given time [ms]
+    fn air_info(&mut self, now: u64) -> AirInfo;
+}
+
+impl<F: FnMut(u64) -> AirInfo> Profile for F {
+    fn air_info(&mut self, now: u64) -> AirInfo {
+        self(now)
+    }
+}
+
+/// Profile replaying a script of measurements, one per sample, in a loop
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptedProfile {
+    samples: Vec<AirInfo>,
+    index: usize,
+}
+
+impl ScriptedProfile {
+    /// Create a profile from a non-empty script
+    pub fn new(samples: Vec<AirInfo>) -> Self {
+        assert!(!samples.is_empty(), "empty script");
+        ScriptedProfile { samples, index: 0 }
+    }
+}
+
+impl Profile for ScriptedProfile {
+    fn air_info(&mut self, _now: u64) -> AirInfo {
+        let air_info = self.samples[self.index];
+        self.index = (self.index + 1) % self.samples.len();
+        air_info
+    }
+}
+
+/// Profile of a random walk of the PM2.5 mass concentration
+///
+/// The other values are derived from it with fixed ratios, so measurements
+/// are always physically consistent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RandomProfile {
+    state: u64,
+    mass_pm2_5: f32,
+}
+
+impl RandomProfile {
+    /// Create a profile from a seed
+    pub fn new(seed: u64) -> Self {
+        RandomProfile {
+            state: seed | 1,
+            mass_pm2_5: 10.0,
+        }
+    }
+
+    /// Uniform random number in `[-1, 1)`
+    fn next(&mut self) -> f32 {
+        // xorshift64
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
+
+impl Profile for RandomProfile {
+    fn air_info(&mut self, _now: u64) -> AirInfo {
+        self.mass_pm2_5 = (self.mass_pm2_5 + self.next()).clamp(0.5, 500.0);
+        let mass = self.mass_pm2_5;
+        let number = mass * 6.5;
+
+        AirInfo {
+            mass_pm1_0: mass * 0.9,
+            mass_pm2_5: mass,
+            mass_pm4_0: mass * 1.02,
+            mass_pm10: mass * 1.04,
+            number_pm0_5: number * 0.8,
+            number_pm1_0: number * 0.95,
+            number_pm2_5: number,
+            number_pm4_0: number * 1.001,
+            number_pm10: number * 1.002,
+            typical_size: 0.5,
+        }
+    }
+}
+
+/// Virtual clock, shared between its clones
+///
+/// As a delay it advances the time instead of waiting.
+#[derive(Debug, Clone, Default)]
+pub struct SimClock {
+    now: Arc<AtomicU64>,
+}
+
+impl SimClock {
+    /// Create a clock at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advance the time [ms]
+    pub fn advance(&self, ms: u64) {
+        self.now.fetch_add(ms, Ordering::SeqCst);
+    }
+
+    /// Set the time [ms]
+    pub fn set(&self, ms: u64) {
+        self.now.store(ms, Ordering::SeqCst);
+    }
+
+    /// Current time [ms]
+    pub fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+impl Clock for SimClock {
+    fn now_ms(&mut self) -> u64 {
+        self.now()
+    }
+}
+
+impl delay::DelayMs<u8> for SimClock {
+    fn delay_ms(&mut self, ms: u8) {
+        self.advance(u64::from(ms));
+    }
+}
+
+impl delay::DelayMs<u32> for SimClock {
+    fn delay_ms(&mut self, ms: u32) {
+        self.advance(u64::from(ms));
+    }
+}
+
+/// Simulated SPS30
+#[derive(Debug)]
+pub struct VirtualSps30<P, C> {
+    profile: P,
+    clock: C,
+    address: u8,
+    state: SimState,
+    /// Whether the I2C interface was activated by a wake-up pulse
+    interface_active: bool,
+    format: u8,
+    measurement_started: u64,
+    /// Number of samples read since the measurement started
+    samples_read: u64,
+    cleaning_until: u64,
+    auto_cleaning_interval: u32,
+    status: u32,
+    serial: [u8; 32],
+    firmware: (u8, u8),
+    /// Last measurement made available
+    measurement: AirInfo,
+    /// Command whose response is read next
+    pointer: Option<[u8; 2]>,
+    /// Number of transactions so far
+    transactions: u64,
+    /// Faults to inject, by transaction number
+    scheduled: Vec<(u64, Fault)>,
+    nack_next: bool,
+    corrupt_crc: Option<usize>,
+    data_ready_stuck: Option<bool>,
+    frozen: bool,
+}
+
+impl<P: Profile, C: Clock> VirtualSps30<P, C> {
+    /// Create an idle device at the default address
+    pub fn new(profile: P, clock: C) -> Self {
+        let mut serial = [0; 32];
+        serial[..16].clone_from_slice(b"SIM0000000000000");
+
+        VirtualSps30 {
+            profile,
+            clock,
+            address: DEV_ADDR,
+            state: SimState::Idle,
+            interface_active: true,
+            format: FORMAT_FLOAT,
+            measurement_started: 0,
+            samples_read: 0,
+            cleaning_until: 0,
+            auto_cleaning_interval: DEFAULT_AUTO_CLEANING_INTERVAL,
+            status: 0,
+            serial,
+            firmware: (2, 2),
+            measurement: AirInfo::from_array(&[0.0; 10]),
+            pointer: None,
+            transactions: 0,
+            scheduled: Vec::new(),
+            nack_next: false,
+            corrupt_crc: None,
+            data_ready_stuck: None,
+            frozen: false,
+        }
+    }
+
+    /// Inject a fault
+    pub fn inject(&mut self, fault: Fault) {
+        match fault {
+            Fault::Nack => self.nack_next = true,
+            Fault::CorruptCrc(word) => self.corrupt_crc = Some(word),
+            Fault::StuckDataReady(ready) => self.data_ready_stuck = Some(ready),
+            Fault::FrozenMeasurement => self.frozen = true,
+            Fault::StatusBits(bits) => self.status |= bits,
+            Fault::PowerLoss => self.power_on(),
+        }
+    }
+
+    /// Inject a fault right before the given transaction, counting reads and
+    /// writes from zero
+    pub fn schedule(&mut self, transaction: u64, fault: Fault) {
+        self.scheduled.push((transaction, fault));
+    }
+
+    /// Remove the injected and scheduled faults
+    pub fn clear_faults(&mut self) {
+        self.scheduled.clear();
+        self.nack_next = false;
+        self.corrupt_crc = None;
+        self.data_ready_stuck = None;
+        self.frozen = false;
+    }
+
+    /// Number of transactions so far
+    pub fn transactions(&self) -> u64 {
+        self.transactions
+    }
+
+    /// Current operating mode
+    pub fn state(&self) -> SimState {
+        self.state
+    }
+
+    /// Whether the fan cleaning is running
+    pub fn is_fan_cleaning(&mut self) -> bool {
+        self.state == SimState::Measuring && self.clock.now_ms() < self.cleaning_until
+    }
+
+    /// Set the serial number, padded with zeros
+    pub fn set_serial_number(&mut self, serial: &[u8]) {
+        self.serial = [0; 32];
+        let len = serial.len().min(31);
+        self.serial[..len].clone_from_slice(&serial[..len]);
+    }
+
+    /// Set the firmware version
+    pub fn set_firmware_version(&mut self, major: u8, minor: u8) {
+        self.firmware = (major, minor);
+    }
+
+    /// Set the raw device status register
+    pub fn set_status_register(&mut self, status: u32) {
+        self.status = status;
+    }
+
+    /// Auto-cleaning interval [s]
+    pub fn auto_cleaning_interval(&self) -> u32 {
+        self.auto_cleaning_interval
+    }
+
+    fn power_on(&mut self) {
+        self.state = SimState::Idle;
+        self.interface_active = true;
+        self.format = FORMAT_FLOAT;
+        self.measurement_started = 0;
+        self.samples_read = 0;
+        self.cleaning_until = 0;
+        self.status = 0;
+        self.measurement = AirInfo::from_array(&[0.0; 10]);
+        self.pointer = None;
+    }
+
+    /// Count a transaction, injecting the faults due, and return whether it
+    /// is acknowledged
+    fn begin_transaction(&mut self) -> bool {
+        let transaction = self.transactions;
+        self.transactions += 1;
+
+        let mut i = 0;
+        while i < self.scheduled.len() {
+            if self.scheduled[i].0 == transaction {
+                let (_, fault) = self.scheduled.remove(i);
+                self.inject(fault);
+            } else {
+                i += 1;
+            }
+        }
+
+        !core::mem::replace(&mut self.nack_next, false)
+    }
+
+    fn data_ready(&mut self) -> bool {
+        let elapsed = self.clock.now_ms().saturating_sub(self.measurement_started);
+        elapsed / SAMPLE_INTERVAL_MS > self.samples_read
+    }
+
+    fn read_measured_values(&mut self, out: &mut Vec<u8>) {
+        let now = self.clock.now_ms();
+        if self.data_ready() {
+            self.samples_read = now.saturating_sub(self.measurement_started) / SAMPLE_INTERVAL_MS;
+            if !self.frozen {
+                self.measurement = self.profile.air_info(now);
+            }
+        }
+
+        for (i, value) in self.measurement.to_array().iter().enumerate() {
+            if self.format == FORMAT_UINT16 {
+                // The typical particle size is in nm in the integer format
+                let value = if i == 9 { *value * 1000.0 } else { *value };
+                let value = if value > f32::from(u16::MAX) { u16::MAX } else { value as u16 };
+                out.extend_from_slice(&value.to_be_bytes());
+            } else {
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+    }
+
+    /// Execute a command, returning whether it is acknowledged
+    fn command(&mut self, cmd: [u8; 2], args: &[u8]) -> bool {
+        use SimState::*;
+
+        let legal = match cmd {
+            Register::START_MEASUREMENT => self.state == Idle && args.len() == 2
+                && (args[0] == FORMAT_FLOAT || args[0] == FORMAT_UINT16),
+            Register::STOP_MEASUREMENT
+            | Register::READ_DATA_READY_FLAG
+            | Register::READ_MEASURED_VALUES
+            | Register::START_FAN_CLEANING => self.state == Measuring,
+            Register::SLEEP => self.state == Idle,
+            Register::WAKE_UP => self.state == Sleeping && self.interface_active,
+            Register::READ_WRITE_AUTO_CLEANING_INTERVAL => self.state != Sleeping
+                && (args.is_empty() || args.len() == 4),
+            Register::READ_DEVICE_PRODUCT_TYPE
+            | Register::READ_DEVICE_SERIAL_NUMBER
+            | Register::READ_FIRMWARE_VERSION
+            | Register::READ_DEVICE_STATUS_REGISTER
+            | Register::CLEAR_DEVICE_STATUS_REGISTER
+            | Register::DEVICE_RESET => self.state != Sleeping,
+            _ => false,
+        };
+        if !legal {
+            return false;
+        }
+
+        let now = self.clock.now_ms();
+        self.pointer = None;
+        match cmd {
+            Register::START_MEASUREMENT => {
+                self.state = Measuring;
+                self.format = args[0];
+                self.measurement_started = now;
+                self.samples_read = 0;
+            }
+            Register::STOP_MEASUREMENT => {
+                self.state = Idle;
+                self.cleaning_until = 0;
+            }
+            Register::SLEEP => {
+                self.state = Sleeping;
+                self.interface_active = false;
+            }
+            Register::WAKE_UP => self.state = Idle,
+            Register::START_FAN_CLEANING => self.cleaning_until = now + FAN_CLEANING_MS,
+            Register::READ_WRITE_AUTO_CLEANING_INTERVAL if args.len() == 4 => {
+                self.auto_cleaning_interval = u32::from_be_bytes([args[0], args[1], args[2], args[3]]);
+            }
+            Register::CLEAR_DEVICE_STATUS_REGISTER => self.status = 0,
+            Register::DEVICE_RESET => {
+                self.state = Idle;
+                self.cleaning_until = 0;
+            }
+            _ => self.pointer = Some(cmd),
+        }
+
+        true
+    }
+
+    /// Response to a read of the last command, without CRCs
+    fn response(&mut self) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+
+        match self.pointer? {
+            Register::READ_DATA_READY_FLAG => {
+                let ready = self.data_ready_stuck.unwrap_or_else(|| self.data_ready());
+                out.extend_from_slice(&[0x00, ready as u8]);
+            }
+            Register::READ_MEASURED_VALUES => self.read_measured_values(&mut out),
+            Register::READ_WRITE_AUTO_CLEANING_INTERVAL => {
+                out.extend_from_slice(&self.auto_cleaning_interval.to_be_bytes());
+            }
+            Register::READ_DEVICE_PRODUCT_TYPE => out.extend_from_slice(b"00080000"),
+            Register::READ_DEVICE_SERIAL_NUMBER => out.extend_from_slice(&self.serial),
+            Register::READ_FIRMWARE_VERSION => out.extend_from_slice(&[self.firmware.0, self.firmware.1]),
+            Register::READ_DEVICE_STATUS_REGISTER => out.extend_from_slice(&self.status.to_be_bytes()),
+            _ => return None,
+        }
+
+        Some(out)
+    }
+}
+
+/// Append the CRC after every word
+fn add_crc(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(data.len() / 2 * 3);
+    for word in data.chunks(2) {
+        res.extend_from_slice(word);
+        if word.len() == 2 {
+            res.push(calc_crc(&[word[0], word[1]]));
+        }
+    }
+    res
+}
+
+/// Check and strip the CRC after every word
+fn strip_crc(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() % 3 != 0 {
+        return None;
+    }
+
+    let mut res = Vec::with_capacity(data.len() / 3 * 2);
+    for chunk in data.chunks(3) {
+        if calc_crc(&[chunk[0], chunk[1]]) != chunk[2] {
+            return None;
+        }
+        res.extend_from_slice(&chunk[..2]);
+    }
+    Some(res)
+}
+
+impl<P: Profile, C: Clock> i2c::Write for VirtualSps30<P, C> {
+    type Error = SimError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        if !self.begin_transaction() || address != self.address {
+            return Err(SimError::Nack);
+        }
+        if bytes.is_empty() {
+            // Wake-up pulse, not acknowledged while sleeping
+            if self.state == SimState::Sleeping {
+                self.interface_active = true;
+                return Err(SimError::Nack);
+            }
+            return Ok(());
+        }
+        if bytes.len() < 2 || self.state == SimState::Sleeping && !self.interface_active {
+            return Err(SimError::Nack);
+        }
+
+        let args = strip_crc(&bytes[2..]).ok_or(SimError::Nack)?;
+        if self.command([bytes[0], bytes[1]], &args) {
+            Ok(())
+        } else {
+            Err(SimError::Nack)
+        }
+    }
+}
+
+impl<P: Profile, C: Clock> i2c::Read for VirtualSps30<P, C> {
+    type Error = SimError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        if !self.begin_transaction() || address != self.address || self.state == SimState::Sleeping {
+            return Err(SimError::Nack);
+        }
+
+        let mut response = add_crc(&self.response().ok_or(SimError::Nack)?);
+        if let Some(word) = self.corrupt_crc.take() {
+            if let Some(crc) = response.get_mut(word * 3 + 2) {
+                *crc ^= 0xFF;
+            }
+        }
+        for (i, b) in buffer.iter_mut().enumerate() {
+            // The bus reads high once the device stops sending
+            *b = response.get(i).copied().unwrap_or(0xFF);
+        }
+
+        Ok(())
+    }
+}
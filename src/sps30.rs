@@ -1,6 +1,15 @@
 use crate::register_access::sps30::{DEV_ADDR, Register, StatusRegisterBits};
 use crate::Sps30;
-use crate::types::{AirInfo, Error, StatusRegisterResult};
+use crate::clock::{Clock, NoClock};
+use crate::fan_cleaning::{FanCleaning, DEFAULT_AUTO_CLEANING_INTERVAL};
+use crate::power::{Mode, Residency};
+#[cfg(feature = "float")]
+use crate::quality::Reading;
+use crate::quality::{QualityFlags, ReadingU16};
+#[cfg(feature = "float")]
+use crate::types::AirInfo;
+use crate::types::{AirInfoU16, Error, FieldSelection, OutputFormat, PartialAirInfo, StatusRegisterResult};
+use crate::warm_up::WarmUp;
 use byteorder::{BigEndian, ByteOrder};
 use embedded_hal::blocking::{delay, i2c};
 
@@ -13,27 +22,221 @@ D: delay::DelayMs<u8> {
             i2c,
             delay,
             address: DEV_ADDR,
+            #[cfg(feature = "float")]
+            calibration: None,
+            #[cfg(feature = "float")]
+            humidity: None,
+            clock: None::<NoClock>,
+            measurement_started: None,
+            warm_up: WarmUp::default(),
+            cleaning_started: None,
+            auto_cleaning_interval: DEFAULT_AUTO_CLEANING_INTERVAL,
+            fan_cleaning: FanCleaning::default(),
+            mode: Mode::Idle,
+            mode_since: None,
+            residency: Residency::default(),
+            output_format: OutputFormat::default(),
+        }
+    }
+}
+
+impl<I2C, D, C, E> Sps30<I2C, D, C>
+where I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+D: delay::DelayMs<u8>,
+C: Clock {
+    /// Attach a clock, used to timestamp the readings
+    pub fn with_clock<C2: Clock>(self, mut clock: C2) -> Sps30<I2C, D, C2> {
+        let now = clock.now_ms();
+
+        Sps30 {
+            i2c: self.i2c,
+            delay: self.delay,
+            address: self.address,
+            #[cfg(feature = "float")]
+            calibration: self.calibration,
+            #[cfg(feature = "float")]
+            humidity: self.humidity,
+            clock: Some(clock),
+            measurement_started: None,
+            warm_up: self.warm_up,
+            cleaning_started: None,
+            auto_cleaning_interval: self.auto_cleaning_interval,
+            fan_cleaning: self.fan_cleaning,
+            mode: self.mode,
+            mode_since: Some(now),
+            residency: Residency::default(),
+            output_format: self.output_format,
         }
     }
 
+    /// Use a different I2C address, e.g. behind an address translator
+    pub fn with_address(mut self, address: u8) -> Self {
+        self.address = address;
+        self
+    }
+
     /// Destory driver instance
     pub fn destroy(self) -> I2C {
         self.i2c
     }
 
+    /// Set the stabilization window after entering measurement mode
+    pub fn set_warm_up(&mut self, warm_up: WarmUp) {
+        self.warm_up = warm_up;
+    }
+
+    /// Time measurement mode was entered [ms], if a clock is attached
+    pub fn measurement_started(&self) -> Option<u64> {
+        self.measurement_started
+    }
+
+    /// Set the fan cleaning window
+    pub fn set_fan_cleaning(&mut self, fan_cleaning: FanCleaning) {
+        self.fan_cleaning = fan_cleaning;
+    }
+
+    /// Auto-cleaning interval [s] last read from or written to the device
+    /// The default of the device until then
+    pub fn auto_cleaning_interval(&self) -> u32 {
+        self.auto_cleaning_interval
+    }
+
+    /// Predicted start of the next automatic fan cleaning [ms]
+    /// Requires a clock and the sensor to be in measurement mode
+    pub fn next_auto_cleaning(&mut self) -> Option<u64> {
+        let now = self.now()?;
+        self.fan_cleaning.next_auto_cleaning(self.measurement_started?, self.auto_cleaning_interval, now)
+    }
+
+    /// Whether a manual or automatic fan cleaning is in progress
+    /// Always false without a clock
+    pub fn is_fan_cleaning(&mut self) -> bool {
+        match self.now() {
+            Some(now) => self.is_fan_cleaning_at(now),
+            None => false,
+        }
+    }
+
+    fn is_fan_cleaning_at(&self, now: u64) -> bool {
+        let manual = self
+            .cleaning_started
+            .map_or(false, |started| self.fan_cleaning.is_manual_cleaning(started, now));
+        let auto = self
+            .measurement_started
+            .map_or(false, |started| self.fan_cleaning.is_auto_cleaning(started, self.auto_cleaning_interval, now));
+
+        manual || auto
+    }
+
+    pub(crate) fn now(&mut self) -> Option<u64> {
+        self.clock.as_mut().map(Clock::now_ms)
+    }
+
+    /// Operating mode, as tracked from the issued commands
+    pub fn mode(&mut self) -> Mode {
+        if let Some(now) = self.now() {
+            self.account_mode(now);
+        }
+        self.mode
+    }
+
+    /// Time spent in every mode since the clock was attached or the log
+    /// was reset
+    /// Always empty without a clock
+    pub fn mode_residency(&mut self) -> Residency {
+        if let Some(now) = self.now() {
+            self.account_mode(now);
+        }
+        self.residency
+    }
+
+    /// Reset the mode residency log
+    pub fn reset_mode_residency(&mut self) {
+        if let Some(now) = self.now() {
+            self.account_mode(now);
+        }
+        self.residency = Residency::default();
+    }
+
+    fn enter_mode(&mut self, mode: Mode) {
+        if let Some(now) = self.now() {
+            self.account_mode(now);
+        }
+        self.mode = mode;
+    }
+
+    fn account_mode(&mut self, now: u64) {
+        let since = match self.mode_since {
+            Some(since) => since,
+            None => return,
+        };
+        let elapsed = now.saturating_sub(since);
+
+        if self.mode == Mode::FanCleaning {
+            let end = self.cleaning_started.unwrap_or(since) + u64::from(self.fan_cleaning.duration_ms);
+            let cleaning = end.saturating_sub(since).min(elapsed);
+            self.residency.add(Mode::FanCleaning, cleaning);
+            self.residency.add(Mode::Measurement, elapsed - cleaning);
+            if now >= end {
+                self.mode = Mode::Measurement;
+            }
+        } else {
+            self.residency.add(self.mode, elapsed);
+        }
+
+        self.mode_since = Some(now);
+    }
+
     /// Enter measurement mode
+    /// The output format is float with the `float` feature and uint16 without
     /// Command execution time: 20 ms
     pub fn start_measurement(&mut self) -> Result<(), Error<E>> {
+        self.start_measurement_with_format(OutputFormat::default())
+    }
+
+    /// Enter measurement mode, waking the sensor up if it is sleeping
+    /// A sensor left measuring, e.g. by a process that was killed, does not
+    /// acknowledge the start; it is then stopped and started again, so the
+    /// output format is the one of `start_measurement()`
+    /// Returns whether the sensor was measuring already
+    /// Command execution time: 5 - 65 ms
+    pub fn ensure_measuring(&mut self) -> Result<bool, Error<E>> {
+        // Not acknowledged unless the sensor is sleeping
+        let _ = self.wake_up();
+
+        match self.start_measurement() {
+            Ok(()) => Ok(false),
+            Err(Error::I2C(_)) => {
+                // Fails as well if the start failed for another reason
+                self.stop_measurement()?;
+                self.start_measurement()?;
+                Ok(true)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Enter measurement mode with an output format
+    /// Command execution time: 20 ms
+    pub fn start_measurement_with_format(&mut self, format: OutputFormat) -> Result<(), Error<E>> {
         let mut data: [u8; 5] = [0; 5];
         data[..2].clone_from_slice(&Register::START_MEASUREMENT[..2]);
-        data[2] = 0x03;
+        data[2] = format.argument();
     
         self.write_data(&mut data)?;
         self.delay.delay_ms(20);
+        self.measurement_started = self.now();
+        self.output_format = format;
+        self.enter_mode(Mode::Measurement);
 
         Ok(())
     }
 
+    /// Output format selected by the last start of the measurement
+    pub fn output_format(&self) -> OutputFormat {
+        self.output_format
+    }
+
     /// Exit measurement mode
     /// Command execution time: 20 ms
     pub fn stop_measurement(&mut self) -> Result<(), Error<E>> {
@@ -41,6 +244,9 @@ D: delay::DelayMs<u8> {
 
         self.write_data(&mut data)?;
         self.delay.delay_ms(20);
+        self.measurement_started = None;
+        self.cleaning_started = None;
+        self.enter_mode(Mode::Idle);
 
         Ok(())
     }
@@ -62,28 +268,180 @@ D: delay::DelayMs<u8> {
     }
 
     /// Read the measured values
+    /// Values read in the uint16 output format are converted to float
+    /// The calibration profile, if any, is applied to the values
+    /// Its humidity terms are left out while no relative humidity is set,
+    /// see `set_relative_humidity()`
     /// Command execution time: -
+    #[cfg(feature = "float")]
     pub fn read_measured_values(&mut self) -> Result<AirInfo, Error<E>> {
+        self.read_values(FieldSelection::All.count())
+    }
+
+    /// Read the selected measured values only
+    /// Reads the shortest frame holding them, 24 instead of 60 bytes for the
+    /// mass concentrations in the float output format
+    /// The calibration profile, if any, is applied to the values, without
+    /// its humidity terms while no relative humidity is set
+    /// Command execution time: -
+    #[cfg(feature = "float")]
+    pub fn read_selected_values(&mut self, selection: FieldSelection) -> Result<PartialAirInfo<f32>, Error<E>> {
+        let air_info = self.read_values(selection.count())?;
+        Ok(PartialAirInfo::from_array(selection, &air_info.to_array()))
+    }
+
+    /// Read the measured values in the integer format
+    /// Values read in the float output format are rounded to integers
+    /// The calibration profile is not applied
+    /// Command execution time: -
+    pub fn read_measured_values_u16(&mut self) -> Result<AirInfoU16, Error<E>> {
+        let values = self.read_values_u16(FieldSelection::All.count())?;
+        Ok(AirInfoU16::from_array(&values))
+    }
+
+    /// Read the selected measured values only in the integer format
+    /// Reads the shortest frame holding them
+    /// The calibration profile is not applied
+    /// Command execution time: -
+    pub fn read_selected_values_u16(&mut self, selection: FieldSelection) -> Result<PartialAirInfo<u16>, Error<E>> {
+        let values = self.read_values_u16(selection.count())?;
+        Ok(PartialAirInfo::from_array(selection, &values))
+    }
+
+    /// Read the first `count` values, NaN for the others, and calibrate them
+    #[cfg(feature = "float")]
+    fn read_values(&mut self, count: usize) -> Result<AirInfo, Error<E>> {
+        let mut air_info = match self.output_format {
+            OutputFormat::Float => AirInfo::from_array(&self.read_float_values(count)?),
+            OutputFormat::UInt16 => AirInfo::from(AirInfoU16::from_array(&self.read_uint16_values(count)?)),
+        };
+
+        if let Some(profile) = &self.calibration {
+            air_info = match self.humidity {
+                Some(rh) => profile.apply_with_humidity(&air_info, rh),
+                None => profile.apply(&air_info),
+            };
+        }
+
+        Ok(air_info)
+    }
+
+    /// Read the first `count` values in the integer format, not available for
+    /// the others
+    fn read_values_u16(&mut self, count: usize) -> Result<[u16; 10], Error<E>> {
+        match self.output_format {
+            #[cfg(feature = "float")]
+            OutputFormat::Float => {
+                let air_info = AirInfo::from_array(&self.read_float_values(count)?);
+                Ok(AirInfoU16::from(air_info).to_array())
+            }
+            OutputFormat::UInt16 => self.read_uint16_values(count),
+        }
+    }
+
+    fn read_uint16_values(&mut self, count: usize) -> Result<[u16; 10], Error<E>> {
+        let mut data: [u8; 2] = Register::READ_MEASURED_VALUES;
+        self.write_data(&mut data)?;
+
+        let mut buffer: [u8; 30] = [0; 30];
+        self.read_data(&mut buffer[..count * 3])?;
+
+        let mut values = [AirInfoU16::NOT_AVAILABLE; 10];
+        for (value, chunk) in values.iter_mut().zip(buffer[..count * 2].chunks(2)) {
+            *value = u16::from_be_bytes([chunk[0], chunk[1]]);
+        }
+        Ok(values)
+    }
+
+    #[cfg(feature = "float")]
+    fn read_float_values(&mut self, count: usize) -> Result<[f32; 10], Error<E>> {
         let mut data: [u8; 2] = Register::READ_MEASURED_VALUES;
         self.write_data(&mut data)?;
 
         let mut buffer: [u8; 60] = [0; 60];
-        self.read_data(&mut buffer)?;
+        self.read_data(&mut buffer[..count * 6])?;
 
-        let air_info: AirInfo = AirInfo {
-            mass_pm1_0: BigEndian::read_f32(&buffer[0..]),
-            mass_pm2_5: BigEndian::read_f32(&buffer[4..]), 
-            mass_pm4_0: BigEndian::read_f32(&buffer[4 * 2..]),
-            mass_pm10: BigEndian::read_f32(&buffer[4 * 3..]),
-            number_pm0_5: BigEndian::read_f32(&buffer[4 * 4..]),
-            number_pm1_0: BigEndian::read_f32(&buffer[4 * 5..]),
-            number_pm2_5: BigEndian::read_f32(&buffer[4 * 6..]),
-            number_pm4_0: BigEndian::read_f32(&buffer[4 * 7..]),
-            number_pm10: BigEndian::read_f32(&buffer[4 * 8..]),
-            typical_size: BigEndian::read_f32(&buffer[4 * 9..]),
-        };
+        let mut values = [f32::NAN; 10];
+        for (value, chunk) in values.iter_mut().zip(buffer[..count * 4].chunks(4)) {
+            *value = BigEndian::read_f32(chunk);
+        }
+        Ok(values)
+    }
 
-        Ok(air_info)
+    /// Read the measured values and check their physical consistency
+    /// With a clock attached, readings taken before the sensor stabilized
+    /// or while the fan is cleaning are flagged as such
+    /// Command execution time: -
+    #[cfg(feature = "float")]
+    pub fn read_validated_values(&mut self) -> Result<Reading, Error<E>> {
+        let mut reading = self.read_measured_values()?.validate();
+        reading.timestamp = self.now();
+
+        if let Some(now) = reading.timestamp {
+            let window_ms = self.warm_up.window_ms(reading.air_info.number_pm10);
+            reading.flags |= self.state_flags(now, window_ms);
+        }
+
+        Ok(reading)
+    }
+
+    /// Read the measured values in the integer format and check their
+    /// physical consistency
+    /// With a clock attached, readings taken before the sensor stabilized
+    /// or while the fan is cleaning are flagged as such
+    /// Command execution time: -
+    pub fn read_validated_values_u16(&mut self) -> Result<ReadingU16, Error<E>> {
+        let mut reading = self.read_measured_values_u16()?.validate();
+        reading.timestamp = self.now();
+
+        if let Some(now) = reading.timestamp {
+            let window_ms = self.warm_up.window_ms_u16(reading.air_info.number_pm10);
+            reading.flags |= self.state_flags(now, window_ms);
+        }
+
+        Ok(reading)
+    }
+
+    /// Flags for the sensor state at `now`, still warming up after
+    /// `window_ms` in measurement mode or fan cleaning
+    fn state_flags(&self, now: u64, window_ms: u32) -> QualityFlags {
+        let mut flags = QualityFlags::empty();
+        if let Some(started) = self.measurement_started {
+            if now.saturating_sub(started) < u64::from(window_ms) {
+                flags |= QualityFlags::WARMING_UP;
+            }
+        }
+        if self.is_fan_cleaning_at(now) {
+            flags |= QualityFlags::FAN_CLEANING;
+        }
+        flags
+    }
+
+    /// Read the measured values, dropping readings taken before the sensor
+    /// stabilized or while the fan is cleaning
+    /// Command execution time: -
+    #[cfg(feature = "float")]
+    pub fn read_stable_values(&mut self) -> Result<Option<Reading>, Error<E>> {
+        let reading = self.read_validated_values()?;
+
+        if is_stable(reading.flags) {
+            Ok(Some(reading))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Read the measured values in the integer format, dropping readings
+    /// taken before the sensor stabilized or while the fan is cleaning
+    /// Command execution time: -
+    pub fn read_stable_values_u16(&mut self) -> Result<Option<ReadingU16>, Error<E>> {
+        let reading = self.read_validated_values_u16()?;
+
+        if is_stable(reading.flags) {
+            Ok(Some(reading))
+        } else {
+            Ok(None)
+        }
     }
     
     /// Enter sleep mode
@@ -93,6 +451,7 @@ D: delay::DelayMs<u8> {
 
         self.write_data(&mut data)?;
         self.delay.delay_ms(5);
+        self.enter_mode(Mode::Sleep);
 
         Ok(())
     }
@@ -105,6 +464,7 @@ D: delay::DelayMs<u8> {
         self.write_data(&mut [])?;
         self.write_data(&mut data)?;
         self.delay.delay_ms(5);
+        self.enter_mode(Mode::Idle);
 
         Ok(())
     }
@@ -117,6 +477,8 @@ D: delay::DelayMs<u8> {
 
         self.write_data(&mut data)?;
         self.delay.delay_ms(5);
+        self.enter_mode(Mode::FanCleaning);
+        self.cleaning_started = self.now();
 
         Ok(())
     }
@@ -132,7 +494,8 @@ D: delay::DelayMs<u8> {
         let mut buffer: [u8; 6] = [0; 6];
         self.read_data(&mut buffer)?;
 
-        Ok(BigEndian::read_u32(&buffer))
+        self.auto_cleaning_interval = BigEndian::read_u32(&buffer);
+        Ok(self.auto_cleaning_interval)
     }
 
     /// Write the interval[s] of the periodic fan-cleaning
@@ -144,6 +507,7 @@ D: delay::DelayMs<u8> {
 
         self.write_data(&mut data)?;
         self.delay.delay_ms(20);
+        self.auto_cleaning_interval = n;
 
         Ok(())
     }
@@ -238,7 +602,16 @@ D: delay::DelayMs<u8> {
 
         self.write_data(&mut data)?;
         self.delay.delay_ms(100);
+        self.measurement_started = None;
+        self.cleaning_started = None;
+        self.enter_mode(Mode::Idle);
         
         Ok(())
     }
 }
+
+/// Whether a reading with `flags` was taken after the sensor stabilized and
+/// outside of a fan cleaning
+fn is_stable(flags: QualityFlags) -> bool {
+    !flags.contains(QualityFlags::WARMING_UP) && !flags.contains(QualityFlags::FAN_CLEANING)
+}
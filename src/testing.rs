@@ -0,0 +1,233 @@
+//! Frame builders for tests against `embedded-hal-mock`
+//!
+//! The builders return the bytes the driver writes for every command and
+//! CRC-correct responses, so expectations can be written without
+//! duplicating the command table and the CRC. [`ManualClock`] is a clock the
+//! test sets or advances by hand:
+//!
+//! ```
+//! use sps30_i2c::Sps30;
+//! use sps30_i2c::testing::{self, DEV_ADDR};
+//! use embedded_hal_mock::{delay::MockNoop, i2c::Mock, i2c::Transaction};
+//!
+//! let expectations = [
+//!     Transaction::write(DEV_ADDR, testing::read_firmware_version()),
+//!     Transaction::read(DEV_ADDR, testing::firmware_version_frame(2, 1)),
+//! ];
+//! let mut sensor = Sps30::new_sps30(Mock::new(&expectations), MockNoop);
+//!
+//! assert_eq!(sensor.read_firmware_version().unwrap(), (2, 1));
+//! sensor.destroy().done();
+//! ```
+
+use crate::clock::Clock;
+pub use crate::crc::calc_crc;
+pub use crate::register_access::sps30::{DEV_ADDR, Register};
+#[cfg(feature = "float")]
+use crate::types::AirInfo;
+use crate::types::{AirInfoU16, OutputFormat};
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use embedded_hal::blocking::delay;
+
+/// Product type returned by the device
+const PRODUCT_TYPE: &[u8; 8] = b"00080000";
+
+/// Clock set by the test, shared between its clones
+///
+/// As a delay it advances the time instead of waiting.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Rc<Cell<u64>>,
+}
+
+impl ManualClock {
+    /// Create a clock at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advance the time [ms]
+    pub fn advance(&self, ms: u64) {
+        self.now.set(self.now.get() + ms);
+    }
+
+    /// Set the time [ms]
+    pub fn set(&self, ms: u64) {
+        self.now.set(ms);
+    }
+
+    /// Current time [ms]
+    pub fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&mut self) -> u64 {
+        self.now()
+    }
+}
+
+impl delay::DelayMs<u8> for ManualClock {
+    fn delay_ms(&mut self, ms: u8) {
+        self.advance(u64::from(ms));
+    }
+}
+
+impl delay::DelayMs<u32> for ManualClock {
+    fn delay_ms(&mut self, ms: u32) {
+        self.advance(u64::from(ms));
+    }
+}
+
+/// Append the CRC after every word of the data
+pub fn with_crc(data: &[u8]) -> Vec<u8> {
+    assert!(data.len() % 2 == 0, "odd number of bytes");
+
+    let mut res = Vec::with_capacity(data.len() / 2 * 3);
+    for word in data.chunks(2) {
+        res.extend_from_slice(word);
+        res.push(calc_crc(&[word[0], word[1]]));
+    }
+    res
+}
+
+/// Write frame of a command with arguments, which get a CRC after every
+/// word
+pub fn command_frame(command: [u8; 2], args: &[u8]) -> Vec<u8> {
+    let mut res = command.to_vec();
+    res.extend_from_slice(&with_crc(args));
+    res
+}
+
+/// Write frame of `start_measurement_with_format()`
+///
+/// `start_measurement()` uses [`OutputFormat::default()`].
+///
+/// [`OutputFormat::default()`]: ../enum.OutputFormat.html
+pub fn start_measurement(format: OutputFormat) -> Vec<u8> {
+    command_frame(Register::START_MEASUREMENT, &[format.argument(), 0x00])
+}
+
+/// Write frame of `stop_measurement()`
+pub fn stop_measurement() -> Vec<u8> {
+    Register::STOP_MEASUREMENT.to_vec()
+}
+
+/// Write frame of `read_data_ready_flag()`
+pub fn read_data_ready_flag() -> Vec<u8> {
+    Register::READ_DATA_READY_FLAG.to_vec()
+}
+
+/// Write frame of `read_measured_values()`
+pub fn read_measured_values() -> Vec<u8> {
+    Register::READ_MEASURED_VALUES.to_vec()
+}
+
+/// Write frame of `sleep()`
+pub fn sleep() -> Vec<u8> {
+    Register::SLEEP.to_vec()
+}
+
+/// Write frames of `wake_up()`: the wake-up pulse and the command
+pub fn wake_up() -> [Vec<u8>; 2] {
+    [Vec::new(), Register::WAKE_UP.to_vec()]
+}
+
+/// Write frame of `start_fan_cleaning()`
+pub fn start_fan_cleaning() -> Vec<u8> {
+    Register::START_FAN_CLEANING.to_vec()
+}
+
+/// Write frame of `read_auto_cleaning_interval()`
+pub fn read_auto_cleaning_interval() -> Vec<u8> {
+    Register::READ_WRITE_AUTO_CLEANING_INTERVAL.to_vec()
+}
+
+/// Write frame of `write_auto_cleaning_interval()`
+pub fn write_auto_cleaning_interval(interval: u32) -> Vec<u8> {
+    command_frame(Register::READ_WRITE_AUTO_CLEANING_INTERVAL, &interval.to_be_bytes())
+}
+
+/// Write frame of `read_device_product_type()`
+pub fn read_device_product_type() -> Vec<u8> {
+    Register::READ_DEVICE_PRODUCT_TYPE.to_vec()
+}
+
+/// Write frame of `read_device_serial_number()`
+pub fn read_device_serial_number() -> Vec<u8> {
+    Register::READ_DEVICE_SERIAL_NUMBER.to_vec()
+}
+
+/// Write frame of `read_firmware_version()`
+pub fn read_firmware_version() -> Vec<u8> {
+    Register::READ_FIRMWARE_VERSION.to_vec()
+}
+
+/// Write frame of `read_device_status_register()`
+pub fn read_device_status_register() -> Vec<u8> {
+    Register::READ_DEVICE_STATUS_REGISTER.to_vec()
+}
+
+/// Write frame of `clear_device_status_register()`
+pub fn clear_device_status_register() -> Vec<u8> {
+    Register::CLEAR_DEVICE_STATUS_REGISTER.to_vec()
+}
+
+/// Write frame of `device_reset()`
+pub fn device_reset() -> Vec<u8> {
+    Register::DEVICE_RESET.to_vec()
+}
+
+/// Response to `read_data_ready_flag()`
+pub fn data_ready_frame(ready: bool) -> Vec<u8> {
+    with_crc(&[0x00, ready as u8])
+}
+
+/// Response to `read_measured_values()` in the float output format
+#[cfg(feature = "float")]
+pub fn measured_values_frame(air_info: &AirInfo) -> Vec<u8> {
+    let data: Vec<u8> = air_info.to_array().iter().flat_map(|v| v.to_be_bytes()).collect();
+    with_crc(&data)
+}
+
+/// Response to `read_measured_values_u16()` in the uint16 output format
+pub fn measured_values_frame_u16(air_info: &AirInfoU16) -> Vec<u8> {
+    let data: Vec<u8> = air_info.to_array().iter().flat_map(|v| v.to_be_bytes()).collect();
+    with_crc(&data)
+}
+
+/// Response to `read_auto_cleaning_interval()`
+pub fn auto_cleaning_interval_frame(interval: u32) -> Vec<u8> {
+    with_crc(&interval.to_be_bytes())
+}
+
+/// Response to `read_device_product_type()`
+pub fn product_type_frame() -> Vec<u8> {
+    with_crc(PRODUCT_TYPE)
+}
+
+/// Response to `read_device_serial_number()`
+///
+/// The serial number is padded with zeros to 32 bytes; longer serials are
+/// truncated.
+pub fn serial_number_frame(serial: &[u8]) -> Vec<u8> {
+    let mut data = [0; 32];
+    let len = serial.len().min(data.len());
+    data[..len].clone_from_slice(&serial[..len]);
+    with_crc(&data)
+}
+
+/// Response to `read_firmware_version()`
+pub fn firmware_version_frame(major: u8, minor: u8) -> Vec<u8> {
+    with_crc(&[major, minor])
+}
+
+/// Response to `read_device_status_register()`, see
+/// [`StatusRegisterResult`](../struct.StatusRegisterResult.html) for the
+/// bits
+pub fn status_register_frame(status: u32) -> Vec<u8> {
+    with_crc(&status.to_be_bytes())
+}
@@ -1,3 +1,6 @@
+#[cfg(feature = "float")]
+use crate::codec::{dequantize, quantize, UINT16_SCALES};
+
 /// All possible errors in this crate
 #[derive(Debug)]
 pub enum Error<E> {
@@ -7,8 +10,40 @@ pub enum Error<E> {
     ChecksumMismatch,
 }
 
+/// Output format of the measured values, selected when starting the measurement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Big-endian IEEE754 float values
+    #[cfg(feature = "float")]
+    Float,
+    /// Big-endian unsigned 16-bit integer values, from firmware version 2.0
+    UInt16,
+}
+
+impl OutputFormat {
+    /// Argument of the start measurement command
+    pub(crate) fn argument(self) -> u8 {
+        match self {
+            #[cfg(feature = "float")]
+            OutputFormat::Float => 0x03,
+            OutputFormat::UInt16 => 0x05,
+        }
+    }
+}
+
+impl Default for OutputFormat {
+    /// Float with the `float` feature, uint16 without
+    fn default() -> Self {
+        #[cfg(feature = "float")]
+        return OutputFormat::Float;
+        #[cfg(not(feature = "float"))]
+        return OutputFormat::UInt16;
+    }
+}
+
 /// Measurement results
-#[derive(Debug)]
+#[cfg(feature = "float")]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct AirInfo {
     /// Mass Concentration PM1.0 [μg/m³]
     pub mass_pm1_0: f32,
@@ -32,8 +67,201 @@ pub struct AirInfo {
     pub typical_size: f32,
 }
 
+#[cfg(feature = "float")]
+impl AirInfo {
+    /// Values in the order the device reports them
+    pub(crate) fn to_array(self) -> [f32; 10] {
+        [
+            self.mass_pm1_0,
+            self.mass_pm2_5,
+            self.mass_pm4_0,
+            self.mass_pm10,
+            self.number_pm0_5,
+            self.number_pm1_0,
+            self.number_pm2_5,
+            self.number_pm4_0,
+            self.number_pm10,
+            self.typical_size,
+        ]
+    }
+
+    /// Measurement from values in the order the device reports them
+    pub(crate) fn from_array(values: &[f32; 10]) -> Self {
+        AirInfo {
+            mass_pm1_0: values[0],
+            mass_pm2_5: values[1],
+            mass_pm4_0: values[2],
+            mass_pm10: values[3],
+            number_pm0_5: values[4],
+            number_pm1_0: values[5],
+            number_pm2_5: values[6],
+            number_pm4_0: values[7],
+            number_pm10: values[8],
+            typical_size: values[9],
+        }
+    }
+}
+
+/// Measurement results in the integer output format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct AirInfoU16 {
+    /// Mass Concentration PM1.0 [μg/m³]
+    pub mass_pm1_0: u16,
+    /// Mass Concentration PM2.5 [μg/m³]
+    pub mass_pm2_5: u16,
+    /// Mass Concentration PM4.0 [μg/m³]
+    pub mass_pm4_0: u16,
+    /// Mass Concentration PM10 [μg/m³]
+    pub mass_pm10: u16,
+    /// Number Concentration PM0.5 [#/cm³]
+    pub number_pm0_5: u16,
+    /// Number Concentration PM1.0 [#/cm³]
+    pub number_pm1_0: u16,
+    /// Number Concentration PM2.5 [#/cm³]
+    pub number_pm2_5: u16,
+    /// Number Concentration PM4.0 [#/cm³]
+    pub number_pm4_0: u16,
+    /// Number Concentration PM10 [#/cm³]
+    pub number_pm10: u16,
+    /// Typical Particle Size [nm]
+    pub typical_size: u16,
+}
+
+impl AirInfoU16 {
+    /// Value of unknown or invalid fields, never reported by the device
+    pub const NOT_AVAILABLE: u16 = 0xFFFF;
+
+    /// Values in the order the device reports them
+    pub(crate) fn to_array(self) -> [u16; 10] {
+        [
+            self.mass_pm1_0,
+            self.mass_pm2_5,
+            self.mass_pm4_0,
+            self.mass_pm10,
+            self.number_pm0_5,
+            self.number_pm1_0,
+            self.number_pm2_5,
+            self.number_pm4_0,
+            self.number_pm10,
+            self.typical_size,
+        ]
+    }
+
+    /// Measurement from values in the order the device reports them
+    pub(crate) fn from_array(values: &[u16; 10]) -> Self {
+        AirInfoU16 {
+            mass_pm1_0: values[0],
+            mass_pm2_5: values[1],
+            mass_pm4_0: values[2],
+            mass_pm10: values[3],
+            number_pm0_5: values[4],
+            number_pm1_0: values[5],
+            number_pm2_5: values[6],
+            number_pm4_0: values[7],
+            number_pm10: values[8],
+            typical_size: values[9],
+        }
+    }
+}
+
+/// Rounded to the integer format, not available for unknown, invalid and
+/// negative values and saturated below it
+#[cfg(feature = "float")]
+impl From<AirInfo> for AirInfoU16 {
+    fn from(air_info: AirInfo) -> Self {
+        let mut values = [0; 10];
+        for ((value, float), scale) in values.iter_mut().zip(air_info.to_array().iter()).zip(UINT16_SCALES.iter()) {
+            *value = quantize(*float, *scale);
+        }
+        AirInfoU16::from_array(&values)
+    }
+}
+
+/// Converted to the float units, with NaN for not available
+#[cfg(feature = "float")]
+impl From<AirInfoU16> for AirInfo {
+    fn from(air_info: AirInfoU16) -> Self {
+        let mut values = [0.0; 10];
+        for ((value, integer), scale) in values.iter_mut().zip(air_info.to_array().iter()).zip(UINT16_SCALES.iter()) {
+            *value = dequantize(*integer, *scale);
+        }
+        AirInfo::from_array(&values)
+    }
+}
+
+/// Measured values to read, a prefix of the frame in the order the device
+/// reports them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FieldSelection {
+    /// Mass concentrations
+    Mass,
+    /// Mass and number concentrations
+    MassAndNumber,
+    /// Mass and number concentrations and typical particle size
+    All,
+}
+
+impl FieldSelection {
+    /// Number of values read
+    pub fn count(self) -> usize {
+        match self {
+            FieldSelection::Mass => 4,
+            FieldSelection::MassAndNumber => 9,
+            FieldSelection::All => 10,
+        }
+    }
+}
+
+/// Measurement results of a partial read, `None` for the fields not read
+///
+/// `T` is `f32` for the float units of [`AirInfo`] and `u16` for the
+/// integer ones of [`AirInfoU16`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PartialAirInfo<T> {
+    /// Mass Concentration PM1.0
+    pub mass_pm1_0: T,
+    /// Mass Concentration PM2.5
+    pub mass_pm2_5: T,
+    /// Mass Concentration PM4.0
+    pub mass_pm4_0: T,
+    /// Mass Concentration PM10
+    pub mass_pm10: T,
+    /// Number Concentration PM0.5
+    pub number_pm0_5: Option<T>,
+    /// Number Concentration PM1.0
+    pub number_pm1_0: Option<T>,
+    /// Number Concentration PM2.5
+    pub number_pm2_5: Option<T>,
+    /// Number Concentration PM4.0
+    pub number_pm4_0: Option<T>,
+    /// Number Concentration PM10
+    pub number_pm10: Option<T>,
+    /// Typical Particle Size
+    pub typical_size: Option<T>,
+}
+
+impl<T: Copy> PartialAirInfo<T> {
+    /// Selected values from all values in the order the device reports them
+    pub(crate) fn from_array(selection: FieldSelection, values: &[T; 10]) -> Self {
+        let field = |i: usize| if i < selection.count() { Some(values[i]) } else { None };
+        PartialAirInfo {
+            mass_pm1_0: values[0],
+            mass_pm2_5: values[1],
+            mass_pm4_0: values[2],
+            mass_pm10: values[3],
+            number_pm0_5: field(4),
+            number_pm1_0: field(5),
+            number_pm2_5: field(6),
+            number_pm4_0: field(7),
+            number_pm10: field(8),
+            typical_size: field(9),
+        }
+    }
+}
+
 /// Device status register bits
 /// False is OK, True indicates a problem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct StatusRegisterResult {
     /// Fan speed out of range
     pub speed: bool,
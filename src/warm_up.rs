@@ -0,0 +1,80 @@
+//! Warm-up after entering measurement mode
+//!
+//! The readings need some time after [`start_measurement()`] to stabilize,
+//! and more at low concentrations. According to the datasheet, the start-up
+//! time is 8 s at 200 #/cm³ and above, 16 s from 100 to 200 #/cm³ and 30 s
+//! below.
+//!
+//! With a clock attached, readings taken within the stabilization window
+//! are flagged with [`QualityFlags::WARMING_UP`].
+//!
+//! [`start_measurement()`]: ../struct.Sps30.html#method.start_measurement
+//! [`QualityFlags::WARMING_UP`]: ../quality/struct.QualityFlags.html#associatedconstant.WARMING_UP
+
+/// Stabilization window configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WarmUp {
+    /// Stabilization time at or above `low_concentration` [ms]
+    pub stabilization_ms: u32,
+    /// Number concentration PM10 [#/cm³] below which the concentration is low
+    pub low_concentration: u16,
+    /// Stabilization time at low concentration [ms]
+    pub low_concentration_ms: u32,
+    /// Number concentration PM10 [#/cm³] below which the concentration is
+    /// very low
+    pub very_low_concentration: u16,
+    /// Stabilization time at very low concentration [ms]
+    pub very_low_concentration_ms: u32,
+}
+
+impl WarmUp {
+    /// Stabilization window for the number concentration PM10 [#/cm³]
+    #[cfg(feature = "float")]
+    pub fn window_ms(&self, number_pm10: f32) -> u32 {
+        if number_pm10 < f32::from(self.very_low_concentration) {
+            self.very_low_concentration_ms
+        } else if number_pm10 < f32::from(self.low_concentration) {
+            self.low_concentration_ms
+        } else {
+            self.stabilization_ms
+        }
+    }
+
+    /// Stabilization window for the number concentration PM10 in the integer
+    /// format [#/cm³]
+    pub fn window_ms_u16(&self, number_pm10: u16) -> u32 {
+        if number_pm10 < self.very_low_concentration {
+            self.very_low_concentration_ms
+        } else if number_pm10 < self.low_concentration {
+            self.low_concentration_ms
+        } else {
+            self.stabilization_ms
+        }
+    }
+
+    /// Whether a reading taken `elapsed_ms` after the start is still
+    /// warming up
+    #[cfg(feature = "float")]
+    pub fn is_warming_up(&self, elapsed_ms: u64, number_pm10: f32) -> bool {
+        elapsed_ms < u64::from(self.window_ms(number_pm10))
+    }
+
+    /// Whether a reading in the integer format taken `elapsed_ms` after the
+    /// start is still warming up
+    pub fn is_warming_up_u16(&self, elapsed_ms: u64, number_pm10: u16) -> bool {
+        elapsed_ms < u64::from(self.window_ms_u16(number_pm10))
+    }
+}
+
+impl Default for WarmUp {
+    /// Start-up times of the datasheet
+    fn default() -> Self {
+        WarmUp {
+            stabilization_ms: 8_000,
+            low_concentration: 200,
+            low_concentration_ms: 16_000,
+            very_low_concentration: 100,
+            very_low_concentration_ms: 30_000,
+        }
+    }
+}
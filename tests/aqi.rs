@@ -0,0 +1,52 @@
+use sps30_i2c::aqi::{index_u16, pm10_index, pm2_5_index, Aqi, Category, Pollutant, MAX_INDEX};
+use sps30_i2c::AirInfoU16;
+
+#[test]
+fn test_sub_indices() {
+    assert_eq!(pm2_5_index(0), 0);
+    assert_eq!(pm2_5_index(90), 50);
+    assert_eq!(pm2_5_index(91), 51);
+    assert_eq!(pm2_5_index(120), 56);
+    assert_eq!(pm2_5_index(354), 100);
+    assert_eq!(pm2_5_index(3254), MAX_INDEX);
+    assert_eq!(pm2_5_index(10_000), MAX_INDEX);
+
+    assert_eq!(pm10_index(54), 50);
+    assert_eq!(pm10_index(100), 73);
+    assert_eq!(pm10_index(424), 300);
+    assert_eq!(pm10_index(u32::MAX), MAX_INDEX);
+
+    assert_eq!(Category::from_index(50), Category::Good);
+    assert_eq!(Category::from_index(150), Category::UnhealthyForSensitiveGroups);
+    assert_eq!(Category::from_index(301).name(), "Hazardous");
+}
+
+#[test]
+fn test_index_u16() {
+    let mut air_info = AirInfoU16 { mass_pm2_5: 12, mass_pm10: 100, ..AirInfoU16::default() };
+    assert_eq!(index_u16(&air_info), Some(Aqi { index: 73, category: Category::Moderate, pollutant: Pollutant::Pm10 }));
+
+    air_info.mass_pm10 = 20;
+    assert_eq!(index_u16(&air_info).unwrap().pollutant, Pollutant::Pm2_5);
+
+    air_info.mass_pm2_5 = AirInfoU16::NOT_AVAILABLE;
+    assert_eq!(index_u16(&air_info), None);
+}
+
+#[cfg(feature = "float")]
+#[test]
+fn test_index() {
+    use sps30_i2c::aqi::index;
+    use sps30_i2c::AirInfo;
+
+    let mut air_info = AirInfo::from(AirInfoU16::default());
+    air_info.mass_pm2_5 = 35.49;
+    air_info.mass_pm10 = 10.0;
+    assert_eq!(index(&air_info), Some(Aqi { index: 100, category: Category::Moderate, pollutant: Pollutant::Pm2_5 }));
+
+    air_info.mass_pm10 = f32::NAN;
+    assert_eq!(index(&air_info), None);
+    air_info.mass_pm10 = 10.0;
+    air_info.mass_pm2_5 = -1.0;
+    assert_eq!(index(&air_info), None);
+}
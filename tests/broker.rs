@@ -0,0 +1,277 @@
+#![cfg(all(feature = "sim", unix))]
+
+use sps30_i2c::broker::{self, Broker, BrokerClient, ClientError, Event, PROTOCOL_VERSION};
+use sps30_i2c::sim::{RandomProfile, SimClock, SimState, VirtualSps30};
+use sps30_i2c::Sps30;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::{env, fs, process};
+
+type SimBroker = Broker<VirtualSps30<RandomProfile, SimClock>, SimClock, SimClock>;
+
+fn broker(clock: &SimClock) -> SimBroker {
+    let mut sim = VirtualSps30::new(RandomProfile::new(4), clock.clone());
+    sim.set_serial_number(b"BROKER01");
+    sim.set_firmware_version(2, 2);
+    let sensor = Sps30::new_sps30(sim, clock.clone()).with_clock(clock.clone());
+    let mut broker = Broker::new(sensor);
+    broker.start().unwrap();
+    broker
+}
+
+/// Socket path unique to the test
+fn socket(name: &str) -> (PathBuf, UnixListener) {
+    let path = env::temp_dir().join(format!("sps30-broker-{}-{}.sock", name, process::id()));
+    let _ = fs::remove_file(&path);
+    let listener = broker::bind(&path).unwrap();
+    (path, listener)
+}
+
+/// Serve on its own thread until the returned flag is raised, then return
+/// the broker
+fn serve(mut broker: SimBroker, listener: UnixListener) -> (Arc<AtomicBool>, thread::JoinHandle<SimBroker>) {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let flag = shutdown.clone();
+    let serving = thread::spawn(move || {
+        broker::serve(&mut broker, &listener, &flag).unwrap();
+        broker
+    });
+    (shutdown, serving)
+}
+
+fn refused(res: Result<(), ClientError>) -> String {
+    match res {
+        Err(ClientError::Refused(code)) => code,
+        res => panic!("not refused: {:?}", res),
+    }
+}
+
+#[test]
+fn test_requests() {
+    let clock = SimClock::new();
+    let broker = broker(&clock);
+    let (path, listener) = socket("requests");
+
+    let (shutdown, serving) = serve(broker, listener);
+
+    let mut client = BrokerClient::connect(&path).unwrap();
+    assert_eq!(client.id(), 1);
+    match client.reading() {
+        Err(ClientError::Refused(code)) => assert_eq!(code, "no_reading"),
+        res => panic!("{:?}", res),
+    }
+
+    let info = client.info().unwrap();
+    assert_eq!(info.product_type, "00080000");
+    assert_eq!(info.serial, "BROKER01");
+    assert_eq!(info.firmware, (2, 2));
+    assert!(!client.status().unwrap().fan);
+    assert_eq!(client.auto_cleaning_interval().unwrap(), 604_800);
+
+    client.subscribe().unwrap();
+    clock.advance(1_000);
+    let pushed = match client.next_event().unwrap() {
+        Event::Reading(reading) => reading,
+        event => panic!("{:?}", event),
+    };
+    assert!(pushed.timestamp.is_some());
+    assert_eq!(client.reading().unwrap(), pushed);
+
+    shutdown.store(true, Ordering::SeqCst);
+    let broker = serving.join().unwrap();
+
+    assert_eq!(broker.clients(), 1);
+    let sim = broker.shutdown().unwrap().destroy();
+    assert_eq!(sim.state(), SimState::Sleeping);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_handshake() {
+    let clock = SimClock::new();
+    let broker = broker(&clock);
+    let (path, listener) = socket("handshake");
+
+    let exchange = |line: &str| {
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(line.as_bytes()).unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let response = lines.next().unwrap().unwrap();
+        // Closed after a failed handshake
+        (response, lines.next().is_none())
+    };
+
+    let (shutdown, serving) = serve(broker, listener);
+
+    assert_eq!(exchange("INFO\n"), ("ERROR hello_required".to_string(), true));
+    assert_eq!(exchange("HELLO 99\n"), ("ERROR unsupported_version".to_string(), true));
+
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream.write_all(format!("HELLO {}\nFROB\n", PROTOCOL_VERSION).as_bytes()).unwrap();
+    let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), format!("WELCOME {} 3", PROTOCOL_VERSION));
+    assert_eq!(lines.next().unwrap().unwrap(), "ERROR unknown_command");
+    stream.write_all(b"ACQUIRE\nSET-INTERVAL soon\n").unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "EVENT LEASE 3");
+    assert_eq!(lines.next().unwrap().unwrap(), "OK");
+    assert_eq!(lines.next().unwrap().unwrap(), "ERROR invalid_argument");
+
+    shutdown.store(true, Ordering::SeqCst);
+    let broker = serving.join().unwrap();
+    broker.shutdown().unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_lease() {
+    let clock = SimClock::new();
+    let broker = broker(&clock).with_lease_timeout(Duration::from_secs(1));
+    let (path, listener) = socket("lease");
+
+    let (shutdown, serving) = serve(broker, listener);
+
+    let mut first = BrokerClient::connect(&path).unwrap();
+    let mut second = BrokerClient::connect(&path).unwrap();
+
+    assert_eq!(refused(first.device_reset()), "lease_required");
+    first.acquire().unwrap();
+    assert_eq!(first.next_event().unwrap(), Event::Lease(Some(first.id())));
+    assert_eq!(second.next_event().unwrap(), Event::Lease(Some(first.id())));
+
+    // The holder is named to the others
+    let busy = format!("busy {}", first.id());
+    assert_eq!(refused(second.acquire()), busy);
+    assert_eq!(refused(second.write_auto_cleaning_interval(10)), busy);
+
+    first.write_auto_cleaning_interval(3_600).unwrap();
+    assert_eq!(first.next_event().unwrap(), Event::Interval(3_600));
+    assert_eq!(second.next_event().unwrap(), Event::Interval(3_600));
+    assert_eq!(second.auto_cleaning_interval().unwrap(), 3_600);
+    first.device_reset().unwrap();
+    assert_eq!(second.next_event().unwrap(), Event::Reset);
+
+    first.release().unwrap();
+    assert_eq!(second.next_event().unwrap(), Event::Lease(None));
+    second.acquire().unwrap();
+    assert_eq!(second.next_event().unwrap(), Event::Lease(Some(second.id())));
+
+    // Released when the holder disconnects
+    drop(second);
+    assert_eq!(first.next_event().unwrap(), Event::Reset);
+    assert_eq!(first.next_event().unwrap(), Event::Lease(None));
+    assert_eq!(first.next_event().unwrap(), Event::Lease(Some(2)));
+    assert_eq!(first.next_event().unwrap(), Event::Lease(None));
+
+    // And when it expires
+    first.acquire().unwrap();
+    assert_eq!(first.next_event().unwrap(), Event::Lease(Some(first.id())));
+    assert_eq!(first.next_event().unwrap(), Event::Lease(None));
+    assert_eq!(refused(first.device_reset()), "lease_required");
+
+    shutdown.store(true, Ordering::SeqCst);
+    let broker = serving.join().unwrap();
+    broker.shutdown().unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_line_length() {
+    let clock = SimClock::new();
+    let (path, listener) = socket("line-length");
+    let (shutdown, serving) = serve(broker(&clock), listener);
+
+    // Pipelined requests longer than a line together are all answered
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream.write_all(format!("HELLO {}\n{}", PROTOCOL_VERSION, "STATUS\n".repeat(100)).as_bytes()).unwrap();
+    let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+    assert!(lines.next().unwrap().unwrap().starts_with("WELCOME "));
+    for _ in 0..100 {
+        assert_eq!(lines.next().unwrap().unwrap(), "STATUS 0 0 0");
+    }
+
+    // A longer line is not, the rest of it is left unread
+    stream.write_all(&[b'A'; 300]).unwrap();
+    assert!(!matches!(lines.next(), Some(Ok(_))));
+
+    shutdown.store(true, Ordering::SeqCst);
+    let broker = serving.join().unwrap();
+    assert_eq!(broker.clients(), 0);
+    broker.shutdown().unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_stalled_client() {
+    let clock = SimClock::new();
+    let (path, listener) = socket("stalled");
+    let (shutdown, serving) = serve(broker(&clock), listener);
+
+    // Never reads the events
+    let mut stalled = UnixStream::connect(&path).unwrap();
+    stalled.write_all(format!("HELLO {}\n", PROTOCOL_VERSION).as_bytes()).unwrap();
+
+    let mut stream = UnixStream::connect(&path).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    let pairs = 500;
+    let draining = thread::spawn(move || reader.lines().take(1 + 4 * pairs).count());
+    stream.write_all(format!("HELLO {}\n", PROTOCOL_VERSION).as_bytes()).unwrap();
+    for _ in 0..pairs {
+        stream.write_all(b"ACQUIRE\nRELEASE\n").unwrap();
+    }
+    assert_eq!(draining.join().unwrap(), 1 + 4 * pairs);
+
+    shutdown.store(true, Ordering::SeqCst);
+    let broker = serving.join().unwrap();
+    assert_eq!(broker.clients(), 1);
+    broker.shutdown().unwrap();
+
+    // Closed by the broker
+    let mut rest = Vec::new();
+    stalled.read_to_end(&mut rest).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_socket_permissions() {
+    let (path, listener) = socket("permissions");
+
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    UnixStream::connect(&path).unwrap();
+    assert!(listener.accept().is_ok());
+    assert!(!path.with_extension("sock.tmp").exists());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_client_limit() {
+    let clock = SimClock::new();
+    let (path, listener) = socket("limit");
+    let (shutdown, serving) = serve(broker(&clock), listener);
+
+    let mut clients: Vec<_> = (0..16).map(|_| BrokerClient::connect(&path).unwrap()).collect();
+    let mut refused = UnixStream::connect(&path).unwrap();
+    let mut rest = Vec::new();
+    match refused.read_to_end(&mut rest) {
+        Ok(0) => {}
+        Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {}
+        res => panic!("not closed: {:?}", res),
+    }
+    for client in &mut clients {
+        client.info().unwrap();
+    }
+
+    // A disconnected client makes room
+    drop(clients.pop());
+    thread::sleep(Duration::from_millis(100));
+    BrokerClient::connect(&path).unwrap().info().unwrap();
+
+    shutdown.store(true, Ordering::SeqCst);
+    serving.join().unwrap().shutdown().unwrap();
+    fs::remove_file(&path).unwrap();
+}
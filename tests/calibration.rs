@@ -0,0 +1,131 @@
+#![cfg(feature = "float")]
+
+use sps30_i2c::calibration::{CalibrationProfile, CalibrationRegistry, Correction};
+use sps30_i2c::testing::{self, DEV_ADDR};
+use sps30_i2c::Sps30;
+use embedded_hal_mock::{delay::MockNoop as NoopDelay, i2c::Mock as I2cMock,
+    i2c::Transaction as I2cTrans};
+
+fn measured_values(value: f32) -> Vec<u8> {
+    let data: Vec<u8> = (0..10).flat_map(|_| value.to_be_bytes()).collect();
+    testing::with_crc(&data)
+}
+
+#[test]
+fn test_correction() {
+    assert_eq!(Correction::IDENTITY.apply(3.0), 3.0);
+    assert_eq!(Correction::linear(2.0, 1.0).apply(3.0), 7.0);
+    assert_eq!(Correction { coefficients: [1.0, 0.0, 2.0, 1.0], humidity: 0.0 }.apply(2.0), 17.0);
+}
+
+#[test]
+fn test_correction_with_humidity() {
+    let correction = Correction { coefficients: [1.0, 1.0, 0.0, 0.0], humidity: -0.1 };
+
+    assert_eq!(correction.apply(10.0), 11.0);
+    assert_eq!(correction.apply_with_humidity(10.0, 50.0), 6.0);
+}
+
+#[test]
+fn test_registry_find() {
+    let profiles = [CalibrationProfile::new(b"AAAA"), CalibrationProfile::new(b"BBBB")];
+    let registry = CalibrationRegistry::new(&profiles);
+
+    let mut serial = [0; 32];
+    serial[..4].clone_from_slice(b"BBBB");
+
+    assert_eq!(registry.find(&serial), Some(&profiles[1]));
+    serial[..4].clone_from_slice(b"CCCC");
+    assert_eq!(registry.find(&serial), None);
+}
+
+#[test]
+fn test_load_calibration_profile() {
+    let mut profile = CalibrationProfile::new(b"8F1C2A0B6E3D9A47");
+    profile.mass_pm2_5 = Correction::linear(0.5, 1.0);
+    let profiles = [profile];
+    let registry = CalibrationRegistry::new(&profiles);
+
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::read_device_serial_number()),
+        I2cTrans::read(DEV_ADDR, testing::serial_number_frame(b"8F1C2A0B6E3D9A47")),
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, measured_values(10.0)),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    assert!(sensor.load_calibration_profile(&registry).unwrap());
+    let air_info = sensor.read_measured_values().unwrap();
+    assert_eq!(air_info.mass_pm1_0, 10.0);
+    assert_eq!(air_info.mass_pm2_5, 6.0);
+
+    sensor.destroy();
+}
+
+#[test]
+fn test_calibration_with_humidity() {
+    let mut profile = CalibrationProfile::new(b"");
+    profile.mass_pm10 = Correction { coefficients: [0.0, 1.0, 0.0, 0.0], humidity: -0.1 };
+
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, measured_values(10.0)),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+    sensor.set_calibration_profile(Some(profile));
+    sensor.set_relative_humidity(Some(50.0));
+
+    assert_eq!(sensor.read_measured_values().unwrap().mass_pm10, 5.0);
+
+    sensor.destroy();
+}
+
+#[test]
+fn test_load_unknown_calibration_profile() {
+    let registry = CalibrationRegistry::new(&[]);
+
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::read_device_serial_number()),
+        I2cTrans::read(DEV_ADDR, testing::serial_number_frame(b"8F1C2A0B6E3D9A47")),
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, measured_values(10.0)),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    assert!(!sensor.load_calibration_profile(&registry).unwrap());
+    assert_eq!(sensor.read_measured_values().unwrap().mass_pm2_5, 10.0);
+
+    sensor.destroy();
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_profile_serde() {
+    let json = r#"{
+        "serial": "8F1C2A0B6E3D9A47",
+        "mass_pm2_5": { "coefficients": [1.0, 0.5, 0.0, 0.0] }
+    }"#;
+    let profile: CalibrationProfile = serde_json::from_str(json).unwrap();
+
+    let mut expected = CalibrationProfile::new(b"8F1C2A0B6E3D9A47");
+    expected.mass_pm2_5 = Correction::linear(0.5, 1.0);
+    assert_eq!(profile, expected);
+
+    let round_trip: CalibrationProfile =
+        serde_json::from_str(&serde_json::to_string(&profile).unwrap()).unwrap();
+    assert_eq!(round_trip, expected);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_profile_serde_non_utf8_serial() {
+    let profile = CalibrationProfile::new(&[0x38, 0xFF, 0xFE, 0x41]);
+
+    let json = serde_json::to_string(&profile).unwrap();
+    assert!(json.contains("[56,255,254,65]"));
+    let round_trip: CalibrationProfile = serde_json::from_str(&json).unwrap();
+    assert_eq!(round_trip, profile);
+
+    let json = format!(r#"{{ "serial": [{}] }}"#, vec!["1"; 33].join(","));
+    assert!(serde_json::from_str::<CalibrationProfile>(&json).is_err());
+}
@@ -0,0 +1,108 @@
+use sps30_i2c::cleaning_scheduler::{CleaningScheduler, MemoryStore, RuntimeStore, SchedulerError};
+use sps30_i2c::testing::{self, ManualClock, DEV_ADDR};
+use sps30_i2c::{OutputFormat, Sps30};
+use embedded_hal_mock::{delay::MockNoop as NoopDelay, i2c::Mock as I2cMock,
+    i2c::Transaction as I2cTrans};
+
+fn new_sensor(expectations: &[I2cTrans], clock: &ManualClock) -> Sps30<I2cMock, NoopDelay, ManualClock> {
+    Sps30::new_sps30(I2cMock::new(expectations), NoopDelay).with_clock(clock.clone())
+}
+
+#[test]
+fn test_runtime_survives_power_cycle() {
+    let clock = ManualClock::new();
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::default())),
+        I2cTrans::write(DEV_ADDR, testing::stop_measurement()),
+    ];
+    let mut sensor = new_sensor(&expectations, &clock);
+    let mut scheduler = CleaningScheduler::new(MemoryStore::default(), 60).unwrap();
+
+    assert!(!scheduler.start_measurement(&mut sensor).unwrap());
+    clock.set(30_000);
+    assert!(!scheduler.poll(&mut sensor).unwrap());
+    clock.set(40_000);
+    scheduler.stop_measurement(&mut sensor).unwrap();
+    sensor.destroy();
+
+    // Power cycle: the clock restarts and the runtime is restored
+    let clock = ManualClock::new();
+    let store = scheduler.destroy();
+    assert_eq!(store, MemoryStore(40_000));
+
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::default())),
+        I2cTrans::write(DEV_ADDR, testing::start_fan_cleaning()),
+    ];
+    let mut sensor = new_sensor(&expectations, &clock);
+    let mut scheduler = CleaningScheduler::new(store, 60).unwrap();
+
+    clock.set(100);
+    assert!(!scheduler.start_measurement(&mut sensor).unwrap());
+    clock.set(15_000);
+    assert!(!scheduler.poll(&mut sensor).unwrap());
+    clock.set(20_100);
+    assert!(scheduler.poll(&mut sensor).unwrap());
+    assert_eq!(scheduler.runtime_ms(20_100), 0);
+    assert_eq!(scheduler.destroy(), MemoryStore(0));
+
+    sensor.destroy();
+}
+
+#[test]
+fn test_cleaning_due_while_idle() {
+    let clock = ManualClock::new();
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::default())),
+        I2cTrans::write(DEV_ADDR, testing::stop_measurement()),
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::default())),
+        I2cTrans::write(DEV_ADDR, testing::start_fan_cleaning()),
+    ];
+    let mut sensor = new_sensor(&expectations, &clock);
+    let mut scheduler = CleaningScheduler::new(MemoryStore::default(), 60).unwrap();
+
+    scheduler.start_measurement(&mut sensor).unwrap();
+    clock.set(70_000);
+    scheduler.stop_measurement(&mut sensor).unwrap();
+    assert!(scheduler.is_due(100_000));
+    clock.set(100_000);
+    assert!(!scheduler.poll(&mut sensor).unwrap());
+
+    clock.set(200_000);
+    assert!(scheduler.start_measurement(&mut sensor).unwrap());
+    assert!(!scheduler.is_due(200_000));
+
+    sensor.destroy();
+}
+
+#[test]
+fn test_runtime_is_persisted_while_running() {
+    let clock = ManualClock::new();
+    let expectations = [I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::default()))];
+    let mut sensor = new_sensor(&expectations, &clock);
+    let mut scheduler = CleaningScheduler::with_default_budget(MemoryStore(1_000)).unwrap();
+
+    scheduler.start_measurement(&mut sensor).unwrap();
+    clock.set(30_000);
+    scheduler.poll(&mut sensor).unwrap();
+    clock.set(61_000);
+    scheduler.poll(&mut sensor).unwrap();
+
+    let mut store = scheduler.destroy();
+    assert_eq!(store.load().unwrap(), 62_000);
+
+    sensor.destroy();
+}
+
+#[test]
+fn test_no_clock() {
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&[]), NoopDelay);
+    let mut scheduler = CleaningScheduler::new(MemoryStore::default(), 60).unwrap();
+
+    match scheduler.start_measurement(&mut sensor) {
+        Err(SchedulerError::NoClock) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    sensor.destroy().done();
+}
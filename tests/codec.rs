@@ -0,0 +1,98 @@
+#![cfg(feature = "float")]
+
+use sps30_i2c::codec::{
+    decode_delta, decode_lpp, decode_uint16, dequantize, encode_delta, encode_lpp, encode_uint16, quantize, CodecError,
+    LPP_ANALOG_INPUT, LPP_CONCENTRATION, LPP_LEN, NOT_AVAILABLE, UINT16_LEN,
+};
+use sps30_i2c::AirInfo;
+
+fn air_info(mass: f32, number: f32, typical_size: f32) -> AirInfo {
+    AirInfo {
+        mass_pm1_0: mass,
+        mass_pm2_5: mass + 1.0,
+        mass_pm4_0: mass + 2.0,
+        mass_pm10: mass + 3.0,
+        number_pm0_5: number,
+        number_pm1_0: number + 10.0,
+        number_pm2_5: number + 20.0,
+        number_pm4_0: number + 30.0,
+        number_pm10: number + 40.0,
+        typical_size,
+    }
+}
+
+#[test]
+fn test_quantize() {
+    assert_eq!(quantize(12.49, 1.0), 12);
+    assert_eq!(quantize(12.5, 1.0), 13);
+    assert_eq!(quantize(0.5234, 1000.0), 523);
+    assert_eq!(quantize(-0.4, 1.0), 0);
+    assert_eq!(quantize(-1.0, 1.0), NOT_AVAILABLE);
+    assert_eq!(quantize(f32::NAN, 1.0), NOT_AVAILABLE);
+    assert_eq!(quantize(1e9, 1.0), NOT_AVAILABLE - 1);
+    assert_eq!(dequantize(523, 1000.0), 0.523);
+    assert!(dequantize(NOT_AVAILABLE, 1.0).is_nan());
+}
+
+#[test]
+fn test_uint16() {
+    let bytes = encode_uint16(&air_info(12.3, 100.6, 0.5234));
+    assert_eq!(bytes.len(), UINT16_LEN);
+    assert_eq!(bytes[..4], [0, 12, 0, 13]);
+    assert_eq!(bytes[8..10], 101u16.to_be_bytes());
+    assert_eq!(bytes[18..], 523u16.to_be_bytes());
+
+    let decoded = decode_uint16(&bytes);
+    assert_eq!(decoded, air_info(12.0, 101.0, 0.523));
+}
+
+#[test]
+fn test_delta() {
+    let readings: Vec<AirInfo> = (0..20)
+        .map(|i| air_info(10.0 + (i % 3) as f32, 80.0 - i as f32, 0.5 + i as f32 / 1_000.0))
+        .collect();
+    let mut bytes = [0; 256];
+    let len = encode_delta(&readings, &mut bytes).unwrap();
+    assert_eq!(bytes[0], 20);
+    // One byte per field for small changes
+    assert_eq!(len, 1 + UINT16_LEN + 19 * 10);
+
+    let mut decoded = [air_info(0.0, 0.0, 0.0); 20];
+    assert_eq!(decode_delta(&bytes[..len], &mut decoded), Ok(20));
+    for (decoded, reading) in decoded.iter().zip(readings.iter()) {
+        assert_eq!(encode_uint16(decoded), encode_uint16(reading));
+    }
+
+    // Large jumps and unknown values
+    let mut jumped = air_info(0.0, 0.0, 0.3);
+    jumped.mass_pm10 = f32::NAN;
+    let pair = [air_info(1_000.0, 3_000.0, 10.0), jumped];
+    let len = encode_delta(&pair, &mut bytes).unwrap();
+    assert_eq!(decode_delta(&bytes[..len], &mut decoded), Ok(2));
+    assert!(decoded[1].mass_pm10.is_nan());
+    assert_eq!(decoded[1].number_pm10, 40.0);
+    assert_eq!(decoded[1].typical_size, 0.3);
+
+    assert_eq!(encode_delta(&[], &mut bytes), Err(CodecError::Invalid));
+    assert_eq!(encode_delta(&readings, &mut bytes[..40]), Err(CodecError::BufferTooSmall));
+    assert_eq!(decode_delta(&bytes[..len - 1], &mut decoded), Err(CodecError::Truncated));
+    assert_eq!(decode_delta(&bytes[..len], &mut decoded[..1]), Err(CodecError::BufferTooSmall));
+    bytes[len] = 0;
+    assert_eq!(decode_delta(&bytes[..len + 1], &mut decoded), Err(CodecError::Invalid));
+}
+
+#[test]
+fn test_lpp() {
+    let bytes = encode_lpp(&air_info(12.3, 100.6, 0.5234), 3);
+    assert_eq!(bytes.len(), LPP_LEN);
+    assert_eq!(bytes[..4], [3, LPP_CONCENTRATION, 0, 12]);
+    assert_eq!(bytes[36..], [12, LPP_ANALOG_INPUT, 0, 52]);
+    assert_eq!(decode_lpp(&bytes, 3), Ok(air_info(12.0, 101.0, 0.52)));
+
+    let mut unknown = air_info(1.0, 1.0, 1.0);
+    unknown.typical_size = f32::NAN;
+    assert!(decode_lpp(&encode_lpp(&unknown, 1), 1).unwrap().typical_size.is_nan());
+
+    assert_eq!(decode_lpp(&bytes, 1), Err(CodecError::Invalid));
+    assert_eq!(decode_lpp(&bytes[..LPP_LEN - 1], 3), Err(CodecError::Truncated));
+}
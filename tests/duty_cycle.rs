@@ -0,0 +1,221 @@
+use sps30_i2c::duty_cycle::{DutyCycleConfig, DutyCycleScheduler, DutyCycleState};
+use sps30_i2c::quality::QualityFlags;
+use sps30_i2c::testing::{self, ManualClock, DEV_ADDR};
+use sps30_i2c::{AirInfoU16, OutputFormat, Sps30};
+#[cfg(feature = "float")]
+use embedded_hal_mock::MockError;
+use embedded_hal_mock::{delay::MockNoop as NoopDelay, i2c::Mock as I2cMock,
+    i2c::Transaction as I2cTrans};
+#[cfg(feature = "float")]
+use std::io::ErrorKind;
+
+const CONFIG: DutyCycleConfig = DutyCycleConfig {
+    period_ms: 60_000,
+    warm_up_ms: 10_000,
+    samples: 2,
+    poll_interval_ms: 1_000,
+    data_ready_timeout_ms: 3_000,
+};
+
+#[cfg(feature = "float")]
+fn wake_up() -> [I2cTrans; 2] {
+    let [pulse, command] = testing::wake_up();
+    [I2cTrans::write(DEV_ADDR, pulse), I2cTrans::write(DEV_ADDR, command)]
+}
+
+fn data_ready(ready: bool) -> [I2cTrans; 2] {
+    [
+        I2cTrans::write(DEV_ADDR, testing::read_data_ready_flag()),
+        I2cTrans::read(DEV_ADDR, testing::data_ready_frame(ready)),
+    ]
+}
+
+#[cfg(feature = "float")]
+fn measured_values(mass: f32) -> [I2cTrans; 2] {
+    let values: [f32; 10] = [mass, mass, mass, mass, 10.0, 10.0, 10.0, 10.0, 10.0, 0.5];
+    let data: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+
+    [
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, testing::with_crc(&data)),
+    ]
+}
+
+#[cfg(feature = "float")]
+fn cycle(first: bool) -> Vec<I2cTrans> {
+    let mut expectations = Vec::new();
+    if !first {
+        expectations.extend_from_slice(&wake_up());
+    }
+    expectations.push(I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::Float)));
+    expectations.extend_from_slice(&data_ready(true));
+    expectations.extend_from_slice(&measured_values(4.0));
+    expectations.extend_from_slice(&data_ready(false));
+    expectations.extend_from_slice(&data_ready(true));
+    expectations.extend_from_slice(&measured_values(8.0));
+    expectations.push(I2cTrans::write(DEV_ADDR, testing::stop_measurement()));
+    expectations.push(I2cTrans::write(DEV_ADDR, testing::sleep()));
+    expectations
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_poll() {
+    let mut expectations = cycle(true);
+    expectations.extend_from_slice(&wake_up());
+    expectations.push(I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::Float)));
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+    let mut scheduler = DutyCycleScheduler::new(CONFIG);
+
+    assert_eq!(scheduler.poll(&mut sensor, 0).unwrap(), None);
+    assert_eq!(scheduler.state(), DutyCycleState::WarmingUp);
+    assert_eq!(scheduler.next_poll_at(), 10_000);
+
+    // Too early, nothing happens
+    assert_eq!(scheduler.poll(&mut sensor, 5_000).unwrap(), None);
+
+    assert_eq!(scheduler.poll(&mut sensor, 10_000).unwrap(), None);
+    assert_eq!(scheduler.state(), DutyCycleState::Sampling);
+    assert_eq!(scheduler.poll(&mut sensor, 11_000).unwrap(), None);
+
+    let reading = scheduler.poll(&mut sensor, 12_000).unwrap().unwrap();
+    assert_eq!(reading.air_info.mass_pm2_5, 6.0);
+    assert_eq!(reading.timestamp, Some(12_000));
+    assert!(reading.is_valid());
+    assert_eq!(scheduler.state(), DutyCycleState::Waiting);
+    assert_eq!(scheduler.next_poll_at(), 60_000);
+
+    assert_eq!(scheduler.poll(&mut sensor, 59_000).unwrap(), None);
+    assert_eq!(scheduler.poll(&mut sensor, 60_000).unwrap(), None);
+    assert_eq!(scheduler.state(), DutyCycleState::WarmingUp);
+
+    sensor.destroy();
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_run_cycle() {
+    let mut expectations = cycle(true);
+    expectations.extend(cycle(false));
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+    let mut scheduler = DutyCycleScheduler::new(CONFIG);
+    let mut clock = ManualClock::new();
+    let mut delay = clock.clone();
+
+    let reading = scheduler.run_cycle(&mut sensor, &mut clock, &mut delay).unwrap();
+    assert_eq!(reading.air_info.mass_pm10, 6.0);
+    assert_eq!(clock.now(), 12_000);
+
+    let reading = scheduler.run_cycle(&mut sensor, &mut clock, &mut delay).unwrap();
+    assert_eq!(reading.timestamp, Some(72_000));
+
+    sensor.destroy();
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_stop_is_retried() {
+    let mut expectations = vec![I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::Float))];
+    expectations.extend_from_slice(&data_ready(true));
+    expectations.extend_from_slice(&measured_values(4.0));
+    expectations.extend_from_slice(&data_ready(true));
+    expectations.extend_from_slice(&measured_values(8.0));
+    expectations.push(I2cTrans::write(DEV_ADDR, testing::stop_measurement()));
+    expectations.push(I2cTrans::write(DEV_ADDR, testing::sleep()).with_error(MockError::Io(ErrorKind::Other)));
+    expectations.push(I2cTrans::write(DEV_ADDR, testing::sleep()));
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+    let mut scheduler = DutyCycleScheduler::new(CONFIG);
+
+    scheduler.poll(&mut sensor, 0).unwrap();
+    scheduler.poll(&mut sensor, 10_000).unwrap();
+    assert!(scheduler.poll(&mut sensor, 11_000).is_err());
+    assert_eq!(scheduler.state(), DutyCycleState::Stopping);
+
+    // Only the sleep is retried, and the samples are kept
+    let reading = scheduler.poll(&mut sensor, 12_000).unwrap().unwrap();
+    assert_eq!(reading.air_info.mass_pm2_5, 6.0);
+    assert_eq!(scheduler.state(), DutyCycleState::Waiting);
+    assert_eq!(scheduler.next_poll_at(), 60_000);
+
+    sensor.destroy();
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_data_ready_timeout() {
+    let mut expectations = vec![I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::Float))];
+    expectations.extend_from_slice(&data_ready(true));
+    expectations.extend_from_slice(&measured_values(4.0));
+    for _ in 0..3 {
+        expectations.extend_from_slice(&data_ready(false));
+    }
+    expectations.push(I2cTrans::write(DEV_ADDR, testing::stop_measurement()));
+    expectations.push(I2cTrans::write(DEV_ADDR, testing::sleep()));
+    expectations.extend(cycle(false));
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+    let mut scheduler = DutyCycleScheduler::new(CONFIG);
+
+    scheduler.poll(&mut sensor, 0).unwrap();
+    assert_eq!(scheduler.poll(&mut sensor, 10_000).unwrap(), None);
+    assert_eq!(scheduler.poll(&mut sensor, 11_000).unwrap(), None);
+    assert_eq!(scheduler.poll(&mut sensor, 12_000).unwrap(), None);
+    assert_eq!(scheduler.state(), DutyCycleState::Sampling);
+    assert_eq!(scheduler.poll(&mut sensor, 13_000).unwrap(), None);
+    assert_eq!(scheduler.state(), DutyCycleState::Waiting);
+    assert_eq!(scheduler.next_poll_at(), 60_000);
+
+    // The next cycle does not see the discarded sample
+    let mut clock = ManualClock::new();
+    clock.set(60_000);
+    let mut delay = clock.clone();
+    let reading = scheduler.run_cycle(&mut sensor, &mut clock, &mut delay).unwrap();
+    assert_eq!(reading.air_info.mass_pm2_5, 6.0);
+
+    sensor.destroy();
+}
+
+fn measured_values_u16(mass: u16, typical_size: u16) -> [I2cTrans; 2] {
+    let air_info = AirInfoU16 {
+        mass_pm1_0: mass,
+        mass_pm2_5: mass,
+        mass_pm4_0: mass,
+        mass_pm10: mass,
+        number_pm0_5: 10,
+        number_pm1_0: 10,
+        number_pm2_5: 10,
+        number_pm4_0: 10,
+        number_pm10: 10,
+        typical_size,
+    };
+
+    [
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, testing::measured_values_frame_u16(&air_info)),
+    ]
+}
+
+#[test]
+fn test_run_cycle_u16() {
+    let mut expectations = vec![I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::UInt16))];
+    expectations.extend_from_slice(&data_ready(true));
+    expectations.extend_from_slice(&measured_values_u16(4, 500));
+    expectations.extend_from_slice(&data_ready(false));
+    expectations.extend_from_slice(&data_ready(true));
+    expectations.extend_from_slice(&measured_values_u16(7, AirInfoU16::NOT_AVAILABLE));
+    expectations.push(I2cTrans::write(DEV_ADDR, testing::stop_measurement()));
+    expectations.push(I2cTrans::write(DEV_ADDR, testing::sleep()));
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+    let mut scheduler = DutyCycleScheduler::new(CONFIG);
+    let mut clock = ManualClock::new();
+    let mut delay = clock.clone();
+
+    let reading = scheduler.run_cycle_u16(&mut sensor, &mut clock, &mut delay).unwrap();
+    assert_eq!(reading.air_info.mass_pm2_5, 6);
+    assert_eq!(reading.air_info.number_pm10, 10);
+    assert_eq!(reading.air_info.typical_size, AirInfoU16::NOT_AVAILABLE);
+    assert_eq!(reading.flags, QualityFlags::NOT_FINITE);
+    assert_eq!(reading.timestamp, Some(12_000));
+    assert_eq!(scheduler.state(), DutyCycleState::Waiting);
+
+    sensor.destroy();
+}
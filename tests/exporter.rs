@@ -0,0 +1,185 @@
+#![cfg(feature = "sim")]
+
+use sps30_i2c::exporter::{self, Exporter, STALE_AFTER_MS};
+use sps30_i2c::sim::{Fault, RandomProfile, SimClock, SimState, VirtualSps30, STATUS_FAN};
+use sps30_i2c::Sps30;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type SimSensor = Sps30<VirtualSps30<RandomProfile, SimClock>, SimClock, SimClock>;
+
+fn sensor(sim: VirtualSps30<RandomProfile, SimClock>, clock: &SimClock) -> SimSensor {
+    Sps30::new_sps30(sim, clock.clone()).with_clock(clock.clone())
+}
+
+fn render<I2C, E>(exporter: &Exporter<I2C, SimClock, SimClock>) -> String
+where I2C: embedded_hal::blocking::i2c::Read<Error = E> + embedded_hal::blocking::i2c::Write<Error = E> {
+    let mut out = Vec::new();
+    exporter.render(&mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn test_sampling() {
+    let clock = SimClock::new();
+    let mut sim = VirtualSps30::new(RandomProfile::new(3), clock.clone());
+    sim.set_serial_number(b"EXPORT01");
+    sim.set_firmware_version(2, 2);
+    sim.inject(Fault::StatusBits(STATUS_FAN));
+    let mut exporter = Exporter::new(sensor(sim, &clock));
+
+    let metrics = render(&exporter);
+    assert!(metrics.contains("sps30_up 0\n"));
+    assert!(!metrics.contains("sps30_info"));
+
+    exporter.start().unwrap();
+    assert!(!exporter.poll());
+    clock.advance(1_000);
+    assert!(exporter.poll());
+    assert!(exporter.reading().is_some());
+
+    let metrics = render(&exporter);
+    assert!(metrics.contains("sps30_mass_pm2_5{serial=\"EXPORT01\"} "));
+    assert!(metrics.contains("sps30_quality_flag{serial=\"EXPORT01\",flag=\"warming_up\"} 1\n"));
+    assert!(metrics.contains("sps30_status_fan{serial=\"EXPORT01\"} 1\n"));
+    assert!(metrics.contains("sps30_status_laser{serial=\"EXPORT01\"} 0\n"));
+    assert!(metrics.contains("sps30_info{serial=\"EXPORT01\",firmware=\"2.2\"} 1\n"));
+    assert!(metrics.contains("sps30_samples_total 1\n"));
+    assert!(metrics.contains("sps30_errors_total{kind=\"i2c\"} 0\n"));
+    assert!(metrics.contains("sps30_up 1\n"));
+
+    let sim = exporter.shutdown().unwrap().destroy();
+    assert_eq!(sim.state(), SimState::Sleeping);
+}
+
+#[test]
+fn test_start_measuring_sensor() {
+    let clock = SimClock::new();
+    let sim = VirtualSps30::new(RandomProfile::new(3), clock.clone());
+
+    // Left measuring, as by a process that was killed
+    let mut killed = sensor(sim, &clock);
+    killed.start_measurement().unwrap();
+    let sim = killed.destroy();
+    assert_eq!(sim.state(), SimState::Measuring);
+
+    let mut exporter = Exporter::new(sensor(sim, &clock));
+    exporter.start().unwrap();
+    clock.advance(1_000);
+    assert!(exporter.poll());
+    assert_eq!(exporter.errors(), (0, 0));
+
+    let sim = exporter.shutdown().unwrap().destroy();
+    assert_eq!(sim.state(), SimState::Sleeping);
+}
+
+#[test]
+fn test_error_counters() {
+    let clock = SimClock::new();
+    let mut sim = VirtualSps30::new(RandomProfile::new(3), clock.clone());
+    // Transactions 0 to 6 start the exporter, a sample takes 6 more
+    sim.schedule(7, Fault::Nack);
+    sim.schedule(11, Fault::CorruptCrc(0));
+    let mut exporter = Exporter::new(sensor(sim, &clock));
+    exporter.start().unwrap();
+
+    clock.advance(1_000);
+    assert!(!exporter.poll());
+    assert_eq!(exporter.errors(), (1, 0));
+    assert!(render(&exporter).contains("sps30_up 0\n"));
+
+    assert!(!exporter.poll());
+    assert_eq!(exporter.errors(), (1, 1));
+
+    clock.advance(1_000);
+    assert!(exporter.poll());
+    let metrics = render(&exporter);
+    assert!(metrics.contains("sps30_errors_total{kind=\"i2c\"} 1\n"));
+    assert!(metrics.contains("sps30_errors_total{kind=\"checksum\"} 1\n"));
+    assert!(metrics.contains("sps30_samples_total 1\n"));
+    assert!(metrics.contains("sps30_up 1\n"));
+}
+
+#[test]
+fn test_stale_sample() {
+    let clock = SimClock::new();
+    let mut sim = VirtualSps30::new(RandomProfile::new(3), clock.clone());
+    // Transactions 0 to 6 start the exporter, a sample takes 6 more
+    sim.schedule(13, Fault::StuckDataReady(false));
+    let mut exporter = Exporter::new(sensor(sim, &clock));
+    exporter.start().unwrap();
+
+    clock.advance(1_000);
+    assert!(exporter.poll());
+    clock.advance(STALE_AFTER_MS - 1);
+    assert!(!exporter.poll());
+    assert!(exporter.reading().is_some());
+
+    clock.advance(1);
+    assert!(!exporter.poll());
+    assert!(exporter.reading().is_none());
+    let metrics = render(&exporter);
+    assert!(!metrics.contains("sps30_mass_pm2_5"));
+    assert!(!metrics.contains("sps30_status_fan"));
+    assert!(metrics.contains("sps30_samples_total 1\n"));
+    assert!(metrics.contains("sps30_up 1\n"));
+}
+
+fn request(address: &str, request: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn test_serve() {
+    let clock = SimClock::new();
+    let sim = VirtualSps30::new(RandomProfile::new(3), clock.clone());
+    let mut exporter = Exporter::new(sensor(sim, &clock));
+    exporter.start().unwrap();
+    clock.advance(1_000);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let flag = shutdown.clone();
+    let server = thread::spawn(move || {
+        exporter::serve(&mut exporter, &listener, &flag).unwrap();
+        exporter
+    });
+
+    // A client that sends nothing does not hold up the others
+    let _silent = TcpStream::connect(&address).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    let started = Instant::now();
+
+    let response = request(&address, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+    assert!(response.contains("\r\n\r\n# HELP sps30_mass_pm1_0 "));
+    assert!(response.contains("sps30_samples_total 1\n"));
+    assert!(started.elapsed() < Duration::from_millis(1_000));
+
+    let response = request(&address, "HEAD /metrics HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\n"));
+
+    let response = request(&address, "GET / HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    let response = request(&address, "POST /metrics HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+
+    shutdown.store(true, Ordering::SeqCst);
+    let exporter = server.join().unwrap();
+    let sim = exporter.shutdown().unwrap().destroy();
+    assert_eq!(sim.state(), SimState::Sleeping);
+}
@@ -0,0 +1,98 @@
+#![cfg(feature = "float")]
+
+use sps30_i2c::fan_cleaning::FanCleaning;
+use sps30_i2c::quality::QualityFlags;
+use sps30_i2c::warm_up::WarmUp;
+use sps30_i2c::testing::{self, ManualClock, DEV_ADDR};
+use sps30_i2c::{OutputFormat, Sps30};
+use embedded_hal_mock::{delay::MockNoop as NoopDelay, i2c::Mock as I2cMock,
+    i2c::Transaction as I2cTrans};
+
+fn measured_values() -> [I2cTrans; 2] {
+    let values: [f32; 10] = [1.0, 1.0, 1.0, 1.0, 500.0, 500.0, 500.0, 500.0, 500.0, 0.5];
+    let data: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+
+    [
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, testing::with_crc(&data)),
+    ]
+}
+
+fn sensor(expectations: &[I2cTrans], clock: &ManualClock) -> Sps30<I2cMock, NoopDelay, ManualClock> {
+    let mut sensor = Sps30::new_sps30(I2cMock::new(expectations), NoopDelay)
+        .with_clock(clock.clone());
+    sensor.set_warm_up(WarmUp { stabilization_ms: 0, low_concentration_ms: 0, very_low_concentration_ms: 0,
+        ..WarmUp::default() });
+    sensor
+}
+
+#[test]
+fn test_auto_cleaning_schedule() {
+    let fan_cleaning = FanCleaning::default();
+
+    assert_eq!(fan_cleaning.next_auto_cleaning(1_000, 60, 1_000), Some(61_000));
+    assert_eq!(fan_cleaning.next_auto_cleaning(1_000, 60, 61_000), Some(61_000));
+    assert_eq!(fan_cleaning.next_auto_cleaning(1_000, 60, 61_001), Some(121_000));
+    assert_eq!(fan_cleaning.next_auto_cleaning(1_000, 0, 61_001), None);
+
+    assert!(!fan_cleaning.is_auto_cleaning(1_000, 60, 60_999));
+    assert!(fan_cleaning.is_auto_cleaning(1_000, 60, 61_000));
+    assert!(fan_cleaning.is_auto_cleaning(1_000, 60, 70_999));
+    assert!(!fan_cleaning.is_auto_cleaning(1_000, 60, 71_000));
+    assert!(fan_cleaning.is_auto_cleaning(1_000, 60, 125_000));
+    assert!(!fan_cleaning.is_auto_cleaning(1_000, 0, 1_000));
+}
+
+#[test]
+fn test_manual_cleaning_is_flagged() {
+    let mut expectations = vec![
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::Float)),
+        I2cTrans::write(DEV_ADDR, testing::start_fan_cleaning()),
+    ];
+    expectations.extend_from_slice(&measured_values());
+    expectations.extend_from_slice(&measured_values());
+    expectations.extend_from_slice(&measured_values());
+    let clock = ManualClock::new();
+    let mut sensor = sensor(&expectations, &clock);
+
+    sensor.start_measurement().unwrap();
+    clock.set(5_000);
+    sensor.start_fan_cleaning().unwrap();
+    assert!(sensor.is_fan_cleaning());
+
+    clock.set(10_000);
+    assert_eq!(sensor.read_validated_values().unwrap().flags, QualityFlags::FAN_CLEANING);
+    assert_eq!(sensor.read_stable_values().unwrap(), None);
+
+    clock.set(15_000);
+    assert!(!sensor.is_fan_cleaning());
+    assert!(sensor.read_stable_values().unwrap().is_some());
+
+    sensor.destroy();
+}
+
+#[test]
+fn test_auto_cleaning_is_flagged() {
+    let mut expectations = vec![
+        I2cTrans::write(DEV_ADDR, testing::write_auto_cleaning_interval(60)),
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::Float)),
+    ];
+    expectations.extend_from_slice(&measured_values());
+    expectations.extend_from_slice(&measured_values());
+    let clock = ManualClock::new();
+    let mut sensor = sensor(&expectations, &clock);
+
+    sensor.write_auto_cleaning_interval(60).unwrap();
+    clock.set(1_000);
+    sensor.start_measurement().unwrap();
+    assert_eq!(sensor.next_auto_cleaning(), Some(61_000));
+
+    clock.set(50_000);
+    assert!(sensor.read_validated_values().unwrap().is_valid());
+
+    clock.set(62_000);
+    assert_eq!(sensor.read_validated_values().unwrap().flags, QualityFlags::FAN_CLEANING);
+    assert_eq!(sensor.next_auto_cleaning(), Some(121_000));
+
+    sensor.destroy();
+}
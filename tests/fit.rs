@@ -0,0 +1,99 @@
+#![cfg(feature = "std")]
+
+use sps30_i2c::fit::{self, FitError, Model, Series};
+
+fn series(csv: &str) -> Series {
+    Series::from_csv(csv.as_bytes()).unwrap()
+}
+
+#[test]
+fn test_read_csv() {
+    let s = series("timestamp,mass_pm2_5,humidity\n20,2.0,\n2020-08-11T12:00:00Z,1.0,50\n");
+
+    assert_eq!(s.columns, vec!["mass_pm2_5", "humidity"]);
+    assert_eq!(s.timestamps, vec![20.0, 1_597_147_200.0]);
+    assert_eq!(s.rows[0][0], 2.0);
+    assert!(s.rows[0][1].is_nan());
+}
+
+#[test]
+fn test_read_csv_error() {
+    match Series::from_csv("timestamp,mass_pm2_5\n0,1.0,2.0\n".as_bytes()) {
+        Err(FitError::Parse { line: 2, .. }) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn test_align() {
+    let sensor = series("t,x\n0,0\n10,0\n20,0\n100,0\n");
+    let reference = series("t,x\n3,0\n12,0\n19,0\n");
+
+    assert_eq!(fit::align(&sensor, &reference, 5.0), vec![(0, 0), (1, 1), (2, 2)]);
+}
+
+#[test]
+fn test_calibrate_linear() {
+    let mut sensor = String::from("timestamp,mass_pm2_5,mass_pm10\n");
+    let mut reference = String::from("timestamp,mass_pm2_5\n");
+    for i in 0..20 {
+        let x = i as f64;
+        sensor.push_str(&format!("{},{},{}\n", i * 60, x, x));
+        reference.push_str(&format!("{},{}\n", i * 60 + 5, 2.0 * x + 1.0));
+    }
+
+    let calibration = fit::calibrate(&series(&sensor), &series(&reference), b"SERIAL", 10.0, Model::Linear).unwrap();
+
+    assert_eq!(calibration.reports.len(), 1);
+    let linear = &calibration.reports[0].fits[0];
+    assert_eq!(linear.model, Model::Linear);
+    assert_eq!(linear.samples, 20);
+    assert!((linear.r_squared - 1.0).abs() < 1e-9);
+    assert!(linear.rmse < 1e-9);
+    assert!(linear.bias.abs() < 1e-9);
+
+    let profile = calibration.profile;
+    assert!((profile.mass_pm2_5.apply(3.0) - 7.0).abs() < 1e-4);
+    assert_eq!(profile.mass_pm10.apply(3.0), 3.0);
+    assert_eq!(&profile.serial[..6], b"SERIAL");
+}
+
+#[test]
+fn test_calibrate_humidity() {
+    let mut sensor = String::from("timestamp,mass_pm2_5,humidity\n");
+    let mut reference = String::from("timestamp,mass_pm2_5\n");
+    for i in 0..30 {
+        let x = (i % 7) as f64 * 3.0;
+        let rh = 40.0 + (i % 5) as f64 * 10.0;
+        sensor.push_str(&format!("{},{},{}\n", i, x, rh));
+        reference.push_str(&format!("{},{}\n", i, x - 0.1 * rh + 1.0));
+    }
+
+    let calibration = fit::calibrate(&series(&sensor), &series(&reference), b"", 0.0, Model::Humidity).unwrap();
+
+    let correction = calibration.profile.mass_pm2_5;
+    assert!((correction.humidity + 0.1).abs() < 1e-4);
+    assert!((correction.apply_with_humidity(6.0, 50.0) - 2.0).abs() < 1e-3);
+}
+
+#[test]
+fn test_calibrate_humidity_without_column() {
+    let sensor = series("timestamp,mass_pm2_5\n0,1\n1,2\n");
+
+    match fit::calibrate(&sensor, &sensor, b"", 0.0, Model::Humidity) {
+        Err(FitError::MissingColumn(c)) => assert_eq!(c, "humidity"),
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn test_fit_rejects() {
+    let x: Vec<f64> = (0..10).map(f64::from).collect();
+    let y: Vec<f64> = x.iter().map(|x| 2.0 * x + 1.0).collect();
+    assert!(fit::fit(&x, &y, None, Model::Polynomial(3)).is_some());
+    assert!(fit::fit(&x, &y, None, Model::Polynomial(4)).is_none());
+
+    // The normal equations overflow to infinity and NaN
+    let x: Vec<f64> = (1..10).map(|i| f64::from(i) * 1e200).collect();
+    assert!(fit::fit(&x, &x, None, Model::Linear).is_none());
+}
@@ -0,0 +1,253 @@
+#![cfg(feature = "flash")]
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+use sps30_i2c::flash_log::{FlashLog, FlashLogError, Sample};
+use sps30_i2c::quality::{QualityFlags, Reading};
+use sps30_i2c::AirInfo;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const SECTOR: usize = 256;
+const SECTORS: usize = 4;
+
+#[derive(Debug)]
+struct State {
+    data: Vec<u8>,
+    erases: [u32; SECTORS],
+    /// Writes and erases left before the power is cut
+    budget: Option<usize>,
+    operations: usize,
+}
+
+/// NOR flash in RAM, shared so it survives the log
+#[derive(Debug, Clone)]
+struct RamFlash(Rc<RefCell<State>>);
+
+impl RamFlash {
+    fn new() -> Self {
+        RamFlash(Rc::new(RefCell::new(State {
+            data: vec![0xFF; SECTOR * SECTORS],
+            erases: [0; SECTORS],
+            budget: None,
+            operations: 0,
+        })))
+    }
+
+    /// Cut the power during the operation after `operations` more
+    fn cut_after(&self, operations: usize) {
+        self.0.borrow_mut().budget = Some(operations);
+    }
+
+    fn restore_power(&self) {
+        self.0.borrow_mut().budget = None;
+    }
+
+    /// Count an operation, `false` if the power is cut during it
+    fn powered(&self) -> Result<bool, NorFlashErrorKind> {
+        let mut state = self.0.borrow_mut();
+        state.operations += 1;
+        match state.budget {
+            Some(0) => {
+                state.budget = Some(usize::MAX);
+                Ok(false)
+            }
+            Some(usize::MAX) => Err(NorFlashErrorKind::Other),
+            Some(n) => {
+                state.budget = Some(n - 1);
+                Ok(true)
+            }
+            None => Ok(true),
+        }
+    }
+}
+
+impl ErrorType for RamFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        bytes.copy_from_slice(&self.0.borrow().data[start..start + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SECTOR * SECTORS
+    }
+}
+
+impl NorFlash for RamFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = SECTOR;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let (from, to) = (from as usize, to as usize);
+        assert!(from % SECTOR == 0 && to % SECTOR == 0);
+        // A cut erase leaves the second half of the sector programmed
+        let end = if self.powered()? { to } else { from + SECTOR / 2 };
+        let mut state = self.0.borrow_mut();
+        state.data[from..end].iter_mut().for_each(|b| *b = 0xFF);
+        for sector in from / SECTOR..to / SECTOR {
+            state.erases[sector] += 1;
+        }
+        if end == to {
+            Ok(())
+        } else {
+            Err(NorFlashErrorKind::Other)
+        }
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        assert!(start % Self::WRITE_SIZE == 0 && bytes.len() % Self::WRITE_SIZE == 0);
+        // A cut write programs the first half of the bytes
+        let len = if self.powered()? { bytes.len() } else { bytes.len() / 2 };
+        let mut state = self.0.borrow_mut();
+        for (cell, byte) in state.data[start..start + len].iter_mut().zip(bytes) {
+            // Programming only clears bits
+            *cell &= byte;
+        }
+        if len == bytes.len() {
+            Ok(())
+        } else {
+            Err(NorFlashErrorKind::Other)
+        }
+    }
+}
+
+fn reading(i: u32) -> Reading {
+    let x = i as f32;
+    Reading {
+        air_info: AirInfo {
+            mass_pm1_0: x + 0.1,
+            mass_pm2_5: x + 0.2,
+            mass_pm4_0: x + 0.3,
+            mass_pm10: x + 0.4,
+            number_pm0_5: x + 3.5,
+            number_pm1_0: x + 4.5,
+            number_pm2_5: x + 5.5,
+            number_pm4_0: x + 6.5,
+            number_pm10: x + 7.5,
+            typical_size: 0.5 + x / 1_000.0,
+        },
+        flags: if i & 1 == 1 { QualityFlags::WARMING_UP } else { QualityFlags::empty() },
+        timestamp: Some(1_000 * u64::from(i)),
+    }
+}
+
+fn pending(log: &mut FlashLog<RamFlash>) -> Vec<Sample> {
+    log.pending().collect::<Result<_, _>>().unwrap()
+}
+
+fn sequences(log: &mut FlashLog<RamFlash>) -> Vec<u32> {
+    pending(log).iter().map(|sample| sample.sequence).collect()
+}
+
+#[test]
+fn test_append_acknowledge() {
+    let flash = RamFlash::new();
+    let mut log = FlashLog::open(flash.clone()).unwrap();
+    assert_eq!(log.next_sequence(), 1);
+    assert!(pending(&mut log).is_empty());
+
+    for i in 1..=3 {
+        assert_eq!(log.append(1_000 * u64::from(i), &reading(i)).unwrap(), i);
+    }
+    let samples = pending(&mut log);
+    assert_eq!(samples.len(), 3);
+    for (i, sample) in (1..).zip(samples) {
+        assert_eq!(sample, Sample { sequence: i, reading: reading(i) });
+    }
+
+    // Scaled to uint16, unknown values are kept as NaN
+    let mut unknown = reading(4);
+    unknown.air_info.mass_pm10 = f32::NAN;
+    unknown.air_info.number_pm0_5 = -1.0;
+    log.append(4_000, &unknown).unwrap();
+    let air_info = pending(&mut log)[3].reading.air_info;
+    assert!(air_info.mass_pm10.is_nan() && air_info.number_pm0_5.is_nan());
+
+    log.acknowledge(2).unwrap();
+    log.acknowledge(1).unwrap();
+    assert_eq!(sequences(&mut log), [3, 4]);
+
+    // Recovered when opened again
+    drop(log);
+    let mut log = FlashLog::open(flash).unwrap();
+    assert_eq!(log.next_sequence(), 5);
+    assert_eq!(log.acknowledged(), 2);
+    assert_eq!(sequences(&mut log), [3, 4]);
+    log.acknowledge(100).unwrap();
+    assert_eq!(log.acknowledged(), 4);
+    assert!(pending(&mut log).is_empty());
+}
+
+#[test]
+fn test_wear_leveling() {
+    let flash = RamFlash::new();
+    let mut log = FlashLog::open(flash.clone()).unwrap();
+
+    for i in 1..=200 {
+        log.append(0, &reading(i)).unwrap();
+        if i % 10 == 0 {
+            log.acknowledge(i).unwrap();
+        }
+    }
+    let erases = flash.0.borrow().erases;
+    assert!(erases.iter().max().unwrap() - erases.iter().min().unwrap() <= 1, "{:?}", erases);
+    assert!(erases.iter().sum::<u32>() > 10, "{:?}", erases);
+
+    // The oldest readings are dropped when the ring is full
+    for i in 201..=230 {
+        log.append(0, &reading(i)).unwrap();
+    }
+    let sequences = sequences(&mut log);
+    assert_eq!(*sequences.last().unwrap(), 230);
+    assert!(sequences.len() < 30);
+    assert!(sequences.windows(2).all(|w| w[1] == w[0] + 1));
+
+    drop(log);
+    assert_eq!(FlashLog::open(flash).unwrap().next_sequence(), 231);
+}
+
+#[test]
+fn test_power_loss() {
+    /// Append readings and acknowledge them in batches of 4
+    fn run(flash: &RamFlash, appended: &mut u32, acknowledged: &mut u32) -> Result<(), FlashLogError<NorFlashErrorKind>> {
+        let mut log = FlashLog::open(flash.clone())?;
+        for i in 1..=24 {
+            *appended = log.append(0, &reading(i))?;
+            if i % 4 == 0 {
+                log.acknowledge(i)?;
+                *acknowledged = i;
+            }
+        }
+        Ok(())
+    }
+
+    let flash = RamFlash::new();
+    run(&flash, &mut 0, &mut 0).unwrap();
+    let operations = flash.0.borrow().operations;
+
+    for cut in 0..operations {
+        let flash = RamFlash::new();
+        flash.cut_after(cut);
+        let (mut appended, mut acknowledged) = (0, 0);
+        assert!(run(&flash, &mut appended, &mut acknowledged).is_err());
+        flash.restore_power();
+
+        let mut log = FlashLog::open(flash.clone()).unwrap();
+        assert_eq!(log.next_sequence(), appended + 1, "cut after {}", cut);
+        assert_eq!(log.acknowledged(), acknowledged, "cut after {}", cut);
+        let expected: Vec<u32> = (acknowledged + 1..=appended).collect();
+        assert_eq!(sequences(&mut log), expected, "cut after {}", cut);
+
+        // Appending resumes
+        assert_eq!(log.append(0, &reading(99)).unwrap(), appended + 1);
+        log.acknowledge(appended).unwrap();
+        assert_eq!(sequences(&mut log), [appended + 1]);
+    }
+}
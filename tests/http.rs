@@ -0,0 +1,245 @@
+#![cfg(all(feature = "sim", feature = "http"))]
+
+use sps30_i2c::http::{self, accept_key, HttpApi};
+use sps30_i2c::sim::{RandomProfile, SimClock, SimState, VirtualSps30};
+use sps30_i2c::Sps30;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type SimApi = HttpApi<VirtualSps30<RandomProfile, SimClock>, SimClock, SimClock>;
+
+fn api(clock: &SimClock) -> SimApi {
+    let mut sim = VirtualSps30::new(RandomProfile::new(5), clock.clone());
+    sim.set_serial_number(b"HTTP0001");
+    sim.set_firmware_version(2, 3);
+    let sensor = Sps30::new_sps30(sim, clock.clone()).with_clock(clock.clone());
+    let mut api = HttpApi::new(sensor).with_token("secret");
+    api.start().unwrap();
+    api
+}
+
+/// Serve the API on its own thread until the returned flag is raised, then
+/// return it
+fn serve(mut api: SimApi) -> (String, Arc<AtomicBool>, thread::JoinHandle<SimApi>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let flag = shutdown.clone();
+    let server = thread::spawn(move || {
+        http::serve(&mut api, &listener, &flag).unwrap();
+        api
+    });
+    (address, shutdown, server)
+}
+
+/// Send a request, returning the status line and the body
+fn request(address: &str, request: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+fn json(body: &str) -> serde_json::Value {
+    serde_json::from_str(body).unwrap()
+}
+
+/// Read a server frame, returning its opcode and payload
+fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(head[1] & 0x80, 0, "server frames are not masked");
+    let len = match head[1] {
+        126 => {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            usize::from(u16::from_be_bytes(len))
+        }
+        len => usize::from(len),
+    };
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+    (head[0], payload)
+}
+
+#[test]
+fn test_accept_key() {
+    // Example of RFC 6455
+    assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+}
+
+#[test]
+fn test_poll() {
+    let clock = SimClock::new();
+    let mut api = api(&clock);
+    assert!(!api.poll().unwrap());
+    assert!(api.reading().is_none());
+
+    clock.advance(1_000);
+    assert!(api.poll().unwrap());
+    assert!(api.reading().is_some());
+    assert_eq!(api.stream_clients(), 0);
+
+    let sim = api.shutdown().unwrap().destroy();
+    assert_eq!(sim.state(), SimState::Sleeping);
+}
+
+#[test]
+fn test_endpoints() {
+    let clock = SimClock::new();
+    let (address, shutdown, server) = serve(api(&clock));
+
+    // A client that sends nothing does not hold up the others
+    let _silent = TcpStream::connect(&address).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    let started = Instant::now();
+
+    let (status, body) = request(&address, "GET /api/reading HTTP/1.1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+    assert!(started.elapsed() < Duration::from_millis(1_000));
+    assert_eq!(json(&body)["error"], "no_reading");
+
+    let (status, body) = request(&address, "GET /api/info HTTP/1.1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let info = json(&body);
+    assert_eq!(info["product_type"], "00080000");
+    assert_eq!(info["serial"], "HTTP0001");
+    assert_eq!(info["firmware"], "2.3");
+
+    let (status, body) = request(&address, "GET /api/status HTTP/1.1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(json(&body), serde_json::json!({ "speed": false, "laser": false, "fan": false }));
+
+    let (status, body) = request(&address, "GET /api/auto-cleaning-interval HTTP/1.1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(json(&body)["interval"], 604_800);
+
+    let (status, body) = request(&address, "POST /api/fan-cleaning HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 403 Forbidden");
+    assert_eq!(json(&body)["error"], "forbidden");
+
+    let (status, _) = request(&address, "POST /api/reset HTTP/1.1\r\nAuthorization: Bearer wrong\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 403 Forbidden");
+
+    let (status, _) = request(
+        &address,
+        "POST /api/fan-cleaning HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: 0\r\n\r\n",
+    );
+    assert_eq!(status, "HTTP/1.1 204 No Content");
+
+    let (status, _) = request(&address, "POST /api/reset HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 204 No Content");
+
+    let (status, body) = request(&address, "GET /api/reset HTTP/1.1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+    assert_eq!(json(&body)["error"], "method_not_allowed");
+
+    let (status, _) = request(&address, "GET /metrics HTTP/1.1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+    let (status, body) = request(&address, "GET /api/stream HTTP/1.1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 426 Upgrade Required");
+    assert_eq!(json(&body)["error"], "websocket_required");
+
+    shutdown.store(true, Ordering::SeqCst);
+    let mut api = server.join().unwrap();
+
+    // Measuring again after the reset
+    clock.advance(1_000);
+    assert!(api.poll().unwrap());
+    let sim = api.shutdown().unwrap().destroy();
+    assert_eq!(sim.state(), SimState::Sleeping);
+}
+
+#[test]
+fn test_trickling_client() {
+    let clock = SimClock::new();
+    let (address, shutdown, server) = serve(api(&clock));
+
+    // The whole request head has to arrive within the timeout
+    let mut stream = TcpStream::connect(&address).unwrap();
+    let started = Instant::now();
+    let mut closed = false;
+    for byte in b"GET /api/info HTTP/1.1\r\nX-Padding: ".iter().cycle() {
+        if stream.write_all(&[*byte]).is_err() {
+            closed = true;
+            break;
+        }
+        if started.elapsed() > Duration::from_millis(4_000) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(closed);
+    assert!(started.elapsed() < Duration::from_millis(4_000));
+
+    shutdown.store(true, Ordering::SeqCst);
+    let api = server.join().unwrap();
+    api.shutdown().unwrap();
+}
+
+#[test]
+fn test_stream() {
+    let clock = SimClock::new();
+    let api = api(&clock);
+    clock.advance(1_000);
+    let (address, shutdown, server) = serve(api);
+
+    let (status, body) = request(&address, "GET /api/reading HTTP/1.1\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let reading = json(&body);
+    assert_eq!(reading["serial"], "HTTP0001");
+    assert!(reading["mass_pm2_5"].is_number());
+    assert!(reading["flags"].is_array());
+
+    let mut stream = TcpStream::connect(&address).unwrap();
+    stream
+        .write_all(
+            b"GET /api/stream HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+    // The last sample right away
+    let (opcode, payload) = read_frame(&mut stream);
+    assert_eq!(opcode, 0x81);
+    assert_eq!(json(std::str::from_utf8(&payload).unwrap()), reading);
+
+    // Then every new one
+    clock.advance(1_000);
+    let (opcode, payload) = read_frame(&mut stream);
+    assert_eq!(opcode, 0x81);
+    let next = json(std::str::from_utf8(&payload).unwrap());
+    assert!(next["timestamp"].as_u64() >= reading["timestamp"].as_u64());
+
+    // Masked ping with payload "hi"
+    let mask = [1, 2, 3, 4];
+    stream.write_all(&[0x89, 0x82, 1, 2, 3, 4, b'h' ^ mask[0], b'i' ^ mask[1]]).unwrap();
+    assert_eq!(read_frame(&mut stream), (0x8A, b"hi".to_vec()));
+
+    // Masked close with status code 1000
+    stream.write_all(&[0x88, 0x82, 1, 2, 3, 4, 0x03 ^ mask[0], 0xE8 ^ mask[1]]).unwrap();
+    assert_eq!(read_frame(&mut stream), (0x88, vec![0x03, 0xE8]));
+
+    shutdown.store(true, Ordering::SeqCst);
+    let api = server.join().unwrap();
+    assert_eq!(api.stream_clients(), 0);
+    let sim = api.shutdown().unwrap().destroy();
+    assert_eq!(sim.state(), SimState::Sleeping);
+}
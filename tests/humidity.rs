@@ -0,0 +1,64 @@
+#![cfg(feature = "float")]
+
+use sps30_i2c::humidity::HygroscopicGrowth;
+use sps30_i2c::AirInfo;
+
+fn air_info(mass: f32) -> AirInfo {
+    AirInfo {
+        mass_pm1_0: mass,
+        mass_pm2_5: mass,
+        mass_pm4_0: mass,
+        mass_pm10: mass,
+        number_pm0_5: 0.0,
+        number_pm1_0: 0.0,
+        number_pm2_5: 0.0,
+        number_pm4_0: 0.0,
+        number_pm10: 0.0,
+        typical_size: 0.0,
+    }
+}
+
+#[test]
+fn test_dry_air_is_not_corrected() {
+    let corrected = HygroscopicGrowth::URBAN.correct(&air_info(10.0), 0.0);
+
+    assert_eq!(corrected.factor, 1.0);
+    assert_eq!(corrected.mass_pm2_5, 10.0);
+}
+
+#[test]
+fn test_growth_factor() {
+    let factor = HygroscopicGrowth::URBAN.factor(80.0);
+
+    assert!((factor - 1.9212).abs() < 1e-3);
+}
+
+#[test]
+fn test_correct_mass() {
+    let growth = HygroscopicGrowth::new(0.38, 1.65);
+    let corrected = growth.correct(&air_info(19.212), 80.0);
+
+    assert!((corrected.mass_pm1_0 - 10.0).abs() < 1e-2);
+    assert!((corrected.mass_pm10 - 10.0).abs() < 1e-2);
+    assert_eq!(corrected.factor, growth.factor(80.0));
+}
+
+#[test]
+fn test_humidity_is_clamped() {
+    let growth = HygroscopicGrowth::SEA_SALT;
+
+    assert_eq!(growth.factor(100.0), growth.factor(growth.max_humidity));
+    assert_eq!(growth.factor(-5.0), 1.0);
+}
+
+#[test]
+fn test_invalid_humidity_is_not_corrected() {
+    let mut growth = HygroscopicGrowth::URBAN;
+    assert_eq!(growth.factor(f32::NAN), 1.0);
+    assert_eq!(growth.correct(&air_info(10.0), f32::NAN).mass_pm2_5, 10.0);
+
+    growth.max_humidity = 100.0;
+    assert_eq!(growth.factor(100.0), 1.0);
+    growth.max_humidity = f32::NAN;
+    assert_eq!(growth.factor(80.0), 1.0);
+}
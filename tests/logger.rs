@@ -0,0 +1,186 @@
+#![cfg(feature = "std")]
+
+use flate2::read::GzDecoder;
+use sps30_i2c::logger::{read_log, DataLogger, LogFormat, LoggerConfig, RECORD_LEN};
+use sps30_i2c::quality::{QualityFlags, Reading};
+use sps30_i2c::AirInfo;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::{env, process};
+
+/// 2023-11-14T00:00:00Z [ms]
+const DAY: u64 = 1_699_920_000_000;
+const MS_PER_DAY: u64 = 86_400_000;
+
+/// Empty directory unique to the test
+fn directory(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("sps30-logger-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn reading(timestamp: u64, i: u32) -> Reading {
+    let x = i as f32;
+    Reading {
+        air_info: AirInfo {
+            mass_pm1_0: x + 0.1,
+            mass_pm2_5: x + 0.2,
+            mass_pm4_0: x + 0.3,
+            mass_pm10: x + 0.4,
+            number_pm0_5: x * 3.5,
+            number_pm1_0: x * 4.5,
+            number_pm2_5: x * 5.5,
+            number_pm4_0: x * 6.5,
+            number_pm10: x * 7.5,
+            typical_size: 0.5 + x / 1_000.0,
+        },
+        flags: if i & 1 == 1 { QualityFlags::WARMING_UP } else { QualityFlags::empty() },
+        timestamp: Some(timestamp),
+    }
+}
+
+fn names(paths: &[PathBuf]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+        .collect()
+}
+
+fn read_all(paths: &[PathBuf]) -> Vec<Reading> {
+    paths.iter().flat_map(|path| read_log(path).unwrap()).collect()
+}
+
+#[test]
+fn test_size_rotation() {
+    for &(format, extension) in [(LogFormat::Csv, "csv"), (LogFormat::Binary, "bin")].iter() {
+        let dir = directory(extension);
+        let mut config = LoggerConfig::new(&dir);
+        config.format = format;
+        config.max_file_size = Some(1_000);
+        let mut logger = DataLogger::open(config).unwrap();
+
+        let expected: Vec<Reading> = (0..60).map(|i| reading(DAY + u64::from(i) * 1_000, i)).collect();
+        for r in &expected {
+            logger.log(r.timestamp.unwrap(), r).unwrap();
+        }
+        logger.finish_closed().unwrap();
+
+        let files = logger.log_files().unwrap();
+        let names = names(&files);
+        assert!(files.len() > 2, "{:?}", names);
+        assert_eq!(names[0], format!("sps30-20231114-0000.{}.gz", extension));
+        assert_eq!(names.last().unwrap(), &format!("sps30-20231114-{:04}.{}", files.len() - 1, extension));
+        assert_eq!(logger.active_file(), Some(files.last().unwrap().as_path()));
+        for path in &files[..files.len() - 1] {
+            assert!(fs::metadata(path).unwrap().len() < 1_000);
+        }
+        assert_eq!(read_all(&files), expected);
+
+        logger.close().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn test_daily_rotation_and_retention() {
+    let dir = directory("retention");
+    let mut config = LoggerConfig::new(&dir);
+    config.prefix = "air".to_string();
+    config.compress = false;
+    config.max_files = Some(3);
+    config.max_age_days = Some(2);
+
+    let mut logger = DataLogger::open(config.clone()).unwrap();
+    for day in 0..3 {
+        logger.log(DAY + day * MS_PER_DAY, &reading(0, 1)).unwrap();
+    }
+    logger.finish_closed().unwrap();
+    assert_eq!(
+        names(&logger.log_files().unwrap()),
+        ["air-20231114-0000.csv", "air-20231115-0000.csv", "air-20231116-0000.csv"]
+    );
+
+    // Older than two days
+    logger.log(DAY + 3 * MS_PER_DAY, &reading(0, 2)).unwrap();
+    logger.finish_closed().unwrap();
+    assert_eq!(
+        names(&logger.log_files().unwrap()),
+        ["air-20231115-0000.csv", "air-20231116-0000.csv", "air-20231117-0000.csv"]
+    );
+    logger.close().unwrap();
+
+    // Beyond three closed files, besides the active one
+    config.max_age_days = None;
+    config.rotate_daily = false;
+    config.max_file_size = Some(1);
+    let mut logger = DataLogger::open(config).unwrap();
+    for i in 3..5 {
+        logger.log(DAY + 3 * MS_PER_DAY, &reading(0, i)).unwrap();
+    }
+    logger.finish_closed().unwrap();
+    assert_eq!(
+        names(&logger.log_files().unwrap()),
+        ["air-20231116-0000.csv", "air-20231117-0000.csv", "air-20231117-0001.csv", "air-20231117-0002.csv"]
+    );
+    logger.close().unwrap();
+
+    // Files of other loggers are left alone
+    fs::write(dir.join("notes.txt"), "keep").unwrap();
+    DataLogger::open(LoggerConfig::new(&dir)).unwrap();
+    assert!(dir.join("notes.txt").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn append(path: &Path, bytes: &[u8]) {
+    OpenOptions::new().append(true).open(path).unwrap().write_all(bytes).unwrap();
+}
+
+#[test]
+fn test_crash_recovery() {
+    for &format in [LogFormat::Csv, LogFormat::Binary].iter() {
+        let dir = directory(format.extension());
+        let mut config = LoggerConfig::new(&dir);
+        config.format = format;
+
+        let mut logger = DataLogger::open(config.clone()).unwrap();
+        let mut expected = vec![reading(DAY, 0), reading(DAY + MS_PER_DAY, 1)];
+        for r in &expected {
+            logger.log(r.timestamp.unwrap(), r).unwrap();
+        }
+        logger.finish_closed().unwrap();
+        let files = logger.log_files().unwrap();
+        let active = logger.active_file().unwrap().to_path_buf();
+        // Power cut in the middle of a record and of a compression
+        drop(logger);
+        append(&active, &[b'7'; RECORD_LEN / 2]);
+        fs::write(dir.join("sps30-20231114-0000.csv.gz.tmp"), b"\x1f\x8b").unwrap();
+        let closed = dir.join(format!("sps30-20231114-0001.{}", format.extension()));
+        let mut content = Vec::new();
+        GzDecoder::new(File::open(&files[0]).unwrap()).read_to_end(&mut content).unwrap();
+        fs::write(&closed, content).unwrap();
+
+        let mut logger = DataLogger::open(config).unwrap();
+        assert_eq!(logger.active_file(), Some(active.as_path()));
+        let r = reading(DAY + MS_PER_DAY + 1_000, 2);
+        logger.log(r.timestamp.unwrap(), &r).unwrap();
+        expected.insert(1, reading(DAY, 0));
+        expected.push(r);
+
+        let files = logger.log_files().unwrap();
+        assert_eq!(
+            names(&files),
+            [
+                format!("sps30-20231114-0000.{}.gz", format.extension()),
+                format!("sps30-20231114-0001.{}.gz", format.extension()),
+                format!("sps30-20231115-0000.{}", format.extension()),
+            ]
+        );
+        assert!(!dir.join("sps30-20231114-0000.csv.gz.tmp").exists());
+        assert_eq!(read_all(&files), expected);
+
+        logger.close().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+#![cfg(feature = "sim")]
+
+use sps30_i2c::modbus::{self, crc16, ModbusServer, NOT_AVAILABLE};
+use sps30_i2c::sim::{Fault, RandomProfile, SimClock, SimState, VirtualSps30, STATUS_FAN};
+use sps30_i2c::Sps30;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+type SimServer = ModbusServer<VirtualSps30<RandomProfile, SimClock>, SimClock, SimClock>;
+
+fn server(clock: &SimClock) -> SimServer {
+    let mut sim = VirtualSps30::new(RandomProfile::new(9), clock.clone());
+    sim.inject(Fault::StatusBits(STATUS_FAN));
+    let sensor = Sps30::new_sps30(sim, clock.clone()).with_clock(clock.clone());
+    let mut server = ModbusServer::new(sensor);
+    server.start().unwrap();
+    server
+}
+
+/// Read request PDU
+fn read(function: u8, address: u16, count: u16) -> Vec<u8> {
+    let mut pdu = vec![function];
+    pdu.extend_from_slice(&address.to_be_bytes());
+    pdu.extend_from_slice(&count.to_be_bytes());
+    pdu
+}
+
+/// Registers of a read response PDU
+fn registers(pdu: &[u8]) -> Vec<u16> {
+    assert_eq!(usize::from(pdu[1]), pdu.len() - 2, "{:02x?}", pdu);
+    pdu[2..].chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect()
+}
+
+fn write_interval(interval: u32) -> Vec<u8> {
+    let mut pdu = vec![0x10, 0, 0, 0, 2, 4];
+    pdu.extend_from_slice(&interval.to_be_bytes());
+    pdu
+}
+
+#[test]
+fn test_crc16() {
+    assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]).to_le_bytes(), [0xC5, 0xCD]);
+}
+
+#[test]
+fn test_register_map() {
+    let clock = SimClock::new();
+    let mut server = server(&clock);
+
+    // Nothing sampled yet
+    let floats = registers(&server.process(&read(0x04, 0, 20)));
+    assert!(f32::from_bits(u32::from(floats[0]) << 16 | u32::from(floats[1])).is_nan());
+    assert_eq!(registers(&server.process(&read(0x04, 100, 10))), vec![NOT_AVAILABLE; 10]);
+    assert_eq!(registers(&server.process(&read(0x04, 200, 2))), vec![0, 0]);
+    assert_eq!(server.process(&read(0x02, 0, 3)), vec![0x02, 1, 0b000]);
+
+    clock.advance(1_000);
+    assert!(server.poll().unwrap());
+    let reading = *server.reading().unwrap();
+    let a = reading.air_info;
+    let values = [
+        a.mass_pm1_0,
+        a.mass_pm2_5,
+        a.mass_pm4_0,
+        a.mass_pm10,
+        a.number_pm0_5,
+        a.number_pm1_0,
+        a.number_pm2_5,
+        a.number_pm4_0,
+        a.number_pm10,
+        a.typical_size,
+    ];
+
+    let floats = registers(&server.process(&read(0x04, 0, 20)));
+    for (i, value) in values.iter().enumerate() {
+        let bits = u32::from(floats[2 * i]) << 16 | u32::from(floats[2 * i + 1]);
+        assert_eq!(f32::from_bits(bits), *value);
+    }
+    let scaled = registers(&server.process(&read(0x04, 100, 10)));
+    assert_eq!(scaled[1], (values[1] * 10.0).round() as u16);
+    assert_eq!(scaled[9], (values[9] * 1000.0).round() as u16);
+    assert_eq!(registers(&server.process(&read(0x04, 200, 2))), vec![reading.flags.bits(), 1]);
+    // Fan speed, laser, fan
+    assert_eq!(server.process(&read(0x02, 0, 3)), vec![0x02, 1, 0b100]);
+
+    // 604800 s
+    assert_eq!(registers(&server.process(&read(0x03, 0, 2))), vec![0x0009, 0x3A80]);
+    assert_eq!(registers(&server.process(&read(0x03, 1, 1))), vec![0x3A80]);
+    assert_eq!(server.process(&write_interval(3_600)), vec![0x10, 0, 0, 0, 2]);
+    assert_eq!(registers(&server.process(&read(0x03, 0, 2))), vec![0, 3_600]);
+
+    assert_eq!(server.process(&read(0x01, 0, 1)), vec![0x01, 1, 0]);
+    assert_eq!(server.process(&[0x05, 0, 0, 0xFF, 0x00]), vec![0x05, 0, 0, 0xFF, 0x00]);
+    assert_eq!(server.process(&read(0x01, 0, 1)), vec![0x01, 1, 1]);
+
+    let sim = server.shutdown().unwrap().destroy();
+    assert_eq!(sim.state(), SimState::Sleeping);
+}
+
+#[test]
+fn test_holding_registers_cached() {
+    let clock = SimClock::new();
+    let transactions = server(&clock).shutdown().unwrap().destroy().transactions();
+
+    let mut server = server(&clock);
+    for _ in 0..10 {
+        assert_eq!(registers(&server.process(&read(0x03, 0, 2))), vec![0x0009, 0x3A80]);
+    }
+    assert_eq!(server.shutdown().unwrap().destroy().transactions(), transactions);
+}
+
+#[test]
+fn test_exceptions() {
+    let clock = SimClock::new();
+    let mut server = server(&clock);
+
+    // Illegal function
+    assert_eq!(server.process(&[0x2B, 0x0E, 0x01, 0x00]), vec![0xAB, 0x01]);
+    assert_eq!(server.process(&[0x06, 0, 0, 0, 1]), vec![0x86, 0x01]);
+    assert_eq!(server.process(&[]), vec![0x80, 0x01]);
+    // Illegal data address
+    assert_eq!(server.process(&read(0x04, 19, 2)), vec![0x84, 0x02]);
+    assert_eq!(server.process(&read(0x04, 202, 1)), vec![0x84, 0x02]);
+    assert_eq!(server.process(&read(0x02, 1, 3)), vec![0x82, 0x02]);
+    assert_eq!(server.process(&read(0x03, 1, 2)), vec![0x83, 0x02]);
+    assert_eq!(server.process(&[0x10, 0, 1, 0, 1, 2, 0, 0]), vec![0x90, 0x02]);
+    assert_eq!(server.process(&[0x05, 0, 1, 0xFF, 0x00]), vec![0x85, 0x02]);
+    // Illegal data value
+    assert_eq!(server.process(&read(0x04, 0, 0)), vec![0x84, 0x03]);
+    assert_eq!(server.process(&read(0x04, 0, 126)), vec![0x84, 0x03]);
+    assert_eq!(server.process(&[0x04, 0, 0]), vec![0x84, 0x03]);
+    assert_eq!(server.process(&[0x05, 0, 0, 0x12, 0x34]), vec![0x85, 0x03]);
+    assert_eq!(server.process(&[0x10, 0, 0, 0, 2, 3, 0, 0, 0]), vec![0x90, 0x03]);
+}
+
+/// Send a request to a Modbus TCP server, returning the response PDU
+fn tcp_request(stream: &mut TcpStream, transaction: u16, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = transaction.to_be_bytes().to_vec();
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+    frame.push(0xFF);
+    frame.extend_from_slice(pdu);
+    stream.write_all(&frame).unwrap();
+
+    let mut head = [0; 7];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(head[..2], transaction.to_be_bytes());
+    assert_eq!(head[2..4], [0, 0]);
+    assert_eq!(head[6], 0xFF);
+    let mut res = vec![0; usize::from(u16::from_be_bytes([head[4], head[5]])) - 1];
+    stream.read_exact(&mut res).unwrap();
+    res
+}
+
+#[test]
+fn test_tcp() {
+    let clock = SimClock::new();
+    let mut server = server(&clock);
+    clock.advance(1_000);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let flag = shutdown.clone();
+    let serving = thread::spawn(move || {
+        modbus::serve_tcp(&mut server, &listener, &flag).unwrap();
+        server
+    });
+
+    let mut first = TcpStream::connect(address).unwrap();
+    let mut second = TcpStream::connect(address).unwrap();
+    let samples = registers(&tcp_request(&mut first, 1, &read(0x04, 201, 1)));
+    assert_eq!(samples, vec![1]);
+    assert_eq!(tcp_request(&mut second, 7, &write_interval(0)), vec![0x10, 0, 0, 0, 2]);
+    assert_eq!(registers(&tcp_request(&mut first, 2, &read(0x03, 0, 2))), vec![0, 0]);
+    assert_eq!(tcp_request(&mut second, 8, &[0x2B]), vec![0xAB, 0x01]);
+
+    shutdown.store(true, Ordering::SeqCst);
+    serving.join().unwrap().shutdown().unwrap();
+}
+
+#[test]
+fn test_tcp_clients() {
+    let clock = SimClock::new();
+    let mut server = server(&clock);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let flag = shutdown.clone();
+    let serving = thread::spawn(move || {
+        modbus::serve_tcp(&mut server, &listener, &flag).unwrap();
+        server
+    });
+
+    // Pipelines requests and never reads the responses
+    let mut stalled = TcpStream::connect(address).unwrap();
+    let mut frames = Vec::new();
+    for transaction in 0..1_000u16 {
+        frames.extend_from_slice(&transaction.to_be_bytes());
+        frames.extend_from_slice(&[0, 0, 0, 6, 0xFF]);
+        frames.extend_from_slice(&read(0x04, 0, 125));
+    }
+    stalled.write_all(&frames).unwrap();
+
+    let mut clients: Vec<TcpStream> = (0..7).map(|_| TcpStream::connect(address).unwrap()).collect();
+    for (transaction, client) in clients.iter_mut().enumerate() {
+        assert_eq!(registers(&tcp_request(client, transaction as u16, &read(0x03, 0, 2))), vec![9, 0x3A80]);
+    }
+
+    // Over the limit
+    let mut refused = TcpStream::connect(address).unwrap();
+    refused.set_read_timeout(Some(Duration::from_millis(1_000))).unwrap();
+    match refused.read(&mut [0]) {
+        Ok(len) => assert_eq!(len, 0),
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+    }
+
+    drop(stalled);
+    shutdown.store(true, Ordering::SeqCst);
+    serving.join().unwrap().shutdown().unwrap();
+}
+
+fn rtu_frame(address: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = vec![address];
+    frame.extend_from_slice(pdu);
+    frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+    frame
+}
+
+/// Read a response frame, `None` if none arrives
+fn rtu_response(line: &mut UnixStream) -> Option<Vec<u8>> {
+    let mut buffer = [0; 256];
+    let len = line.read(&mut buffer).ok()?;
+    let frame = &buffer[..len];
+    assert_eq!(crc16(&frame[..len - 2]).to_le_bytes(), frame[len - 2..]);
+    Some(frame[..len - 2].to_vec())
+}
+
+#[test]
+fn test_rtu() {
+    let clock = SimClock::new();
+    let mut server = server(&clock).with_unit_id(17);
+    assert_eq!(server.unit_id(), 17);
+
+    let (mut line, mut port) = UnixStream::pair().unwrap();
+    port.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+    line.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let flag = shutdown.clone();
+    let serving = thread::spawn(move || {
+        modbus::serve_rtu(&mut server, &mut port, &flag).unwrap();
+        server
+    });
+
+    line.write_all(&rtu_frame(17, &read(0x03, 0, 2))).unwrap();
+    assert_eq!(rtu_response(&mut line).unwrap(), vec![17, 0x03, 4, 0x00, 0x09, 0x3A, 0x80]);
+
+    // Other units and corrupted frames are ignored
+    line.write_all(&rtu_frame(18, &read(0x03, 0, 2))).unwrap();
+    assert_eq!(rtu_response(&mut line), None);
+    let mut corrupted = rtu_frame(17, &read(0x03, 0, 2));
+    corrupted[3] ^= 1;
+    line.write_all(&corrupted).unwrap();
+    assert_eq!(rtu_response(&mut line), None);
+
+    // Broadcasts are executed without an answer
+    line.write_all(&rtu_frame(0, &write_interval(60))).unwrap();
+    assert_eq!(rtu_response(&mut line), None);
+    line.write_all(&rtu_frame(17, &read(0x03, 0, 2))).unwrap();
+    assert_eq!(rtu_response(&mut line).unwrap(), vec![17, 0x03, 4, 0, 0, 0, 60]);
+
+    shutdown.store(true, Ordering::SeqCst);
+    serving.join().unwrap().shutdown().unwrap();
+}
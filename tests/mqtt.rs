@@ -0,0 +1,254 @@
+#![cfg(feature = "sim")]
+
+use sps30_i2c::mqtt::{Connect, HomeAssistant, Message, MqttClient, MqttConfig, MqttError, MqttPublisher, Packet};
+use sps30_i2c::quality::QualityFlags;
+use sps30_i2c::sim::{RandomProfile, SimClock, SimState, VirtualSps30};
+use sps30_i2c::{AirInfo, Sps30};
+use std::io::{Cursor, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Largest packet the test broker reads [bytes]
+const MAX_LEN: usize = 65_536;
+
+/// Accept one client, acknowledge it with the return code and collect its
+/// packets until it disconnects
+fn broker(return_code: u8) -> (String, thread::JoinHandle<Vec<Packet>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut packets = vec![Packet::read(&mut stream, MAX_LEN).unwrap()];
+        stream.write_all(&Packet::ConnAck { session_present: false, return_code }.encode().unwrap()).unwrap();
+
+        while let Ok(packet) = Packet::read(&mut stream, MAX_LEN) {
+            if packet == Packet::PingReq {
+                stream.write_all(&Packet::PingResp.encode().unwrap()).unwrap();
+            }
+            let done = packet == Packet::Disconnect;
+            packets.push(packet);
+            if done {
+                break;
+            }
+        }
+        packets
+    });
+
+    (address, handle)
+}
+
+fn published(packets: &[Packet]) -> Vec<&Message> {
+    packets
+        .iter()
+        .filter_map(|p| match p {
+            Packet::Publish(message) => Some(message),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_codec() {
+    let packets = [
+        Packet::Connect(Connect {
+            client_id: "client".into(),
+            keep_alive_s: 30,
+            username: Some("user".into()),
+            password: Some(b"secret".to_vec()),
+            will: Some(Message::new("a/b", "offline", true)),
+        }),
+        Packet::ConnAck { session_present: true, return_code: 0 },
+        Packet::Publish(Message::new("topic", vec![0x55; 300], false)),
+        Packet::PingReq,
+        Packet::PingResp,
+        Packet::Disconnect,
+    ];
+
+    for packet in packets.iter() {
+        let encoded = packet.encode().unwrap();
+        assert_eq!(Packet::read(&mut Cursor::new(encoded), MAX_LEN).unwrap(), *packet);
+    }
+
+    // Remaining length of 307 bytes takes two bytes
+    assert_eq!(&packets[2].encode().unwrap()[..3], &[0x30, 0xB3, 0x02]);
+    assert_eq!(Packet::Disconnect.encode().unwrap(), [0xE0, 0x00]);
+
+    let long = "t".repeat(usize::from(u16::MAX) + 1);
+    let message = Message::new(long, "payload", false);
+    assert!(matches!(Packet::Publish(message).encode(), Err(MqttError::TooLarge)));
+    assert!(matches!(Packet::read(&mut Cursor::new([0x30, 0x05, 0x00]), MAX_LEN), Err(MqttError::Io(_))));
+    // Rejected before the body is read
+    let huge = [0x30, 0xFF, 0xFF, 0xFF, 0x7F];
+    assert!(matches!(Packet::read(&mut Cursor::new(huge), MAX_LEN), Err(MqttError::TooLarge)));
+    assert!(matches!(Packet::read(&mut Cursor::new([0xD0, 0x01, 0x00]), 0), Err(MqttError::TooLarge)));
+}
+
+#[test]
+fn test_connection_refused() {
+    let (address, broker) = broker(5);
+
+    let connect = Connect {
+        client_id: "client".into(),
+        keep_alive_s: 0,
+        username: None,
+        password: None,
+        will: None,
+    };
+    match MqttClient::connect(TcpStream::connect(address).unwrap(), connect) {
+        Err(MqttError::Refused(5)) => {}
+        res => panic!("unexpected result: {:?}", res.map(drop)),
+    }
+    broker.join().unwrap();
+}
+
+#[test]
+fn test_keep_alive() {
+    let (address, broker) = broker(0);
+
+    let connect = Connect {
+        client_id: "client".into(),
+        keep_alive_s: 1,
+        username: None,
+        password: None,
+        will: None,
+    };
+    let mut client = MqttClient::connect(TcpStream::connect(address).unwrap(), connect).unwrap();
+    assert!(!client.keep_alive().unwrap());
+    thread::sleep(Duration::from_millis(600));
+    assert!(client.keep_alive().unwrap());
+    assert!(!client.keep_alive().unwrap());
+    client.disconnect().unwrap();
+
+    let packets = broker.join().unwrap();
+    assert_eq!(packets[1..], [Packet::PingReq, Packet::Disconnect]);
+}
+
+#[test]
+fn test_silent_broker() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    // Acknowledges the connection, then neither answers nor disconnects
+    let broker = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        Packet::read(&mut stream, MAX_LEN).unwrap();
+        stream.write_all(&Packet::ConnAck { session_present: false, return_code: 0 }.encode().unwrap()).unwrap();
+        Packet::read(&mut stream, MAX_LEN).unwrap();
+        stream
+    });
+
+    let connect = Connect {
+        client_id: "client".into(),
+        keep_alive_s: 1,
+        username: None,
+        password: None,
+        will: None,
+    };
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let mut client = MqttClient::connect(stream, connect).unwrap();
+    thread::sleep(Duration::from_millis(600));
+    let started = Instant::now();
+    assert!(matches!(client.keep_alive(), Err(MqttError::Io(_))));
+    assert!(started.elapsed() < Duration::from_secs(3));
+
+    broker.join().unwrap();
+}
+
+#[test]
+fn test_discovery() {
+    let home_assistant = HomeAssistant::new("AB 12/34").with_firmware_version(2, 2);
+    assert_eq!(home_assistant.unique_id(), "sps30_AB_12_34");
+
+    let discovery = home_assistant.discovery();
+    assert_eq!(discovery.len(), 10);
+    assert!(discovery.iter().all(|m| m.retain));
+    assert_eq!(discovery[1].topic, "homeassistant/sensor/sps30_AB_12_34/mass_pm2_5/config");
+
+    let config: serde_json::Value = serde_json::from_slice(&discovery[1].payload).unwrap();
+    assert_eq!(config["unique_id"], "sps30_AB_12_34_mass_pm2_5");
+    assert_eq!(config["device_class"], "pm25");
+    assert_eq!(config["unit_of_measurement"], "µg/m³");
+    assert_eq!(config["state_class"], "measurement");
+    assert_eq!(config["state_topic"], "sps30/AB_12_34/mass_pm2_5");
+    assert_eq!(config["availability_topic"], "sps30/AB_12_34/availability");
+    assert_eq!(config["device"]["identifiers"][0], "sps30_AB_12_34");
+    assert_eq!(config["device"]["sw_version"], "2.2");
+
+    let classes: Vec<_> = discovery
+        .iter()
+        .map(|m| serde_json::from_slice::<serde_json::Value>(&m.payload).unwrap()["device_class"].clone())
+        .collect();
+    assert_eq!(classes[0], "pm1");
+    assert_eq!(classes[3], "pm10");
+    assert!(classes[2].is_null() && classes[4].is_null());
+}
+
+#[test]
+fn test_states() {
+    let home_assistant = HomeAssistant::new("SER").with_base_topic("home/air");
+    let mut reading = AirInfo {
+        mass_pm1_0: 1.5,
+        mass_pm2_5: 2.0,
+        mass_pm4_0: 2.5,
+        mass_pm10: f32::NAN,
+        number_pm0_5: 1.0,
+        number_pm1_0: 2.0,
+        number_pm2_5: 3.0,
+        number_pm4_0: 4.0,
+        number_pm10: 5.0,
+        typical_size: 0.5,
+    }
+    .validate();
+    reading.flags |= QualityFlags::WARMING_UP;
+
+    let states = home_assistant.states(&reading);
+    assert_eq!(states.len(), 10);
+    assert_eq!(states[0], Message::new("home/air/SER/mass_pm1_0", "1.5", false));
+    assert!(states.iter().all(|m| m.topic != "home/air/SER/mass_pm10"));
+    assert_eq!(states[9].topic, "home/air/SER/flags");
+    assert!(states[9].payload.starts_with(b"[\"not_finite\","));
+    assert!(states[9].payload.ends_with(b",\"warming_up\"]"));
+}
+
+#[test]
+fn test_publisher() {
+    let (address, broker) = broker(0);
+
+    let clock = SimClock::new();
+    let mut sim = VirtualSps30::new(RandomProfile::new(5), clock.clone());
+    sim.set_serial_number(b"MQTT0001");
+    let sensor = Sps30::new_sps30(sim, clock.clone()).with_clock(clock.clone());
+    let config = MqttConfig {
+        username: Some("user".into()),
+        ..MqttConfig::default()
+    };
+
+    let mut publisher = MqttPublisher::start(sensor, TcpStream::connect(address).unwrap(), &config).unwrap();
+    assert!(!publisher.poll().unwrap());
+    clock.advance(1_000);
+    assert!(publisher.poll().unwrap());
+    let sim = publisher.shutdown().unwrap().destroy();
+    assert_eq!(sim.state(), SimState::Sleeping);
+
+    let packets = broker.join().unwrap();
+    match &packets[0] {
+        Packet::Connect(connect) => {
+            assert_eq!(connect.client_id, "sps30_MQTT0001");
+            assert_eq!(connect.username.as_deref(), Some("user"));
+            assert_eq!(connect.will, Some(Message::new("sps30/MQTT0001/availability", "offline", true)));
+        }
+        p => panic!("unexpected packet: {:?}", p),
+    }
+    assert_eq!(packets.last(), Some(&Packet::Disconnect));
+
+    let messages = published(&packets);
+    assert_eq!(messages.len(), 10 + 1 + 11 + 1);
+    assert!(messages[..10].iter().all(|m| m.topic.ends_with("/config") && m.retain));
+    assert_eq!(*messages[10], Message::new("sps30/MQTT0001/availability", "online", true));
+    assert_eq!(messages[11].topic, "sps30/MQTT0001/mass_pm1_0");
+    assert!(!messages[11].retain);
+    assert_eq!(*messages[21], Message::new("sps30/MQTT0001/flags", "[\"warming_up\"]", false));
+    assert_eq!(*messages[22], Message::new("sps30/MQTT0001/availability", "offline", true));
+}
@@ -0,0 +1,181 @@
+#![cfg(feature = "std")]
+
+use sps30_i2c::output::{Format, OutputWriter, Record};
+use sps30_i2c::quality::QualityFlags;
+use sps30_i2c::AirInfo;
+
+const AIR_INFO: AirInfo = AirInfo {
+    mass_pm1_0: 1.5,
+    mass_pm2_5: 2.5,
+    mass_pm4_0: 3.0,
+    mass_pm10: 3.5,
+    number_pm0_5: 10.0,
+    number_pm1_0: 12.0,
+    number_pm2_5: 12.5,
+    number_pm4_0: 13.0,
+    number_pm10: 13.25,
+    typical_size: 0.625,
+};
+
+fn records() -> [Record<'static>; 2] {
+    [
+        Record {
+            timestamp: 1_700_000_000_000,
+            serial: "ABCD1234",
+            air_info: AIR_INFO,
+            flags: Some(QualityFlags::WARMING_UP | QualityFlags::FAN_CLEANING),
+        },
+        Record {
+            timestamp: 1_700_000_001_000,
+            serial: "ABCD1234",
+            air_info: AIR_INFO,
+            flags: None,
+        },
+    ]
+}
+
+fn render(format: Format) -> String {
+    let mut writer = OutputWriter::new(Vec::new(), format);
+    for record in records().iter() {
+        writer.write(record).unwrap();
+    }
+    String::from_utf8(writer.into_inner()).unwrap()
+}
+
+#[test]
+fn test_format_names() {
+    for format in Format::ALL.iter() {
+        assert_eq!(format.to_string().parse::<Format>().unwrap(), *format);
+    }
+    assert_eq!("jsonl".parse::<Format>().unwrap(), Format::JsonLines);
+    assert!("xml".parse::<Format>().is_err());
+}
+
+#[test]
+fn test_table() {
+    let output = render(Format::Table);
+    let lines: Vec<_> = output.lines().collect();
+
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("    timestamp serial           "));
+    assert!(lines[0].ends_with("typical_size flags"));
+    assert!(lines[1].starts_with("1700000000000 ABCD1234         "));
+    assert!(lines[1].ends_with("       0.625 warming_up,fan_cleaning"));
+    assert!(lines[2].ends_with("       0.625 "));
+}
+
+#[test]
+fn test_csv() {
+    assert_eq!(render(Format::Csv), "\
+timestamp,serial,mass_pm1_0,mass_pm2_5,mass_pm4_0,mass_pm10,number_pm0_5,number_pm1_0,number_pm2_5,number_pm4_0,number_pm10,typical_size,flags
+1700000000000,ABCD1234,1.5,2.5,3,3.5,10,12,12.5,13,13.25,0.625,warming_up|fan_cleaning
+1700000001000,ABCD1234,1.5,2.5,3,3.5,10,12,12.5,13,13.25,0.625,
+");
+}
+
+#[test]
+fn test_json_lines() {
+    assert_eq!(render(Format::JsonLines), concat!(
+        r#"{"timestamp":1700000000000,"serial":"ABCD1234","mass_pm1_0":1.5,"mass_pm2_5":2.5,"mass_pm4_0":3,"#,
+        r#""mass_pm10":3.5,"number_pm0_5":10,"number_pm1_0":12,"number_pm2_5":12.5,"number_pm4_0":13,"#,
+        r#""number_pm10":13.25,"typical_size":0.625,"flags":["warming_up","fan_cleaning"]}"#, "\n",
+        r#"{"timestamp":1700000001000,"serial":"ABCD1234","mass_pm1_0":1.5,"mass_pm2_5":2.5,"mass_pm4_0":3,"#,
+        r#""mass_pm10":3.5,"number_pm0_5":10,"number_pm1_0":12,"number_pm2_5":12.5,"number_pm4_0":13,"#,
+        r#""number_pm10":13.25,"typical_size":0.625}"#, "\n",
+    ));
+
+    for line in render(Format::JsonLines).lines() {
+        let value: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(value["serial"], "ABCD1234");
+    }
+}
+
+#[test]
+fn test_json_lines_escaping() {
+    let mut air_info = AIR_INFO;
+    air_info.mass_pm10 = f32::NAN;
+    let record = Record { timestamp: 0, serial: "A\"B", air_info, flags: Some(QualityFlags::empty()) };
+
+    let mut writer = OutputWriter::new(Vec::new(), Format::JsonLines);
+    writer.write(&record).unwrap();
+    let output = String::from_utf8(writer.into_inner()).unwrap();
+
+    let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(value["serial"], "A\"B");
+    assert!(value["mass_pm10"].is_null());
+    assert_eq!(value["flags"], serde_json::json!([]));
+}
+
+#[test]
+fn test_influx() {
+    assert_eq!(render(Format::Influx), "\
+sps30,serial=ABCD1234 mass_pm1_0=1.5,mass_pm2_5=2.5,mass_pm4_0=3,mass_pm10=3.5,number_pm0_5=10,number_pm1_0=12,\
+number_pm2_5=12.5,number_pm4_0=13,number_pm10=13.25,typical_size=0.625,flags=192i 1700000000000000000
+sps30,serial=ABCD1234 mass_pm1_0=1.5,mass_pm2_5=2.5,mass_pm4_0=3,mass_pm10=3.5,number_pm0_5=10,number_pm1_0=12,\
+number_pm2_5=12.5,number_pm4_0=13,number_pm10=13.25,typical_size=0.625 1700000001000000000
+");
+}
+
+#[test]
+fn test_influx_without_fields() {
+    let mut record = records()[1];
+    let nan = AirInfo {
+        mass_pm1_0: f32::NAN,
+        mass_pm2_5: f32::NAN,
+        mass_pm4_0: f32::NAN,
+        mass_pm10: f32::NAN,
+        number_pm0_5: f32::NAN,
+        number_pm1_0: f32::NAN,
+        number_pm2_5: f32::NAN,
+        number_pm4_0: f32::NAN,
+        number_pm10: f32::INFINITY,
+        typical_size: f32::NAN,
+    };
+    let mut writer = OutputWriter::new(Vec::new(), Format::Influx);
+    writer.write(&Record { air_info: nan, ..record }).unwrap();
+    record.air_info.mass_pm1_0 = f32::NAN;
+    writer.write(&record).unwrap();
+
+    let output = String::from_utf8(writer.into_inner()).unwrap();
+    assert_eq!(output.lines().count(), 1);
+    assert!(output.starts_with("sps30,serial=ABCD1234 mass_pm2_5=2.5,"));
+}
+
+#[test]
+fn test_prometheus() {
+    let output = render(Format::Prometheus);
+    let blocks: Vec<_> = output.split("\n\n").collect();
+    assert_eq!(blocks.len(), 2);
+
+    let first: Vec<_> = blocks[0].lines().collect();
+    assert_eq!(first.len(), 10 * 3 + 2 + 8);
+    assert_eq!(&first[..3], &[
+        "# HELP sps30_mass_pm1_0 Mass concentration PM1.0 [ug/m3]",
+        "# TYPE sps30_mass_pm1_0 gauge",
+        "sps30_mass_pm1_0{serial=\"ABCD1234\"} 1.5 1700000000000",
+    ]);
+    assert!(first.contains(&"sps30_typical_size{serial=\"ABCD1234\"} 0.625 1700000000000"));
+    assert!(first.contains(&"sps30_quality_flag{serial=\"ABCD1234\",flag=\"warming_up\"} 1 1700000000000"));
+    assert!(first.contains(&"sps30_quality_flag{serial=\"ABCD1234\",flag=\"negative\"} 0 1700000000000"));
+
+    // The metrics are described once
+    assert_eq!(blocks[1].lines().count(), 10);
+    assert!(!blocks[1].contains("# HELP") && !blocks[1].contains("# TYPE"));
+    assert!(!blocks[1].contains("sps30_quality_flag"));
+}
+
+#[test]
+fn test_prometheus_flags_described_once() {
+    let [mut first, mut second] = records();
+    first.flags = None;
+    second.flags = Some(QualityFlags::WARMING_UP);
+    let mut writer = OutputWriter::new(Vec::new(), Format::Prometheus);
+    for record in [first, second, second].iter() {
+        writer.write(record).unwrap();
+    }
+    let output = String::from_utf8(writer.into_inner()).unwrap();
+
+    assert_eq!(output.matches("# TYPE sps30_quality_flag gauge").count(), 1);
+    assert_eq!(output.matches("# TYPE sps30_mass_pm1_0 gauge").count(), 1);
+    assert_eq!(output.matches("sps30_quality_flag{serial=\"ABCD1234\",flag=\"warming_up\"} 1").count(), 2);
+}
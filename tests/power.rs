@@ -0,0 +1,96 @@
+#![cfg(feature = "float")]
+
+use sps30_i2c::duty_cycle::DutyCycleConfig;
+use sps30_i2c::power::{Mode, PowerModel, Residency, DAY_MS};
+use sps30_i2c::testing::{self, ManualClock, DEV_ADDR};
+use sps30_i2c::{OutputFormat, Sps30};
+use embedded_hal_mock::{delay::MockNoop as NoopDelay, i2c::Mock as I2cMock,
+    i2c::Transaction as I2cTrans};
+
+#[test]
+fn test_duty_cycle_residency() {
+    let residency = Residency::for_duty_cycle(&DutyCycleConfig::default(), 0);
+
+    assert_eq!(residency.total_ms(), DAY_MS);
+    assert_eq!(residency.measurement_ms, 144 * 30_000);
+    assert_eq!(residency.idle_ms, 144 * 30);
+    assert_eq!(residency.fan_cleaning_ms, 0);
+
+    let residency = Residency::for_duty_cycle(&DutyCycleConfig::default(), 3_600);
+    assert_eq!(residency.total_ms(), DAY_MS);
+    assert_eq!(residency.fan_cleaning_ms, 12_000);
+    assert_eq!(residency.measurement_ms, 144 * 30_000 - 12_000);
+}
+
+#[test]
+fn test_continuous_measurement_residency() {
+    let config = DutyCycleConfig { period_ms: 1_000, warm_up_ms: 0, samples: 1, ..DutyCycleConfig::default() };
+    let residency = Residency::for_duty_cycle(&config, 0);
+
+    assert_eq!(residency.measurement_ms, DAY_MS);
+    assert_eq!(residency.sleep_ms, 0);
+}
+
+#[test]
+fn test_charge_per_day() {
+    let model = PowerModel::default();
+    let charge = model.charge_per_day_mah(&DutyCycleConfig::default(), 0);
+
+    assert!((charge - 72.87).abs() < 0.01);
+
+    let residency = Residency::for_duty_cycle(&DutyCycleConfig::default(), 0);
+    assert!((model.average_current_ma(&residency) - charge / 24.0).abs() < 1e-3);
+    assert_eq!(model.average_current_ma(&Residency::default()), 0.0);
+    assert_eq!(model.current_ma(Mode::Sleep), 0.038);
+}
+
+#[test]
+fn test_mode_residency_log() {
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::Float)),
+        I2cTrans::write(DEV_ADDR, testing::start_fan_cleaning()),
+        I2cTrans::write(DEV_ADDR, testing::stop_measurement()),
+        I2cTrans::write(DEV_ADDR, testing::sleep()),
+    ];
+    let clock = ManualClock::new();
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay)
+        .with_clock(clock.clone());
+
+    clock.set(1_000);
+    sensor.start_measurement().unwrap();
+    clock.set(5_000);
+    sensor.start_fan_cleaning().unwrap();
+    clock.set(8_000);
+    assert_eq!(sensor.mode(), Mode::FanCleaning);
+    clock.set(20_000);
+    assert_eq!(sensor.mode(), Mode::Measurement);
+    sensor.stop_measurement().unwrap();
+    clock.set(21_000);
+    sensor.sleep().unwrap();
+    clock.set(31_000);
+
+    assert_eq!(sensor.mode(), Mode::Sleep);
+    assert_eq!(sensor.mode_residency(), Residency {
+        idle_ms: 2_000,
+        measurement_ms: 9_000,
+        fan_cleaning_ms: 10_000,
+        sleep_ms: 10_000,
+    });
+
+    sensor.reset_mode_residency();
+    assert_eq!(sensor.mode_residency().total_ms(), 0);
+
+    sensor.destroy();
+}
+
+#[test]
+fn test_no_clock_logs_nothing() {
+    let expectations = [I2cTrans::write(DEV_ADDR, testing::sleep())];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    sensor.sleep().unwrap();
+    assert_eq!(sensor.mode(), Mode::Sleep);
+    assert_eq!(sensor.mode_residency(), Residency::default());
+
+    sensor.destroy();
+}
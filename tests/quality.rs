@@ -0,0 +1,181 @@
+use sps30_i2c::quality::QualityFlags;
+use sps30_i2c::testing::{self, DEV_ADDR};
+#[cfg(feature = "float")]
+use sps30_i2c::AirInfo;
+use sps30_i2c::{AirInfoU16, OutputFormat, Sps30};
+use embedded_hal_mock::{delay::MockNoop as NoopDelay, i2c::Mock as I2cMock,
+    i2c::Transaction as I2cTrans};
+
+#[cfg(feature = "float")]
+fn good_air_info() -> AirInfo {
+    AirInfo {
+        mass_pm1_0: 5.0,
+        mass_pm2_5: 6.0,
+        mass_pm4_0: 6.5,
+        mass_pm10: 6.7,
+        number_pm0_5: 30.0,
+        number_pm1_0: 36.0,
+        number_pm2_5: 37.0,
+        number_pm4_0: 37.1,
+        number_pm10: 37.2,
+        typical_size: 0.5,
+    }
+}
+
+fn good_air_info_u16() -> AirInfoU16 {
+    AirInfoU16 {
+        mass_pm1_0: 5,
+        mass_pm2_5: 6,
+        mass_pm4_0: 7,
+        mass_pm10: 7,
+        number_pm0_5: 30,
+        number_pm1_0: 36,
+        number_pm2_5: 37,
+        number_pm4_0: 37,
+        number_pm10: 38,
+        typical_size: 500,
+    }
+}
+
+#[test]
+fn test_flags() {
+    let mut flags = QualityFlags::empty();
+    assert!(flags.is_empty());
+
+    flags |= QualityFlags::NEGATIVE;
+    flags.insert(QualityFlags::NOT_FINITE);
+    assert!(flags.contains(QualityFlags::NEGATIVE | QualityFlags::NOT_FINITE));
+    assert!(!flags.contains(QualityFlags::MASS_OUT_OF_RANGE));
+
+    flags.remove(QualityFlags::NEGATIVE);
+    assert_eq!(flags, QualityFlags::from_bits(QualityFlags::NOT_FINITE.bits()));
+
+    flags |= QualityFlags::FAN_CLEANING;
+    assert_eq!(flags.names().collect::<Vec<_>>(), ["not_finite", "fan_cleaning"]);
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_validate_good_reading() {
+    let reading = good_air_info().validate();
+
+    assert!(reading.is_valid());
+    assert_eq!(reading.air_info, good_air_info());
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_validate_zero_reading() {
+    let air_info = AirInfo {
+        mass_pm1_0: 0.0,
+        mass_pm2_5: 0.0,
+        mass_pm4_0: 0.0,
+        mass_pm10: 0.0,
+        number_pm0_5: 0.0,
+        number_pm1_0: 0.0,
+        number_pm2_5: 0.0,
+        number_pm4_0: 0.0,
+        number_pm10: 0.0,
+        typical_size: 0.0,
+    };
+
+    assert!(air_info.validate().is_valid());
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_validate_bad_readings() {
+    let mut air_info = good_air_info();
+    air_info.mass_pm2_5 = f32::NAN;
+    assert!(air_info.validate().flags.contains(QualityFlags::NOT_FINITE));
+
+    let mut air_info = good_air_info();
+    air_info.mass_pm1_0 = -1.0;
+    assert!(air_info.validate().flags.contains(QualityFlags::NEGATIVE));
+
+    let mut air_info = good_air_info();
+    air_info.mass_pm2_5 = 7.0;
+    assert_eq!(air_info.validate().flags, QualityFlags::MASS_NOT_CUMULATIVE);
+
+    let mut air_info = good_air_info();
+    air_info.number_pm0_5 = 40.0;
+    assert_eq!(air_info.validate().flags, QualityFlags::NUMBER_NOT_CUMULATIVE);
+
+    let mut air_info = good_air_info();
+    air_info.mass_pm10 = 1200.0;
+    assert_eq!(air_info.validate().flags, QualityFlags::MASS_OUT_OF_RANGE);
+
+    let mut air_info = good_air_info();
+    air_info.typical_size = 12.0;
+    assert_eq!(air_info.validate().flags, QualityFlags::TYPICAL_SIZE_OUT_OF_RANGE);
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_read_validated_values() {
+    let data: Vec<u8> = (0..10)
+        .flat_map(|i| if i == 1 { f32::NAN } else { 0.0 }.to_be_bytes())
+        .collect();
+
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, testing::with_crc(&data)),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    let reading = sensor.read_validated_values().unwrap();
+    assert!(!reading.is_valid());
+    assert!(reading.flags.contains(QualityFlags::NOT_FINITE));
+
+    sensor.destroy();
+}
+
+#[test]
+fn test_validate_u16() {
+    let reading = good_air_info_u16().validate();
+    assert!(reading.is_valid());
+    assert_eq!(reading.air_info, good_air_info_u16());
+    assert!(AirInfoU16::default().validate().is_valid());
+
+    let mut air_info = good_air_info_u16();
+    air_info.mass_pm2_5 = AirInfoU16::NOT_AVAILABLE;
+    assert!(air_info.validate().flags.contains(QualityFlags::NOT_FINITE));
+    assert!(!air_info.validate().flags.contains(QualityFlags::MASS_OUT_OF_RANGE));
+
+    let mut air_info = good_air_info_u16();
+    air_info.mass_pm2_5 = 8;
+    assert_eq!(air_info.validate().flags, QualityFlags::MASS_NOT_CUMULATIVE);
+
+    let mut air_info = good_air_info_u16();
+    air_info.number_pm0_5 = 40;
+    assert_eq!(air_info.validate().flags, QualityFlags::NUMBER_NOT_CUMULATIVE);
+
+    let mut air_info = good_air_info_u16();
+    air_info.mass_pm10 = 1200;
+    assert_eq!(air_info.validate().flags, QualityFlags::MASS_OUT_OF_RANGE);
+
+    let mut air_info = good_air_info_u16();
+    air_info.typical_size = 12_000;
+    assert_eq!(air_info.validate().flags, QualityFlags::TYPICAL_SIZE_OUT_OF_RANGE);
+}
+
+#[test]
+fn test_read_validated_values_u16() {
+    let mut air_info = good_air_info_u16();
+    air_info.mass_pm1_0 = 9;
+
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::UInt16)),
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, testing::measured_values_frame_u16(&air_info)),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    sensor.start_measurement_with_format(OutputFormat::UInt16).unwrap();
+    let reading = sensor.read_validated_values_u16().unwrap();
+    assert_eq!(reading.air_info, air_info);
+    assert_eq!(reading.flags, QualityFlags::MASS_NOT_CUMULATIVE);
+    assert_eq!(reading.timestamp, None);
+
+    sensor.destroy();
+}
@@ -0,0 +1,149 @@
+use sps30_i2c::record::{BufferLog, Direction, InvalidLog, LogFull, LogReader, Recorder, Replay,
+    ReplayError, MAGIC};
+use sps30_i2c::testing::{self, ManualClock, DEV_ADDR};
+use sps30_i2c::{Error, OutputFormat, Sps30};
+use embedded_hal_mock::{delay::MockNoop as NoopDelay, i2c::Mock as I2cMock,
+    i2c::Transaction as I2cTrans, MockError};
+use std::io::ErrorKind;
+
+fn expectations() -> Vec<I2cTrans> {
+    vec![
+        I2cTrans::write(DEV_ADDR, testing::read_firmware_version()),
+        I2cTrans::read(DEV_ADDR, testing::firmware_version_frame(2, 2)),
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::default())),
+        I2cTrans::write(DEV_ADDR, testing::read_data_ready_flag()),
+        I2cTrans::read(DEV_ADDR, vec![0x00, 0x00, 0x00]).with_error(MockError::Io(ErrorKind::Other)),
+    ]
+}
+
+fn record(buffer: &mut [u8]) -> usize {
+    let clock = ManualClock::new();
+    let recorder = Recorder::new(I2cMock::new(&expectations()), clock.clone(), BufferLog::new(buffer));
+    let mut sensor = Sps30::new_sps30(recorder, NoopDelay);
+
+    clock.advance(300);
+    assert_eq!(sensor.read_firmware_version().unwrap(), (2, 2));
+    clock.advance(200);
+    sensor.start_measurement().unwrap();
+    clock.advance(1_000);
+    match sensor.read_data_ready_flag() {
+        Err(Error::I2C(MockError::Io(ErrorKind::Other))) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    let (mut i2c, _, log) = sensor.destroy().destroy();
+    i2c.done();
+    log.as_slice().len()
+}
+
+#[test]
+fn test_record() {
+    let mut buffer = [0; 64];
+    let len = record(&mut buffer);
+    let log = &buffer[..len];
+    assert!(log.starts_with(&MAGIC));
+
+    let entries: Vec<_> = LogReader::new(log).unwrap().map(Result::unwrap).collect();
+    assert_eq!(entries.len(), 5);
+
+    assert_eq!(entries[0].direction, Direction::Write);
+    assert_eq!(entries[0].data, &[0xD1, 0x00]);
+    assert_eq!(entries[0].timestamp, 300);
+    assert_eq!(entries[1].direction, Direction::Read);
+    assert_eq!(entries[1].data, &testing::firmware_version_frame(2, 2)[..]);
+    assert_eq!(entries[2].timestamp, 500);
+    assert_eq!(entries[2].len, 5);
+    assert_eq!(entries[4].timestamp, 1_500);
+    assert_eq!(entries[4].address, DEV_ADDR);
+    assert_eq!(entries[4].len, 3);
+    assert!(entries[4].data.is_empty());
+    assert!(entries[4].failed && !entries[3].failed);
+    assert_eq!(entries[4].error, Some("Io(Other)"));
+    assert_eq!(entries[3].error, None);
+}
+
+#[test]
+fn test_replay() {
+    let mut buffer = [0; 64];
+    let len = record(&mut buffer);
+
+    let mut sensor = Sps30::new_sps30(Replay::new(&buffer[..len]).unwrap(), NoopDelay);
+    assert_eq!(sensor.read_firmware_version().unwrap(), (2, 2));
+    sensor.start_measurement().unwrap();
+    match sensor.read_data_ready_flag() {
+        Err(Error::I2C(ReplayError::Recorded)) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    let replay = sensor.destroy();
+    assert!(replay.is_finished());
+    assert_eq!(replay.position(), 5);
+    assert_eq!(replay.timestamp(), 1_500);
+}
+
+#[test]
+fn test_replay_mismatch() {
+    let mut buffer = [0; 64];
+    let len = record(&mut buffer);
+
+    let mut sensor = Sps30::new_sps30(Replay::new(&buffer[..len]).unwrap(), NoopDelay);
+    match sensor.read_device_serial_number() {
+        Err(Error::I2C(ReplayError::Mismatch { index: 0 })) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_replay_end_of_log() {
+    let mut sensor = Sps30::new_sps30(Replay::new(&[]).unwrap(), NoopDelay);
+    match sensor.stop_measurement() {
+        Err(Error::I2C(ReplayError::EndOfLog)) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_invalid_log() {
+    assert!(Replay::new(b"SPS30").is_err());
+
+    let mut buffer = [0; 64];
+    let len = record(&mut buffer);
+    let entries: Vec<_> = LogReader::new(&buffer[..len - 1]).unwrap().collect();
+    assert_eq!(entries.last(), Some(&Err(InvalidLog)));
+}
+
+#[test]
+fn test_log_full() {
+    let mut buffer = [0; 8];
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, vec![0xD3, 0x04]),
+        I2cTrans::write(DEV_ADDR, testing::read_firmware_version()),
+        I2cTrans::read(DEV_ADDR, testing::firmware_version_frame(2, 2)),
+    ];
+    let recorder = Recorder::new(I2cMock::new(&expectations), ManualClock::new(), BufferLog::new(&mut buffer));
+    let mut sensor = Sps30::new_sps30(recorder, NoopDelay);
+
+    // The transfers still succeed, the recording stops
+    sensor.device_reset().unwrap();
+    assert_eq!(sensor.read_firmware_version().unwrap(), (2, 2));
+
+    let recorder = sensor.destroy();
+    assert_eq!(recorder.log_error(), Some(&LogFull));
+    let (mut i2c, _, log) = recorder.destroy();
+    i2c.done();
+    assert_eq!(log.as_slice().len(), 8);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_io_log() {
+    use sps30_i2c::record::IoLog;
+
+    let expectations = [I2cTrans::write(DEV_ADDR, vec![0xD3, 0x04])];
+    let recorder = Recorder::new(I2cMock::new(&expectations), ManualClock::new(), IoLog(Vec::new()));
+    let mut sensor = Sps30::new_sps30(recorder, NoopDelay);
+    sensor.device_reset().unwrap();
+
+    let (_, _, IoLog(log)) = sensor.destroy().destroy();
+    assert_eq!(log, [&MAGIC[..], &[0x00, DEV_ADDR, 0x00, 0x02, 0xD3, 0x04]].concat());
+}
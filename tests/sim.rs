@@ -0,0 +1,253 @@
+#![cfg(feature = "sim")]
+
+use sps30_i2c::sim::{Fault, RandomProfile, ScriptedProfile, SimClock, SimError, SimState, VirtualSps30,
+    STATUS_FAN, STATUS_LASER};
+use sps30_i2c::{AirInfo, Error, Sps30};
+use embedded_hal::blocking::i2c::{Read, Write};
+
+const DEV_ADDR: u8 = 0x69;
+
+fn air_info(mass: f32) -> AirInfo {
+    AirInfo {
+        mass_pm1_0: mass,
+        mass_pm2_5: mass,
+        mass_pm4_0: mass,
+        mass_pm10: mass,
+        number_pm0_5: 1.0,
+        number_pm1_0: 1.0,
+        number_pm2_5: 1.0,
+        number_pm4_0: 1.0,
+        number_pm10: 1.0,
+        typical_size: 0.5,
+    }
+}
+
+fn scripted() -> (Sps30<VirtualSps30<ScriptedProfile, SimClock>, SimClock>, SimClock) {
+    let clock = SimClock::new();
+    let profile = ScriptedProfile::new(vec![air_info(1.0), air_info(2.0)]);
+    let sim = VirtualSps30::new(profile, clock.clone());
+    (Sps30::new_sps30(sim, clock.clone()), clock)
+}
+
+fn is_nack<T: std::fmt::Debug>(res: Result<T, Error<SimError>>) -> bool {
+    matches!(res, Err(Error::I2C(SimError::Nack)))
+}
+
+#[test]
+fn test_device_information() {
+    let clock = SimClock::new();
+    let mut sim = VirtualSps30::new(RandomProfile::new(7), clock.clone());
+    sim.set_serial_number(b"ABCDEF0123456789");
+    sim.set_firmware_version(2, 1);
+    sim.set_status_register(1 << 21 | 1 << 4);
+    let mut sensor = Sps30::new_sps30(sim, clock);
+
+    assert_eq!(&sensor.read_device_product_type().unwrap(), b"00080000");
+    assert_eq!(&sensor.read_device_serial_number().unwrap()[..17], b"ABCDEF0123456789\0");
+    assert_eq!(sensor.read_firmware_version().unwrap(), (2, 1));
+
+    let status = sensor.read_device_status_register().unwrap();
+    assert!(status.speed && status.fan && !status.laser);
+    sensor.clear_device_status_register().unwrap();
+    assert!(!sensor.read_device_status_register().unwrap().speed);
+
+    sensor.write_auto_cleaning_interval(3_600).unwrap();
+    assert_eq!(sensor.read_auto_cleaning_interval().unwrap(), 3_600);
+}
+
+#[test]
+fn test_data_ready_at_1_hz() {
+    let (mut sensor, clock) = scripted();
+
+    sensor.start_measurement().unwrap();
+    assert!(!sensor.read_data_ready_flag().unwrap());
+
+    clock.advance(1_000);
+    assert!(sensor.read_data_ready_flag().unwrap());
+    assert_eq!(sensor.read_measured_values().unwrap(), air_info(1.0));
+    assert!(!sensor.read_data_ready_flag().unwrap());
+    // Reading again returns the last measurement
+    assert_eq!(sensor.read_measured_values().unwrap(), air_info(1.0));
+
+    clock.advance(1_000);
+    assert!(sensor.read_data_ready_flag().unwrap());
+    assert_eq!(sensor.read_measured_values().unwrap(), air_info(2.0));
+
+    clock.advance(5_000);
+    assert_eq!(sensor.read_measured_values().unwrap(), air_info(1.0));
+}
+
+#[test]
+fn test_illegal_commands_are_nacked() {
+    let (mut sensor, _) = scripted();
+
+    // Idle
+    assert!(is_nack(sensor.stop_measurement()));
+    assert!(is_nack(sensor.read_data_ready_flag()));
+    assert!(is_nack(sensor.read_measured_values()));
+    assert!(is_nack(sensor.start_fan_cleaning()));
+    assert!(is_nack(sensor.wake_up()));
+
+    // Measuring
+    sensor.start_measurement().unwrap();
+    assert!(is_nack(sensor.start_measurement()));
+    assert!(is_nack(sensor.sleep()));
+    sensor.start_fan_cleaning().unwrap();
+    sensor.stop_measurement().unwrap();
+
+    // Sleeping
+    sensor.sleep().unwrap();
+    assert!(is_nack(sensor.start_measurement()));
+    assert!(is_nack(sensor.read_firmware_version()));
+    assert!(is_nack(sensor.device_reset()));
+    sensor.wake_up().unwrap();
+    sensor.read_firmware_version().unwrap();
+
+    let sim = sensor.destroy();
+    assert_eq!(sim.state(), SimState::Idle);
+}
+
+#[test]
+fn test_device_reset() {
+    let (mut sensor, _) = scripted();
+
+    sensor.start_measurement().unwrap();
+    sensor.device_reset().unwrap();
+    assert!(is_nack(sensor.read_measured_values()));
+
+    assert_eq!(sensor.destroy().state(), SimState::Idle);
+}
+
+#[test]
+fn test_fan_cleaning() {
+    let (mut sensor, clock) = scripted();
+
+    sensor.start_measurement().unwrap();
+    sensor.start_fan_cleaning().unwrap();
+    clock.advance(9_000);
+    let mut sim = sensor.destroy();
+    assert!(sim.is_fan_cleaning());
+
+    clock.advance(1_000);
+    assert!(!sim.is_fan_cleaning());
+}
+
+#[test]
+fn test_raw_transfers() {
+    let clock = SimClock::new();
+    let mut sim = VirtualSps30::new(ScriptedProfile::new(vec![air_info(12.7)]), clock.clone());
+
+    // Wrong address
+    assert_eq!(sim.write(0x42, &[0xD1, 0x00]), Err(SimError::Nack));
+    // Bad CRC on the argument
+    assert_eq!(sim.write(DEV_ADDR, &[0x00, 0x10, 0x05, 0x00, 0x00]), Err(SimError::Nack));
+    // Unknown command
+    assert_eq!(sim.write(DEV_ADDR, &[0x12, 0x34]), Err(SimError::Nack));
+    // Read without a pointer
+    let mut buffer = [0; 3];
+    assert_eq!(sim.read(DEV_ADDR, &mut buffer), Err(SimError::Nack));
+
+    // Unsigned 16-bit integer output format
+    sim.write(DEV_ADDR, &[0x00, 0x10, 0x05, 0x00, 0xF6]).unwrap();
+    assert_eq!(sim.state(), SimState::Measuring);
+    clock.advance(1_000);
+    sim.write(DEV_ADDR, &[0x03, 0x00]).unwrap();
+    let mut buffer = [0; 30];
+    sim.read(DEV_ADDR, &mut buffer).unwrap();
+    assert_eq!(&buffer[..2], &[0x00, 0x0C]);
+    // The typical particle size in nm
+    assert_eq!(&buffer[27..29], &500u16.to_be_bytes());
+}
+
+#[test]
+fn test_random_profile_is_consistent() {
+    let mut profile = RandomProfile::new(42);
+
+    for t in 0..1_000 {
+        let air_info = sps30_i2c::sim::Profile::air_info(&mut profile, t);
+        assert!(air_info.validate().is_valid());
+    }
+}
+
+#[test]
+fn test_fault_nack() {
+    let (mut sensor, _) = scripted();
+    sensor.start_measurement().unwrap();
+
+    let mut sim = sensor.destroy();
+    // Transactions 1 and 2 are the write and the read of the data-ready flag
+    sim.schedule(sim.transactions() + 1, Fault::Nack);
+    let mut sensor = Sps30::new_sps30(sim, SimClock::new());
+    assert!(is_nack(sensor.read_data_ready_flag()));
+    sensor.read_data_ready_flag().unwrap();
+}
+
+#[test]
+fn test_fault_corrupt_crc() {
+    let (mut sensor, clock) = scripted();
+    sensor.start_measurement().unwrap();
+    clock.advance(1_000);
+
+    let mut sim = sensor.destroy();
+    sim.inject(Fault::CorruptCrc(9));
+    let mut sensor = Sps30::new_sps30(sim, clock);
+    match sensor.read_measured_values() {
+        Err(Error::ChecksumMismatch) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(sensor.read_measured_values().unwrap(), air_info(1.0));
+}
+
+#[test]
+fn test_fault_stuck_data_ready_and_frozen_measurement() {
+    let (mut sensor, clock) = scripted();
+    sensor.start_measurement().unwrap();
+    clock.advance(1_000);
+    assert_eq!(sensor.read_measured_values().unwrap(), air_info(1.0));
+
+    let mut sim = sensor.destroy();
+    sim.inject(Fault::StuckDataReady(true));
+    sim.inject(Fault::FrozenMeasurement);
+    let mut sensor = Sps30::new_sps30(sim, clock.clone());
+    assert!(sensor.read_data_ready_flag().unwrap());
+    clock.advance(2_000);
+    assert_eq!(sensor.read_measured_values().unwrap(), air_info(1.0));
+    assert!(sensor.read_data_ready_flag().unwrap());
+
+    let mut sim = sensor.destroy();
+    sim.inject(Fault::StuckDataReady(false));
+    let mut sensor = Sps30::new_sps30(sim, clock.clone());
+    clock.advance(1_000);
+    assert!(!sensor.read_data_ready_flag().unwrap());
+
+    let mut sim = sensor.destroy();
+    sim.clear_faults();
+    let mut sensor = Sps30::new_sps30(sim, clock);
+    assert!(sensor.read_data_ready_flag().unwrap());
+    assert_eq!(sensor.read_measured_values().unwrap(), air_info(2.0));
+}
+
+#[test]
+fn test_fault_status_bits() {
+    let (sensor, clock) = scripted();
+
+    let mut sim = sensor.destroy();
+    sim.inject(Fault::StatusBits(STATUS_FAN | STATUS_LASER));
+    let mut sensor = Sps30::new_sps30(sim, clock);
+    let status = sensor.read_device_status_register().unwrap();
+    assert!(status.fan && status.laser && !status.speed);
+}
+
+#[test]
+fn test_fault_power_loss() {
+    let (mut sensor, clock) = scripted();
+    sensor.write_auto_cleaning_interval(3_600).unwrap();
+    sensor.start_measurement().unwrap();
+
+    let mut sim = sensor.destroy();
+    sim.schedule(sim.transactions(), Fault::PowerLoss);
+    let mut sensor = Sps30::new_sps30(sim, clock);
+    assert!(is_nack(sensor.read_measured_values()));
+    assert_eq!(sensor.read_auto_cleaning_interval().unwrap(), 3_600);
+    sensor.start_measurement().unwrap();
+}
@@ -1,51 +1,33 @@
-use sps30_i2c::Sps30;
+use sps30_i2c::testing::{self, calc_crc, DEV_ADDR, Register};
+use sps30_i2c::{AirInfoU16, FieldSelection, OutputFormat, PartialAirInfo, Sps30};
 use embedded_hal_mock::{delay::MockNoop as NoopDelay, i2c::Mock as I2cMock,
-    i2c::Transaction as I2cTrans};
-
-const DEV_ADDR: u8 = 0x69;
-struct Register {}
-impl Register {
-    pub const START_MEASUREMENT: [u8; 2] = [0x00, 0x10];
-    pub const STOP_MEASUREMENT: [u8; 2] = [0x01, 0x04];
-    pub const READ_DATA_READY_FLAG: [u8; 2] = [0x02, 0x02];
-    pub const READ_MEASURED_VALUES: [u8; 2] = [0x03, 0x00];
-    pub const SLEEP: [u8; 2] = [0x10, 0x01];
-    pub const WAKE_UP: [u8; 2] = [0x11, 0x03];
-    pub const START_FAN_CLEANING: [u8; 2] = [0x56, 0x07];
-    pub const READ_WRITE_AUTO_CLEANING_INTERVAL: [u8; 2] = [0x80, 0x04];
-    pub const READ_DEVICE_PRODUCT_TYPE: [u8; 2] = [0xD0, 0x02];
-    pub const READ_DEVICE_SERIAL_NUMBER: [u8; 2] = [0xD0, 0x33];
-    pub const READ_FIRMWARE_VERSION: [u8; 2] = [0xD1, 0x00];
-    pub const READ_DEVICE_STATUS_REGISTER: [u8; 2] = [0xD2, 0x06];
-    pub const CLEAR_DEVICE_STATUS_REGISTER: [u8; 2] = [0xD2, 0x10];
-    pub const DEVICE_RESET: [u8; 2] = [0xD3, 0x04];
-}
+    i2c::Transaction as I2cTrans, MockError};
+use std::io::ErrorKind;
 
-fn calc_crc(data: &[u8; 2]) -> u8 {
-    let mut crc: u8 = 0xFF;
-
-    for i in 0..2 {
-        crc ^= data[i];
-        for _ in 0..8 {
-            if crc & 0x80 != 0 {
-                crc = (crc << 1) ^ 0x31;
-            } else {
-                crc = crc << 1;
-            }
-        }
-    }
+#[test]
+fn test_create_destroy() {
+    let sensor = Sps30::new_sps30(I2cMock::new(&[]), NoopDelay);
 
-    crc
+    sensor.destroy();
 }
 
 #[test]
-fn test_create_destroy() {
-    let sensor = Sps30::new_sps30(I2cMock::new(&[]), NoopDelay);
+fn test_with_address() {
+    let mut cmd: Vec<u8> = Vec::new();
+    cmd.extend_from_slice(&Register::STOP_MEASUREMENT);
+
+    let expectations = [
+        I2cTrans::write(0x42, cmd),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay).with_address(0x42);
+
+    sensor.stop_measurement().unwrap();
 
     sensor.destroy();
 }
 
 #[test]
+#[cfg(feature = "float")]
 fn test_start_measurement() {
     let mut cmd: Vec<u8> = Vec::new();
     cmd.extend_from_slice(&Register::START_MEASUREMENT);
@@ -61,6 +43,69 @@ fn test_start_measurement() {
     sensor.destroy();
 }
 
+#[test]
+fn test_start_measurement_uint16() {
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::UInt16)),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    sensor.start_measurement_with_format(OutputFormat::UInt16).unwrap();
+    assert_eq!(sensor.output_format(), OutputFormat::UInt16);
+
+    sensor.destroy();
+}
+
+#[test]
+fn test_ensure_measuring() {
+    let nack = || MockError::Io(ErrorKind::Other);
+    let [pulse, wake_up] = testing::wake_up();
+    let start = testing::start_measurement(OutputFormat::default());
+
+    // Idle: the wake-up command is not acknowledged
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, pulse.clone()),
+        I2cTrans::write(DEV_ADDR, wake_up.clone()).with_error(nack()),
+        I2cTrans::write(DEV_ADDR, start.clone()),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+    assert!(!sensor.ensure_measuring().unwrap());
+    sensor.destroy().done();
+
+    // Sleeping
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, pulse.clone()),
+        I2cTrans::write(DEV_ADDR, wake_up.clone()),
+        I2cTrans::write(DEV_ADDR, start.clone()),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+    assert!(!sensor.ensure_measuring().unwrap());
+    sensor.destroy().done();
+
+    // Left measuring: neither the wake-up nor the start are acknowledged
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, pulse.clone()),
+        I2cTrans::write(DEV_ADDR, wake_up.clone()).with_error(nack()),
+        I2cTrans::write(DEV_ADDR, start.clone()).with_error(nack()),
+        I2cTrans::write(DEV_ADDR, testing::stop_measurement()),
+        I2cTrans::write(DEV_ADDR, start.clone()),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+    assert!(sensor.ensure_measuring().unwrap());
+    sensor.destroy().done();
+
+    // Not answering at all
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, pulse),
+        I2cTrans::write(DEV_ADDR, wake_up).with_error(nack()),
+        I2cTrans::write(DEV_ADDR, start).with_error(nack()),
+        I2cTrans::write(DEV_ADDR, testing::stop_measurement()).with_error(nack()),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+    assert!(sensor.ensure_measuring().is_err());
+    sensor.destroy().done();
+}
+
 #[test]
 fn test_stop_measurement() {
     let mut cmd: Vec<u8> = Vec::new();
@@ -96,6 +141,7 @@ fn test_read_data_ready_flag() {
 }
 
 #[test]
+#[cfg(feature = "float")]
 fn test_read_measured_values() {
     let mut cmd: Vec<u8> = Vec::new();
     cmd.extend_from_slice(&Register::READ_MEASURED_VALUES);
@@ -118,6 +164,99 @@ fn test_read_measured_values() {
     sensor.destroy();
 }
 
+#[test]
+fn test_read_measured_values_u16() {
+    let values = AirInfoU16 {
+        mass_pm1_0: 12,
+        mass_pm2_5: 13,
+        mass_pm4_0: 14,
+        mass_pm10: 15,
+        number_pm0_5: 80,
+        number_pm1_0: 90,
+        number_pm2_5: 91,
+        number_pm4_0: 92,
+        number_pm10: 93,
+        typical_size: 523,
+    };
+    let mut expectations = vec![
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::UInt16)),
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, testing::measured_values_frame_u16(&values)),
+    ];
+    // Converted to float
+    if cfg!(feature = "float") {
+        expectations.push(I2cTrans::write(DEV_ADDR, testing::read_measured_values()));
+        expectations.push(I2cTrans::read(DEV_ADDR, testing::measured_values_frame_u16(&values)));
+    }
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    sensor.start_measurement_with_format(OutputFormat::UInt16).unwrap();
+    assert_eq!(sensor.read_measured_values_u16().unwrap(), values);
+    #[cfg(feature = "float")]
+    {
+        let air_info = sensor.read_measured_values().unwrap();
+        assert_eq!((air_info.mass_pm2_5, air_info.typical_size), (13.0, 0.523));
+    }
+
+    sensor.destroy();
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_read_selected_values() {
+    // Mass concentrations only
+    let data: Vec<u8> = [1.5f32, 2.5, 3.5, 4.5].iter().flat_map(|v| v.to_be_bytes()).collect();
+    let res = testing::with_crc(&data);
+    assert_eq!(res.len(), 24);
+
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, res),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    let air_info = sensor.read_selected_values(FieldSelection::Mass).unwrap();
+    assert_eq!(air_info, PartialAirInfo {
+        mass_pm1_0: 1.5,
+        mass_pm2_5: 2.5,
+        mass_pm4_0: 3.5,
+        mass_pm10: 4.5,
+        ..PartialAirInfo::default()
+    });
+
+    sensor.destroy();
+}
+
+#[test]
+fn test_read_selected_values_u16() {
+    // Mass and number concentrations, without the typical particle size
+    let data: Vec<u8> = [12u16, 13, 14, 15, 80, 90, 91, 92, 93].iter().flat_map(|v| v.to_be_bytes()).collect();
+
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::UInt16)),
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, testing::with_crc(&data)),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    sensor.start_measurement_with_format(OutputFormat::UInt16).unwrap();
+    let air_info = sensor.read_selected_values_u16(FieldSelection::MassAndNumber).unwrap();
+    assert_eq!(air_info, PartialAirInfo {
+        mass_pm1_0: 12,
+        mass_pm2_5: 13,
+        mass_pm4_0: 14,
+        mass_pm10: 15,
+        number_pm0_5: Some(80),
+        number_pm1_0: Some(90),
+        number_pm2_5: Some(91),
+        number_pm4_0: Some(92),
+        number_pm10: Some(93),
+        typical_size: None,
+    });
+
+    sensor.destroy();
+}
+
 #[test]
 fn test_sleep() {
     let mut cmd: Vec<u8> = Vec::new();
@@ -0,0 +1,138 @@
+#![cfg(feature = "testing")]
+
+use sps30_i2c::clock::Clock;
+use sps30_i2c::testing::{self, ManualClock, DEV_ADDR, Register};
+#[cfg(feature = "float")]
+use sps30_i2c::AirInfo;
+use sps30_i2c::{AirInfoU16, OutputFormat, Sps30};
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal_mock::{delay::MockNoop as NoopDelay, i2c::Mock as I2cMock,
+    i2c::Transaction as I2cTrans};
+
+#[test]
+fn test_with_crc() {
+    assert_eq!(testing::with_crc(&[0xBE, 0xEF, 0x00, 0x00]), [0xBE, 0xEF, 0x92, 0x00, 0x00, 0x81]);
+    assert_eq!(testing::command_frame(Register::START_MEASUREMENT, &[0x03, 0x00]),
+        [0x00, 0x10, 0x03, 0x00, 0xAC]);
+}
+
+#[test]
+fn test_commands() {
+    let [pulse, wake_up] = testing::wake_up();
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::default())),
+        I2cTrans::write(DEV_ADDR, testing::start_fan_cleaning()),
+        I2cTrans::write(DEV_ADDR, testing::stop_measurement()),
+        I2cTrans::write(DEV_ADDR, testing::write_auto_cleaning_interval(3_600)),
+        I2cTrans::write(DEV_ADDR, testing::sleep()),
+        I2cTrans::write(DEV_ADDR, pulse),
+        I2cTrans::write(DEV_ADDR, wake_up),
+        I2cTrans::write(DEV_ADDR, testing::clear_device_status_register()),
+        I2cTrans::write(DEV_ADDR, testing::device_reset()),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    sensor.start_measurement().unwrap();
+    sensor.start_fan_cleaning().unwrap();
+    sensor.stop_measurement().unwrap();
+    sensor.write_auto_cleaning_interval(3_600).unwrap();
+    sensor.sleep().unwrap();
+    sensor.wake_up().unwrap();
+    sensor.clear_device_status_register().unwrap();
+    sensor.device_reset().unwrap();
+
+    sensor.destroy().done();
+}
+
+#[test]
+fn test_responses() {
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::read_data_ready_flag()),
+        I2cTrans::read(DEV_ADDR, testing::data_ready_frame(true)),
+        I2cTrans::write(DEV_ADDR, testing::read_auto_cleaning_interval()),
+        I2cTrans::read(DEV_ADDR, testing::auto_cleaning_interval_frame(3_600)),
+        I2cTrans::write(DEV_ADDR, testing::read_device_product_type()),
+        I2cTrans::read(DEV_ADDR, testing::product_type_frame()),
+        I2cTrans::write(DEV_ADDR, testing::read_device_serial_number()),
+        I2cTrans::read(DEV_ADDR, testing::serial_number_frame(b"ABCD1234")),
+        I2cTrans::write(DEV_ADDR, testing::read_firmware_version()),
+        I2cTrans::read(DEV_ADDR, testing::firmware_version_frame(2, 2)),
+        I2cTrans::write(DEV_ADDR, testing::read_device_status_register()),
+        I2cTrans::read(DEV_ADDR, testing::status_register_frame(1 << 5)),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    assert!(sensor.read_data_ready_flag().unwrap());
+    assert_eq!(sensor.read_auto_cleaning_interval().unwrap(), 3_600);
+    assert_eq!(&sensor.read_device_product_type().unwrap(), b"00080000");
+    assert_eq!(&sensor.read_device_serial_number().unwrap()[..9], b"ABCD1234\0");
+    assert_eq!(sensor.read_firmware_version().unwrap(), (2, 2));
+    assert!(sensor.read_device_status_register().unwrap().laser);
+
+    sensor.destroy().done();
+}
+
+#[test]
+fn test_measured_values_u16() {
+    let air_info = AirInfoU16 {
+        mass_pm1_0: 1,
+        mass_pm2_5: 2,
+        mass_pm4_0: 3,
+        mass_pm10: 4,
+        number_pm0_5: 10,
+        number_pm1_0: 11,
+        number_pm2_5: 12,
+        number_pm4_0: 13,
+        number_pm10: 14,
+        typical_size: 600,
+    };
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::UInt16)),
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, testing::measured_values_frame_u16(&air_info)),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    sensor.start_measurement_with_format(OutputFormat::UInt16).unwrap();
+    assert_eq!(sensor.read_measured_values_u16().unwrap(), air_info);
+
+    sensor.destroy().done();
+}
+
+#[cfg(feature = "float")]
+#[test]
+fn test_measured_values() {
+    let air_info = AirInfo {
+        mass_pm1_0: 1.5,
+        mass_pm2_5: 2.5,
+        mass_pm4_0: 3.5,
+        mass_pm10: 4.5,
+        number_pm0_5: 10.0,
+        number_pm1_0: 11.0,
+        number_pm2_5: 12.0,
+        number_pm4_0: 13.0,
+        number_pm10: 14.0,
+        typical_size: 0.6,
+    };
+    let expectations = [
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, testing::measured_values_frame(&air_info)),
+    ];
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    assert_eq!(sensor.read_measured_values().unwrap(), air_info);
+
+    sensor.destroy().done();
+}
+
+#[test]
+fn test_manual_clock() {
+    let clock = ManualClock::new();
+    let mut shared = clock.clone();
+
+    clock.set(1_000);
+    assert_eq!(shared.now_ms(), 1_000);
+    clock.advance(500);
+    shared.delay_ms(250u32);
+    assert_eq!(clock.now(), 1_750);
+}
@@ -0,0 +1,160 @@
+use sps30_i2c::quality::QualityFlags;
+use sps30_i2c::warm_up::WarmUp;
+use sps30_i2c::testing::{self, ManualClock, DEV_ADDR};
+use sps30_i2c::{AirInfoU16, OutputFormat, Sps30};
+use embedded_hal_mock::{delay::MockNoop as NoopDelay, i2c::Mock as I2cMock,
+    i2c::Transaction as I2cTrans};
+
+#[cfg(feature = "float")]
+fn measured_values(number: f32) -> [I2cTrans; 2] {
+    let values: [f32; 10] = [1.0, 1.0, 1.0, 1.0, number, number, number, number, number, 0.5];
+    let data: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+
+    [
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, testing::with_crc(&data)),
+    ]
+}
+
+fn measured_values_u16(number: u16) -> [I2cTrans; 2] {
+    let air_info = AirInfoU16 {
+        mass_pm1_0: 1,
+        mass_pm2_5: 1,
+        mass_pm4_0: 1,
+        mass_pm10: 1,
+        number_pm0_5: number,
+        number_pm1_0: number,
+        number_pm2_5: number,
+        number_pm4_0: number,
+        number_pm10: number,
+        typical_size: 500,
+    };
+
+    [
+        I2cTrans::write(DEV_ADDR, testing::read_measured_values()),
+        I2cTrans::read(DEV_ADDR, testing::measured_values_frame_u16(&air_info)),
+    ]
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_warm_up_window() {
+    let warm_up = WarmUp::default();
+
+    assert_eq!(warm_up.window_ms(500.0), 8_000);
+    assert_eq!(warm_up.window_ms(150.0), 16_000);
+    assert_eq!(warm_up.window_ms(20.0), 30_000);
+    assert!(warm_up.is_warming_up(7_999, 500.0));
+    assert!(!warm_up.is_warming_up(8_000, 500.0));
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_no_clock_is_never_warming_up() {
+    let mut expectations = vec![I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::Float))];
+    expectations.extend_from_slice(&measured_values(500.0));
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay);
+
+    sensor.start_measurement().unwrap();
+    let reading = sensor.read_validated_values().unwrap();
+    assert!(reading.is_valid());
+    assert_eq!(reading.timestamp, None);
+    assert_eq!(sensor.measurement_started(), None);
+
+    sensor.destroy();
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_readings_are_tagged_while_warming_up() {
+    let mut expectations = vec![I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::Float))];
+    expectations.extend_from_slice(&measured_values(500.0));
+    expectations.extend_from_slice(&measured_values(500.0));
+    expectations.extend_from_slice(&measured_values(50.0));
+    expectations.push(I2cTrans::write(DEV_ADDR, testing::stop_measurement()));
+    expectations.extend_from_slice(&measured_values(500.0));
+
+    let clock = ManualClock::new();
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay)
+        .with_clock(clock.clone());
+
+    clock.set(1_000);
+    sensor.start_measurement().unwrap();
+    assert_eq!(sensor.measurement_started(), Some(1_000));
+
+    clock.set(5_000);
+    let reading = sensor.read_validated_values().unwrap();
+    assert_eq!(reading.flags, QualityFlags::WARMING_UP);
+    assert_eq!(reading.timestamp, Some(5_000));
+
+    clock.set(9_000);
+    assert!(sensor.read_validated_values().unwrap().is_valid());
+
+    // Low concentrations need longer to stabilize
+    assert_eq!(sensor.read_validated_values().unwrap().flags, QualityFlags::WARMING_UP);
+
+    sensor.stop_measurement().unwrap();
+    assert_eq!(sensor.measurement_started(), None);
+    assert!(sensor.read_validated_values().unwrap().is_valid());
+
+    sensor.destroy();
+}
+
+#[test]
+#[cfg(feature = "float")]
+fn test_read_stable_values() {
+    let mut expectations = vec![I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::Float))];
+    expectations.extend_from_slice(&measured_values(500.0));
+    expectations.extend_from_slice(&measured_values(500.0));
+
+    let clock = ManualClock::new();
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay)
+        .with_clock(clock.clone());
+    sensor.set_warm_up(WarmUp { stabilization_ms: 2_000, ..WarmUp::default() });
+
+    sensor.start_measurement().unwrap();
+    clock.set(1_000);
+    assert_eq!(sensor.read_stable_values().unwrap(), None);
+    clock.set(2_000);
+    assert!(sensor.read_stable_values().unwrap().is_some());
+
+    sensor.destroy();
+}
+
+#[test]
+fn test_warm_up_window_u16() {
+    let warm_up = WarmUp::default();
+
+    assert_eq!(warm_up.window_ms_u16(500), 8_000);
+    assert_eq!(warm_up.window_ms_u16(200), 8_000);
+    assert_eq!(warm_up.window_ms_u16(150), 16_000);
+    assert_eq!(warm_up.window_ms_u16(20), 30_000);
+    assert!(warm_up.is_warming_up_u16(7_999, 500));
+    assert!(!warm_up.is_warming_up_u16(8_000, 500));
+}
+
+#[test]
+fn test_read_stable_values_u16() {
+    let mut expectations = vec![I2cTrans::write(DEV_ADDR, testing::start_measurement(OutputFormat::UInt16))];
+    expectations.extend_from_slice(&measured_values_u16(500));
+    expectations.extend_from_slice(&measured_values_u16(50));
+    expectations.extend_from_slice(&measured_values_u16(500));
+
+    let clock = ManualClock::new();
+    let mut sensor = Sps30::new_sps30(I2cMock::new(&expectations), NoopDelay)
+        .with_clock(clock.clone());
+
+    sensor.start_measurement_with_format(OutputFormat::UInt16).unwrap();
+    clock.set(5_000);
+    let reading = sensor.read_validated_values_u16().unwrap();
+    assert_eq!(reading.flags, QualityFlags::WARMING_UP);
+    assert_eq!(reading.timestamp, Some(5_000));
+
+    // Low concentrations need longer to stabilize
+    clock.set(9_000);
+    assert_eq!(sensor.read_stable_values_u16().unwrap(), None);
+    let reading = sensor.read_stable_values_u16().unwrap().unwrap();
+    assert_eq!(reading.air_info.number_pm10, 500);
+
+    sensor.destroy();
+}